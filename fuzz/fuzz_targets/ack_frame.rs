@@ -0,0 +1,20 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate quic;
+
+use quic::frames::ack_frame::AckFrame;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((frame, len)) = AckFrame::decode(data) {
+        assert!(len <= data.len());
+
+        let bytes = frame.as_bytes();
+        let (parsed, parsed_len) = AckFrame::decode(&bytes).expect("serialized ACK does not parse");
+
+        assert_eq!(parsed, frame);
+        assert_eq!(parsed_len, bytes.len());
+
+        // Must not panic, however odd the blocks are.
+        let _ = frame.ranges();
+    }
+});
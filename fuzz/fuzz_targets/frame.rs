@@ -0,0 +1,23 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate bytes;
+extern crate quic;
+
+use bytes::Bytes;
+use quic::frames::QuicFrame;
+
+// A frame that parses has to come back the same after a round trip, and
+// take up exactly as many bytes as it was written in.
+fuzz_target!(|data: &[u8]| {
+    let buf = Bytes::from(data);
+
+    if let Ok((frame, len)) = QuicFrame::decode(&buf) {
+        assert!(len <= buf.len());
+
+        let bytes = Bytes::from(frame.as_bytes());
+        let (parsed, parsed_len) = QuicFrame::decode(&bytes).expect("serialized frame does not parse");
+
+        assert_eq!(parsed, frame);
+        assert_eq!(parsed_len, bytes.len());
+    }
+});
@@ -0,0 +1,28 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate bytes;
+extern crate quic;
+
+use bytes::Bytes;
+use quic::frames::QuicFrame;
+use quic::frames::V1Frame;
+
+// The length a frame decodes with has to cover all of it and nothing
+// more: decoding just those bytes gives the same frame and length back.
+fuzz_target!(|data: &[u8]| {
+    let buf = Bytes::from(data);
+
+    if let Ok((frame, len)) = QuicFrame::decode(&buf) {
+        assert!(len <= buf.len());
+
+        let decoded = QuicFrame::decode(&buf.slice_to(len)).expect("frame does not parse from its own length");
+        assert_eq!(decoded, (frame, len));
+    }
+
+    if let Ok((frame, len)) = V1Frame::decode(&buf) {
+        assert!(len <= buf.len());
+
+        let decoded = V1Frame::decode(&buf.slice_to(len)).expect("frame does not parse from its own length");
+        assert_eq!(decoded, (frame, len));
+    }
+});
@@ -0,0 +1,26 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate bytes;
+extern crate quic;
+
+use bytes::{Bytes, BytesMut};
+use quic::frames::V1Frame;
+
+// A frame that parses has to come back the same after a round trip, and
+// take up exactly as many bytes as it was written in.
+fuzz_target!(|data: &[u8]| {
+    let buf = Bytes::from(data);
+
+    if let Ok((frame, len)) = V1Frame::decode(&buf) {
+        assert!(len <= buf.len());
+
+        let mut bytes = BytesMut::new();
+        frame.write_to(&mut bytes).expect("parsed frame does not serialize");
+
+        let bytes = bytes.freeze();
+        let (parsed, parsed_len) = V1Frame::decode(&bytes).expect("serialized frame does not parse");
+
+        assert_eq!(parsed, frame);
+        assert_eq!(parsed_len, bytes.len());
+    }
+});
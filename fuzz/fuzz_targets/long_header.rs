@@ -0,0 +1,16 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate quic;
+
+use quic::header::LongHeader;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(header) = LongHeader::from_bytes(data) {
+        assert!(header.header_len() <= data.len());
+
+        let bytes = header.as_bytes();
+
+        assert_eq!(bytes.len(), header.header_len());
+        assert_eq!(LongHeader::from_bytes(&bytes).expect("serialized header does not parse"), header);
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate bytes;
+extern crate quic;
+
+use bytes::Bytes;
+use quic::packet::QuicPacket;
+
+fuzz_target!(|data: &[u8]| {
+    let buf = Bytes::from(data);
+
+    let _ = QuicPacket::from_bytes(&buf);
+
+    if let Ok(packet) = QuicPacket::from_bytes_unprotected(&buf) {
+        let bytes = Bytes::from(packet.as_bytes().expect("parsed packet does not serialize"));
+
+        assert_eq!(QuicPacket::from_bytes_unprotected(&bytes).expect("serialized packet does not parse"), packet);
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate bytes;
+extern crate quic;
+
+use bytes::Bytes;
+use quic::packet::QuicPacket;
+use quic::packet::QuicPayload;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(frames) = QuicPacket::parse_decrypted_payload(&Bytes::from(data)) {
+        let payload = QuicPayload::Frames(frames);
+        let bytes = Bytes::from(payload.as_bytes());
+
+        let parsed = QuicPacket::parse_decrypted_payload(&bytes).expect("serialized payload does not parse");
+
+        assert_eq!(QuicPayload::Frames(parsed), payload);
+    }
+});
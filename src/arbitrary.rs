@@ -0,0 +1,577 @@
+//! quickcheck generators for frames, headers and packets. Everything
+//! generated is something the parsers accept, so serializing it and
+//! parsing it again has to give back the same value.
+
+use bytes::Bytes;
+use quickcheck::{Arbitrary, Gen};
+
+use frames::QuicFrame;
+use frames::V1Frame;
+use frames::stream_frame::StreamFrame;
+use frames::ack_frame::{AckFrame, AckBlock, AckTimestamp, AckTimestampValue};
+use frames::max_data_frame::MaxDataFrame;
+use frames::max_stream_data_frame::MaxStreamDataFrame;
+use frames::max_stream_id_frame::MaxStreamIdFrame;
+use frames::blocked_frame::BlockedFrame;
+use frames::stream_blocked_frame::StreamBlockedFrame;
+use frames::stream_id_needed_frame::StreamIdNeededFrame;
+use frames::padding_frame::PaddingFrame;
+use frames::ping_frame::PingFrame;
+use frames::new_connection_id_frame::NewConnectionIdFrame;
+use frames::connection_close_frame::ConnectionCloseFrame;
+use frames::goaway_frame::GoAwayFrame;
+use frames::reset_stream_frame::ResetStreamFrame;
+use frames::crypto_frame::CryptoFrame;
+use frames::new_token_frame::NewTokenFrame;
+use frames::max_streams_frame::{MaxStreamsFrame, MAX_STREAM_COUNT};
+use frames::streams_blocked_frame::StreamsBlockedFrame;
+use frames::retire_connection_id_frame::RetireConnectionIdFrame;
+use frames::path_challenge_frame::PathChallengeFrame;
+use frames::path_response_frame::PathResponseFrame;
+use frames::handshake_done_frame::HandshakeDoneFrame;
+use frames::v1_ack_frame::{V1AckFrame, AckRange, EcnCounts};
+use frames::v1_stream_frame::V1StreamFrame;
+use frames::v1_reset_stream_frame::V1ResetStreamFrame;
+use frames::stop_sending_frame::StopSendingFrame;
+use frames::v1_max_stream_data_frame::V1MaxStreamDataFrame;
+use frames::data_blocked_frame::DataBlockedFrame;
+use frames::stream_data_blocked_frame::StreamDataBlockedFrame;
+use frames::v1_new_connection_id_frame::V1NewConnectionIdFrame;
+use frames::v1_connection_close_frame::V1ConnectionCloseFrame;
+
+use header::QuicHeader;
+use header::LongHeader;
+use header::ShortHeader;
+
+use packet::QuicPacket;
+use packet::QuicPayload;
+use packet::VersionNegotiationPayload;
+use packet::ShortPacketType;
+use packet::PacketType;
+use packet::{ONE_BYTE, TWO_BYTES, FOUR_BYTES};
+use packet::{VERSION_NEGOTIATION, CLIENT_CLEARTEXT, NON_FINAL_CLEARTEXT, FINAL_SERVER_CLEAR_TEXT};
+use packet::{RTT0_ENCRYPTED, RETRY};
+
+use util::MAX_VARINT;
+
+/// A number up to `max`, of a random bit width so that every field size
+/// gets used rather than mostly the largest. Now and then it is right on
+/// the edge of a field size, where encoders go wrong.
+fn uint<G: Gen>(g: &mut G, max: u64) -> u64 {
+    if g.gen_weighted_bool(4) {
+        let edge = 1u64.checked_shl(*g.choose(&[8, 14, 16, 30, 32, 48, 62]).unwrap()).unwrap();
+        let value = edge - g.gen_range(0, 2);
+
+        return if value <= max { value } else { max };
+    }
+
+    let bits = g.gen_range(0, 65);
+    let value = if bits == 0 { 0 } else { g.gen::<u64>() >> (64 - bits) };
+
+    value % max.saturating_add(1).max(1)
+}
+
+fn bytes<G: Gen>(g: &mut G, min_len: usize, max_len: usize) -> Bytes {
+    let len = g.gen_range(min_len, max_len + 1);
+
+    Bytes::from((0..len).map(|_| g.gen()).collect::<Vec<u8>>())
+}
+
+fn payload_len<G: Gen>(g: &mut G) -> usize {
+    g.gen_range(0, g.size() * 4 + 1)
+}
+
+impl Arbitrary for StreamFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> StreamFrame {
+        let fin = g.gen();
+        let data_length_present = g.gen();
+
+        // Only a FIN may come without data.
+        let min_len = if fin { 0 } else { 1 };
+        let max_len = payload_len(g).max(min_len);
+        let stream_data = bytes(g, min_len, max_len);
+
+        StreamFrame {
+            fin: fin,
+            data_length_present: data_length_present,
+            data_length: if data_length_present { Some(stream_data.len() as u16) } else { None },
+            stream_id: uint(g, u32::MAX as u64) as u32,
+            offset: uint(g, u64::MAX - stream_data.len() as u64),
+            stream_data: stream_data,
+        }
+    }
+}
+
+impl Arbitrary for AckFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> AckFrame {
+        let largest_ack = uint(g, (1 << 48) - 1);
+        let first_ack_len = uint(g, largest_ack);
+
+        // Each block has to stay at or above packet zero.
+        let mut ack_blocks = Vec::new();
+        let mut range_start = largest_ack - first_ack_len;
+
+        for _ in 0..g.gen_range(0, g.size() + 1) {
+            if range_start == 0 {
+                break;
+            }
+
+            let gap = uint(g, (range_start - 1).min(u8::MAX as u64));
+            let range_end = range_start - gap - 1;
+            let block_len = uint(g, range_end + 1);
+
+            ack_blocks.push(AckBlock { gap: gap as u8, block_len: block_len });
+
+            range_start = if block_len == 0 { range_end + 1 } else { range_end - (block_len - 1) };
+        }
+
+        let num_blocks = if ack_blocks.is_empty() && g.gen() {
+            None
+        } else {
+            Some(ack_blocks.len() as u8)
+        };
+
+        let timestamps: Vec<AckTimestamp> = (0..g.gen_range(0, 4)).map(|_| AckTimestamp {
+            delta_la: g.gen(),
+            time_since_prev: AckTimestampValue::from_u16(g.gen()).unwrap(),
+        }).collect();
+
+        AckFrame {
+            num_blocks: num_blocks,
+            num_ts: timestamps.len() as u8,
+            largest_ack: largest_ack,
+            ack_delay: g.gen(),
+            first_ack_len: first_ack_len,
+            ack_blocks: if ack_blocks.is_empty() { None } else { Some(ack_blocks) },
+            delta_la: if timestamps.is_empty() { None } else { Some(g.gen()) },
+            first_ts: if timestamps.is_empty() { None } else { Some(g.gen()) },
+            timestamps: if timestamps.is_empty() { None } else { Some(timestamps) },
+        }
+    }
+}
+
+impl Arbitrary for MaxDataFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> MaxDataFrame {
+        MaxDataFrame { max_data: uint(g, u64::MAX) }
+    }
+}
+
+impl Arbitrary for MaxStreamDataFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> MaxStreamDataFrame {
+        MaxStreamDataFrame {
+            stream_id: g.gen(),
+            max_stream_data: uint(g, u64::MAX),
+        }
+    }
+}
+
+impl Arbitrary for MaxStreamIdFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> MaxStreamIdFrame {
+        MaxStreamIdFrame { max_stream_id: g.gen() }
+    }
+}
+
+impl Arbitrary for BlockedFrame {
+    fn arbitrary<G: Gen>(_: &mut G) -> BlockedFrame {
+        BlockedFrame {}
+    }
+}
+
+impl Arbitrary for StreamBlockedFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> StreamBlockedFrame {
+        StreamBlockedFrame { stream_id: g.gen() }
+    }
+}
+
+impl Arbitrary for StreamIdNeededFrame {
+    fn arbitrary<G: Gen>(_: &mut G) -> StreamIdNeededFrame {
+        StreamIdNeededFrame {}
+    }
+}
+
+impl Arbitrary for PaddingFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> PaddingFrame {
+        PaddingFrame { length: g.gen_range(1, g.size() + 2) }
+    }
+}
+
+impl Arbitrary for PingFrame {
+    fn arbitrary<G: Gen>(_: &mut G) -> PingFrame {
+        PingFrame {}
+    }
+}
+
+impl Arbitrary for NewConnectionIdFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> NewConnectionIdFrame {
+        NewConnectionIdFrame {
+            sequence: g.gen(),
+            connection_id: g.gen(),
+            packet_number_gap: g.gen(),
+        }
+    }
+}
+
+impl Arbitrary for ConnectionCloseFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> ConnectionCloseFrame {
+        let reason_phrase = if g.gen() {
+            let len = g.gen_range(1, g.size() + 2);
+            Some((0..len).map(|_| g.gen::<char>()).collect::<String>())
+        } else {
+            None
+        };
+
+        ConnectionCloseFrame {
+            error_code: g.gen(),
+            reason_length: reason_phrase.as_ref().map_or(0, |phrase| phrase.len() as u16),
+            reason_phrase: reason_phrase,
+        }
+    }
+}
+
+impl Arbitrary for GoAwayFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> GoAwayFrame {
+        GoAwayFrame {
+            largest_client_stream_id: g.gen(),
+            largest_server_stream_id: g.gen(),
+        }
+    }
+}
+
+impl Arbitrary for ResetStreamFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> ResetStreamFrame {
+        ResetStreamFrame {
+            error_code: g.gen(),
+            stream_id: g.gen(),
+            final_offset: uint(g, u64::MAX),
+        }
+    }
+}
+
+impl Arbitrary for CryptoFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> CryptoFrame {
+        let max_len = payload_len(g);
+        let crypto_data = bytes(g, 0, max_len);
+
+        CryptoFrame {
+            offset: uint(g, MAX_VARINT - crypto_data.len() as u64),
+            crypto_data: crypto_data,
+        }
+    }
+}
+
+impl Arbitrary for NewTokenFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> NewTokenFrame {
+        let max_len = payload_len(g).max(1);
+
+        NewTokenFrame { token: bytes(g, 1, max_len) }
+    }
+}
+
+impl Arbitrary for MaxStreamsFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> MaxStreamsFrame {
+        MaxStreamsFrame {
+            bidirectional: g.gen(),
+            maximum_streams: uint(g, MAX_STREAM_COUNT),
+        }
+    }
+}
+
+impl Arbitrary for StreamsBlockedFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> StreamsBlockedFrame {
+        StreamsBlockedFrame {
+            bidirectional: g.gen(),
+            stream_limit: uint(g, MAX_STREAM_COUNT),
+        }
+    }
+}
+
+impl Arbitrary for RetireConnectionIdFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> RetireConnectionIdFrame {
+        RetireConnectionIdFrame { sequence_number: uint(g, MAX_VARINT) }
+    }
+}
+
+impl Arbitrary for PathChallengeFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> PathChallengeFrame {
+        PathChallengeFrame { data: g.gen() }
+    }
+}
+
+impl Arbitrary for PathResponseFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> PathResponseFrame {
+        PathResponseFrame { data: g.gen() }
+    }
+}
+
+impl Arbitrary for HandshakeDoneFrame {
+    fn arbitrary<G: Gen>(_: &mut G) -> HandshakeDoneFrame {
+        HandshakeDoneFrame {}
+    }
+}
+
+impl Arbitrary for V1AckFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> V1AckFrame {
+        let largest_acknowledged = uint(g, MAX_VARINT);
+        let first_ack_range = uint(g, largest_acknowledged);
+
+        let mut ack_ranges = Vec::new();
+        let mut smallest = largest_acknowledged - first_ack_range;
+
+        for _ in 0..g.gen_range(0, g.size() + 1) {
+            if smallest < 2 {
+                break;
+            }
+
+            let gap = uint(g, smallest - 2);
+            let largest = smallest - gap - 2;
+            let length = uint(g, largest);
+
+            ack_ranges.push(AckRange { gap: gap, length: length });
+            smallest = largest - length;
+        }
+
+        let ecn_counts = if g.gen() {
+            Some(EcnCounts {
+                ect0_count: uint(g, MAX_VARINT),
+                ect1_count: uint(g, MAX_VARINT),
+                ecn_ce_count: uint(g, MAX_VARINT),
+            })
+        } else {
+            None
+        };
+
+        V1AckFrame {
+            largest_acknowledged: largest_acknowledged,
+            ack_delay: uint(g, MAX_VARINT),
+            first_ack_range: first_ack_range,
+            ack_ranges: ack_ranges,
+            ecn_counts: ecn_counts,
+        }
+    }
+}
+
+impl Arbitrary for V1StreamFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> V1StreamFrame {
+        let max_len = payload_len(g);
+        let stream_data = bytes(g, 0, max_len);
+
+        V1StreamFrame {
+            stream_id: uint(g, MAX_VARINT),
+            offset: uint(g, MAX_VARINT - stream_data.len() as u64),
+            length_present: g.gen(),
+            fin: g.gen(),
+            stream_data: stream_data,
+        }
+    }
+}
+
+impl Arbitrary for V1ResetStreamFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> V1ResetStreamFrame {
+        V1ResetStreamFrame {
+            stream_id: uint(g, MAX_VARINT),
+            application_error_code: uint(g, MAX_VARINT),
+            final_size: uint(g, MAX_VARINT),
+        }
+    }
+}
+
+impl Arbitrary for StopSendingFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> StopSendingFrame {
+        StopSendingFrame {
+            stream_id: uint(g, MAX_VARINT),
+            application_error_code: uint(g, MAX_VARINT),
+        }
+    }
+}
+
+impl Arbitrary for V1MaxStreamDataFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> V1MaxStreamDataFrame {
+        V1MaxStreamDataFrame {
+            stream_id: uint(g, MAX_VARINT),
+            maximum_stream_data: uint(g, MAX_VARINT),
+        }
+    }
+}
+
+impl Arbitrary for DataBlockedFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> DataBlockedFrame {
+        DataBlockedFrame { maximum_data: uint(g, MAX_VARINT) }
+    }
+}
+
+impl Arbitrary for StreamDataBlockedFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> StreamDataBlockedFrame {
+        StreamDataBlockedFrame {
+            stream_id: uint(g, MAX_VARINT),
+            maximum_stream_data: uint(g, MAX_VARINT),
+        }
+    }
+}
+
+impl Arbitrary for V1NewConnectionIdFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> V1NewConnectionIdFrame {
+        let sequence_number = uint(g, MAX_VARINT);
+
+        V1NewConnectionIdFrame {
+            sequence_number: sequence_number,
+            retire_prior_to: uint(g, sequence_number),
+            connection_id: bytes(g, 1, 20),
+            stateless_reset_token: g.gen(),
+        }
+    }
+}
+
+impl Arbitrary for V1ConnectionCloseFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> V1ConnectionCloseFrame {
+        let len = g.gen_range(0, g.size() + 1);
+
+        V1ConnectionCloseFrame {
+            error_code: uint(g, MAX_VARINT),
+            frame_type: if g.gen() { Some(uint(g, MAX_VARINT)) } else { None },
+            reason_phrase: (0..len).map(|_| g.gen::<char>()).collect(),
+        }
+    }
+}
+
+impl Arbitrary for QuicFrame {
+    fn arbitrary<G: Gen>(g: &mut G) -> QuicFrame {
+        match g.gen_range(0, 14) {
+            0 => QuicFrame::Stream(StreamFrame::arbitrary(g)),
+            1 => QuicFrame::Ack(AckFrame::arbitrary(g)),
+            2 => QuicFrame::MaxData(MaxDataFrame::arbitrary(g)),
+            3 => QuicFrame::MaxStreamData(MaxStreamDataFrame::arbitrary(g)),
+            4 => QuicFrame::MaxStreamId(MaxStreamIdFrame::arbitrary(g)),
+            5 => QuicFrame::Blocked(BlockedFrame::arbitrary(g)),
+            6 => QuicFrame::StreamBlocked(StreamBlockedFrame::arbitrary(g)),
+            7 => QuicFrame::StreamIdNeeded(StreamIdNeededFrame::arbitrary(g)),
+            8 => QuicFrame::Padding(PaddingFrame::arbitrary(g)),
+            9 => QuicFrame::Ping(PingFrame::arbitrary(g)),
+            10 => QuicFrame::NewConnectionId(NewConnectionIdFrame::arbitrary(g)),
+            11 => QuicFrame::ConnectionClose(ConnectionCloseFrame::arbitrary(g)),
+            12 => QuicFrame::GoAway(GoAwayFrame::arbitrary(g)),
+            _ => QuicFrame::ResetStream(ResetStreamFrame::arbitrary(g)),
+        }
+    }
+}
+
+impl Arbitrary for V1Frame {
+    fn arbitrary<G: Gen>(g: &mut G) -> V1Frame {
+        match g.gen_range(0, 20) {
+            0 => V1Frame::Padding(PaddingFrame::arbitrary(g)),
+            1 => V1Frame::Ping(PingFrame::arbitrary(g)),
+            2 => V1Frame::Ack(V1AckFrame::arbitrary(g)),
+            3 => V1Frame::ResetStream(V1ResetStreamFrame::arbitrary(g)),
+            4 => V1Frame::StopSending(StopSendingFrame::arbitrary(g)),
+            5 => V1Frame::Crypto(CryptoFrame::arbitrary(g)),
+            6 => V1Frame::NewToken(NewTokenFrame::arbitrary(g)),
+            7 => V1Frame::Stream(V1StreamFrame::arbitrary(g)),
+            8 => V1Frame::MaxData(MaxDataFrame::arbitrary(g)),
+            9 => V1Frame::MaxStreamData(V1MaxStreamDataFrame::arbitrary(g)),
+            10 => V1Frame::MaxStreams(MaxStreamsFrame::arbitrary(g)),
+            11 => V1Frame::DataBlocked(DataBlockedFrame::arbitrary(g)),
+            12 => V1Frame::StreamDataBlocked(StreamDataBlockedFrame::arbitrary(g)),
+            13 => V1Frame::StreamsBlocked(StreamsBlockedFrame::arbitrary(g)),
+            14 => V1Frame::NewConnectionId(V1NewConnectionIdFrame::arbitrary(g)),
+            15 => V1Frame::RetireConnectionId(RetireConnectionIdFrame::arbitrary(g)),
+            16 => V1Frame::PathChallenge(PathChallengeFrame::arbitrary(g)),
+            17 => V1Frame::PathResponse(PathResponseFrame::arbitrary(g)),
+            18 => V1Frame::ConnectionClose(V1ConnectionCloseFrame::arbitrary(g)),
+            _ => V1Frame::HandshakeDone(HandshakeDoneFrame::arbitrary(g)),
+        }
+    }
+}
+
+impl Arbitrary for ShortHeader {
+    fn arbitrary<G: Gen>(g: &mut G) -> ShortHeader {
+        let packet_type = ShortPacketType::arbitrary(g);
+        let max_packet_number = (1u64 << (8 * packet_type.packet_number_len())) - 1;
+        let conn_id_bit = g.gen();
+
+        ShortHeader {
+            key_phase_bit: g.gen(),
+            conn_id_bit: conn_id_bit,
+            connection_id: if conn_id_bit { Some(g.gen()) } else { None },
+            packet_number: uint(g, max_packet_number),
+            packet_type: packet_type,
+        }
+    }
+}
+
+impl Arbitrary for ShortPacketType {
+    fn arbitrary<G: Gen>(g: &mut G) -> ShortPacketType {
+        *g.choose(&[ONE_BYTE, TWO_BYTES, FOUR_BYTES]).unwrap()
+    }
+}
+
+/// The long header types a draft-05 packet can be parsed with.
+const LONG_PACKET_TYPES: [PacketType; 6] = [
+    VERSION_NEGOTIATION, CLIENT_CLEARTEXT, NON_FINAL_CLEARTEXT, FINAL_SERVER_CLEAR_TEXT, RTT0_ENCRYPTED, RETRY,
+];
+
+impl Arbitrary for LongHeader {
+    fn arbitrary<G: Gen>(g: &mut G) -> LongHeader {
+        LongHeader {
+            packet_type: *g.choose(&LONG_PACKET_TYPES).unwrap(),
+            connection_id: g.gen(),
+            packet_number: g.gen(),
+            version: g.gen_range(1, u32::MAX),
+            // Draft-05 has no token field.
+            token: Bytes::new(),
+        }
+    }
+}
+
+impl Arbitrary for QuicHeader {
+    fn arbitrary<G: Gen>(g: &mut G) -> QuicHeader {
+        if g.gen() {
+            QuicHeader::Long(LongHeader::arbitrary(g))
+        } else {
+            QuicHeader::Short(ShortHeader::arbitrary(g))
+        }
+    }
+}
+
+/// Frames for one packet. Whatever runs to the end of the packet, such as
+/// a STREAM frame without a length or PADDING, can only come last.
+fn packet_frames<G: Gen>(g: &mut G) -> Vec<QuicFrame> {
+    let count = g.gen_range(1, 6);
+    let mut frames = Vec::with_capacity(count);
+
+    while frames.len() < count {
+        let frame = QuicFrame::arbitrary(g);
+
+        let runs_to_end = match frame {
+            QuicFrame::Stream(ref frame) => !frame.data_length_present,
+            QuicFrame::Padding(_) => true,
+            _ => false,
+        };
+
+        if runs_to_end && frames.len() + 1 < count {
+            continue;
+        }
+
+        frames.push(frame);
+    }
+
+    frames
+}
+
+impl Arbitrary for QuicPacket {
+    fn arbitrary<G: Gen>(g: &mut G) -> QuicPacket {
+        let header = QuicHeader::arbitrary(g);
+
+        let payload = match header {
+            QuicHeader::Long(ref header) if header.packet_type == VERSION_NEGOTIATION =>
+                QuicPayload::VersionNegotiation(VersionNegotiationPayload {
+                    versions: (0..g.gen_range(0, 8)).map(|_| g.gen()).collect(),
+                }),
+            QuicHeader::Long(ref header) if header.packet_type == RETRY => QuicPayload::Frames(vec![]),
+            _ => QuicPayload::Frames(packet_frames(g)),
+        };
+
+        QuicPacket {
+            header: header,
+            payload: payload,
+        }
+    }
+}
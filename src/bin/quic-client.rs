@@ -0,0 +1,246 @@
+//! Connects to a server, sends the same data on each of a number of
+//! streams, prints what comes back, and reports how the transfer went.
+//!
+//! Each stream is finished once the data is written, and the client waits
+//! for the server to finish its side of every stream, or for the timeout
+//! to run out.
+
+#![allow(clippy::redundant_field_names)]
+
+extern crate quic;
+extern crate bytes;
+extern crate futures;
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::{Async, Sink, Stream};
+
+use quic::client::{ClientConfig, QuicClient};
+use quic::connection::ConnectionStats;
+use quic::recovery::{NewReno, Unlimited};
+use quic::version;
+
+const USAGE: &str = "\
+usage: quic-client [options] host port
+
+Sends a file, or stdin, on each stream and writes the responses to stdout.
+Transfer statistics go to stderr.
+
+The handshake is an insecure stand-in for TLS: the keys follow from a
+random sent in the clear, so anyone watching can read the traffic. Key log
+lines use INSECURE_HANDSHAKE_* labels, not TLS ones. This is only built
+with --features insecure-handshake.
+
+options:
+    -n, --streams N              number of streams to open (default 1)
+    -f, --file PATH              send this file instead of stdin
+    --quic-version HEX           wire version to offer (default ff000005;
+                                 5a0a0a0a lets the server send Retry)
+    --cc newreno|none            congestion control (default newreno;
+                                 none sends as fast as flow control allows)
+    --keylog PATH                append 1-RTT secrets to PATH
+                                 (default $SSLKEYLOGFILE)
+    --timeout SECS               give up after this long (default 10)
+    -h, --help                   print this message";
+
+/// Stream data is written in pieces no larger than this.
+const CHUNK_SIZE: usize = 16384;
+
+#[derive(Debug)]
+struct Options {
+    host: String,
+    port: u16,
+    streams: usize,
+    file: Option<PathBuf>,
+    config: ClientConfig,
+    timeout: Duration,
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("quic-client: {}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("quic-client: {}", message);
+    process::exit(1);
+}
+
+fn parse_args() -> Options {
+    let mut args = env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut streams = 1;
+    let mut file = None;
+    let mut config = ClientConfig::default();
+    let mut timeout = Duration::from_secs(10);
+
+    config.key_log = env::var_os("SSLKEYLOGFILE").map(PathBuf::from);
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().unwrap_or_else(|| usage_error(&format!("{} needs a value", name)));
+
+        match arg.as_str() {
+            "-n" | "--streams" => {
+                streams = value(&arg).parse().unwrap_or_else(|_| usage_error("--streams needs a number"));
+            },
+            "-f" | "--file" => file = Some(PathBuf::from(value(&arg))),
+            "--quic-version" => {
+                let number = u32::from_str_radix(value(&arg).trim_start_matches("0x"), 16)
+                    .unwrap_or_else(|_| usage_error("--quic-version needs a hex version number"));
+
+                if version::find(number).is_none() {
+                    usage_error(&format!("version {:08x} is not supported", number));
+                }
+
+                config.connection.version = number;
+            },
+            "--cc" => {
+                config.connection.congestion_controller = match value(&arg).as_str() {
+                    "newreno" => || Box::new(NewReno::new()),
+                    "none" => || Box::new(Unlimited),
+                    _ => usage_error("--cc must be newreno or none"),
+                };
+            },
+            "--keylog" => config.key_log = Some(PathBuf::from(value(&arg))),
+            "--timeout" => {
+                let seconds = value(&arg).parse().unwrap_or_else(|_| usage_error("--timeout needs a number of seconds"));
+                timeout = Duration::from_secs(seconds);
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            _ if arg.starts_with('-') && arg != "-" => usage_error(&format!("unknown option {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+
+    if positional.len() != 2 {
+        usage_error("expected a host and a port");
+    }
+
+    let port = positional[1].parse().unwrap_or_else(|_| usage_error("the port must be a number"));
+
+    Options {
+        host: positional[0].clone(),
+        port: port,
+        streams: streams,
+        file: file.filter(|path| path.to_str() != Some("-")),
+        config: config,
+        timeout: timeout,
+    }
+}
+
+fn main() {
+    let options = parse_args();
+
+    let mut data = Vec::new();
+    let read = match options.file {
+        Some(ref path) => File::open(path).and_then(|mut file| file.read_to_end(&mut data)),
+        None => io::stdin().read_to_end(&mut data),
+    };
+
+    if let Err(err) = read {
+        fail(&format!("reading input: {}", err));
+    }
+
+    let deadline = Instant::now() + options.timeout;
+
+    let mut client = QuicClient::with_config(&options.host, options.port, options.config.clone())
+        .unwrap_or_else(|err| fail(&format!("{}:{}: {}", options.host, options.port, err)));
+
+    if let Err(err) = client.connect(options.timeout) {
+        fail(&format!("handshake with {} failed: {}", client.address, err));
+    }
+
+    let start = Instant::now();
+    let data = Bytes::from(data);
+    let mut streams = Vec::with_capacity(options.streams);
+
+    for _ in 0..options.streams {
+        let id = client.connection.open_stream().unwrap_or_else(|err| fail(&format!("opening stream: {}", err)));
+        let stream = client.connection.stream(id).unwrap();
+
+        for offset in (0..data.len()).step_by(CHUNK_SIZE) {
+            let end = std::cmp::min(offset + CHUNK_SIZE, data.len());
+            stream.start_send(data.slice(offset, end)).unwrap_or_else(|err| fail(&format!("stream {}: {}", id, err)));
+        }
+
+        stream.close().unwrap_or_else(|err| fail(&format!("stream {}: {}", id, err)));
+        streams.push((id, Vec::new(), false));
+    }
+
+    let complete = |streams: &[(u32, Vec<u8>, bool)]| streams.iter().all(|&(_, _, finished)| finished);
+
+    while !complete(&streams) && !client.connection.is_closed() && Instant::now() < deadline {
+        if let Err(err) = client.poll(Duration::from_millis(50)) {
+            fail(&format!("{}", err));
+        }
+
+        for &mut (id, ref mut response, ref mut finished) in &mut streams {
+            let stream = client.connection.stream(id).unwrap();
+
+            while !*finished {
+                match stream.poll() {
+                    Ok(Async::Ready(Some(bytes))) => response.extend_from_slice(&bytes),
+                    Ok(Async::Ready(None)) => *finished = true,
+                    Ok(Async::NotReady) => break,
+                    Err(err) => fail(&format!("stream {}: {}", id, err)),
+                }
+            }
+        }
+    }
+
+    let elapsed = start.elapsed();
+    let stats = client.connection.stats();
+    let _ = client.close("");
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    for &(id, ref response, _) in &streams {
+        if streams.len() > 1 {
+            eprintln!("stream {}: {} bytes", id, response.len());
+        }
+
+        stdout.write_all(response).and_then(|_| stdout.flush())
+            .unwrap_or_else(|err| fail(&format!("writing output: {}", err)));
+    }
+
+    let sent = (data.len() * streams.len()) as u64;
+    let received = streams.iter().map(|(_, response, _)| response.len() as u64).sum();
+    report(&stats, elapsed, sent, received);
+
+    if !complete(&streams) {
+        fail("timed out before every stream was answered");
+    }
+}
+
+fn report(stats: &ConnectionStats, elapsed: Duration, sent: u64, received: u64) {
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    let rate = |bytes: u64| if seconds > 0.0 { bytes as f64 * 8.0 / seconds / 1e6 } else { 0.0 };
+    let millis = |duration: Duration| duration.as_secs() as f64 * 1e3 + duration.subsec_nanos() as f64 / 1e6;
+
+    if let Some(handshake) = stats.handshake_duration {
+        eprintln!("handshake:  {:.3} ms", millis(handshake));
+    }
+
+    eprintln!("transfer:   {} bytes sent, {} bytes received in {:.3} s", sent, received, seconds);
+    eprintln!("throughput: {:.3} Mbit/s up, {:.3} Mbit/s down", rate(sent), rate(received));
+    eprintln!("packets:    {} sent ({} bytes), {} received ({} bytes)",
+              stats.packets_sent, stats.bytes_sent, stats.packets_received, stats.bytes_received);
+    eprintln!("rtt:        {:.3} ms smoothed, {:.3} ms min, {:.3} ms latest",
+              millis(stats.rtt.smoothed()), millis(stats.rtt.min()), millis(stats.rtt.latest()));
+
+    let loss = if stats.packets_sent > 0 { stats.packets_lost as f64 * 100.0 / stats.packets_sent as f64 } else { 0.0 };
+
+    eprintln!("loss:       {} packets ({:.2}%), {} retransmitted ({} bytes)",
+              stats.packets_lost, loss, stats.packets_retransmitted, stats.bytes_retransmitted);
+}
@@ -0,0 +1,434 @@
+//! Prints the headers and frames of QUIC datagrams.
+//!
+//! Input can be hex on the command line, or files holding hex (one
+//! datagram per line), a single raw datagram, or a pcap/pcapng capture.
+
+extern crate quic;
+extern crate bytes;
+#[macro_use]
+extern crate serde_json;
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::{Cursor, Read};
+use std::process;
+use std::time::UNIX_EPOCH;
+
+use bytes::{Bytes, BytesMut};
+use serde_json::Value;
+
+use quic::capture::{CapturedDatagram, PcapReader};
+use quic::connection::Side;
+use quic::crypto::InitialKeys;
+use quic::frames::QuicFrame;
+use quic::frames::ack_frame::AckFrame;
+use quic::header::QuicHeader;
+use quic::packet::{QuicPacket, QuicPayload};
+use quic::packet::{CLIENT_CLEARTEXT, RTT0_ENCRYPTED};
+use quic::qlog;
+use quic::version;
+use quic::version::{QuicVersion, DRAFT_05};
+
+const USAGE: &str = "\
+usage: quic-dissect [options] [input...]
+
+Reads datagrams from each input file, or from stdin when none are given.
+Files are read as pcap/pcapng, hex with one datagram per line, or a single
+raw datagram, whichever fits.
+
+options:
+    --hex          inputs are hex datagrams rather than file names
+    --raw          read files as one raw datagram each
+    --pcap         read files as packet captures
+    --unprotected  read protected payloads as cleartext frames, rather than
+                   opening cleartext packets with the Initial keys
+    --json         print one JSON object per datagram
+    -h, --help     print this message";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Detect,
+    Hex,
+    Raw,
+    Pcap,
+}
+
+#[derive(Debug)]
+struct Options {
+    format: Format,
+    unprotected: bool,
+    json: bool,
+    inputs: Vec<String>,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        format: Format::Detect,
+        unprotected: false,
+        json: false,
+        inputs: Vec::new(),
+    };
+
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--hex" => options.format = Format::Hex,
+            "--raw" => options.format = Format::Raw,
+            "--pcap" => options.format = Format::Pcap,
+            "--unprotected" => options.unprotected = true,
+            "--json" => options.json = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            _ if arg.starts_with("--") => {
+                eprintln!("unknown option {}\n\n{}", arg, USAGE);
+                process::exit(2);
+            },
+            _ => options.inputs.push(arg),
+        }
+    }
+
+    options
+}
+
+fn main() {
+    let options = parse_args();
+    let mut datagrams = Vec::new();
+
+    if options.format == Format::Hex && !options.inputs.is_empty() {
+        for (index, input) in options.inputs.iter().enumerate() {
+            match parse_hex(input) {
+                Some(payload) => datagrams.push(datagram(payload)),
+                None => fail(&format!("argument {} is not hex", index + 1)),
+            }
+        }
+    } else if options.inputs.is_empty() {
+        let mut contents = Vec::new();
+
+        if let Err(err) = io::stdin().read_to_end(&mut contents) {
+            fail(&format!("stdin: {}", err));
+        }
+
+        match read_datagrams(options.format, contents) {
+            Ok(read) => datagrams.extend(read),
+            Err(err) => fail(&format!("stdin: {}", err)),
+        }
+    } else {
+        for path in &options.inputs {
+            let mut contents = Vec::new();
+
+            if let Err(err) = File::open(path).and_then(|mut file| file.read_to_end(&mut contents)) {
+                fail(&format!("{}: {}", path, err));
+            }
+
+            match read_datagrams(options.format, contents) {
+                Ok(read) => datagrams.extend(read),
+                Err(err) => fail(&format!("{}: {}", path, err)),
+            }
+        }
+    }
+
+    let mut failures = 0;
+
+    for (index, datagram) in datagrams.iter().enumerate() {
+        let packet = if options.unprotected {
+            QuicPacket::decode_unprotected(wire_version(&datagram.payload), &datagram.payload)
+        } else {
+            open_initial(&datagram.payload)
+        };
+
+        if packet.is_err() {
+            failures += 1;
+        }
+
+        if options.json {
+            println!("{}", datagram_json(index + 1, datagram, &packet));
+        } else {
+            print_datagram(index + 1, datagram, &packet);
+        }
+    }
+
+    if failures > 0 {
+        process::exit(1);
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("quic-dissect: {}", message);
+    process::exit(2);
+}
+
+/// The version a long header names, if it is one of ours. Short headers
+/// don't name one, so they are read as draft-05.
+fn wire_version(payload: &[u8]) -> &'static dyn QuicVersion {
+    version::detect(payload).unwrap_or(&DRAFT_05)
+}
+
+/// Cleartext packets are sealed with keys anyone can derive from the
+/// connection ID, so they are opened with whichever side's keys sealed
+/// them. Everything else is decoded without its payload.
+fn open_initial(payload: &Bytes) -> quic::error::Result<QuicPacket> {
+    let version = wire_version(payload);
+
+    let header = match version.parse_header(payload)? {
+        QuicHeader::Long(header) => header,
+        QuicHeader::Short(_) => return QuicPacket::decode(version, payload),
+    };
+
+    if !header.is_protected() || header.packet_type == RTT0_ENCRYPTED {
+        return QuicPacket::decode(version, payload);
+    }
+
+    // Either side's remote key is the other side's local one.
+    let receiver = if header.packet_type == CLIENT_CLEARTEXT { Side::Server } else { Side::Client };
+    let keys = InitialKeys::from_connection_id(receiver, version.initial_salt(), header.connection_id)?;
+
+    QuicPacket::open(version, &keys.remote, BytesMut::from(&payload[..]))
+}
+
+fn datagram(payload: Vec<u8>) -> CapturedDatagram {
+    CapturedDatagram {
+        time: None,
+        source: "0.0.0.0:0".parse().unwrap(),
+        destination: "0.0.0.0:0".parse().unwrap(),
+        payload: Bytes::from(payload),
+    }
+}
+
+fn read_datagrams(format: Format, contents: Vec<u8>) -> quic::error::Result<Vec<CapturedDatagram>> {
+    let format = match format {
+        Format::Detect if is_capture(&contents) => Format::Pcap,
+        Format::Detect if hex_lines(&contents).is_some() => Format::Hex,
+        Format::Detect => Format::Raw,
+        format => format,
+    };
+
+    match format {
+        Format::Pcap => {
+            let mut reader = PcapReader::new(Cursor::new(contents))?;
+            let mut datagrams = Vec::new();
+
+            while let Some(datagram) = reader.next_datagram()? {
+                datagrams.push(datagram);
+            }
+
+            Ok(datagrams)
+        },
+        Format::Hex => match hex_lines(&contents) {
+            Some(lines) => Ok(lines.into_iter().map(datagram).collect()),
+            None => Err(quic::error::QuicError::ParseError),
+        },
+        _ => Ok(vec![datagram(contents)]),
+    }
+}
+
+fn is_capture(contents: &[u8]) -> bool {
+    let magics: [[u8; 4]; 5] = [
+        [0x0a, 0x0d, 0x0d, 0x0a],
+        [0xd4, 0xc3, 0xb2, 0xa1],
+        [0xa1, 0xb2, 0xc3, 0xd4],
+        [0x4d, 0x3c, 0xb2, 0xa1],
+        [0xa1, 0xb2, 0x3c, 0x4d],
+    ];
+
+    contents.len() >= 4 && magics.iter().any(|magic| contents[..4] == magic[..])
+}
+
+/// One datagram per non-empty line, or None if the text is not all hex.
+fn hex_lines(contents: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let text = ::std::str::from_utf8(contents).ok()?;
+
+    let lines: Option<Vec<Vec<u8>>> = text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_hex)
+        .collect();
+
+    lines.and_then(|lines| if lines.is_empty() { None } else { Some(lines) })
+}
+
+/// Hex digits, optionally prefixed with 0x and split by spaces or colons.
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    let text = text.trim_start_matches("0x");
+    let digits: Vec<u8> = text.bytes().filter(|byte| !b" \t:".contains(byte)).collect();
+
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+
+    digits.chunks(2)
+        .map(|pair| ::std::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+fn print_datagram(index: usize, datagram: &CapturedDatagram, packet: &quic::error::Result<QuicPacket>) {
+    print!("datagram {}: {} bytes", index, datagram.payload.len());
+
+    if datagram.source.port() != 0 {
+        print!(", {} -> {}", datagram.source, datagram.destination);
+    }
+
+    if let Some(since_epoch) = datagram.time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+        print!(", at {}.{:06}", since_epoch.as_secs(), since_epoch.subsec_micros());
+    }
+
+    println!();
+
+    let packet = match *packet {
+        Ok(ref packet) => packet,
+        Err(ref err) => return println!("  error: {}", err),
+    };
+
+    match packet.header {
+        QuicHeader::Long(ref header) => {
+            println!("  long header: {:?}, version {:08x}, connection id {:016x}, packet number {}",
+                     header.packet_type, header.version, header.connection_id, header.packet_number);
+
+            if !header.token.is_empty() {
+                println!("  token: {}", hex(&header.token));
+            }
+        },
+        QuicHeader::Short(ref header) => {
+            print!("  short header: {:?}, key phase {}", header.packet_type, header.key_phase_bit as u8);
+
+            if let Some(connection_id) = header.connection_id {
+                print!(", connection id {:016x}", connection_id);
+            }
+
+            println!(", packet number {}", header.packet_number);
+        },
+    }
+
+    match packet.payload {
+        QuicPayload::Frames(ref frames) if frames.is_empty() => {
+            if let QuicHeader::Short(_) = packet.header {
+                println!("  (protected payload)");
+            }
+        },
+        QuicPayload::Frames(ref frames) => {
+            for frame in frames {
+                print_frame(frame);
+            }
+        },
+        QuicPayload::VersionNegotiation(ref payload) => {
+            let versions: Vec<String> = payload.versions.iter().map(|version| format!("{:08x}", version)).collect();
+            println!("  supported versions: {}", versions.join(", "));
+        },
+        QuicPayload::PublicReset(ref payload) => println!("  public reset: {:?}", payload),
+    }
+}
+
+fn print_frame(frame: &QuicFrame) {
+    match *frame {
+        QuicFrame::Stream(ref f) => {
+            println!("  STREAM stream {}, offset {}..{}, {} bytes{}",
+                     f.stream_id, f.offset, f.offset + f.stream_data.len() as u64, f.stream_data.len(),
+                     if f.fin { ", fin" } else { "" });
+        },
+        QuicFrame::Ack(ref f) => {
+            let delay = f.ack_delay_micros().unwrap_or(0);
+            println!("  ACK largest {}, delay {}us, ranges {}", f.largest_ack, delay, ranges(&f.ranges()));
+
+            for (packet_number, micros) in ack_timestamps(f) {
+                println!("    packet {} received at +{}us", packet_number, micros);
+            }
+        },
+        QuicFrame::Padding(ref f) => println!("  PADDING {} bytes", f.length),
+        ref frame => println!("  {:?}", frame),
+    }
+}
+
+/// Inclusive ranges, highest first, as "10-12, 7".
+fn ranges(ranges: &[(u64, u64)]) -> String {
+    let ranges: Vec<String> = ranges.iter()
+        .map(|&(smallest, largest)| if smallest == largest {
+            smallest.to_string()
+        } else {
+            format!("{}-{}", smallest, largest)
+        })
+        .collect();
+
+    ranges.join(", ")
+}
+
+/// The packets an ACK carries receive times for, each with its time in
+/// microseconds after the largest acknowledged packet was received. The
+/// first timestamp is given relative to that packet, the rest to the
+/// timestamp before.
+fn ack_timestamps(frame: &AckFrame) -> Vec<(u64, u64)> {
+    let mut timestamps = Vec::new();
+
+    if let (Some(delta_la), Some(first_ts)) = (frame.delta_la, frame.first_ts) {
+        let mut micros = first_ts as u64;
+        timestamps.push((frame.largest_ack.saturating_sub(delta_la as u64), micros));
+
+        for timestamp in frame.timestamps.as_ref().map(|t| &t[..]).unwrap_or(&[]) {
+            micros += timestamp.time_since_prev.microseconds;
+            timestamps.push((frame.largest_ack.saturating_sub(timestamp.delta_la as u64), micros));
+        }
+    }
+
+    timestamps
+}
+
+fn datagram_json(index: usize, datagram: &CapturedDatagram, packet: &quic::error::Result<QuicPacket>) -> Value {
+    let mut json = json!({ "datagram": index, "length": datagram.payload.len() });
+
+    if datagram.source.port() != 0 {
+        json["source"] = json!(datagram.source.to_string());
+        json["destination"] = json!(datagram.destination.to_string());
+    }
+
+    if let Some(since_epoch) = datagram.time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+        json["time"] = json!(since_epoch.as_secs() as f64 + since_epoch.subsec_nanos() as f64 / 1e9);
+    }
+
+    let packet = match *packet {
+        Ok(ref packet) => packet,
+        Err(ref err) => {
+            json["error"] = json!(err.to_string());
+            json["raw"] = json!(hex(&datagram.payload));
+            return json;
+        },
+    };
+
+    json["header"] = qlog::header_json(&packet.header);
+
+    match packet.payload {
+        QuicPayload::Frames(ref frames) => {
+            json["frames"] = Value::Array(frames.iter().map(frame_json).collect());
+        },
+        QuicPayload::VersionNegotiation(ref payload) => {
+            json["supported_versions"] = Value::Array(payload.versions.iter()
+                .map(|version| json!(format!("{:08x}", version)))
+                .collect());
+        },
+        QuicPayload::PublicReset(_) => {},
+    }
+
+    json
+}
+
+/// The qlog form of the frame, plus the fields qlog has no place for.
+fn frame_json(frame: &QuicFrame) -> Value {
+    let mut json = qlog::frame_json(frame);
+
+    if let QuicFrame::Ack(ref f) = *frame {
+        json["largest_acknowledged"] = json!(f.largest_ack);
+
+        let timestamps = ack_timestamps(f);
+
+        if !timestamps.is_empty() {
+            json["timestamps"] = Value::Array(timestamps.into_iter()
+                .map(|(packet_number, micros)| json!({ "packet_number": packet_number, "time_us": micros }))
+                .collect());
+        }
+    }
+
+    json
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,442 @@
+//! A server for trying out clients locally. Each stream the client opens
+//! is echoed back, discarded, or answered with a file from a directory,
+//! and a summary of every connection is printed as it closes.
+
+#![allow(clippy::redundant_field_names)]
+
+extern crate quic;
+extern crate bytes;
+extern crate futures;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Read;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use futures::{Async, Sink, Stream};
+
+use quic::capture::KeyLog;
+use quic::connection::{ConnectionState, QuicConnection};
+use quic::insecure_handshake;
+use quic::server::{QuicServer, ServerConfig};
+use quic::stream::QuicStream;
+
+const USAGE: &str = "\
+usage: quic-server [options]
+
+The handshake is an insecure stand-in for TLS: the keys follow from a
+random sent in the clear, so anyone watching can read the traffic. Key log
+lines use INSECURE_HANDSHAKE_* labels, not TLS ones. This is only built
+with --features insecure-handshake.
+
+There are no --cert or --key options: the crate has no TLS to present a
+certificate with. They come with a real handshake.
+
+options:
+    -l, --listen ADDR       address to listen on (default 127.0.0.1:4433)
+    --mode MODE             what to do with each stream:
+                              echo     send back what the client sends
+                              discard  read and drop it
+                              files    read a request line, GET <path>, and
+                                       send that file from --root
+    --root DIR              directory served in files mode
+    --keylog PATH           append 1-RTT secrets to PATH
+                            (default $SSLKEYLOGFILE)
+    -h, --help              print this message";
+
+/// Files are sent in pieces no larger than this.
+const CHUNK_SIZE: usize = 16384;
+
+/// Longest time to block on the socket, so timers fire close to on time.
+const MAX_WAIT_MS: u64 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Echo,
+    Discard,
+    Files,
+}
+
+#[derive(Debug)]
+struct Options {
+    listen: SocketAddr,
+    mode: Mode,
+    root: Option<PathBuf>,
+    key_log: Option<PathBuf>,
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("quic-server: {}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("quic-server: {}", message);
+    process::exit(1);
+}
+
+fn parse_args() -> Options {
+    let mut args = env::args().skip(1);
+    let mut options = Options {
+        listen: "127.0.0.1:4433".parse().unwrap(),
+        mode: Mode::Echo,
+        root: None,
+        key_log: env::var_os("SSLKEYLOGFILE").map(PathBuf::from),
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().unwrap_or_else(|| usage_error(&format!("{} needs a value", name)));
+
+        match arg.as_str() {
+            "-l" | "--listen" => {
+                options.listen = value(&arg).parse().unwrap_or_else(|_| usage_error("--listen needs an address and port"));
+            },
+            "--mode" => {
+                options.mode = match value(&arg).as_str() {
+                    "echo" => Mode::Echo,
+                    "discard" => Mode::Discard,
+                    "files" => Mode::Files,
+                    mode => usage_error(&format!("unknown mode {}", mode)),
+                };
+            },
+            "--root" => options.root = Some(PathBuf::from(value(&arg))),
+            "--keylog" => options.key_log = Some(PathBuf::from(value(&arg))),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            _ => usage_error(&format!("unknown argument {}", arg)),
+        }
+    }
+
+    let root_is_dir = match options.root {
+        Some(ref root) => root.is_dir(),
+        None => false,
+    };
+
+    if options.mode == Mode::Files && !root_is_dir {
+        usage_error("files mode needs --root naming a directory");
+    }
+
+    options
+}
+
+/// What a stream is doing with the client's data.
+#[derive(Debug)]
+enum Handler {
+    Echo,
+    Discard,
+    /// Collecting the request line.
+    Request(Vec<u8>),
+    /// The response has been sent; anything more is ignored.
+    Done,
+}
+
+#[derive(Debug)]
+struct Session {
+    remote: SocketAddr,
+    started: Instant,
+    handlers: HashMap<u32, Handler>,
+    streams: usize,
+    stream_bytes: u64,
+}
+
+fn main() {
+    let options = parse_args();
+
+    let socket = UdpSocket::bind(options.listen)
+        .unwrap_or_else(|err| fail(&format!("binding {}: {}", options.listen, err)));
+
+    let mut key_log = options.key_log.as_ref().map(|path| {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .unwrap_or_else(|err| fail(&format!("{}: {}", path.display(), err)));
+
+        KeyLog::new(file)
+    });
+
+    let mut server = QuicServer::new(ServerConfig::default())
+        .unwrap_or_else(|err| fail(&format!("{}", err)));
+    let mut sessions: HashMap<u64, Session> = HashMap::new();
+    let mut buf = vec![0; 65536];
+
+    eprintln!("quic-server: listening on {}", socket.local_addr().unwrap_or(options.listen));
+
+    loop {
+        let now = Instant::now();
+        let mut wait = Duration::from_millis(MAX_WAIT_MS);
+
+        if let Some(deadline) = server.next_timeout() {
+            wait = if deadline > now { std::cmp::min(wait, deadline - now) } else { Duration::from_millis(1) };
+        }
+
+        socket.set_read_timeout(Some(std::cmp::max(wait, Duration::from_millis(1))))
+            .unwrap_or_else(|err| fail(&format!("{}", err)));
+
+        match socket.recv_from(&mut buf) {
+            Ok((len, remote)) => {
+                // Datagrams that don't belong to anyone are just dropped.
+                let _ = server.on_datagram(Instant::now(), remote, BytesMut::from(&buf[..len]));
+
+                while let Some(connection_id) = server.accept() {
+                    sessions.insert(connection_id, Session {
+                        remote: remote,
+                        started: Instant::now(),
+                        handlers: HashMap::new(),
+                        streams: 0,
+                        stream_bytes: 0,
+                    });
+                }
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {},
+            Err(err) => fail(&format!("{}", err)),
+        }
+
+        let now = Instant::now();
+
+        let closed = match server.next_timeout() {
+            Some(deadline) if deadline <= now => server.on_timeout(now),
+            _ => Vec::new(),
+        };
+
+        for connection in closed {
+            if let Some(session) = sessions.remove(&connection.connection_id) {
+                summarize(&connection, &session);
+            }
+        }
+
+        let mut finished = Vec::new();
+
+        for (&connection_id, session) in &mut sessions {
+            let connection = match server.connection(connection_id) {
+                Some(connection) => connection,
+                None => {
+                    finished.push(connection_id);
+                    continue;
+                },
+            };
+
+            if let Err(err) = serve(connection, session, &options, &mut key_log) {
+                eprintln!("quic-server: connection {:016x}: {}", connection_id, err);
+            }
+
+            if connection.close_reason.is_some() {
+                summarize(connection, session);
+                finished.push(connection_id);
+            }
+        }
+
+        for connection_id in finished {
+            sessions.remove(&connection_id);
+        }
+
+        let mut datagram = BytesMut::new();
+
+        loop {
+            match server.poll_datagram(Instant::now(), &mut datagram) {
+                Ok(Some(remote)) => {
+                    if let Err(err) = socket.send_to(&datagram, remote) {
+                        eprintln!("quic-server: sending to {}: {}", remote, err);
+                    }
+                },
+                Ok(None) => break,
+                Err(err) => {
+                    eprintln!("quic-server: {}", err);
+                    break;
+                },
+            }
+
+            datagram.clear();
+        }
+    }
+}
+
+/// Answers the handshake, then gives each stream's data to its handler.
+fn serve(connection: &mut QuicConnection, session: &mut Session, options: &Options,
+         key_log: &mut Option<KeyLog<File>>) -> quic::error::Result<()> {
+    if let Some(hello) = insecure_handshake::answer(connection, Instant::now())? {
+        if let Some(ref mut key_log) = *key_log {
+            insecure_handshake::log_secrets(key_log, connection, &hello.random)?;
+        }
+    }
+
+    if connection.state != ConnectionState::Open {
+        return Ok(());
+    }
+
+    while let Some(id) = connection.accept_stream() {
+        session.streams += 1;
+        session.handlers.insert(id, match options.mode {
+            Mode::Echo => Handler::Echo,
+            Mode::Discard => Handler::Discard,
+            Mode::Files => Handler::Request(Vec::new()),
+        });
+    }
+
+    let mut ended = Vec::new();
+
+    for (&id, handler) in &mut session.handlers {
+        let stream = match connection.stream(id) {
+            Some(stream) => stream,
+            None => {
+                ended.push(id);
+                continue;
+            },
+        };
+
+        loop {
+            match stream.poll() {
+                Ok(Async::Ready(Some(data))) => {
+                    session.stream_bytes += data.len() as u64;
+                    on_data(stream, handler, data, options)?;
+                },
+                Ok(Async::Ready(None)) => {
+                    on_fin(stream, handler, options)?;
+                    ended.push(id);
+                    break;
+                },
+                Ok(Async::NotReady) => break,
+                Err(_) => {
+                    ended.push(id);
+                    break;
+                },
+            }
+        }
+    }
+
+    for id in ended {
+        session.handlers.remove(&id);
+    }
+
+    Ok(())
+}
+
+fn on_data(stream: &mut QuicStream, handler: &mut Handler, data: Bytes, options: &Options) -> quic::error::Result<()> {
+    let request = match *handler {
+        Handler::Echo => {
+            stream.start_send(data)?;
+            return Ok(());
+        },
+        Handler::Discard | Handler::Done => return Ok(()),
+        Handler::Request(ref mut request) => {
+            request.extend_from_slice(&data);
+
+            match request.iter().position(|&byte| byte == b'\n') {
+                Some(end) => request[..end].to_vec(),
+                None => return Ok(()),
+            }
+        },
+    };
+
+    *handler = Handler::Done;
+    respond(stream, &request, options)
+}
+
+fn on_fin(stream: &mut QuicStream, handler: &mut Handler, options: &Options) -> quic::error::Result<()> {
+    // A request without a newline ends at the FIN.
+    if let Handler::Request(ref request) = *handler {
+        let request = request.clone();
+        return respond(stream, &request, options);
+    }
+
+    if let Handler::Done = *handler {
+        return Ok(());
+    }
+
+    stream.close()?;
+
+    Ok(())
+}
+
+/// Sends the file a `GET <path>` request line names, or a directory
+/// listing, then finishes the stream.
+fn respond(stream: &mut QuicStream, request: &[u8], options: &Options) -> quic::error::Result<()> {
+    let request = String::from_utf8_lossy(request);
+    let request = request.trim();
+
+    let response = match (request.starts_with("GET "), options.root.as_ref()) {
+        (true, Some(root)) => match resolve(root, request[4..].trim()) {
+            Some(path) => read_path(&path),
+            None => Err(io::Error::new(io::ErrorKind::PermissionDenied, "path leaves the root")),
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "expected GET <path>")),
+    };
+
+    let response = response.unwrap_or_else(|err| format!("error: {}\n", err).into_bytes());
+    let response = Bytes::from(response);
+
+    for offset in (0..response.len()).step_by(CHUNK_SIZE) {
+        let end = std::cmp::min(offset + CHUNK_SIZE, response.len());
+        stream.start_send(response.slice(offset, end))?;
+    }
+
+    stream.close()?;
+
+    Ok(())
+}
+
+/// The path under `root` that a request names, unless it tries to climb
+/// out of it.
+fn resolve(root: &Path, request: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+
+    for component in Path::new(request).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir => {},
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(path)
+}
+
+fn read_path(path: &Path) -> io::Result<Vec<u8>> {
+    if !path.is_dir() {
+        let mut contents = Vec::new();
+        File::open(path)?.read_to_end(&mut contents)?;
+
+        return Ok(contents);
+    }
+
+    let mut names = Vec::new();
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+
+        if entry.file_type()?.is_dir() {
+            name.push('/');
+        }
+
+        names.push(name);
+    }
+
+    names.sort();
+
+    Ok(names.into_iter().map(|name| name + "\n").collect::<String>().into_bytes())
+}
+
+fn summarize(connection: &QuicConnection, session: &Session) {
+    let stats = connection.stats();
+    let millis = |duration: Duration| duration.as_secs() as f64 * 1e3 + duration.subsec_nanos() as f64 / 1e6;
+
+    let reason = match connection.close_reason {
+        Some(reason) if reason.is_empty() => "no error".to_string(),
+        Some(reason) => format!("{:?}", reason),
+        None => "open".to_string(),
+    };
+
+    eprintln!("connection {:016x} from {}: {} after {:.3} ms; {} streams, {} stream bytes received; \
+               {} packets sent ({} bytes), {} received ({} bytes), {} lost; rtt {:.3} ms",
+              connection.connection_id, session.remote, reason, millis(session.started.elapsed()),
+              session.streams, session.stream_bytes,
+              stats.packets_sent, stats.bytes_sent, stats.packets_received, stats.bytes_received,
+              stats.packets_lost, millis(stats.rtt.smoothed()));
+}
@@ -0,0 +1,822 @@
+//! Packet captures of an endpoint's datagrams, and key logs that let
+//! packet analyzers decrypt them.
+//!
+//! `PcapWriter` writes pcapng with made-up IP and UDP headers around each
+//! datagram. `PcapReader` reads pcapng or classic pcap files, including
+//! ones taken off a real interface, and hands back the UDP payloads so
+//! they can be replayed through `QuicPacket::from_bytes`.
+
+use std::cmp;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, BigEndian, LittleEndian, WriteBytesExt};
+use bytes::Bytes;
+
+use error::Result;
+use error::QuicError;
+
+use connection::Side;
+
+use packet::QuicPacket;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const SIMPLE_PACKET_BLOCK: u32 = 0x0000_0003;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const DECRYPTION_SECRETS_BLOCK: u32 = 0x0000_000a;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const TLS_KEY_LOG: u32 = 0x544c_534b;
+const IF_TSRESOL: u16 = 9;
+
+const PCAP_MICROS_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_NANOS_MAGIC: u32 = 0xa1b2_3c4d;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const IPPROTO_UDP: u8 = 17;
+
+/// Longer blocks and frames are taken for a corrupt capture rather than
+/// read into memory.
+const MAX_BLOCK_LEN: usize = 16 * 1024 * 1024;
+
+/// Writes datagrams to a pcapng capture. Each one is wrapped in IP and UDP
+/// headers so that analyzers see ordinary UDP traffic.
+#[derive(Debug)]
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl PcapWriter<File> {
+    pub fn create(path: &::std::path::Path) -> io::Result<PcapWriter<File>> {
+        PcapWriter::new(File::create(path)?)
+    }
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the section header and a single raw IP interface.
+    pub fn new(writer: W) -> io::Result<PcapWriter<W>> {
+        let mut pcap = PcapWriter { writer: writer };
+
+        let mut section = Vec::with_capacity(16);
+        section.write_u32::<LittleEndian>(BYTE_ORDER_MAGIC)?;
+        section.write_u16::<LittleEndian>(1)?;
+        section.write_u16::<LittleEndian>(0)?;
+        // Section length not known up front.
+        section.write_i64::<LittleEndian>(-1)?;
+        pcap.write_block(SECTION_HEADER_BLOCK, &section)?;
+
+        let mut interface = Vec::with_capacity(8);
+        interface.write_u16::<LittleEndian>(LINKTYPE_RAW as u16)?;
+        interface.write_u16::<LittleEndian>(0)?;
+        interface.write_u32::<LittleEndian>(0)?;
+        pcap.write_block(INTERFACE_DESCRIPTION_BLOCK, &interface)?;
+
+        Ok(pcap)
+    }
+
+    /// Records one datagram going from `source` to `destination`. Both
+    /// addresses have to be of the same family.
+    pub fn write_datagram(&mut self, time: SystemTime, source: SocketAddr, destination: SocketAddr, datagram: &[u8]) -> io::Result<()> {
+        let packet = ip_packet(source, destination, datagram)?;
+        let micros = micros_since_epoch(time);
+
+        let mut block = Vec::with_capacity(20 + packet.len());
+        block.write_u32::<LittleEndian>(0)?;
+        block.write_u32::<LittleEndian>((micros >> 32) as u32)?;
+        block.write_u32::<LittleEndian>(micros as u32)?;
+        block.write_u32::<LittleEndian>(packet.len() as u32)?;
+        block.write_u32::<LittleEndian>(packet.len() as u32)?;
+        block.extend_from_slice(&packet);
+
+        self.write_block(ENHANCED_PACKET_BLOCK, &block)
+    }
+
+    /// Embeds an SSLKEYLOGFILE-format key log in the capture, so it can be
+    /// decrypted without a separate key log file. Packets it applies to
+    /// should come after it.
+    pub fn write_key_log(&mut self, key_log: &[u8]) -> io::Result<()> {
+        let mut block = Vec::with_capacity(8 + key_log.len());
+        block.write_u32::<LittleEndian>(TLS_KEY_LOG)?;
+        block.write_u32::<LittleEndian>(key_log.len() as u32)?;
+        block.extend_from_slice(key_log);
+
+        self.write_block(DECRYPTION_SECRETS_BLOCK, &block)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Writes a block around `body`, padding it to 32 bits.
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let padding = (4 - body.len() % 4) % 4;
+        let total_len = (12 + body.len() + padding) as u32;
+
+        self.writer.write_u32::<LittleEndian>(block_type)?;
+        self.writer.write_u32::<LittleEndian>(total_len)?;
+        self.writer.write_all(body)?;
+        self.writer.write_all(&[0; 3][..padding])?;
+        self.writer.write_u32::<LittleEndian>(total_len)
+    }
+}
+
+fn micros_since_epoch(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+
+    since_epoch.as_secs() * 1_000_000 + since_epoch.subsec_nanos() as u64 / 1000
+}
+
+fn ip_packet(source: SocketAddr, destination: SocketAddr, datagram: &[u8]) -> io::Result<Vec<u8>> {
+    let udp_len = 8 + datagram.len();
+
+    let mut packet = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) if udp_len + 20 <= 0xffff => {
+            let mut header = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0];
+            BigEndian::write_u16(&mut header[2..4], (20 + udp_len) as u16);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+
+            let checksum = !fold(sum(0, &header));
+            BigEndian::write_u16(&mut header[10..12], checksum);
+
+            header
+        },
+        (IpAddr::V6(src), IpAddr::V6(dst)) if udp_len <= 0xffff => {
+            let mut header = vec![0x60, 0, 0, 0, 0, 0, IPPROTO_UDP, 64];
+            BigEndian::write_u16(&mut header[4..6], udp_len as u16);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+
+            header
+        },
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "mixed address families or datagram too large")),
+    };
+
+    let udp_start = packet.len();
+    packet.write_u16::<BigEndian>(source.port())?;
+    packet.write_u16::<BigEndian>(destination.port())?;
+    packet.write_u16::<BigEndian>(udp_len as u16)?;
+    packet.write_u16::<BigEndian>(0)?;
+    packet.extend_from_slice(datagram);
+
+    let checksum = udp_checksum(source.ip(), destination.ip(), &packet[udp_start..])?;
+    BigEndian::write_u16(&mut packet[udp_start + 6..udp_start + 8], checksum);
+
+    Ok(packet)
+}
+
+/// One's complement sum of `data` as 16-bit words, added to `acc`.
+fn sum(mut acc: u32, data: &[u8]) -> u32 {
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 { BigEndian::read_u16(chunk) } else { (chunk[0] as u16) << 8 };
+        acc += word as u32;
+    }
+
+    acc
+}
+
+fn fold(mut acc: u32) -> u16 {
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+
+    acc as u16
+}
+
+fn udp_checksum(source: IpAddr, destination: IpAddr, udp: &[u8]) -> io::Result<u16> {
+    let mut pseudo_header = Vec::with_capacity(40);
+
+    match (source, destination) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            pseudo_header.extend_from_slice(&src.octets());
+            pseudo_header.extend_from_slice(&dst.octets());
+        },
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            pseudo_header.extend_from_slice(&src.octets());
+            pseudo_header.extend_from_slice(&dst.octets());
+        },
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "mixed address families")),
+    }
+
+    pseudo_header.extend_from_slice(&[0, IPPROTO_UDP]);
+    pseudo_header.write_u16::<BigEndian>(udp.len() as u16)?;
+
+    // A checksum of zero means "none" in UDP, so it is sent as all ones.
+    Ok(match !fold(sum(sum(0, &pseudo_header), udp)) {
+        0 => 0xffff,
+        checksum => checksum,
+    })
+}
+
+/// A UDP datagram read from a capture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedDatagram {
+    /// When it was captured, if the capture recorded that.
+    pub time: Option<SystemTime>,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: Bytes,
+}
+
+impl CapturedDatagram {
+    /// Parses the datagram as a draft-05 packet.
+    pub fn packet(&self) -> Result<QuicPacket> {
+        QuicPacket::from_bytes(&self.payload)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    fn u16(&self, buf: &[u8]) -> u16 {
+        match *self {
+            Endian::Little => LittleEndian::read_u16(buf),
+            Endian::Big => BigEndian::read_u16(buf),
+        }
+    }
+
+    fn u32(&self, buf: &[u8]) -> u32 {
+        match *self {
+            Endian::Little => LittleEndian::read_u32(buf),
+            Endian::Big => BigEndian::read_u32(buf),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+    link_type: u32,
+    /// Timestamp units per second.
+    resolution: u64,
+}
+
+/// A captured link-layer frame: the interface it came in on, its
+/// timestamp if it has one, and its bytes.
+type Frame = (Interface, Option<u64>, Vec<u8>);
+
+#[derive(Debug)]
+enum Format {
+    Pcapng { interfaces: Vec<Interface> },
+    Pcap { interface: Interface },
+}
+
+/// Reads the UDP datagrams out of a pcapng or classic pcap capture.
+/// Anything else in the capture, and IP fragments, are skipped.
+#[derive(Debug)]
+pub struct PcapReader<R: Read> {
+    reader: R,
+    format: Format,
+    endian: Endian,
+}
+
+impl PcapReader<File> {
+    pub fn open(path: &::std::path::Path) -> Result<PcapReader<File>> {
+        PcapReader::new(File::open(path)?)
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> Result<PcapReader<R>> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+
+        if LittleEndian::read_u32(&magic) == SECTION_HEADER_BLOCK {
+            let mut pcap = PcapReader {
+                reader: reader,
+                format: Format::Pcapng { interfaces: Vec::new() },
+                endian: Endian::Little,
+            };
+            pcap.read_section_header()?;
+
+            return Ok(pcap);
+        }
+
+        let endian = match (LittleEndian::read_u32(&magic), BigEndian::read_u32(&magic)) {
+            (PCAP_MICROS_MAGIC, _) | (PCAP_NANOS_MAGIC, _) => Endian::Little,
+            (_, PCAP_MICROS_MAGIC) | (_, PCAP_NANOS_MAGIC) => Endian::Big,
+            _ => return Err(QuicError::ParseError),
+        };
+
+        let resolution = if endian.u32(&magic) == PCAP_NANOS_MAGIC { 1_000_000_000 } else { 1_000_000 };
+
+        let mut header = [0; 20];
+        reader.read_exact(&mut header)?;
+
+        Ok(PcapReader {
+            reader: reader,
+            format: Format::Pcap {
+                interface: Interface {
+                    link_type: endian.u32(&header[16..20]) & 0xffff,
+                    resolution: resolution,
+                },
+            },
+            endian: endian,
+        })
+    }
+
+    /// The next UDP datagram in the capture, or None at the end of it.
+    pub fn next_datagram(&mut self) -> Result<Option<CapturedDatagram>> {
+        loop {
+            let frame = match self.next_frame()? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+
+            let (interface, timestamp, data) = frame;
+
+            if let Some(mut datagram) = udp_datagram(interface.link_type, &data) {
+                datagram.time = match timestamp {
+                    Some(timestamp) => Some(capture_time(timestamp, interface.resolution)?),
+                    None => None,
+                };
+
+                return Ok(Some(datagram));
+            }
+        }
+    }
+
+    /// The next captured frame with the interface it came from and its
+    /// timestamp.
+    fn next_frame(&mut self) -> Result<Option<Frame>> {
+        match self.format {
+            Format::Pcap { interface } => {
+                let mut header = [0; 16];
+
+                if !self.read_or_end(&mut header)? {
+                    return Ok(None);
+                }
+
+                let seconds = self.endian.u32(&header[0..4]) as u64;
+                let fraction = self.endian.u32(&header[4..8]) as u64;
+                let captured_len = self.endian.u32(&header[8..12]) as usize;
+
+                if captured_len > MAX_BLOCK_LEN {
+                    return Err(QuicError::ParseError);
+                }
+
+                let mut data = vec![0; captured_len];
+                self.reader.read_exact(&mut data)?;
+
+                Ok(Some((interface, Some(seconds * interface.resolution + fraction), data)))
+            },
+            Format::Pcapng { .. } => self.next_pcapng_frame(),
+        }
+    }
+
+    fn next_pcapng_frame(&mut self) -> Result<Option<Frame>> {
+        loop {
+            let mut header = [0; 8];
+
+            if !self.read_or_end(&mut header)? {
+                return Ok(None);
+            }
+
+            let block_type = self.endian.u32(&header[0..4]);
+
+            if block_type == SECTION_HEADER_BLOCK {
+                self.read_section_header()?;
+                continue;
+            }
+
+            let total_len = self.endian.u32(&header[4..8]) as usize;
+
+            if !(12..=MAX_BLOCK_LEN).contains(&total_len) || !total_len.is_multiple_of(4) {
+                return Err(QuicError::ParseError);
+            }
+
+            // The body, then the length again.
+            let mut body = vec![0; total_len - 8];
+            self.reader.read_exact(&mut body)?;
+            body.truncate(total_len - 12);
+
+            let endian = self.endian;
+            let interfaces = match self.format {
+                Format::Pcapng { ref mut interfaces } => interfaces,
+                Format::Pcap { .. } => return Err(QuicError::ParseError),
+            };
+
+            match block_type {
+                INTERFACE_DESCRIPTION_BLOCK if body.len() >= 8 => {
+                    interfaces.push(Interface {
+                        link_type: endian.u16(&body[0..2]) as u32,
+                        resolution: timestamp_resolution(endian, &body[8..])?,
+                    });
+                },
+                ENHANCED_PACKET_BLOCK if body.len() >= 20 => {
+                    let interface = *interfaces.get(endian.u32(&body[0..4]) as usize).ok_or(QuicError::ParseError)?;
+                    let timestamp = (endian.u32(&body[4..8]) as u64) << 32 | endian.u32(&body[8..12]) as u64;
+                    let captured_len = endian.u32(&body[12..16]) as usize;
+
+                    if 20 + captured_len > body.len() {
+                        return Err(QuicError::ParseError);
+                    }
+
+                    return Ok(Some((interface, Some(timestamp), body[20..20 + captured_len].to_vec())));
+                },
+                SIMPLE_PACKET_BLOCK if body.len() >= 4 => {
+                    let interface = *interfaces.first().ok_or(QuicError::ParseError)?;
+                    let original_len = endian.u32(&body[0..4]) as usize;
+                    let captured_len = ::std::cmp::min(original_len, body.len() - 4);
+
+                    return Ok(Some((interface, None, body[4..4 + captured_len].to_vec())));
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Reads the rest of a section header block, whose type has already
+    /// been read. Interfaces belong to their section, so they start over.
+    fn read_section_header(&mut self) -> Result<()> {
+        let mut header = [0; 8];
+        self.reader.read_exact(&mut header)?;
+
+        self.endian = match LittleEndian::read_u32(&header[4..8]) {
+            BYTE_ORDER_MAGIC => Endian::Little,
+            _ if BigEndian::read_u32(&header[4..8]) == BYTE_ORDER_MAGIC => Endian::Big,
+            _ => return Err(QuicError::ParseError),
+        };
+
+        let total_len = self.endian.u32(&header[0..4]) as usize;
+
+        if !(28..=MAX_BLOCK_LEN).contains(&total_len) {
+            return Err(QuicError::ParseError);
+        }
+
+        let mut rest = vec![0; total_len - 12];
+        self.reader.read_exact(&mut rest)?;
+
+        self.format = Format::Pcapng { interfaces: Vec::new() };
+
+        Ok(())
+    }
+
+    /// Fills `buf`, or returns false if the capture ends before it.
+    fn read_or_end(&mut self, buf: &mut [u8]) -> Result<bool> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Timestamp units per second, from an interface's options. Resolutions
+/// too fine to count in 64 bits are an error.
+fn timestamp_resolution(endian: Endian, mut options: &[u8]) -> Result<u64> {
+    while options.len() >= 4 {
+        let code = endian.u16(&options[0..2]);
+        let len = endian.u16(&options[2..4]) as usize;
+
+        if options.len() < 4 + len {
+            break;
+        }
+
+        if code == IF_TSRESOL && len >= 1 {
+            let resolution = options[4];
+
+            // The top bit picks powers of two over powers of ten.
+            let units = if resolution & 0x80 != 0 {
+                match resolution & 0x7f {
+                    exponent if exponent < 64 => Some(1u64 << exponent),
+                    _ => None,
+                }
+            } else {
+                10u64.checked_pow(resolution as u32)
+            };
+
+            return units.ok_or(QuicError::ParseError);
+        }
+
+        options = &options[cmp::min(options.len(), 4 + len.div_ceil(4) * 4)..];
+    }
+
+    Ok(1_000_000)
+}
+
+/// The time a timestamp in `resolution` units per second stands for.
+fn capture_time(timestamp: u64, resolution: u64) -> Result<SystemTime> {
+    let secs = timestamp / resolution;
+    let nanos = (timestamp % resolution) as u128 * 1_000_000_000 / resolution as u128;
+
+    UNIX_EPOCH.checked_add(Duration::new(secs, nanos as u32)).ok_or(QuicError::ParseError)
+}
+
+/// The UDP datagram in a captured frame, if it holds one.
+fn udp_datagram(link_type: u32, frame: &[u8]) -> Option<CapturedDatagram> {
+    let packet = match link_type {
+        LINKTYPE_RAW => frame,
+        // The address family, in the byte order of the capturing host.
+        LINKTYPE_NULL if frame.len() >= 4 => &frame[4..],
+        LINKTYPE_ETHERNET if frame.len() >= 14 => {
+            let mut ethertype = BigEndian::read_u16(&frame[12..14]);
+            let mut start = 14;
+
+            if ethertype == ETHERTYPE_VLAN && frame.len() >= 18 {
+                ethertype = BigEndian::read_u16(&frame[16..18]);
+                start = 18;
+            }
+
+            match ethertype {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => &frame[start..],
+                _ => return None,
+            }
+        },
+        LINKTYPE_LINUX_SLL if frame.len() >= 16 => &frame[16..],
+        _ => return None,
+    };
+
+    match packet.first().map(|byte| byte >> 4) {
+        Some(4) => ipv4_datagram(packet),
+        Some(6) => ipv6_datagram(packet),
+        _ => None,
+    }
+}
+
+fn ipv4_datagram(packet: &[u8]) -> Option<CapturedDatagram> {
+    if packet.len() < 20 {
+        return None;
+    }
+
+    let header_len = (packet[0] & 0x0f) as usize * 4;
+    let total_len = BigEndian::read_u16(&packet[2..4]) as usize;
+    let fragmented = BigEndian::read_u16(&packet[6..8]) & 0x3fff != 0;
+
+    if packet[9] != IPPROTO_UDP || fragmented || header_len < 20 || total_len < header_len || total_len > packet.len() {
+        return None;
+    }
+
+    let source = IpAddr::V4(Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]));
+    let destination = IpAddr::V4(Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]));
+
+    udp(source, destination, &packet[header_len..total_len])
+}
+
+/// Extension headers are not followed; only UDP straight after the fixed
+/// header is found.
+fn ipv6_datagram(packet: &[u8]) -> Option<CapturedDatagram> {
+    if packet.len() < 40 || packet[6] != IPPROTO_UDP {
+        return None;
+    }
+
+    let payload_len = BigEndian::read_u16(&packet[4..6]) as usize;
+
+    if 40 + payload_len > packet.len() {
+        return None;
+    }
+
+    let mut source = [0; 16];
+    let mut destination = [0; 16];
+    source.copy_from_slice(&packet[8..24]);
+    destination.copy_from_slice(&packet[24..40]);
+
+    udp(IpAddr::V6(Ipv6Addr::from(source)), IpAddr::V6(Ipv6Addr::from(destination)), &packet[40..40 + payload_len])
+}
+
+fn udp(source: IpAddr, destination: IpAddr, segment: &[u8]) -> Option<CapturedDatagram> {
+    if segment.len() < 8 {
+        return None;
+    }
+
+    let len = BigEndian::read_u16(&segment[4..6]) as usize;
+
+    if len < 8 || len > segment.len() {
+        return None;
+    }
+
+    Some(CapturedDatagram {
+        time: None,
+        source: SocketAddr::new(source, BigEndian::read_u16(&segment[0..2])),
+        destination: SocketAddr::new(destination, BigEndian::read_u16(&segment[2..4])),
+        payload: Bytes::from(&segment[8..len]),
+    })
+}
+
+/// Writes secrets in the NSS key log format that SSLKEYLOGFILE names,
+/// which packet analyzers use to decrypt captures.
+#[derive(Debug)]
+pub struct KeyLog<W: Write> {
+    writer: W,
+}
+
+impl KeyLog<File> {
+    /// Appends to the file named by `SSLKEYLOGFILE`, if it is set.
+    pub fn from_env() -> io::Result<Option<KeyLog<File>>> {
+        match env::var_os("SSLKEYLOGFILE") {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Ok(Some(KeyLog::new(file)))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl<W: Write> KeyLog<W> {
+    pub fn new(writer: W) -> KeyLog<W> {
+        KeyLog { writer: writer }
+    }
+
+    /// Writes one `<label> <client random> <secret>` line. The handshake
+    /// that produced the secret is identified by its ClientHello random.
+    pub fn log(&mut self, label: &str, client_random: &[u8], secret: &[u8]) -> io::Result<()> {
+        writeln!(self.writer, "{} {} {}", label, hex(client_random), hex(secret))?;
+        self.writer.flush()
+    }
+
+    /// Logs the 1-RTT traffic secrets, given the way round `KeySchedule`
+    /// takes them.
+    pub fn log_1rtt(&mut self, side: Side, client_random: &[u8], local_secret: &[u8], remote_secret: &[u8]) -> io::Result<()> {
+        let (client_secret, server_secret) = match side {
+            Side::Client => (local_secret, remote_secret),
+            Side::Server => (remote_secret, local_secret),
+        };
+
+        self.log("CLIENT_TRAFFIC_SECRET_0", client_random, client_secret)?;
+        self.log("SERVER_TRAFFIC_SECRET_0", client_random, server_secret)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use header::QuicHeader;
+    use header::LongHeader;
+    use packet::QuicPayload;
+    use packet::CLIENT_CLEARTEXT;
+    use packet::QUIC_VERSION;
+    use frames::QuicFrame;
+    use frames::padding_frame::PaddingFrame;
+
+    fn initial_datagram() -> Bytes {
+        let packet = QuicPacket {
+            header: QuicHeader::Long(LongHeader {
+                packet_type: CLIENT_CLEARTEXT,
+                connection_id: 0x0123_4567_89ab_cdef,
+                packet_number: 1,
+                version: QUIC_VERSION,
+                token: Bytes::new(),
+            }),
+            payload: QuicPayload::Frames(vec![QuicFrame::Padding(PaddingFrame { length: 1200 })]),
+        };
+
+        let mut buf = BytesMut::new();
+        packet.write_to(&mut buf).unwrap();
+
+        buf.freeze()
+    }
+
+    #[test]
+    fn pcapng_round_trip() {
+        let client: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let server: SocketAddr = "198.51.100.7:4433".parse().unwrap();
+        let client6: SocketAddr = "[2001:db8::1]:50000".parse().unwrap();
+        let server6: SocketAddr = "[2001:db8::2]:4433".parse().unwrap();
+        let time = UNIX_EPOCH + Duration::new(1_500_000_000, 123_456_000);
+        let datagram = initial_datagram();
+
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_key_log(b"CLIENT_TRAFFIC_SECRET_0 00 11\n").unwrap();
+        writer.write_datagram(time, client, server, &datagram).unwrap();
+        writer.write_datagram(time, server6, client6, b"odd").unwrap();
+        let capture = writer.into_inner();
+
+        // Every block is a multiple of four bytes long.
+        assert_eq!(capture.len() % 4, 0);
+
+        let mut reader = PcapReader::new(&capture[..]).unwrap();
+
+        let first = reader.next_datagram().unwrap().unwrap();
+        assert_eq!(first, CapturedDatagram {
+            time: Some(time),
+            source: client,
+            destination: server,
+            payload: datagram.clone(),
+        });
+        assert_eq!(first.packet().unwrap(), QuicPacket::from_bytes(&datagram).unwrap());
+
+        let second = reader.next_datagram().unwrap().unwrap();
+        assert_eq!((second.source, second.destination), (server6, client6));
+        assert_eq!(&second.payload[..], b"odd");
+
+        assert_eq!(reader.next_datagram().unwrap(), None);
+    }
+
+    #[test]
+    fn writes_valid_checksums() {
+        let source: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let destination: SocketAddr = "10.0.0.2:443".parse().unwrap();
+        let packet = ip_packet(source, destination, b"hello").unwrap();
+
+        // Summing a header along with its checksum gives all ones.
+        assert_eq!(fold(sum(0, &packet[..20])), 0xffff);
+
+        let mut pseudo_header = vec![10, 0, 0, 1, 10, 0, 0, 2, 0, IPPROTO_UDP, 0, 13];
+        pseudo_header.extend_from_slice(&packet[20..]);
+        assert_eq!(fold(sum(0, &pseudo_header)), 0xffff);
+
+        assert!(ip_packet(source, "[::1]:443".parse().unwrap(), b"hello").is_err());
+    }
+
+    #[test]
+    fn reads_classic_pcap_from_ethernet() {
+        let source: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let destination: SocketAddr = "192.0.2.2:4433".parse().unwrap();
+        let ip = ip_packet(source, destination, b"datagram").unwrap();
+
+        let mut capture = Vec::new();
+        capture.write_u32::<BigEndian>(PCAP_NANOS_MAGIC).unwrap();
+        capture.extend_from_slice(&[0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 1]);
+
+        let mut frame = vec![0; 12];
+        frame.write_u16::<BigEndian>(ETHERTYPE_IPV4).unwrap();
+        frame.extend_from_slice(&ip);
+
+        for &(seconds, nanos) in &[(10, 5), (11, 999_999_999)] {
+            capture.write_u32::<BigEndian>(seconds).unwrap();
+            capture.write_u32::<BigEndian>(nanos).unwrap();
+            capture.write_u32::<BigEndian>(frame.len() as u32).unwrap();
+            capture.write_u32::<BigEndian>(frame.len() as u32).unwrap();
+            capture.extend_from_slice(&frame);
+        }
+
+        let mut reader = PcapReader::new(&capture[..]).unwrap();
+
+        let first = reader.next_datagram().unwrap().unwrap();
+        assert_eq!(first.time, Some(UNIX_EPOCH + Duration::new(10, 5)));
+        assert_eq!((first.source, first.destination), (source, destination));
+        assert_eq!(&first.payload[..], b"datagram");
+
+        let second = reader.next_datagram().unwrap().unwrap();
+        assert_eq!(second.time, Some(UNIX_EPOCH + Duration::new(11, 999_999_999)));
+
+        assert_eq!(reader.next_datagram().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_corrupt_lengths_and_resolutions() {
+        let mut capture = Vec::new();
+        capture.write_u32::<BigEndian>(PCAP_MICROS_MAGIC).unwrap();
+        capture.extend_from_slice(&[0; 20]);
+        capture.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+
+        let mut reader = PcapReader::new(&capture[..]).unwrap();
+        assert!(reader.next_datagram().is_err());
+
+        let option = |resolution: u8| {
+            let mut options = Vec::new();
+            options.write_u16::<LittleEndian>(IF_TSRESOL).unwrap();
+            options.write_u16::<LittleEndian>(1).unwrap();
+            options.extend_from_slice(&[resolution, 0, 0, 0]);
+            options
+        };
+
+        assert_eq!(timestamp_resolution(Endian::Little, &option(9)).unwrap(), 1_000_000_000);
+        assert_eq!(timestamp_resolution(Endian::Little, &option(0x80 | 10)).unwrap(), 1024);
+        assert!(timestamp_resolution(Endian::Little, &option(20)).is_err());
+        assert!(timestamp_resolution(Endian::Little, &option(0x80 | 64)).is_err());
+
+        // One unit a second puts the largest timestamp past any SystemTime.
+        assert!(capture_time(u64::MAX, 1).is_err());
+        assert_eq!(capture_time(1_500_000_001, 1_000_000_000).unwrap(), UNIX_EPOCH + Duration::new(1, 500_000_001));
+    }
+
+    #[test]
+    fn key_log_lines() {
+        let mut key_log = KeyLog::new(Vec::new());
+        key_log.log_1rtt(Side::Server, &[0xaa; 4], &[1, 2], &[3, 4]).unwrap();
+
+        assert_eq!(String::from_utf8(key_log.into_inner()).unwrap(),
+                   "CLIENT_TRAFFIC_SECRET_0 aaaaaaaa 0304\nSERVER_TRAFFIC_SECRET_0 aaaaaaaa 0102\n");
+    }
+}
@@ -1,59 +1,148 @@
-//use std::net::UdpSocket;
-use tokio_core::net::UdpSocket;
-use tokio_core::reactor::Core;
-//use std::sync::{Arc, Mutex};
+use std::cmp;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+
 use error::Result;
-use stream::QuicStream;
+use error::TransportErrorFlag;
+
+use connection::ConnectionConfig;
+use connection::QuicConnection;
+use connection::Side;
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    pub connection: ConnectionConfig,
+    /// Append the 1-RTT secrets to this file in SSLKEYLOGFILE format once
+    /// `connect` completes the handshake.
+    pub key_log: Option<PathBuf>,
+}
 
+/// Client endpoint: a connection and the UDP socket it runs over. Unlike
+/// `QuicConnection` this does its own I/O, blocking in `poll`.
 #[derive(Debug)]
 pub struct QuicClient {
     pub socket: UdpSocket,
-    pub current_packet_number: u32,
-    pub address: String,
-    pub streams: Vec<QuicStream>,
+    pub address: SocketAddr,
+    pub config: ClientConfig,
+    pub connection: QuicConnection,
+    recv_buf: Vec<u8>,
 }
 
-
 impl QuicClient {
     pub fn new(address: &str, port: u16) -> Result<QuicClient> {
-        let core = Core::new().unwrap();
-        let handle = core.handle();
+        QuicClient::with_config(address, port, ClientConfig::default())
+    }
 
-        let address = format!("{}:{}", address, port).parse()?;
-        let udp_socket = UdpSocket::bind(&"0.0.0.0:0".parse().unwrap(), &handle)?;
+    pub fn with_config(address: &str, port: u16, config: ClientConfig) -> Result<QuicClient> {
+        let address = (address, port).to_socket_addrs()?
+            .next()
+            .ok_or(io::Error::new(io::ErrorKind::AddrNotAvailable, "no address found"))?;
 
-        let mut client = QuicClient {
-            socket: udp_socket,
-            current_packet_number: QuicClient::get_first_packet_number()?,
-            address: address,
-            streams: vec![]
+        let local: SocketAddr = match address {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
         };
 
-        let tls_stream = QuicStream::new(0, 2u64.pow(60))?;
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(address)?;
+
+        debug!(local:? = socket.local_addr().ok(), remote:? = address; "client socket bound");
+
+        let connection = QuicConnection::new(Side::Client, QuicClient::get_connection_id()?,
+                                             config.connection.clone(), Instant::now())?;
+
+        Ok(QuicClient {
+            socket: socket,
+            address: address,
+            config: config,
+            connection: connection,
+            recv_buf: vec![0; 65536],
+        })
+    }
+
+    fn get_connection_id() -> Result<u64> {
+        use rand::{OsRng, Rng};
+
+        Ok(OsRng::new()?.next_u64())
+    }
+
+    /// Sends whatever the connection has queued, then waits up to `timeout`
+    /// for a datagram, firing the connection's timers as they come due.
+    pub fn poll(&mut self, timeout: Duration) -> Result<()> {
+        self.flush()?;
+
+        let mut until = Instant::now() + timeout;
+
+        if let Some(deadline) = self.connection.next_timeout() {
+            until = cmp::min(until, deadline);
+        }
 
-        client.streams.push(tls_stream);
+        self.receive(until)?;
 
+        let now = Instant::now();
 
-        Ok(client)
+        match self.connection.next_timeout() {
+            Some(deadline) if deadline <= now => self.connection.on_timeout(now),
+            _ => {},
+        }
+
+        self.flush()
+    }
+
+    /// Closes the connection without error and sends the CONNECTION_CLOSE.
+    pub fn close(&mut self, reason: &str) -> Result<()> {
+        self.connection.close(Instant::now(), TransportErrorFlag::empty(), reason);
+        self.flush()
     }
 
-    pub fn get_first_packet_number() -> Result<u32> {
-        use rand::{OsRng};
-        use rand::distributions::{IndependentSample, Range};
+    /// Sends whatever the connection has queued.
+    pub fn flush(&mut self) -> Result<()> {
+        loop {
+            let mut buf = BytesMut::with_capacity(self.connection.max_packet_size());
 
-        let between = Range::new(0u32, 2u32.pow(31) - 1);
-        let mut rng = OsRng::new()?;
+            if !self.connection.poll_datagram(Instant::now(), &mut buf)? {
+                return Ok(());
+            }
 
-        Ok(between.ind_sample(&mut rng))
+            self.socket.send(&buf)?;
+        }
+    }
+
+    /// Hands the connection the next datagram to arrive before `until`, if
+    /// any. Datagrams it can't use are dropped; the connection has already
+    /// done whatever they call for.
+    fn receive(&mut self, until: Instant) -> Result<()> {
+        let now = Instant::now();
+        let wait = if until > now { until - now } else { Duration::from_millis(1) };
+
+        self.socket.set_read_timeout(Some(cmp::max(wait, Duration::from_millis(1))))?;
+
+        match self.socket.recv(&mut self.recv_buf) {
+            Ok(len) => {
+                let datagram = BytesMut::from(&self.recv_buf[..len]);
+
+                if let Err(err) = self.connection.on_datagram(Instant::now(), datagram) {
+                    debug!(conn_id = self.connection.connection_id, err:? = err; "dropped datagram");
+                }
+
+                Ok(())
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => Ok(()),
+            Err(err) => Err(err.into()),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+
     #[test]
     fn create_client() {
         let _ = QuicClient::new("127.0.0.1", 443);
     }
-}
\ No newline at end of file
+}
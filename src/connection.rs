@@ -0,0 +1,2402 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+
+use error::Result;
+use error::QuicError;
+use error::TransportErrorFlag;
+use error::QUIC_INVALID_NEGOTIATED_VALUE;
+use error::QUIC_NETWORK_IDLE_TIMEOUT;
+use error::QUIC_PEER_GOING_AWAY;
+use error::QUIC_CONNECTION_CANCELLED;
+use error::QUIC_INVALID_STREAM_ID;
+use error::QUIC_DECRYPTION_FAILURE;
+use error::QUIC_ENCRYPTION_FAILURE;
+use error::QUIC_INVALID_VERSION;
+
+use crypto::InitialKeys;
+use crypto::KeySchedule;
+use crypto::PacketKey;
+
+use mtu::PathMtu;
+
+use qlog::Qlog;
+use qlog::QlogSink;
+use qlog::FileSink;
+
+use recovery;
+use recovery::CongestionController;
+use recovery::NewReno;
+use recovery::Recovery;
+use recovery::RttEstimator;
+use recovery::PacketNumberSpace;
+use recovery::SentPacket;
+use recovery::Expiry;
+
+use frames::QuicFrame;
+use frames::ping_frame::PingFrame;
+use frames::goaway_frame::GoAwayFrame;
+use frames::reset_stream_frame::ResetStreamFrame;
+use frames::stream_frame::StreamFrame;
+use frames::connection_close_frame::ConnectionCloseFrame;
+
+use header::QuicHeader;
+use header::ShortHeader;
+use header::LongHeader;
+
+use packet::QuicPacket;
+use packet::PacketBuilder;
+use packet::MAX_PACKET_SIZE;
+use packet::QuicPayload;
+use packet::PacketType;
+use packet::FOUR_BYTES;
+use packet::CLIENT_CLEARTEXT;
+use packet::NON_FINAL_CLEARTEXT;
+use packet::RTT0_ENCRYPTED;
+use packet::RETRY;
+use packet::QUIC_VERSION;
+use packet::VersionNegotiationPayload;
+
+use session::AntiReplay;
+use session::SessionTicket;
+
+use stream::QuicStream;
+use stream::StreamState;
+
+use transport_parameters::TransportParameters;
+use transport_parameters::MAX_IDLE_TIMEOUT;
+
+use version;
+use version::QuicVersion;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConnectionState {
+    Handshaking,
+    Open,
+    /// We sent CONNECTION_CLOSE and answer further packets by repeating it.
+    Closing,
+    /// The peer sent CONNECTION_CLOSE; nothing more is sent.
+    Draining,
+    Closed,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    pub transport_parameters: TransportParameters,
+    /// Send a PING if nothing has been sent for this long. Keeps NAT
+    /// bindings alive on otherwise quiet connections.
+    pub keep_alive_interval: Option<Duration>,
+    /// Client only: resume a previous session and send stream data as
+    /// 0-RTT early data before the handshake completes.
+    pub session_ticket: Option<SessionTicket>,
+    /// Server only: consulted before accepting early data. Without one,
+    /// all early data is rejected.
+    pub anti_replay: Option<Arc<Mutex<dyn AntiReplay>>>,
+    /// Largest packet the local interface can send. Above
+    /// `MAX_PACKET_SIZE`, the path is probed to see how much of it gets
+    /// through.
+    pub max_packet_size: usize,
+    /// The wire version to start out with. A client moves to another if
+    /// the server answers with Version Negotiation.
+    pub version: u32,
+    /// Write a qlog trace of each connection into this directory.
+    pub qlog_dir: Option<PathBuf>,
+    /// Makes the congestion controller for each new connection.
+    pub congestion_controller: fn() -> Box<dyn CongestionController>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EarlyDataState {
+    NotAttempted,
+    /// Client: 0-RTT data is being sent. Server: no decision made yet.
+    Pending,
+    Accepted,
+    Rejected,
+}
+
+/// What a connection has done so far, from `QuicConnection::stats`.
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    pub packets_sent: u64,
+    /// Sizes of the packets sent, header and AEAD tag included.
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    /// Sizes of the datagrams passed to `on_datagram`.
+    pub bytes_received: u64,
+    pub packets_lost: u64,
+    pub bytes_lost: u64,
+    /// Packets sent that repeated something sent before.
+    pub packets_retransmitted: u64,
+    /// Stream data sent again, plus the encoded size of any other frames
+    /// sent again.
+    pub bytes_retransmitted: u64,
+    pub rtt: RttEstimator,
+    pub congestion_window: usize,
+    pub bytes_in_flight: usize,
+    /// Streams we opened that are not yet closed.
+    pub local_streams: usize,
+    /// Streams the peer opened that are not yet closed.
+    pub peer_streams: usize,
+    /// Stream data the peer can still send before it fills the windows we
+    /// gave it. The handshake stream is left out.
+    pub receive_credit: u64,
+    /// From creating the connection to the handshake completing.
+    pub handshake_duration: Option<Duration>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> ConnectionConfig {
+        ConnectionConfig {
+            transport_parameters: TransportParameters::default(),
+            keep_alive_interval: None,
+            session_ticket: None,
+            anti_replay: None,
+            max_packet_size: MAX_PACKET_SIZE,
+            version: QUIC_VERSION,
+            qlog_dir: None,
+            congestion_controller: || Box::new(NewReno::new()),
+        }
+    }
+}
+
+/// Connection state machine. Performs no I/O itself: the owner feeds it
+/// received packets and timer expirations, and sends whatever
+/// `poll_transmit` hands back.
+#[derive(Debug)]
+pub struct QuicConnection {
+    pub connection_id: u64,
+    pub side: Side,
+    pub state: ConnectionState,
+    pub close_reason: Option<TransportErrorFlag>,
+    pub config: ConnectionConfig,
+    pub peer_transport_parameters: Option<TransportParameters>,
+    pub streams: HashMap<u32, QuicStream>,
+    /// The GOAWAY we sent, if any. Peer streams above its limits are refused.
+    pub local_go_away: Option<GoAwayFrame>,
+    /// The GOAWAY the peer sent, if any. No new streams may be opened.
+    pub peer_go_away: Option<GoAwayFrame>,
+    pub early_data: EarlyDataState,
+    largest_client_stream_id: u32,
+    largest_server_stream_id: u32,
+    next_stream_id: u32,
+    incoming_streams: VecDeque<u32>,
+    readable_streams: VecDeque<u32>,
+    idle_timeout: Duration,
+    last_received: Instant,
+    last_sent: Instant,
+    pending_frames: VecDeque<QuicFrame>,
+    pending_stream_frames: VecDeque<QuicFrame>,
+    /// Handshake stream data and anything else bound for cleartext packets.
+    initial_frames: VecDeque<QuicFrame>,
+    close_frame: Option<ConnectionCloseFrame>,
+    close_deadline: Option<Instant>,
+    close_pending: bool,
+    packets_received_while_closing: u32,
+    early_data_sent: Vec<StreamFrame>,
+    new_session_ticket: Option<SessionTicket>,
+    initial_keys: Option<InitialKeys>,
+    keys: Option<KeySchedule>,
+    early_data_key: Option<PacketKey>,
+    path_mtu: PathMtu,
+    version: &'static dyn QuicVersion,
+    recovery: Recovery,
+    /// A probe goes out even when the congestion window is full.
+    probe_pending: bool,
+    retry_token: Option<Bytes>,
+    qlog: Option<Qlog>,
+    created: Instant,
+    stats: ConnectionStats,
+    /// How far into each stream data has been sent, so that sending below
+    /// that counts as a retransmission.
+    stream_sent_offsets: HashMap<u32, u64>,
+    /// Frames other than stream frames queued to be sent again.
+    resent_frames: Vec<QuicFrame>,
+}
+
+impl QuicConnection {
+    pub fn new(side: Side, connection_id: u64, config: ConnectionConfig, now: Instant) -> Result<QuicConnection> {
+        let idle_timeout = Duration::from_secs(config.transport_parameters.idle_timeout as u64);
+
+        // Stream 0 carries the handshake; clients open odd-numbered streams
+        // and servers even-numbered ones.
+        let next_stream_id = match side {
+            Side::Client => 1,
+            Side::Server => 2,
+        };
+
+        let mut handshake_stream = QuicStream::new(0, 2u64.pow(60))?;
+        handshake_stream.conn_id = connection_id;
+
+        let mut streams = HashMap::new();
+        streams.insert(0, handshake_stream);
+
+        // A resumed client starts out with the server's remembered
+        // parameters so its early data stays within the old limits.
+        let (early_data, peer_transport_parameters) = match (side, &config.session_ticket) {
+            (Side::Client, Some(ticket)) => (EarlyDataState::Pending, Some(ticket.transport_parameters.clone())),
+            (Side::Client, None) => (EarlyDataState::NotAttempted, None),
+            (Side::Server, _) => (EarlyDataState::NotAttempted, None),
+        };
+
+        let path_mtu = PathMtu::new(config.max_packet_size);
+
+        let version = version::find(config.version)
+            .ok_or(QuicError::TransportError(QUIC_INVALID_VERSION))?;
+
+        let initial_keys = InitialKeys::from_connection_id(side, version.initial_salt(), connection_id)?;
+        let recovery = Recovery::with_congestion_controller((config.congestion_controller)());
+
+        let stats = ConnectionStats {
+            packets_sent: 0,
+            bytes_sent: 0,
+            packets_received: 0,
+            bytes_received: 0,
+            packets_lost: 0,
+            bytes_lost: 0,
+            packets_retransmitted: 0,
+            bytes_retransmitted: 0,
+            rtt: recovery.rtt().clone(),
+            congestion_window: recovery.congestion_window(),
+            bytes_in_flight: 0,
+            local_streams: 0,
+            peer_streams: 0,
+            receive_credit: 0,
+            handshake_duration: None,
+        };
+
+        let qlog = match config.qlog_dir {
+            Some(ref dir) => {
+                let sink = FileSink::create(dir, connection_id, side)?;
+                Some(Qlog::new(Box::new(sink), side, connection_id, now))
+            },
+            None => None,
+        };
+
+        Ok(QuicConnection {
+            connection_id: connection_id,
+            side: side,
+            state: ConnectionState::Handshaking,
+            close_reason: None,
+            config: config,
+            peer_transport_parameters: peer_transport_parameters,
+            streams: streams,
+            local_go_away: None,
+            peer_go_away: None,
+            early_data: early_data,
+            largest_client_stream_id: 0,
+            largest_server_stream_id: 0,
+            next_stream_id: next_stream_id,
+            incoming_streams: VecDeque::new(),
+            readable_streams: VecDeque::new(),
+            idle_timeout: idle_timeout,
+            last_received: now,
+            last_sent: now,
+            pending_frames: VecDeque::with_capacity(16),
+            pending_stream_frames: VecDeque::new(),
+            initial_frames: VecDeque::new(),
+            close_frame: None,
+            close_deadline: None,
+            close_pending: false,
+            packets_received_while_closing: 0,
+            early_data_sent: Vec::new(),
+            new_session_ticket: None,
+            initial_keys: Some(initial_keys),
+            keys: None,
+            early_data_key: None,
+            path_mtu: path_mtu,
+            version: version,
+            recovery: recovery,
+            probe_pending: false,
+            retry_token: None,
+            qlog: qlog,
+            created: now,
+            stats: stats,
+            stream_sent_offsets: HashMap::new(),
+            resent_frames: Vec::new(),
+        })
+    }
+
+    /// Called once the cryptographic handshake has finished, at `now`,
+    /// which ends the handshake time in the stats. A client passes whether
+    /// the server accepted its early data; rejected early data is queued
+    /// again to go out as 1-RTT.
+    pub fn on_handshake_complete(&mut self, now: Instant, peer_params: TransportParameters, early_data_accepted: bool) -> Result<()> {
+        if self.state != ConnectionState::Handshaking {
+            return Ok(());
+        }
+
+        self.set_peer_transport_parameters(peer_params)?;
+        self.set_state(ConnectionState::Open);
+        self.stats.handshake_duration = Some(now.duration_since(self.created));
+
+        // Cleartext packets are neither sent nor read from here on.
+        self.recovery.discard(PacketNumberSpace::Initial);
+        self.initial_keys = None;
+        self.initial_frames.clear();
+
+        if self.side == Side::Client && self.early_data == EarlyDataState::Pending {
+            if early_data_accepted {
+                self.early_data = EarlyDataState::Accepted;
+                self.early_data_sent.clear();
+            } else {
+                self.early_data = EarlyDataState::Rejected;
+
+                // All of it goes again, so none of it should be retransmitted
+                // as lost on top of that.
+                self.recovery.discard(PacketNumberSpace::Application);
+
+                for frame in self.early_data_sent.drain(..).rev() {
+                    self.pending_stream_frames.push_front(QuicFrame::Stream(frame));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Installs the 0-RTT packet protection key the handshake derives from
+    /// the resumed session. Early data is neither sent nor read without it.
+    pub fn set_0rtt_key(&mut self, key: PacketKey) {
+        self.early_data_key = Some(key);
+    }
+
+    /// Installs the 1-RTT packet protection keys exported by the handshake.
+    /// Until then short-header packets are sent and read in the clear.
+    pub fn set_1rtt_keys(&mut self, keys: KeySchedule) {
+        self.keys = Some(keys);
+    }
+
+    /// Rotates to the next generation of 1-RTT keys. Returns false if no
+    /// keys are installed or the previous update hasn't completed yet.
+    pub fn initiate_key_update(&mut self, now: Instant) -> Result<bool> {
+        match self.keys {
+            Some(ref mut keys) => keys.initiate_update(now),
+            None => Ok(false),
+        }
+    }
+
+    pub fn key_phase(&self) -> bool {
+        self.keys.as_ref().is_some_and(|keys| keys.key_phase())
+    }
+
+    /// Server only: the client is resuming with `ticket`. Returns whether
+    /// its early data will be accepted, which the handshake reports back.
+    pub fn on_resumption_attempt(&mut self, ticket: &[u8]) -> bool {
+        if self.side != Side::Server || self.early_data != EarlyDataState::NotAttempted {
+            return false;
+        }
+
+        let accepted = match self.config.anti_replay {
+            Some(ref anti_replay) => match anti_replay.lock() {
+                Ok(mut anti_replay) => anti_replay.accept_early_data(ticket),
+                Err(_) => false,
+            },
+            None => false,
+        };
+
+        self.early_data = if accepted {
+            EarlyDataState::Accepted
+        } else {
+            EarlyDataState::Rejected
+        };
+
+        accepted
+    }
+
+    /// Client only: the server issued a resumption ticket. It is paired with
+    /// the server's transport parameters and kept for `take_session_ticket`.
+    pub fn on_new_session_ticket(&mut self, ticket: Vec<u8>) {
+        if let Some(ref params) = self.peer_transport_parameters {
+            self.new_session_ticket = Some(SessionTicket {
+                ticket: ticket,
+                transport_parameters: params.clone(),
+            });
+        }
+    }
+
+    /// Hands out the most recent resumption ticket, for a `SessionCache`.
+    pub fn take_session_ticket(&mut self) -> Option<SessionTicket> {
+        self.new_session_ticket.take()
+    }
+
+    /// Records the peer's transport parameters. The idle timeout in effect
+    /// is the smaller of the two advertised values; a peer timeout of zero
+    /// or over `MAX_IDLE_TIMEOUT` is rejected.
+    pub fn set_peer_transport_parameters(&mut self, params: TransportParameters) -> Result<()> {
+        if params.idle_timeout == 0 || params.idle_timeout > MAX_IDLE_TIMEOUT {
+            return Err(QuicError::TransportError(QUIC_INVALID_NEGOTIATED_VALUE));
+        }
+
+        let timeout = cmp::min(self.config.transport_parameters.idle_timeout, params.idle_timeout);
+
+        self.idle_timeout = Duration::from_secs(timeout as u64);
+        self.peer_transport_parameters = Some(params);
+
+        Ok(())
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    pub fn rtt(&self) -> &RttEstimator {
+        self.recovery.rtt()
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        let mut stats = self.stats.clone();
+
+        stats.rtt = self.recovery.rtt().clone();
+        stats.congestion_window = self.recovery.congestion_window();
+        stats.bytes_in_flight = self.recovery.bytes_in_flight();
+
+        for stream in self.streams.values() {
+            if stream.id == 0 || stream.state == StreamState::Closed {
+                continue;
+            }
+
+            if self.is_local_stream(stream.id) {
+                stats.local_streams += 1;
+            } else {
+                stats.peer_streams += 1;
+            }
+
+            stats.receive_credit += stream.max_data.saturating_sub(stream.next_offset);
+        }
+
+        stats
+    }
+
+    /// Sends the qlog trace to `sink` from now on, with event times
+    /// relative to `now`. Replaces the file opened for `qlog_dir`, if any.
+    pub fn set_qlog_sink(&mut self, now: Instant, sink: Box<dyn QlogSink>) {
+        self.qlog = Some(Qlog::new(sink, self.side, self.connection_id, now));
+    }
+
+    /// Client only: the token from the server's Retry, which every Client
+    /// Initial sent from then on has to carry.
+    pub fn retry_token(&self) -> Option<&Bytes> {
+        self.retry_token.as_ref()
+    }
+
+    /// The wire version in use.
+    pub fn version(&self) -> u32 {
+        self.version.number()
+    }
+
+    /// Largest packet confirmed to get through to the peer.
+    pub fn max_packet_size(&self) -> usize {
+        self.path_mtu.max_packet_size()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state == ConnectionState::Closed
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state == ConnectionState::Open
+    }
+
+    fn is_terminating(&self) -> bool {
+        match self.state {
+            ConnectionState::Closing | ConnectionState::Draining | ConnectionState::Closed => true,
+            ConnectionState::Handshaking | ConnectionState::Open => false,
+        }
+    }
+
+    /// Closes the connection immediately, telling the peer why. The
+    /// connection lingers in the closing state for a short while so that
+    /// the close can be repeated if the peer keeps sending.
+    pub fn close(&mut self, now: Instant, error: TransportErrorFlag, reason: &str) {
+        if self.is_terminating() {
+            return;
+        }
+
+        // The reason length is a u16 on the wire, so longer phrases are cut
+        // at the last character boundary that fits.
+        let mut end = cmp::min(reason.len(), u16::MAX as usize);
+
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let reason = &reason[..end];
+
+        let reason_phrase = if reason.is_empty() {
+            None
+        } else {
+            Some(reason.to_string())
+        };
+
+        self.close_frame = Some(ConnectionCloseFrame {
+            error_code: error.bits(),
+            reason_length: reason.len() as u16,
+            reason_phrase: reason_phrase,
+        });
+        self.close_pending = true;
+        self.close_deadline = Some(now + self.close_period());
+        self.set_state(ConnectionState::Closing);
+
+        self.on_connection_error(error);
+        self.trace_streams(now);
+    }
+
+    pub fn open_stream(&mut self) -> Result<u32> {
+        if self.is_terminating() {
+            return Err(QuicError::TransportError(self.close_reason.unwrap_or(QUIC_CONNECTION_CANCELLED)));
+        }
+
+        if self.peer_go_away.is_some() {
+            return Err(QuicError::TransportError(QUIC_PEER_GOING_AWAY));
+        }
+
+        // Our own GOAWAY told the peer which of our streams to expect.
+        if self.local_go_away.is_some() {
+            return Err(QuicError::TransportError(QUIC_CONNECTION_CANCELLED));
+        }
+
+        let id = self.next_stream_id;
+        self.next_stream_id += 2;
+
+        self.note_stream_id(id);
+
+        let stream = self.new_stream(id)?;
+        self.streams.insert(id, stream);
+
+        Ok(id)
+    }
+
+    /// Returns the next stream opened by the peer, if any.
+    pub fn accept_stream(&mut self) -> Option<u32> {
+        self.incoming_streams.pop_front()
+    }
+
+    /// Returns the next stream that has had data arrive since it was last
+    /// returned, if any.
+    pub fn readable_stream(&mut self) -> Option<u32> {
+        self.readable_streams.pop_front()
+    }
+
+    pub fn stream(&mut self, id: u32) -> Option<&mut QuicStream> {
+        self.streams.get_mut(&id)
+    }
+
+    /// Announces that no streams beyond those already seen will be accepted.
+    /// Existing streams carry on until they finish.
+    pub fn go_away(&mut self) {
+        if self.local_go_away.is_some() || self.is_terminating() {
+            return;
+        }
+
+        let frame = GoAwayFrame {
+            largest_client_stream_id: self.largest_client_stream_id,
+            largest_server_stream_id: self.largest_server_stream_id,
+        };
+
+        self.local_go_away = Some(frame);
+        self.pending_frames.push_back(QuicFrame::GoAway(frame));
+    }
+
+    /// Streams we opened that the peer's GOAWAY says it will never process.
+    /// The application can safely retry their work on another connection.
+    pub fn unprocessed_streams(&self) -> Vec<u32> {
+        let limit = match self.peer_go_away {
+            Some(ref frame) => self.local_stream_limit(frame),
+            None => return vec![],
+        };
+
+        let mut ids: Vec<u32> = self.streams.keys()
+            .cloned()
+            .filter(|&id| self.is_local_stream(id) && id > limit)
+            .collect();
+        ids.sort();
+
+        ids
+    }
+
+    /// Reads a datagram off the wire, removing header and packet protection
+    /// from short-header packets when 1-RTT keys are installed, and packet
+    /// protection from cleartext and 0-RTT packets. Protected payloads are
+    /// decrypted in place, and frames slice the datagram.
+    pub fn on_datagram(&mut self, now: Instant, buf: BytesMut) -> Result<()> {
+        self.stats.bytes_received += buf.len() as u64;
+
+        let packet = match self.parse_datagram(now, buf) {
+            Ok(packet) => packet,
+            Err(err) => {
+                debug!(conn_id = self.connection_id, error:? = err; "malformed datagram");
+                return self.close_on_error(now, Err(err));
+            },
+        };
+
+        self.on_packet_received(now, packet)
+    }
+
+    fn parse_datagram(&mut self, now: Instant, mut buf: BytesMut) -> Result<QuicPacket> {
+        if buf.is_empty() {
+            return Err(QuicError::ParseError);
+        }
+
+        if buf[0] & 0x80 != 0 {
+            if let QuicHeader::Long(header) = self.version.parse_header(&buf)? {
+                if header.is_protected() {
+                    return match self.long_header_key(header.packet_type, false) {
+                        Some(key) => QuicPacket::open(self.version, key, buf),
+                        None => Err(QuicError::TransportError(QUIC_DECRYPTION_FAILURE)),
+                    };
+                }
+            }
+        }
+
+        let packet = match self.keys {
+            Some(ref mut keys) if buf[0] & 0x80 == 0 => {
+                let version = self.version;
+                let first_byte = buf[0];
+
+                keys.remote_header_key().unprotect(&mut buf,
+                                                   version.short_packet_number_offset(first_byte),
+                                                   version.protected_bits(first_byte),
+                                                   &|first_byte| version.packet_number_len(first_byte))?;
+
+                let header = version.parse_header(&buf)?;
+                let header_len = version.header_len(&header);
+
+                let header = match header {
+                    QuicHeader::Short(header) => header,
+                    QuicHeader::Long(_) => return Err(QuicError::ParseError),
+                };
+
+                let len = {
+                    let (header_bytes, payload) = buf.split_at_mut(header_len);
+                    keys.open_in_place(now, header.key_phase_bit, header.packet_number, header_bytes, payload)?
+                };
+
+                let payload = buf.freeze().slice(header_len, header_len + len);
+
+                QuicPacket {
+                    header: QuicHeader::Short(header),
+                    payload: QuicPayload::Frames(QuicPacket::parse_payload(self.version, &payload)?),
+                }
+            },
+            Some(_) => QuicPacket::decode(self.version, &buf.freeze())?,
+            None => QuicPacket::decode_unprotected(self.version, &buf.freeze())?,
+        };
+
+        Ok(packet)
+    }
+
+    /// A peer that sends something malformed is sent CONNECTION_CLOSE with
+    /// the matching error. Packets that fail to decrypt are only dropped,
+    /// since anyone could have sent them.
+    fn close_on_error(&mut self, now: Instant, result: Result<()>) -> Result<()> {
+        if let Err(QuicError::TransportError(error)) = result {
+            if error != QUIC_DECRYPTION_FAILURE {
+                self.close(now, error, "");
+            } else {
+                debug!(conn_id = self.connection_id; "dropped packet that failed to decrypt");
+            }
+        }
+
+        result
+    }
+
+    /// The key for long-header packets of `packet_type` in one direction:
+    /// the 0-RTT key for early data and the Initial keys for the rest.
+    fn long_header_key(&self, packet_type: PacketType, local: bool) -> Option<&PacketKey> {
+        if packet_type == RTT0_ENCRYPTED {
+            return self.early_data_key.as_ref();
+        }
+
+        self.initial_keys.as_ref().map(|keys| if local { &keys.local } else { &keys.remote })
+    }
+
+    /// Writes the next datagram to send onto the end of `buf`, protected
+    /// with the 1-RTT keys when it carries a short header and keys are
+    /// installed, and with the Initial or 0-RTT key when it carries a long
+    /// one. Returns false if there was nothing to send.
+    pub fn poll_datagram(&mut self, now: Instant, buf: &mut BytesMut) -> Result<bool> {
+        let max_size = self.path_mtu.max_packet_size();
+        self.poll_datagram_within(now, buf, max_size)
+    }
+
+    /// Like `poll_datagram`, but keeps the datagram to `max_size` bytes, for
+    /// a server that may only send so much to an unvalidated address.
+    pub fn poll_datagram_within(&mut self, now: Instant, buf: &mut BytesMut, max_size: usize) -> Result<bool> {
+        let packet = match self.poll_transmit_within(now, max_size) {
+            Some(packet) => packet,
+            None => return Ok(false),
+        };
+
+        let result = self.write_datagram(&packet, buf);
+
+        if let Err(ref err) = result {
+            debug!(conn_id = self.connection_id, packet_number = packet.header.packet_number(), error:? = err; "packet not sent");
+        }
+
+        result.map(|_| true)
+    }
+
+    fn write_datagram(&self, packet: &QuicPacket, buf: &mut BytesMut) -> Result<()> {
+        match (&packet.header, &self.keys) {
+            (QuicHeader::Short(header), Some(keys)) => {
+                let start = buf.len();
+                self.version.write_header(&packet.header, buf);
+
+                let pn_offset = buf.len() - start - header.packet_type.packet_number_len();
+
+                let mut payload = buf.split_off(buf.len());
+                packet.payload.encode(self.version, &mut payload);
+                keys.seal_in_place(header.packet_number, &buf[start..], &mut payload)?;
+
+                buf.unsplit(payload);
+
+                let first_byte = buf[start];
+                keys.local_header_key().protect(&mut buf[start..], pn_offset, header.packet_type.packet_number_len(),
+                                                self.version.protected_bits(first_byte))?;
+            },
+            (QuicHeader::Long(header), _) if header.is_protected() => {
+                let key = self.long_header_key(header.packet_type, true)
+                    .ok_or(QuicError::TransportError(QUIC_ENCRYPTION_FAILURE))?;
+
+                packet.seal(self.version, key, buf)?;
+            },
+            _ => packet.encode(self.version, buf)?,
+        }
+
+        Ok(())
+    }
+
+    pub fn on_packet_received(&mut self, now: Instant, packet: QuicPacket) -> Result<()> {
+        debug!(conn_id = self.connection_id, packet_number = packet.header.packet_number(); "packet received");
+
+        self.stats.packets_received += 1;
+
+        if let Some(ref mut qlog) = self.qlog {
+            qlog.packet_received(now, &packet);
+        }
+
+        match self.state {
+            ConnectionState::Handshaking | ConnectionState::Open => {},
+            ConnectionState::Closing => {
+                self.on_packet_received_while_closing(now, packet);
+                return Ok(());
+            },
+            ConnectionState::Draining | ConnectionState::Closed => return Ok(()),
+        }
+
+        if let QuicPayload::VersionNegotiation(ref payload) = packet.payload {
+            return self.on_version_negotiation(payload);
+        }
+
+        if let QuicHeader::Long(ref header) = packet.header {
+            if header.packet_type == RETRY {
+                self.on_retry(header);
+                return Ok(());
+            }
+        }
+
+        let is_early_data = match packet.header {
+            QuicHeader::Long(ref header) => header.packet_type == RTT0_ENCRYPTED,
+            QuicHeader::Short(_) => false,
+        };
+
+        // Early data is dropped unless we are a server that accepted it;
+        // the client will send it again after the handshake.
+        if is_early_data && (self.side == Side::Client || self.early_data != EarlyDataState::Accepted) {
+            return Ok(());
+        }
+
+        self.last_received = now;
+
+        let space = PacketNumberSpace::from_header(&packet.header);
+
+        let packet_number = packet.header.packet_number();
+
+        if let QuicPayload::Frames(frames) = packet.payload {
+            // Acknowledged from the same space, in cleartext packets for the
+            // handshake and 1-RTT ones for the rest.
+            let ack_eliciting = frames.iter().any(recovery::is_ack_eliciting);
+            self.recovery.on_packet_received(space, now, packet_number, ack_eliciting);
+
+            for frame in frames {
+                let result = self.on_frame_received(now, space, frame);
+
+                if result.is_err() {
+                    return self.close_on_error(now, result);
+                }
+
+                // Nothing that follows the peer's CONNECTION_CLOSE is acted on.
+                if self.state != ConnectionState::Open {
+                    break;
+                }
+            }
+        }
+
+        self.trace_streams(now);
+
+        Ok(())
+    }
+
+    /// A client told the server doesn't speak its version moves to one
+    /// they share, or gives up if there is none. A list that includes the
+    /// version we used can only be forged, so it is ignored. The Initial
+    /// keys depend on the version, so the handshake goes again under new
+    /// ones.
+    fn on_version_negotiation(&mut self, payload: &VersionNegotiationPayload) -> Result<()> {
+        if self.side != Side::Client || self.state != ConnectionState::Handshaking
+            || payload.versions.contains(&self.version.number()) {
+            return Ok(());
+        }
+
+        match version::negotiate(&payload.versions) {
+            Some(version) => {
+                debug!(conn_id = self.connection_id, version = version.number(); "switching version");
+                self.version = version;
+                self.initial_keys = Some(InitialKeys::from_connection_id(self.side, version.initial_salt(),
+                                                                         self.connection_id)?);
+
+                let sent = self.recovery.discard(PacketNumberSpace::Initial);
+                self.resend_initial(sent);
+            },
+            None => {
+                self.set_state(ConnectionState::Closed);
+                self.on_connection_error(QUIC_INVALID_VERSION);
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Queues the frames of cleartext packets that have to go again, in
+    /// the order they were first sent.
+    fn resend_initial(&mut self, sent: Vec<SentPacket>) {
+        let frames: Vec<QuicFrame> = sent.into_iter().flat_map(|packet| packet.frames).collect();
+
+        for frame in frames.into_iter().rev() {
+            self.initial_frames.push_front(frame);
+        }
+    }
+
+    /// The server kept no state for our first flight, so the Client Initial
+    /// and anything sent as early data have to go again. Only the first
+    /// Retry counts.
+    fn on_retry(&mut self, header: &LongHeader) {
+        if self.side != Side::Client || self.state != ConnectionState::Handshaking
+            || self.retry_token.is_some() || header.token.is_empty() {
+            return;
+        }
+
+        debug!(conn_id = self.connection_id, early_frames = self.early_data_sent.len(); "retrying with token");
+
+        self.retry_token = Some(header.token.clone());
+
+        let sent = self.recovery.discard(PacketNumberSpace::Initial);
+        self.resend_initial(sent);
+
+        self.recovery.discard(PacketNumberSpace::Application);
+
+        for frame in self.early_data_sent.drain(..).rev() {
+            self.pending_stream_frames.push_front(QuicFrame::Stream(frame));
+        }
+    }
+
+    fn on_packet_received_while_closing(&mut self, now: Instant, packet: QuicPacket) {
+        if let QuicPayload::Frames(ref frames) = packet.payload {
+            let peer_closed = frames.iter().any(|frame| matches!(*frame, QuicFrame::ConnectionClose(_)));
+
+            if peer_closed {
+                self.enter_draining(now);
+                return;
+            }
+        }
+
+        // Repeat the close on the 1st, 2nd, 4th, 8th... packet received so
+        // a peer that keeps sending can't make us flood it.
+        self.packets_received_while_closing += 1;
+
+        if self.packets_received_while_closing.is_power_of_two() {
+            self.close_pending = true;
+        }
+    }
+
+    fn enter_draining(&mut self, now: Instant) {
+        self.set_state(ConnectionState::Draining);
+        self.close_pending = false;
+        self.pending_frames.clear();
+        self.pending_stream_frames.clear();
+
+        if self.close_deadline.is_none() {
+            self.close_deadline = Some(now + self.close_period());
+        }
+    }
+
+    fn on_connection_error(&mut self, error: TransportErrorFlag) {
+        debug!(conn_id = self.connection_id, error:? = error; "connection error");
+
+        self.close_reason = Some(error);
+        self.pending_frames.clear();
+        self.pending_stream_frames.clear();
+
+        for stream in self.streams.values_mut() {
+            stream.on_connection_error(error);
+        }
+    }
+
+    /// The closing and draining periods last three probe timeouts.
+    fn close_period(&self) -> Duration {
+        self.recovery.probe_timeout() * 3
+    }
+
+    fn on_frame_received(&mut self, now: Instant, space: PacketNumberSpace, frame: QuicFrame) -> Result<()> {
+        trace!(conn_id = self.connection_id, space:? = space, frame:? = frame; "frame received");
+
+        match frame {
+            // A PING only needs to be acknowledged; receiving the packet
+            // has already reset the idle timer.
+            QuicFrame::Ping(_) => {},
+            QuicFrame::GoAway(frame) => self.peer_go_away = Some(frame),
+            QuicFrame::Ack(frame) => {
+                if space == PacketNumberSpace::Application {
+                    if let Some(probe) = self.path_mtu.probe_packet_number() {
+                        if frame.acknowledges(probe) {
+                            self.path_mtu.on_probe_acked();
+                        }
+                    }
+
+                    if let Some(packet_number) = self.path_mtu.unconfirmed_packet_number() {
+                        if frame.acknowledges(packet_number) {
+                            self.path_mtu.on_packet_acked();
+                        }
+                    }
+                }
+
+                let lost = self.recovery.on_ack_received(space, now, &frame)?;
+                self.on_packets_lost(now, space, lost);
+                self.trace_metrics(now);
+            },
+            QuicFrame::Stream(frame) => self.on_stream_frame(frame)?,
+            QuicFrame::ConnectionClose(frame) => {
+                self.enter_draining(now);
+                self.on_connection_error(TransportErrorFlag::from_code(frame.error_code));
+            },
+            _ => {},
+        }
+
+        Ok(())
+    }
+
+    /// Queues the frames from lost packets to be sent again in the space
+    /// they were lost from, with stream data ahead of anything new.
+    fn on_packets_lost(&mut self, now: Instant, space: PacketNumberSpace, lost: Vec<SentPacket>) {
+        let mut stream_frames = Vec::new();
+
+        for packet in lost {
+            debug!(conn_id = self.connection_id, packet_number = packet.packet_number; "packet lost");
+
+            self.stats.packets_lost += 1;
+            self.stats.bytes_lost += packet.size as u64;
+
+            if space == PacketNumberSpace::Application {
+                self.path_mtu.on_packet_lost(packet.packet_number, packet.size);
+            }
+
+            if let Some(ref mut qlog) = self.qlog {
+                qlog.packet_lost(now, space, &packet);
+            }
+
+            for frame in packet.frames {
+                if let QuicFrame::Stream(_) = frame {
+                    stream_frames.push(frame);
+                    continue;
+                }
+
+                self.resent_frames.push(frame.clone());
+
+                match space {
+                    PacketNumberSpace::Application => self.pending_frames.push_back(frame),
+                    _ => self.initial_frames.push_back(frame),
+                }
+            }
+        }
+
+        for frame in stream_frames.into_iter().rev() {
+            match space {
+                PacketNumberSpace::Application => self.pending_stream_frames.push_front(frame),
+                _ => self.initial_frames.push_front(frame),
+            }
+        }
+    }
+
+    /// Sends whatever the space still has unacknowledged ahead of anything
+    /// new, so the probe can stand in for lost packets. A PING does when
+    /// there is nothing to send again.
+    fn on_probe_timeout(&mut self, space: PacketNumberSpace) {
+        if space == PacketNumberSpace::Application {
+            self.probe_pending = true;
+        }
+
+        let mut frames = self.recovery.unacked_frames(space);
+
+        if frames.is_empty() {
+            frames.push(QuicFrame::Ping(PingFrame {}));
+        }
+
+        for frame in frames.into_iter().rev() {
+            match frame {
+                QuicFrame::Stream(_) | QuicFrame::Ping(_) => {},
+                _ => self.resent_frames.push(frame.clone()),
+            }
+
+            match (space, &frame) {
+                (PacketNumberSpace::Application, &QuicFrame::Stream(_)) => self.pending_stream_frames.push_front(frame),
+                (PacketNumberSpace::Application, _) => self.pending_frames.push_front(frame),
+                _ => self.initial_frames.push_front(frame),
+            }
+        }
+    }
+
+    fn on_stream_frame(&mut self, frame: StreamFrame) -> Result<()> {
+        let id = frame.stream_id;
+
+        if !self.streams.contains_key(&id) {
+            if self.is_local_stream(id) {
+                return Err(QuicError::TransportError(QUIC_INVALID_STREAM_ID));
+            }
+
+            let refused = match self.local_go_away {
+                Some(ref go_away) => id > self.peer_stream_limit(go_away),
+                None => false,
+            };
+
+            if refused {
+                self.pending_frames.push_back(QuicFrame::ResetStream(ResetStreamFrame {
+                    error_code: QUIC_PEER_GOING_AWAY.bits(),
+                    stream_id: id,
+                    final_offset: 0,
+                }));
+
+                return Ok(());
+            }
+
+            self.note_stream_id(id);
+
+            let stream = self.new_stream(id)?;
+            self.streams.insert(id, stream);
+            self.incoming_streams.push_back(id);
+        }
+
+        let readable = match self.streams.get_mut(&id) {
+            Some(stream) => stream.on_receive_frame(&frame)?.is_some(),
+            None => false,
+        };
+
+        if readable && !self.readable_streams.contains(&id) {
+            self.readable_streams.push_back(id);
+        }
+
+        Ok(())
+    }
+
+    fn new_stream(&self, id: u32) -> Result<QuicStream> {
+        let mut stream = QuicStream::new(id, self.stream_window())?;
+        stream.conn_id = self.connection_id;
+
+        Ok(stream)
+    }
+
+    fn is_local_stream(&self, id: u32) -> bool {
+        match self.side {
+            Side::Client => !id.is_multiple_of(2),
+            Side::Server => id != 0 && id.is_multiple_of(2),
+        }
+    }
+
+    fn local_stream_limit(&self, frame: &GoAwayFrame) -> u32 {
+        match self.side {
+            Side::Client => frame.largest_client_stream_id,
+            Side::Server => frame.largest_server_stream_id,
+        }
+    }
+
+    fn peer_stream_limit(&self, frame: &GoAwayFrame) -> u32 {
+        match self.side {
+            Side::Client => frame.largest_server_stream_id,
+            Side::Server => frame.largest_client_stream_id,
+        }
+    }
+
+    fn note_stream_id(&mut self, id: u32) {
+        if id % 2 == 1 {
+            self.largest_client_stream_id = cmp::max(self.largest_client_stream_id, id);
+        } else {
+            self.largest_server_stream_id = cmp::max(self.largest_server_stream_id, id);
+        }
+    }
+
+    fn stream_window(&self) -> u64 {
+        self.config.transport_parameters.initial_max_stream_data as u64
+    }
+
+    /// The next instant at which `on_timeout` must be called, if any.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        match self.state {
+            ConnectionState::Handshaking | ConnectionState::Open => {},
+            ConnectionState::Closing | ConnectionState::Draining => return self.close_deadline,
+            ConnectionState::Closed => return None,
+        }
+
+        let mut deadline = self.last_received + self.idle_timeout;
+
+        if let Some(interval) = self.config.keep_alive_interval {
+            deadline = cmp::min(deadline, self.last_sent + interval);
+        }
+
+        if let Some(probe_deadline) = self.path_mtu.next_timeout() {
+            deadline = cmp::min(deadline, probe_deadline);
+        }
+
+        if let Some(recovery_deadline) = self.recovery.next_timeout() {
+            deadline = cmp::min(deadline, recovery_deadline);
+        }
+
+        Some(deadline)
+    }
+
+    pub fn on_timeout(&mut self, now: Instant) {
+        match self.state {
+            ConnectionState::Handshaking | ConnectionState::Open => {},
+            ConnectionState::Closing | ConnectionState::Draining => {
+                if self.close_deadline.is_none_or(|deadline| now >= deadline) {
+                    self.set_state(ConnectionState::Closed);
+                    self.close_pending = false;
+                }
+                return;
+            },
+            ConnectionState::Closed => return,
+        }
+
+        // The idle timer only restarts on receipt, so keep-alives alone
+        // can't hold open a connection to a peer that has gone away.
+        if now >= self.last_received + self.idle_timeout {
+            // Idle expiry closes silently: no CONNECTION_CLOSE is sent.
+            self.set_state(ConnectionState::Closed);
+            self.on_connection_error(QUIC_NETWORK_IDLE_TIMEOUT);
+            self.trace_streams(now);
+            return;
+        }
+
+        self.path_mtu.on_timeout(now);
+
+        match self.recovery.on_timeout(now) {
+            Some(Expiry::Lost(space, lost)) => self.on_packets_lost(now, space, lost),
+            Some(Expiry::Probe(space)) => self.on_probe_timeout(space),
+            None => {},
+        }
+
+        if let Some(interval) = self.config.keep_alive_interval {
+            if now >= self.last_sent + interval {
+                self.pending_frames.push_back(QuicFrame::Ping(PingFrame {}));
+                self.last_sent = now;
+            }
+        }
+    }
+
+    pub fn poll_transmit(&mut self, now: Instant) -> Option<QuicPacket> {
+        let max_size = self.path_mtu.max_packet_size();
+        self.poll_transmit_within(now, max_size)
+    }
+
+    /// Like `poll_transmit`, but builds no packet larger than `max_size`.
+    /// Path MTU probes wait until a full-sized packet may be sent.
+    pub fn poll_transmit_within(&mut self, now: Instant, max_size: usize) -> Option<QuicPacket> {
+        let limited = max_size < self.path_mtu.max_packet_size();
+        let max_size = cmp::min(max_size, self.path_mtu.max_packet_size());
+
+        if let Some(packet) = self.poll_initial(now, max_size) {
+            return Some(packet);
+        }
+
+        let sending = match self.state {
+            ConnectionState::Handshaking => self.sending_early_data(),
+            ConnectionState::Open => true,
+            ConnectionState::Closing => self.close_pending,
+            _ => false,
+        };
+
+        if !sending {
+            return None;
+        }
+
+        let packet_number = self.recovery.next_packet_number(PacketNumberSpace::Application);
+
+        let header = if self.state == ConnectionState::Handshaking {
+            QuicHeader::Long(LongHeader {
+                packet_type: RTT0_ENCRYPTED,
+                connection_id: self.connection_id,
+                packet_number: packet_number as u32,
+                version: self.version.number(),
+                token: Bytes::new(),
+            })
+        } else {
+            QuicHeader::Short(ShortHeader {
+                key_phase_bit: self.key_phase(),
+                conn_id_bit: true,
+                connection_id: Some(self.connection_id),
+                packet_number: packet_number,
+                packet_type: FOUR_BYTES,
+            })
+        };
+
+        let tag_len = match header {
+            QuicHeader::Short(_) => self.keys.as_ref().map_or(0, |keys| keys.local_key().tag_len()),
+            QuicHeader::Long(ref header) => self.long_header_key(header.packet_type, true).map_or(0, PacketKey::tag_len),
+        };
+
+        let probe_size = match self.state {
+            ConnectionState::Open if !limited => self.path_mtu.poll_probe(now),
+            _ => None,
+        };
+
+        if let Some(size) = probe_size {
+            let mut builder = PacketBuilder::new(self.version, header, size, tag_len);
+            builder.push(QuicFrame::Ping(PingFrame {}));
+            builder.pad();
+
+            self.path_mtu.on_probe_sent(now, packet_number, size);
+
+            // A lost probe only means the path is smaller; it isn't a sign
+            // of loss to recover from.
+            return self.on_packet_built(now, builder, false);
+        }
+
+        let mut builder = PacketBuilder::new(self.version, header, max_size, tag_len);
+
+        // Acknowledgements aren't held back by the congestion window.
+        let congestion_limited = !self.probe_pending && !self.recovery.can_send();
+        self.probe_pending = false;
+
+        match self.state {
+            ConnectionState::Handshaking => {
+                if !congestion_limited {
+                    self.queue_stream_frames(false);
+                    builder.fill(&mut self.pending_stream_frames);
+                }
+            },
+            ConnectionState::Open => {
+                if let Some(ack) = self.recovery.poll_ack(PacketNumberSpace::Application, now) {
+                    builder.push(QuicFrame::Ack(ack));
+                }
+
+                if !congestion_limited {
+                    self.queue_stream_frames(true);
+                    builder.fill(&mut self.pending_frames);
+                    builder.fill(&mut self.pending_stream_frames);
+                }
+            },
+            _ => {
+                self.close_pending = false;
+
+                if let Some(ref frame) = self.close_frame {
+                    builder.push(QuicFrame::ConnectionClose(frame.clone()));
+                }
+            },
+        }
+
+        self.on_packet_built(now, builder, true)
+    }
+
+    /// Cleartext packets carrying the handshake stream, and acknowledging
+    /// the peer's, for as long as the Initial keys are around.
+    fn poll_initial(&mut self, now: Instant, max_size: usize) -> Option<QuicPacket> {
+        if self.is_terminating() {
+            return None;
+        }
+
+        let tag_len = match self.initial_keys {
+            Some(ref keys) => keys.local.tag_len(),
+            None => return None,
+        };
+
+        if let Some(stream) = self.streams.get_mut(&0) {
+            while let Some(frame) = stream.poll_send_frame() {
+                self.initial_frames.push_back(QuicFrame::Stream(frame));
+            }
+        }
+
+        let ack = self.recovery.poll_ack(PacketNumberSpace::Initial, now);
+
+        if ack.is_none() && self.initial_frames.is_empty() {
+            return None;
+        }
+
+        let (packet_type, token) = match self.side {
+            Side::Client => (CLIENT_CLEARTEXT, self.retry_token.clone().unwrap_or_default()),
+            Side::Server => (NON_FINAL_CLEARTEXT, Bytes::new()),
+        };
+
+        let header = QuicHeader::Long(LongHeader {
+            packet_type: packet_type,
+            connection_id: self.connection_id,
+            packet_number: self.recovery.next_packet_number(PacketNumberSpace::Initial) as u32,
+            version: self.version.number(),
+            token: token,
+        });
+
+        let mut builder = PacketBuilder::new(self.version, header, max_size, tag_len);
+
+        if let Some(ack) = ack {
+            builder.push(QuicFrame::Ack(ack));
+        }
+
+        builder.fill(&mut self.initial_frames);
+
+        // A server only answers a Client Initial that fills a datagram.
+        if self.side == Side::Client {
+            builder.pad();
+        }
+
+        self.on_packet_built(now, builder, true)
+    }
+
+    fn on_packet_built(&mut self, now: Instant, builder: PacketBuilder, recoverable: bool) -> Option<QuicPacket> {
+        let size = builder.len();
+        let packet = builder.finish()?;
+        let space = PacketNumberSpace::from_header(&packet.header);
+
+        let early_data = match packet.header {
+            QuicHeader::Long(ref header) => header.packet_type == RTT0_ENCRYPTED,
+            QuicHeader::Short(_) => false,
+        };
+
+        if early_data {
+            if let QuicPayload::Frames(ref frames) = packet.payload {
+                for frame in frames {
+                    if let QuicFrame::Stream(ref frame) = *frame {
+                        self.early_data_sent.push(frame.clone());
+                    }
+                }
+            }
+        }
+
+        if recoverable && space == PacketNumberSpace::Application {
+            self.path_mtu.on_packet_sent(packet.header.packet_number(), size);
+        }
+
+        if let QuicPayload::Frames(ref frames) = packet.payload {
+            self.count_retransmitted(frames);
+
+            let retransmittable = frames.iter().filter(|frame| {
+                !matches!(**frame, QuicFrame::Ack(_) | QuicFrame::Padding(_) | QuicFrame::Ping(_) | QuicFrame::ConnectionClose(_))
+            });
+
+            self.recovery.on_packet_sent(space, SentPacket {
+                packet_number: self.recovery.next_packet_number(space),
+                time_sent: now,
+                size: size,
+                ack_eliciting: recoverable && frames.iter().any(recovery::is_ack_eliciting),
+                frames: retransmittable.cloned().collect(),
+            });
+        }
+
+        self.last_sent = now;
+
+        debug!(conn_id = self.connection_id, packet_number = packet.header.packet_number(), size = size; "packet sent");
+
+        self.stats.packets_sent += 1;
+        self.stats.bytes_sent += size as u64;
+
+        if let Some(ref mut qlog) = self.qlog {
+            qlog.packet_sent(now, &packet, size);
+        }
+
+        self.trace_metrics(now);
+        self.trace_streams(now);
+
+        Some(packet)
+    }
+
+    /// Counts what in a packet about to be sent was sent before: stream
+    /// data below where the stream had got to, and frames queued again.
+    fn count_retransmitted(&mut self, frames: &[QuicFrame]) {
+        let mut retransmitted = false;
+        let mut bytes = 0;
+
+        for frame in frames {
+            match *frame {
+                QuicFrame::Stream(ref frame) => {
+                    let end = frame.offset + frame.stream_data.len() as u64;
+                    let sent = self.stream_sent_offsets.entry(frame.stream_id).or_insert(0);
+
+                    if frame.offset < *sent {
+                        retransmitted = true;
+                        bytes += cmp::min(end, *sent) - frame.offset;
+                    }
+
+                    *sent = cmp::max(*sent, end);
+                },
+                ref frame => {
+                    if let Some(index) = self.resent_frames.iter().position(|resent| resent == frame) {
+                        self.resent_frames.remove(index);
+                        retransmitted = true;
+                        bytes += self.version.frame_len(frame) as u64;
+                    }
+                },
+            }
+        }
+
+        if retransmitted {
+            self.stats.packets_retransmitted += 1;
+            self.stats.bytes_retransmitted += bytes;
+        }
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        if self.state != state {
+            debug!(conn_id = self.connection_id, from:? = self.state, to:? = state; "state changed");
+        }
+
+        self.state = state;
+    }
+
+    fn trace_metrics(&mut self, now: Instant) {
+        if let Some(ref mut qlog) = self.qlog {
+            qlog.metrics_updated(now, &self.recovery);
+        }
+    }
+
+    fn trace_streams(&mut self, now: Instant) {
+        if let Some(ref mut qlog) = self.qlog {
+            qlog.streams_updated(now, &self.streams);
+        }
+    }
+
+    fn sending_early_data(&self) -> bool {
+        self.side == Side::Client && self.early_data == EarlyDataState::Pending && self.early_data_key.is_some()
+    }
+
+    /// Queues frames written to streams, in stream order. Stream 0 carries
+    /// the handshake and never goes out as early data.
+    fn queue_stream_frames(&mut self, include_handshake_stream: bool) {
+        let mut ids: Vec<u32> = self.streams.keys().cloned().collect();
+        ids.sort();
+
+        for id in ids {
+            if id == 0 && !include_handshake_stream {
+                continue;
+            }
+
+            if let Some(stream) = self.streams.get_mut(&id) {
+                while let Some(frame) = stream.poll_send_frame() {
+                    self.pending_stream_frames.push_back(QuicFrame::Stream(frame));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use error::QUIC_INVALID_STREAM_DATA;
+    use error::QUIC_INVALID_GOAWAY_DATA;
+    use error::QUIC_INVALID_ACK_DATA;
+    use futures::Async;
+    use futures::Stream;
+    use futures::Sink;
+    use session::SingleUseTickets;
+    use frames::ack_frame::AckFrame;
+    use packet::VERSION_NEGOTIATION;
+    use simulator::LinkConfig;
+    use simulator::Simulator;
+
+    fn ping_packet() -> QuicPacket {
+        QuicPacket {
+            header: QuicHeader::Short(ShortHeader {
+                key_phase_bit: false,
+                conn_id_bit: true,
+                connection_id: Some(1),
+                packet_number: 1,
+                packet_type: FOUR_BYTES,
+            }),
+            payload: QuicPayload::Frames(vec![QuicFrame::Ping(PingFrame {})]),
+        }
+    }
+
+    fn established(side: Side, config: ConnectionConfig, now: Instant) -> QuicConnection {
+        let mut conn = QuicConnection::new(side, 1, config, now).unwrap();
+        conn.on_handshake_complete(now, TransportParameters::default(), false).unwrap();
+        conn
+    }
+
+    fn stream_packet(stream_id: u32) -> QuicPacket {
+        QuicPacket {
+            header: QuicHeader::Short(ShortHeader {
+                key_phase_bit: false,
+                conn_id_bit: true,
+                connection_id: Some(1),
+                packet_number: 1,
+                packet_type: FOUR_BYTES,
+            }),
+            payload: QuicPayload::Frames(vec![QuicFrame::Stream(StreamFrame {
+                fin: false,
+                data_length_present: false,
+                data_length: None,
+                stream_id: stream_id,
+                offset: 0,
+                stream_data: Bytes::from(vec![1, 2, 3]),
+            })]),
+        }
+    }
+
+    fn ack_packet(smallest: u64, largest: u64) -> QuicPacket {
+        QuicPacket {
+            header: stream_packet(1).header,
+            payload: QuicPayload::Frames(vec![QuicFrame::Ack(AckFrame::from_ranges(&[(smallest, largest)], 0))]),
+        }
+    }
+
+    fn go_away_packet(largest_client_stream_id: u32, largest_server_stream_id: u32) -> QuicPacket {
+        QuicPacket {
+            header: QuicHeader::Short(ShortHeader {
+                key_phase_bit: false,
+                conn_id_bit: true,
+                connection_id: Some(1),
+                packet_number: 2,
+                packet_type: FOUR_BYTES,
+            }),
+            payload: QuicPayload::Frames(vec![QuicFrame::GoAway(GoAwayFrame {
+                largest_client_stream_id: largest_client_stream_id,
+                largest_server_stream_id: largest_server_stream_id,
+            })]),
+        }
+    }
+
+    #[test]
+    fn negotiates_smaller_idle_timeout() {
+        let now = Instant::now();
+        let mut conn = established(Side::Client, ConnectionConfig::default(), now);
+
+        conn.set_peer_transport_parameters(TransportParameters {
+            idle_timeout: 10,
+            ..TransportParameters::default()
+        }).unwrap();
+
+        assert_eq!(conn.idle_timeout(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn rejects_invalid_peer_idle_timeout() {
+        let now = Instant::now();
+        let mut conn = QuicConnection::new(Side::Client, 1, ConnectionConfig::default(), now).unwrap();
+
+        for &idle_timeout in &[0, MAX_IDLE_TIMEOUT + 1] {
+            let params = TransportParameters { idle_timeout: idle_timeout, ..TransportParameters::default() };
+
+            match conn.set_peer_transport_parameters(params) {
+                Err(QuicError::TransportError(code)) => assert_eq!(code, QUIC_INVALID_NEGOTIATED_VALUE),
+                result => panic!("expected an error, got {:?}", result),
+            }
+        }
+
+        assert_eq!(conn.idle_timeout(), Duration::from_secs(30));
+        assert!(conn.peer_transport_parameters.is_none());
+    }
+
+    #[test]
+    fn idle_timeout_closes_silently() {
+        let now = Instant::now();
+        let mut conn = established(Side::Client, ConnectionConfig::default(), now);
+
+        conn.on_packet_received(now + Duration::from_secs(20), ping_packet()).unwrap();
+
+        let deadline = conn.next_timeout().unwrap();
+        assert_eq!(deadline, now + Duration::from_secs(50));
+
+        conn.on_timeout(deadline);
+
+        assert!(conn.is_closed());
+        assert_eq!(conn.close_reason, Some(QUIC_NETWORK_IDLE_TIMEOUT));
+        assert!(conn.poll_transmit(deadline).is_none());
+    }
+
+    #[test]
+    fn keep_alive_sends_ping() {
+        let now = Instant::now();
+        let config = ConnectionConfig {
+            keep_alive_interval: Some(Duration::from_secs(5)),
+            ..ConnectionConfig::default()
+        };
+        let mut conn = established(Side::Client, config, now);
+
+        let deadline = conn.next_timeout().unwrap();
+        assert_eq!(deadline, now + Duration::from_secs(5));
+
+        conn.on_timeout(deadline);
+
+        let packet = conn.poll_transmit(deadline).unwrap();
+
+        match packet.payload {
+            QuicPayload::Frames(ref frames) => assert_eq!(frames, &vec![QuicFrame::Ping(PingFrame {})]),
+            _ => panic!("expected frames"),
+        }
+
+        // Until the PING is acknowledged, the probe timeout comes first.
+        assert!(conn.next_timeout().unwrap() < deadline + Duration::from_secs(5));
+
+        conn.on_packet_received(deadline, ack_packet(0, 0)).unwrap();
+        assert_eq!(conn.next_timeout().unwrap(), deadline + Duration::from_secs(5));
+    }
+
+    #[test]
+    fn go_away_refuses_new_peer_streams() {
+        let now = Instant::now();
+        let mut server = established(Side::Server, ConnectionConfig::default(), now);
+
+        server.on_packet_received(now, stream_packet(1)).unwrap();
+        assert_eq!(server.accept_stream(), Some(1));
+        assert_eq!(server.readable_stream(), Some(1));
+
+        server.go_away();
+
+        match server.open_stream() {
+            Err(QuicError::TransportError(err)) => assert_eq!(err, QUIC_CONNECTION_CANCELLED),
+            _ => panic!("expected QUIC_CONNECTION_CANCELLED"),
+        }
+
+        // In-flight streams keep working, new ones are reset.
+        server.on_packet_received(now, stream_packet(1)).unwrap();
+        server.on_packet_received(now, stream_packet(3)).unwrap();
+        assert_eq!(server.accept_stream(), None);
+        assert_eq!(server.readable_stream(), None);
+
+        let packet = server.poll_transmit(now).unwrap();
+
+        match packet.payload {
+            QuicPayload::Frames(ref frames) => match frames[0] {
+                QuicFrame::Ack(ref ack) => assert_eq!(ack.ranges(), vec![(1, 1)]),
+                ref frame => panic!("expected an ACK first, got {:?}", frame),
+            },
+            _ => panic!("expected frames"),
+        }
+
+        match packet.payload {
+            QuicPayload::Frames(ref frames) => assert_eq!(&frames[1..], &[
+                QuicFrame::GoAway(GoAwayFrame {
+                    largest_client_stream_id: 1,
+                    largest_server_stream_id: 0,
+                }),
+                QuicFrame::ResetStream(ResetStreamFrame {
+                    error_code: QUIC_PEER_GOING_AWAY.bits(),
+                    stream_id: 3,
+                    final_offset: 0,
+                }),
+            ]),
+            _ => panic!("expected frames"),
+        }
+    }
+
+    #[test]
+    fn peer_go_away_fails_new_streams() {
+        let now = Instant::now();
+        let mut client = established(Side::Client, ConnectionConfig::default(), now);
+
+        assert_eq!(client.open_stream().unwrap(), 1);
+        assert_eq!(client.open_stream().unwrap(), 3);
+        assert_eq!(client.open_stream().unwrap(), 5);
+
+        client.on_packet_received(now, go_away_packet(1, 0)).unwrap();
+
+        match client.open_stream() {
+            Err(QuicError::TransportError(err)) => assert_eq!(err, QUIC_PEER_GOING_AWAY),
+            _ => panic!("expected QUIC_PEER_GOING_AWAY"),
+        }
+
+        assert_eq!(client.unprocessed_streams(), vec![3, 5]);
+    }
+
+    fn close_packet(error_code: u32) -> QuicPacket {
+        QuicPacket {
+            header: QuicHeader::Short(ShortHeader {
+                key_phase_bit: false,
+                conn_id_bit: true,
+                connection_id: Some(1),
+                packet_number: 3,
+                packet_type: FOUR_BYTES,
+            }),
+            payload: QuicPayload::Frames(vec![QuicFrame::ConnectionClose(ConnectionCloseFrame {
+                error_code: error_code,
+                reason_length: 0,
+                reason_phrase: None,
+            })]),
+        }
+    }
+
+    fn sent_frames(packet: QuicPacket) -> Vec<QuicFrame> {
+        match packet.payload {
+            QuicPayload::Frames(frames) => frames,
+            _ => panic!("expected frames"),
+        }
+    }
+
+    #[test]
+    fn close_repeats_connection_close_while_closing() {
+        let now = Instant::now();
+        let mut conn = established(Side::Client, ConnectionConfig::default(), now);
+        let id = conn.open_stream().unwrap();
+
+        conn.close(now, QUIC_INVALID_STREAM_DATA, "bad data");
+        assert_eq!(conn.state, ConnectionState::Closing);
+
+        let close = ConnectionCloseFrame {
+            error_code: QUIC_INVALID_STREAM_DATA.bits(),
+            reason_length: 8,
+            reason_phrase: Some("bad data".to_string()),
+        };
+
+        assert_eq!(sent_frames(conn.poll_transmit(now).unwrap()), vec![QuicFrame::ConnectionClose(close.clone())]);
+        assert!(conn.poll_transmit(now).is_none());
+
+        // Repeated on the 1st, 2nd and 4th packet, but not the 3rd.
+        let mut repeats = 0;
+        for _ in 0..4 {
+            conn.on_packet_received(now, ping_packet()).unwrap();
+            if let Some(packet) = conn.poll_transmit(now) {
+                assert_eq!(sent_frames(packet), vec![QuicFrame::ConnectionClose(close.clone())]);
+                repeats += 1;
+            }
+        }
+        assert_eq!(repeats, 3);
+
+        match conn.stream(id).unwrap().poll() {
+            Err(QuicError::TransportError(err)) => assert_eq!(err, QUIC_INVALID_STREAM_DATA),
+            _ => panic!("expected stream to fail"),
+        }
+
+        let deadline = conn.next_timeout().unwrap();
+        conn.on_timeout(deadline);
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn peer_close_enters_draining() {
+        let now = Instant::now();
+        let mut conn = established(Side::Client, ConnectionConfig::default(), now);
+        let id = conn.open_stream().unwrap();
+
+        conn.on_packet_received(now, close_packet(QUIC_PEER_GOING_AWAY.bits())).unwrap();
+
+        assert_eq!(conn.state, ConnectionState::Draining);
+        assert_eq!(conn.close_reason, Some(QUIC_PEER_GOING_AWAY));
+
+        conn.on_packet_received(now, ping_packet()).unwrap();
+        assert!(conn.poll_transmit(now).is_none());
+
+        match conn.stream(id).unwrap().poll() {
+            Err(QuicError::TransportError(err)) => assert_eq!(err, QUIC_PEER_GOING_AWAY),
+            _ => panic!("expected stream to fail"),
+        }
+
+        // Three probe timeouts of 333ms + 4 * 166.5ms + 25ms, before any
+        // RTT sample.
+        let deadline = conn.next_timeout().unwrap();
+        assert_eq!(deadline, now + Duration::from_millis(3 * 1024));
+        conn.on_timeout(deadline);
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn frames_after_connection_close_are_ignored() {
+        let now = Instant::now();
+        let mut conn = QuicConnection::new(Side::Server, 1, ConnectionConfig::default(), now).unwrap();
+
+        let mut packet = close_packet(QUIC_PEER_GOING_AWAY.bits());
+
+        if let QuicPayload::Frames(ref mut frames) = packet.payload {
+            frames.extend(sent_frames(stream_packet(1)));
+        }
+
+        conn.on_packet_received(now, packet).unwrap();
+
+        assert_eq!(conn.state, ConnectionState::Draining);
+        assert_eq!(conn.accept_stream(), None);
+        assert!(conn.stream(1).is_none());
+    }
+
+    #[test]
+    fn peer_sees_clean_close() {
+        let now = Instant::now();
+        let mut client = QuicConnection::new(Side::Client, 1, ConnectionConfig::default(), now).unwrap();
+        let mut server = QuicConnection::new(Side::Server, 1, ConnectionConfig::default(), now).unwrap();
+
+        client.close(now, TransportErrorFlag::empty(), "");
+        server.on_packet_received(now, client.poll_transmit(now).unwrap()).unwrap();
+
+        assert_eq!(server.state, ConnectionState::Draining);
+        assert_eq!(server.close_reason, Some(TransportErrorFlag::empty()));
+    }
+
+    #[test]
+    fn close_clamps_long_reason() {
+        let now = Instant::now();
+        let mut conn = QuicConnection::new(Side::Client, 1, ConnectionConfig::default(), now).unwrap();
+
+        let reason = "\u{e9}".repeat(40000);
+        conn.close(now, QUIC_INVALID_STREAM_DATA, &reason);
+
+        let frame = conn.close_frame.clone().unwrap();
+        assert_eq!(frame.reason_length, 65534);
+        assert_eq!(frame.reason_phrase.unwrap().len(), 65534);
+    }
+
+    fn early_data_packet(stream_id: u32) -> QuicPacket {
+        QuicPacket {
+            header: QuicHeader::Long(LongHeader {
+                packet_type: RTT0_ENCRYPTED,
+                connection_id: 1,
+                packet_number: 0,
+                version: QUIC_VERSION,
+                token: Bytes::new(),
+            }),
+            payload: QuicPayload::Frames(vec![QuicFrame::Stream(StreamFrame {
+                fin: false,
+                data_length_present: false,
+                data_length: None,
+                stream_id: stream_id,
+                offset: 0,
+                stream_data: Bytes::from(vec![1, 2, 3]),
+            })]),
+        }
+    }
+
+    fn resuming_client(now: Instant) -> QuicConnection {
+        let config = ConnectionConfig {
+            session_ticket: Some(SessionTicket {
+                ticket: vec![7, 7, 7],
+                transport_parameters: TransportParameters::default(),
+            }),
+            ..ConnectionConfig::default()
+        };
+
+        let mut client = QuicConnection::new(Side::Client, 1, config, now).unwrap();
+        client.set_0rtt_key(PacketKey::from_secret(&[3u8; 32]).unwrap());
+        let id = client.open_stream().unwrap();
+        client.stream(id).unwrap().start_send(Bytes::from(vec![1, 2, 3])).unwrap();
+
+        client
+    }
+
+    #[test]
+    fn client_sends_early_data() {
+        let now = Instant::now();
+        let mut client = resuming_client(now);
+
+        let packet = client.poll_transmit(now).unwrap();
+
+        assert_eq!(packet, early_data_packet(1));
+
+        client.on_handshake_complete(now, TransportParameters::default(), true).unwrap();
+        assert_eq!(client.early_data, EarlyDataState::Accepted);
+        assert!(client.poll_transmit(now).is_none());
+    }
+
+    #[test]
+    fn rejected_early_data_is_resent() {
+        let now = Instant::now();
+        let mut client = resuming_client(now);
+
+        client.poll_transmit(now).unwrap();
+        client.on_handshake_complete(now, TransportParameters::default(), false).unwrap();
+        assert_eq!(client.early_data, EarlyDataState::Rejected);
+
+        let packet = client.poll_transmit(now).unwrap();
+
+        match packet.header {
+            QuicHeader::Short(_) => {},
+            QuicHeader::Long(_) => panic!("expected 1-RTT packet"),
+        }
+
+        assert_eq!(packet.payload, early_data_packet(1).payload);
+    }
+
+    #[test]
+    fn retry_resends_early_data_once() {
+        let now = Instant::now();
+        let mut client = resuming_client(now);
+        client.poll_transmit(now).unwrap();
+
+        let retry = |token: &[u8]| QuicPacket {
+            header: QuicHeader::Long(LongHeader {
+                packet_type: RETRY,
+                connection_id: 1,
+                packet_number: 0,
+                version: QUIC_VERSION,
+                token: Bytes::from(token),
+            }),
+            payload: QuicPayload::Frames(vec![]),
+        };
+
+        client.on_packet_received(now, retry(&[1, 2, 3])).unwrap();
+        assert_eq!(client.retry_token(), Some(&Bytes::from(&[1, 2, 3][..])));
+
+        // The early data goes out again under a new packet number.
+        let packet = client.poll_transmit(now).unwrap();
+        assert_eq!(packet.payload, early_data_packet(1).payload);
+
+        client.on_packet_received(now, retry(&[4, 5, 6])).unwrap();
+        assert_eq!(client.retry_token(), Some(&Bytes::from(&[1, 2, 3][..])));
+        assert!(client.poll_transmit(now).is_none());
+    }
+
+    #[test]
+    fn server_applies_anti_replay() {
+        let now = Instant::now();
+        let anti_replay: Arc<Mutex<dyn AntiReplay>> = Arc::new(Mutex::new(SingleUseTickets::default()));
+        let config = ConnectionConfig {
+            anti_replay: Some(anti_replay),
+            ..ConnectionConfig::default()
+        };
+
+        let mut server = QuicConnection::new(Side::Server, 1, config.clone(), now).unwrap();
+        assert!(server.on_resumption_attempt(&[7, 7, 7]));
+        server.on_packet_received(now, early_data_packet(1)).unwrap();
+        assert_eq!(server.accept_stream(), Some(1));
+
+        let mut replayed = QuicConnection::new(Side::Server, 2, config, now).unwrap();
+        assert!(!replayed.on_resumption_attempt(&[7, 7, 7]));
+        replayed.on_packet_received(now, early_data_packet(1)).unwrap();
+        assert_eq!(replayed.accept_stream(), None);
+    }
+
+    #[test]
+    fn early_data_is_sealed_with_the_0rtt_key() {
+        let now = Instant::now();
+        let mut client = resuming_client(now);
+        let mut datagram = BytesMut::with_capacity(1232);
+        assert!(client.poll_datagram(now, &mut datagram).unwrap());
+
+        // Without the key the server can't read it, and drops it.
+        let anti_replay: Arc<Mutex<dyn AntiReplay>> = Arc::new(Mutex::new(SingleUseTickets::default()));
+        let config = ConnectionConfig {
+            anti_replay: Some(anti_replay),
+            ..ConnectionConfig::default()
+        };
+
+        let mut server = QuicConnection::new(Side::Server, 1, config, now).unwrap();
+        assert!(server.on_resumption_attempt(&[7, 7, 7]));
+        assert!(server.on_datagram(now, datagram.clone()).is_err());
+        assert_eq!(server.accept_stream(), None);
+
+        server.set_0rtt_key(PacketKey::from_secret(&[3u8; 32]).unwrap());
+        server.on_datagram(now, datagram).unwrap();
+        assert_eq!(server.accept_stream(), Some(1));
+    }
+
+    #[test]
+    fn key_update_over_the_wire() {
+        let now = Instant::now();
+        let mut client = established(Side::Client, ConnectionConfig::default(), now);
+        let mut server = established(Side::Server, ConnectionConfig::default(), now);
+
+        client.set_1rtt_keys(KeySchedule::new(&[1u8; 32], &[2u8; 32]).unwrap());
+        server.set_1rtt_keys(KeySchedule::new(&[2u8; 32], &[1u8; 32]).unwrap());
+
+        let id = client.open_stream().unwrap();
+        client.stream(id).unwrap().start_send(Bytes::from(vec![1, 2, 3])).unwrap();
+        let mut datagram = BytesMut::with_capacity(1232);
+        assert!(client.poll_datagram(now, &mut datagram).unwrap());
+        server.on_datagram(now, datagram.take()).unwrap();
+        assert_eq!(server.accept_stream(), Some(id));
+
+        assert!(client.initiate_key_update(now).unwrap());
+        client.stream(id).unwrap().start_send(Bytes::from(vec![4, 5, 6])).unwrap();
+        assert!(client.poll_datagram(now, &mut datagram).unwrap());
+
+        // The key phase bit is hidden by header protection, so the update
+        // only shows once the server has unprotected the packet.
+        server.on_datagram(now, datagram.take()).unwrap();
+        assert!(server.key_phase());
+
+        let stream = server.stream(id).unwrap();
+
+        for expected in [vec![1, 2, 3], vec![4, 5, 6]] {
+            match stream.poll() {
+                Ok(Async::Ready(Some(bytes))) => assert_eq!(bytes, expected),
+                _ => panic!("expected stream data"),
+            }
+        }
+    }
+
+    #[test]
+    fn header_protection_hides_packet_number() {
+        let now = Instant::now();
+        let mut client = established(Side::Client, ConnectionConfig::default(), now);
+        let mut server = established(Side::Server, ConnectionConfig::default(), now);
+
+        client.set_1rtt_keys(KeySchedule::new(&[1u8; 32], &[2u8; 32]).unwrap());
+        server.set_1rtt_keys(KeySchedule::new(&[2u8; 32], &[1u8; 32]).unwrap());
+
+        let id = client.open_stream().unwrap();
+        client.stream(id).unwrap().start_send(Bytes::from(vec![1, 2, 3])).unwrap();
+
+        let packet_number = client.recovery.next_packet_number(PacketNumberSpace::Application);
+        let mut datagram = BytesMut::with_capacity(1232);
+        assert!(client.poll_datagram(now, &mut datagram).unwrap());
+
+        let header = ShortHeader::from_bytes(&datagram).ok();
+        assert!(header.is_none_or(|header| header.packet_number != packet_number));
+
+        server.on_datagram(now, datagram.take()).unwrap();
+        assert_eq!(server.accept_stream(), Some(id));
+    }
+
+    #[test]
+    fn handshake_stream_goes_in_initial_packets() {
+        let now = Instant::now();
+        let mut client = QuicConnection::new(Side::Client, 1, ConnectionConfig::default(), now).unwrap();
+        let mut server = QuicConnection::new(Side::Server, 1, ConnectionConfig::default(), now).unwrap();
+
+        client.stream(0).unwrap().start_send(Bytes::from(vec![1, 2, 3])).unwrap();
+
+        let mut datagram = BytesMut::with_capacity(1232);
+        assert!(client.poll_datagram(now, &mut datagram).unwrap());
+        assert_eq!(datagram.len(), MAX_PACKET_SIZE);
+
+        // Anyone can derive the Initial keys, but without them it's noise.
+        assert!(QuicPacket::decode_unprotected(&version::DRAFT_05, &datagram.clone().freeze()).is_err());
+
+        server.on_datagram(now, datagram.take()).unwrap();
+
+        match server.stream(0).unwrap().poll() {
+            Ok(Async::Ready(Some(bytes))) => assert_eq!(bytes, vec![1, 2, 3]),
+            _ => panic!("expected the handshake data"),
+        }
+
+        // The server acknowledges it from the same space.
+        assert!(server.poll_datagram(now, &mut datagram).unwrap());
+        client.on_datagram(now, datagram.take()).unwrap();
+        assert_eq!(client.stats().bytes_in_flight, 0);
+    }
+
+    #[test]
+    fn probe_resends_client_initial() {
+        let now = Instant::now();
+        let mut client = QuicConnection::new(Side::Client, 1, ConnectionConfig::default(), now).unwrap();
+
+        client.stream(0).unwrap().start_send(Bytes::from(vec![1, 2, 3])).unwrap();
+        let first = client.poll_transmit(now).unwrap();
+        assert_eq!(PacketNumberSpace::from_header(&first.header), PacketNumberSpace::Initial);
+
+        let probe_time = client.next_timeout().unwrap();
+        client.on_timeout(probe_time);
+
+        let probe = client.poll_transmit(probe_time).unwrap();
+        assert_eq!(probe.header.packet_number(), first.header.packet_number() + 1);
+        assert_eq!(probe.payload, first.payload);
+        assert!(client.poll_transmit(probe_time).is_none());
+    }
+
+    #[test]
+    fn congestion_window_limits_sending() {
+        let now = Instant::now();
+        let mut client = established(Side::Client, ConnectionConfig::default(), now);
+
+        let id = client.open_stream().unwrap();
+        client.stream(id).unwrap().start_send(Bytes::from(vec![0; 50000])).unwrap();
+
+        let mut sent = 0;
+        while client.poll_transmit(now).is_some() {
+            sent += 1;
+        }
+
+        let window = client.recovery.congestion_window();
+        assert!(client.recovery.bytes_in_flight() >= window);
+        assert!(client.recovery.bytes_in_flight() < window + client.max_packet_size());
+
+        // Acknowledging them opens the window again, and wider.
+        let ack_time = now + Duration::from_millis(10);
+        client.on_packet_received(ack_time, ack_packet(0, sent - 1)).unwrap();
+
+        assert!(client.recovery.congestion_window() > window);
+        assert!(client.poll_transmit(ack_time).is_some());
+    }
+
+    #[test]
+    fn lost_stream_data_is_sent_again() {
+        let now = Instant::now();
+        let mut client = established(Side::Client, ConnectionConfig::default(), now);
+
+        let id = client.open_stream().unwrap();
+        client.stream(id).unwrap().start_send(Bytes::from(vec![1, 2, 3])).unwrap();
+        let lost = sent_frames(client.poll_transmit(now).unwrap());
+
+        // Nothing comes back, so the probe timeout sends the data again...
+        let probe_time = client.next_timeout().unwrap();
+        client.on_timeout(probe_time);
+        assert_eq!(sent_frames(client.poll_transmit(probe_time).unwrap()), lost);
+
+        // ...and its acknowledgement shows the first packet went missing.
+        let ack_time = probe_time + Duration::from_millis(10);
+        client.on_packet_received(ack_time, ack_packet(1, 1)).unwrap();
+        assert_eq!(client.rtt().latest(), Duration::from_millis(10));
+
+        assert_eq!(sent_frames(client.poll_transmit(ack_time).unwrap()), lost);
+
+        let stats = client.stats();
+        assert_eq!((stats.packets_retransmitted, stats.bytes_retransmitted), (2, 6));
+    }
+
+    #[test]
+    fn stats_match_the_link() {
+        let link = LinkConfig {
+            delay: Duration::from_millis(25),
+            loss: 0.1,
+            ..LinkConfig::default()
+        };
+        let mut sim = Simulator::connected(link, 5);
+
+        assert!(sim.transfer(&vec![7; 30000]));
+
+        let client = sim.client.stats();
+        let server = sim.server.stats();
+
+        assert_eq!(client.packets_sent, sim.to_server.stats.sent);
+        assert_eq!(server.packets_received, sim.to_server.stats.delivered);
+        assert_eq!(server.bytes_received, sim.to_server.stats.bytes_delivered);
+        assert!(client.packets_lost > 0 && client.packets_lost <= sim.to_server.stats.lost);
+        assert!(client.packets_retransmitted > 0);
+        assert!(client.bytes_retransmitted > 0 && client.bytes_retransmitted < client.bytes_lost);
+        assert!(client.rtt.smoothed() >= Duration::from_millis(50));
+        assert!(client.handshake_duration.unwrap() >= Duration::from_millis(50));
+        assert_eq!((client.local_streams, client.peer_streams), (1, 0));
+        assert_eq!((server.local_streams, server.peer_streams), (0, 1));
+        assert_eq!(server.receive_credit, 65536 - 30000);
+
+        // The losses held the congestion window back.
+        let mut clean = Simulator::connected(LinkConfig { delay: Duration::from_millis(25), ..LinkConfig::default() }, 5);
+        assert!(clean.transfer(&vec![7; 30000]));
+        assert!(client.congestion_window < clean.client.stats().congestion_window);
+    }
+
+    #[test]
+    fn qlog_file_traces_loss() {
+        let dir = ::std::env::temp_dir().join(format!("quic-qlog-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&dir).unwrap();
+
+        let now = Instant::now();
+        let config = ConnectionConfig {
+            qlog_dir: Some(dir.clone()),
+            ..ConnectionConfig::default()
+        };
+        let mut client = QuicConnection::new(Side::Client, 0x1234, config, now).unwrap();
+        client.on_handshake_complete(now, TransportParameters::default(), false).unwrap();
+
+        let id = client.open_stream().unwrap();
+        client.stream(id).unwrap().start_send(Bytes::from(vec![1, 2, 3])).unwrap();
+        client.poll_transmit(now).unwrap();
+
+        let probe_time = client.next_timeout().unwrap();
+        client.on_timeout(probe_time);
+        client.poll_transmit(probe_time).unwrap();
+        client.on_packet_received(probe_time + Duration::from_millis(10), ack_packet(1, 1)).unwrap();
+        drop(client);
+
+        let trace = ::std::fs::read_to_string(dir.join("0000000000001234-client.sqlog")).unwrap();
+        ::std::fs::remove_dir_all(&dir).unwrap();
+
+        let records: Vec<::serde_json::Value> = trace.split('\x1e')
+            .filter(|record| !record.is_empty())
+            .map(|record| ::serde_json::from_str(record).unwrap())
+            .collect();
+        let names: Vec<&str> = records[1..].iter().map(|event| event["name"].as_str().unwrap()).collect();
+
+        assert_eq!(records[0]["trace"]["vantage_point"]["type"], "client");
+        assert_eq!(names, vec![
+            "transport:packet_sent", "recovery:metrics_updated",
+            "transport:stream_state_updated", "transport:stream_state_updated",
+            "transport:packet_sent", "recovery:metrics_updated",
+            "transport:packet_received", "recovery:packet_lost", "recovery:metrics_updated",
+        ]);
+        assert_eq!(records[4]["data"], json!({ "stream_id": id, "new": "idle" }));
+        assert_eq!(records[8]["data"]["header"], json!({ "packet_type": "1RTT", "packet_number": 0 }));
+        assert_eq!(records[9]["data"]["latest_rtt"], 10.0);
+        assert!(records[9]["data"]["congestion_window"].as_u64().unwrap() < records[2]["data"]["congestion_window"].as_u64().unwrap());
+    }
+
+    /// Keeps the message and `conn_id` of every record that has one.
+    struct CaptureLogger(Mutex<Vec<(String, u64)>>);
+
+    impl ::log::Log for CaptureLogger {
+        fn enabled(&self, _: &::log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &::log::Record) {
+            if let Some(conn_id) = record.key_values().get(::log::kv::Key::from("conn_id")).and_then(|id| id.to_u64()) {
+                self.0.lock().unwrap().push((record.args().to_string(), conn_id));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn logs_carry_connection_id() {
+        static LOGGER: ::std::sync::OnceLock<CaptureLogger> = ::std::sync::OnceLock::new();
+        let logger = LOGGER.get_or_init(|| CaptureLogger(Mutex::new(Vec::new())));
+        // Another test may have set a logger first; then nothing is captured
+        // and there is nothing to check.
+        if ::log::set_logger(logger).is_err() {
+            return;
+        }
+        ::log::set_max_level(::log::LevelFilter::Trace);
+
+        let now = Instant::now();
+        let mut conn = QuicConnection::new(Side::Server, 0x5eed, ConnectionConfig::default(), now).unwrap();
+        conn.on_handshake_complete(now, TransportParameters::default(), false).unwrap();
+        conn.on_packet_received(now, ping_packet()).unwrap();
+        conn.on_packet_received(now, stream_packet(1)).unwrap();
+
+        let records: Vec<String> = logger.0.lock().unwrap().iter()
+            .filter(|&&(_, conn_id)| conn_id == 0x5eed)
+            .map(|(message, _)| message.clone())
+            .collect();
+
+        assert_eq!(records, vec![
+            "state changed", "packet received", "frame received",
+            "packet received", "frame received", "stream frame received",
+        ]);
+    }
+
+    #[test]
+    fn ack_of_unsent_packet_closes() {
+        let now = Instant::now();
+        let mut client = established(Side::Client, ConnectionConfig::default(), now);
+
+        assert!(client.on_packet_received(now, ack_packet(0, 5)).is_err());
+
+        match sent_frames(client.poll_transmit(now).unwrap()).pop() {
+            Some(QuicFrame::ConnectionClose(frame)) => assert_eq!(frame.error_code, QUIC_INVALID_ACK_DATA.bits()),
+            other => panic!("expected CONNECTION_CLOSE, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn path_mtu_probe_raises_packet_size() {
+        let now = Instant::now();
+        let config = ConnectionConfig {
+            max_packet_size: 8952,
+            ..ConnectionConfig::default()
+        };
+        let mut client = established(Side::Client, config, now);
+        let mut server = established(Side::Server, ConnectionConfig::default(), now);
+
+        client.set_1rtt_keys(KeySchedule::new(&[1u8; 32], &[2u8; 32]).unwrap());
+        server.set_1rtt_keys(KeySchedule::new(&[2u8; 32], &[1u8; 32]).unwrap());
+
+        let mut datagram = BytesMut::new();
+        assert!(client.poll_datagram(now, &mut datagram).unwrap());
+        assert_eq!(datagram.len(), 8952);
+        server.on_datagram(now, datagram.take()).unwrap();
+
+        assert_eq!(client.max_packet_size(), MAX_PACKET_SIZE);
+
+        let ack = QuicPacket {
+            header: stream_packet(2).header,
+            payload: QuicPayload::Frames(vec![QuicFrame::Ack(AckFrame {
+                num_blocks: None,
+                num_ts: 0,
+                largest_ack: 0,
+                ack_delay: 0,
+                first_ack_len: 0,
+                ack_blocks: None,
+                delta_la: None,
+                first_ts: None,
+                timestamps: None,
+            })]),
+        };
+        client.on_packet_received(now, ack).unwrap();
+
+        assert_eq!(client.max_packet_size(), 8952);
+
+        let id = client.open_stream().unwrap();
+        client.stream(id).unwrap().start_send(Bytes::from(vec![7u8; 5000])).unwrap();
+        assert!(client.poll_datagram(now, &mut datagram).unwrap());
+        assert!(datagram.len() > 5000);
+        assert!(client.poll_transmit(now).is_none());
+    }
+
+    #[test]
+    fn malformed_frame_closes_with_its_error() {
+        let now = Instant::now();
+        let mut server = established(Side::Server, ConnectionConfig::default(), now);
+
+        let mut datagram = BytesMut::new();
+        ShortHeader {
+            key_phase_bit: false,
+            conn_id_bit: true,
+            connection_id: Some(1),
+            packet_number: 1,
+            packet_type: FOUR_BYTES,
+        }.write_to(&mut datagram);
+        datagram.extend_from_slice(&[0x07, 0x03, 0x00]);
+
+        assert!(server.on_datagram(now, datagram).is_err());
+        assert_eq!(server.state, ConnectionState::Closing);
+
+        match sent_frames(server.poll_transmit(now).unwrap()).pop() {
+            Some(QuicFrame::ConnectionClose(frame)) => assert_eq!(frame.error_code, QUIC_INVALID_GOAWAY_DATA.bits()),
+            other => panic!("expected CONNECTION_CLOSE, got {:?}", other),
+        }
+    }
+
+    fn version_negotiation_packet(versions: Vec<u32>) -> QuicPacket {
+        QuicPacket {
+            header: QuicHeader::Long(LongHeader {
+                packet_type: VERSION_NEGOTIATION,
+                connection_id: 1,
+                packet_number: 0,
+                version: QUIC_VERSION,
+                token: Bytes::new(),
+            }),
+            payload: QuicPayload::VersionNegotiation(VersionNegotiationPayload {
+                versions: versions,
+            }),
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_version() {
+        let config = ConnectionConfig {
+            version: 0x1a2a3a4a,
+            ..ConnectionConfig::default()
+        };
+
+        assert!(QuicConnection::new(Side::Client, 1, config, Instant::now()).is_err());
+    }
+
+    #[test]
+    fn version_negotiation_without_common_version_fails() {
+        let now = Instant::now();
+        let mut client = QuicConnection::new(Side::Client, 1, ConnectionConfig::default(), now).unwrap();
+
+        // A list naming our own version is ignored.
+        client.on_packet_received(now, version_negotiation_packet(vec![QUIC_VERSION])).unwrap();
+        assert_eq!(client.state, ConnectionState::Handshaking);
+
+        client.on_packet_received(now, version_negotiation_packet(vec![0x1a2a3a4a])).unwrap();
+        assert!(client.is_closed());
+        assert_eq!(client.close_reason, Some(QUIC_INVALID_VERSION));
+    }
+}
@@ -0,0 +1,696 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, WriteBytesExt, BigEndian};
+use bytes::{Bytes, BytesMut};
+use ring::aead;
+use ring::hkdf;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use error::Result;
+use error::QuicError;
+use error::QUIC_DECRYPTION_FAILURE;
+use error::QUIC_ENCRYPTION_FAILURE;
+use error::QUIC_INTERNAL_ERROR;
+use error::QUIC_ADDRESS_VALIDATION_FAILURE;
+
+use connection::Side;
+
+/// How long keys from the previous phase are kept after an update, so that
+/// packets reordered across the update can still be read.
+const OLD_KEY_RETENTION_MS: u64 = 600;
+
+const KEY_LEN: usize = 16;
+const IV_LEN: usize = 12;
+const SECRET_LEN: usize = 32;
+
+struct OutputLen(usize);
+
+impl hkdf::KeyType for OutputLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Salt for the Initial secrets of QUIC version 1 (RFC 9001, section 5.2).
+pub const INITIAL_SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17,
+    0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+/// HKDF-Expand-Label from TLS 1.3 with an empty context.
+pub fn hkdf_expand_label(secret: &[u8], label: &[u8], len: usize) -> Result<Vec<u8>> {
+    expand_label(&hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, secret), label, len)
+}
+
+fn expand_label(prk: &hkdf::Prk, label: &[u8], len: usize) -> Result<Vec<u8>> {
+    let full_label = [&b"tls13 "[..], label].concat();
+
+    let mut length = Vec::with_capacity(2);
+    length.write_u16::<BigEndian>(len as u16);
+
+    let label_len = [full_label.len() as u8];
+    let context_len = [0u8];
+    let info = [&length[..], &label_len[..], &full_label[..], &context_len[..]];
+
+    let okm = prk.expand(&info, OutputLen(len))
+        .map_err(|_| QuicError::TransportError(QUIC_ENCRYPTION_FAILURE))?;
+
+    let mut out = vec![0u8; len];
+    okm.fill(&mut out)
+        .map_err(|_| QuicError::TransportError(QUIC_ENCRYPTION_FAILURE))?;
+
+    Ok(out)
+}
+
+/// AEAD key and IV derived from one traffic secret.
+pub struct PacketKey {
+    key: aead::LessSafeKey,
+    iv: [u8; IV_LEN],
+}
+
+impl fmt::Debug for PacketKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PacketKey {{ .. }}")
+    }
+}
+
+impl PacketKey {
+    pub fn from_secret(secret: &[u8]) -> Result<PacketKey> {
+        let key_bytes = hkdf_expand_label(secret, b"quic key", KEY_LEN)?;
+        let iv_bytes = hkdf_expand_label(secret, b"quic iv", IV_LEN)?;
+
+        let unbound = aead::UnboundKey::new(&aead::AES_128_GCM, &key_bytes)
+            .map_err(|_| QuicError::TransportError(QUIC_ENCRYPTION_FAILURE))?;
+
+        let mut iv = [0u8; IV_LEN];
+        iv.copy_from_slice(&iv_bytes);
+
+        Ok(PacketKey {
+            key: aead::LessSafeKey::new(unbound),
+            iv: iv,
+        })
+    }
+
+    fn nonce(&self, packet_number: u64) -> aead::Nonce {
+        let mut nonce = self.iv;
+
+        for i in 0..8 {
+            nonce[IV_LEN - 1 - i] ^= (packet_number >> (8 * i)) as u8;
+        }
+
+        aead::Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Encrypts `payload`, authenticating `header` along with it.
+    pub fn seal(&self, packet_number: u64, header: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+        let mut in_out = payload.to_vec();
+
+        self.key.seal_in_place_append_tag(self.nonce(packet_number), aead::Aad::from(header), &mut in_out)
+            .map_err(|_| QuicError::TransportError(QUIC_ENCRYPTION_FAILURE))?;
+
+        Ok(in_out)
+    }
+
+    /// Encrypts `payload` in place and appends the authentication tag.
+    pub fn seal_in_place(&self, packet_number: u64, header: &[u8], payload: &mut BytesMut) -> Result<()> {
+        self.key.seal_in_place_append_tag(self.nonce(packet_number), aead::Aad::from(header), payload)
+            .map_err(|_| QuicError::TransportError(QUIC_ENCRYPTION_FAILURE))
+    }
+
+    /// Decrypts `payload` in place and returns the length of the plaintext
+    /// at its start. If this fails the payload is left garbled.
+    pub fn open_in_place(&self, packet_number: u64, header: &[u8], payload: &mut [u8]) -> Result<usize> {
+        let plaintext = self.key.open_in_place(self.nonce(packet_number), aead::Aad::from(header), payload)
+            .map_err(|_| QuicError::TransportError(QUIC_DECRYPTION_FAILURE))?;
+
+        Ok(plaintext.len())
+    }
+
+    pub fn tag_len(&self) -> usize {
+        self.key.algorithm().tag_len()
+    }
+}
+
+/// The client and server Initial secrets. Both come from the destination
+/// connection ID of the client's first packet, so either end can work
+/// them out before any handshake messages have been exchanged.
+pub fn initial_secrets(salt: &[u8], connection_id: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let initial_secret = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(connection_id);
+
+    let client_secret = expand_label(&initial_secret, b"client in", SECRET_LEN)?;
+    let server_secret = expand_label(&initial_secret, b"server in", SECRET_LEN)?;
+
+    Ok((client_secret, server_secret))
+}
+
+/// Packet and header protection for Initial packets in both directions.
+#[derive(Debug)]
+pub struct InitialKeys {
+    pub local: PacketKey,
+    pub remote: PacketKey,
+    pub local_header: HeaderKey,
+    pub remote_header: HeaderKey,
+}
+
+impl InitialKeys {
+    pub fn new(side: Side, salt: &[u8], connection_id: &[u8]) -> Result<InitialKeys> {
+        let (client_secret, server_secret) = initial_secrets(salt, connection_id)?;
+
+        let (local_secret, remote_secret) = match side {
+            Side::Client => (client_secret, server_secret),
+            Side::Server => (server_secret, client_secret),
+        };
+
+        Ok(InitialKeys {
+            local: PacketKey::from_secret(&local_secret)?,
+            remote: PacketKey::from_secret(&remote_secret)?,
+            local_header: HeaderKey::from_secret(&aead::quic::AES_128, &local_secret)?,
+            remote_header: HeaderKey::from_secret(&aead::quic::AES_128, &remote_secret)?,
+        })
+    }
+
+    /// Initial keys for a draft-05 connection, whose IDs are 64-bit numbers
+    /// and go into the secrets big-endian.
+    pub fn from_connection_id(side: Side, salt: &[u8], connection_id: u64) -> Result<InitialKeys> {
+        let mut id = [0; 8];
+        BigEndian::write_u64(&mut id, connection_id);
+
+        InitialKeys::new(side, salt, &id)
+    }
+}
+
+/// First-byte bits hidden by header protection in long and short headers.
+pub const LONG_HEADER_BITS: u8 = 0x0f;
+pub const SHORT_HEADER_BITS: u8 = 0x1f;
+
+/// Header protection (RFC 9001, section 5.4). A mask computed from a sample
+/// of the ciphertext hides some bits of the first byte and the packet
+/// number, so middleboxes can't read or ossify on them.
+pub struct HeaderKey {
+    key: aead::quic::HeaderProtectionKey,
+}
+
+impl fmt::Debug for HeaderKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HeaderKey {{ .. }}")
+    }
+}
+
+impl HeaderKey {
+    pub fn new(algorithm: &'static aead::quic::Algorithm, key: &[u8]) -> Result<HeaderKey> {
+        let key = aead::quic::HeaderProtectionKey::new(algorithm, key)
+            .map_err(|_| QuicError::TransportError(QUIC_ENCRYPTION_FAILURE))?;
+
+        Ok(HeaderKey { key: key })
+    }
+
+    pub fn from_secret(algorithm: &'static aead::quic::Algorithm, secret: &[u8]) -> Result<HeaderKey> {
+        let key = hkdf_expand_label(secret, b"quic hp", algorithm.key_len())?;
+
+        HeaderKey::new(algorithm, &key)
+    }
+
+    /// The five mask bytes for a sample of ciphertext.
+    pub fn mask(&self, sample: &[u8]) -> Result<[u8; 5]> {
+        self.key.new_mask(sample)
+            .map_err(|_| QuicError::TransportError(QUIC_DECRYPTION_FAILURE))
+    }
+
+    /// The sample starts four bytes after the start of the packet number,
+    /// whatever its length, so both ends can find it.
+    fn packet_mask(&self, packet: &[u8], pn_offset: usize) -> Result<[u8; 5]> {
+        let sample_start = pn_offset + 4;
+        let sample_end = sample_start + self.key.algorithm().sample_len();
+
+        if packet.len() < sample_end {
+            return Err(QuicError::TransportError(QUIC_DECRYPTION_FAILURE));
+        }
+
+        self.mask(&packet[sample_start..sample_end])
+    }
+
+    /// Masks `bits` of the first byte and the `pn_len` packet number bytes
+    /// at `pn_offset` of a sealed packet.
+    pub fn protect(&self, packet: &mut [u8], pn_offset: usize, pn_len: usize, bits: u8) -> Result<()> {
+        let mask = self.packet_mask(packet, pn_offset)
+            .map_err(|_| QuicError::TransportError(QUIC_ENCRYPTION_FAILURE))?;
+
+        packet[0] ^= mask[0] & bits;
+
+        for i in 0..pn_len {
+            packet[pn_offset + i] ^= mask[1 + i];
+        }
+
+        Ok(())
+    }
+
+    /// Reverses `protect`. The packet number length is only known once the
+    /// first byte is unmasked, so `pn_len` reads it from there. Returns the
+    /// packet number length.
+    pub fn unprotect(&self, packet: &mut [u8], pn_offset: usize, bits: u8, pn_len: &dyn Fn(u8) -> usize) -> Result<usize> {
+        let mask = self.packet_mask(packet, pn_offset)?;
+
+        packet[0] ^= mask[0] & bits;
+
+        let len = pn_len(packet[0]);
+
+        if len > 4 || packet.len() < pn_offset + len {
+            return Err(QuicError::TransportError(QUIC_DECRYPTION_FAILURE));
+        }
+
+        for i in 0..len {
+            packet[pn_offset + i] ^= mask[1 + i];
+        }
+
+        Ok(len)
+    }
+}
+
+/// 1-RTT keys for both directions, rotated by key updates. The key phase
+/// bit of short headers says which generation protected a packet.
+#[derive(Debug)]
+pub struct KeySchedule {
+    key_phase: bool,
+    generation: u64,
+    local_secret: Vec<u8>,
+    remote_secret: Vec<u8>,
+    local_key: PacketKey,
+    remote_key: PacketKey,
+    next_remote_key: PacketKey,
+    previous_remote_key: Option<(PacketKey, Instant)>,
+    update_acknowledged: bool,
+    /// Header protection keys stay the same across key updates.
+    local_header_key: HeaderKey,
+    remote_header_key: HeaderKey,
+}
+
+impl KeySchedule {
+    pub fn new(local_secret: &[u8], remote_secret: &[u8]) -> Result<KeySchedule> {
+        let next_remote_secret = next_secret(remote_secret)?;
+
+        Ok(KeySchedule {
+            key_phase: false,
+            generation: 0,
+            local_secret: local_secret.to_vec(),
+            remote_secret: remote_secret.to_vec(),
+            local_key: PacketKey::from_secret(local_secret)?,
+            remote_key: PacketKey::from_secret(remote_secret)?,
+            next_remote_key: PacketKey::from_secret(&next_remote_secret)?,
+            previous_remote_key: None,
+            update_acknowledged: true,
+            local_header_key: HeaderKey::from_secret(&aead::quic::AES_128, local_secret)?,
+            remote_header_key: HeaderKey::from_secret(&aead::quic::AES_128, remote_secret)?,
+        })
+    }
+
+    pub fn key_phase(&self) -> bool {
+        self.key_phase
+    }
+
+    /// How many key updates have happened on this connection.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn local_key(&self) -> &PacketKey {
+        &self.local_key
+    }
+
+    pub fn local_header_key(&self) -> &HeaderKey {
+        &self.local_header_key
+    }
+
+    pub fn remote_header_key(&self) -> &HeaderKey {
+        &self.remote_header_key
+    }
+
+    /// Starts a key update. Refused while an earlier update hasn't yet been
+    /// answered by a packet from the peer under the new keys.
+    pub fn initiate_update(&mut self, now: Instant) -> Result<bool> {
+        if !self.update_acknowledged {
+            return Ok(false);
+        }
+
+        self.update(now)?;
+        self.update_acknowledged = false;
+
+        Ok(true)
+    }
+
+    fn update(&mut self, now: Instant) -> Result<()> {
+        let local_secret = next_secret(&self.local_secret)?;
+        let remote_secret = next_secret(&self.remote_secret)?;
+        let next_remote_key = PacketKey::from_secret(&next_secret(&remote_secret)?)?;
+
+        let local_key = PacketKey::from_secret(&local_secret)?;
+        let previous_remote_key = ::std::mem::replace(&mut self.remote_key, ::std::mem::replace(&mut self.next_remote_key, next_remote_key));
+
+        self.local_secret = local_secret;
+        self.remote_secret = remote_secret;
+        self.local_key = local_key;
+        self.previous_remote_key = Some((previous_remote_key, now + Duration::from_millis(OLD_KEY_RETENTION_MS)));
+        self.key_phase = !self.key_phase;
+        self.generation += 1;
+
+        Ok(())
+    }
+
+    pub fn seal(&self, packet_number: u64, header: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+        self.local_key.seal(packet_number, header, payload)
+    }
+
+    pub fn seal_in_place(&self, packet_number: u64, header: &[u8], payload: &mut BytesMut) -> Result<()> {
+        self.local_key.seal_in_place(packet_number, header, payload)
+    }
+
+    /// Decrypts a packet protected under `key_phase`. A phase different
+    /// from ours is either a reordered packet from before the last update
+    /// or the peer starting a new one; the old and the next keys are tried
+    /// in that order, and success with the next keys moves us along too.
+    pub fn open_in_place(&mut self, now: Instant, key_phase: bool, packet_number: u64, header: &[u8],
+                         payload: &mut [u8]) -> Result<usize> {
+        let expired = match self.previous_remote_key {
+            Some((_, expiry)) => now >= expiry,
+            None => false,
+        };
+
+        if expired {
+            self.previous_remote_key = None;
+        }
+
+        if key_phase == self.key_phase {
+            let len = self.remote_key.open_in_place(packet_number, header, payload)?;
+            self.update_acknowledged = true;
+
+            return Ok(len);
+        }
+
+        // A failed attempt garbles the payload, so the old key gets a copy
+        // in case the next key has to be tried on the original.
+        if let Some((ref key, _)) = self.previous_remote_key {
+            let mut copy = payload.to_vec();
+
+            if let Ok(len) = key.open_in_place(packet_number, header, &mut copy) {
+                payload[..len].copy_from_slice(&copy[..len]);
+                return Ok(len);
+            }
+        }
+
+        // We can't be asked to move on again before the peer has answered
+        // our own update.
+        if !self.update_acknowledged {
+            return Err(QuicError::TransportError(QUIC_DECRYPTION_FAILURE));
+        }
+
+        let len = self.next_remote_key.open_in_place(packet_number, header, payload)?;
+        self.update(now)?;
+
+        Ok(len)
+    }
+}
+
+fn next_secret(secret: &[u8]) -> Result<Vec<u8>> {
+    hkdf_expand_label(secret, b"quic ku", SECRET_LEN)
+}
+
+const TOKEN_TIME_LEN: usize = 8;
+
+/// Issues and checks the address validation tokens a server sends in Retry
+/// packets. A token holds the time it was issued and an HMAC over that time
+/// and the client's address, so checking one needs no per-client state.
+#[derive(Debug, Clone)]
+pub struct TokenKey {
+    key: hmac::Key,
+    epoch: Instant,
+    lifetime: Duration,
+}
+
+impl TokenKey {
+    /// Token times are counted from `epoch`, and a token stops being
+    /// accepted `lifetime` after it was issued.
+    pub fn new(secret: &[u8], epoch: Instant, lifetime: Duration) -> TokenKey {
+        TokenKey {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            epoch: epoch,
+            lifetime: lifetime,
+        }
+    }
+
+    /// A key with a random secret. Tokens it issues can't be checked by
+    /// any other key, including one made after a restart.
+    pub fn generate(epoch: Instant, lifetime: Duration) -> Result<TokenKey> {
+        let mut secret = [0; SECRET_LEN];
+        SystemRandom::new().fill(&mut secret)
+            .map_err(|_| QuicError::TransportError(QUIC_INTERNAL_ERROR))?;
+
+        Ok(TokenKey::new(&secret, epoch, lifetime))
+    }
+
+    pub fn issue(&self, now: Instant, address: &SocketAddr) -> Bytes {
+        let issued = self.millis_since_epoch(now);
+
+        let mut token = Vec::with_capacity(TOKEN_TIME_LEN + hmac::HMAC_SHA256.digest_algorithm().output_len);
+        token.write_u64::<BigEndian>(issued);
+        token.extend_from_slice(self.sign(issued, address).as_ref());
+
+        Bytes::from(token)
+    }
+
+    /// Fails with `QUIC_ADDRESS_VALIDATION_FAILURE` unless `token` was
+    /// issued by this key to `address` and hasn't expired.
+    pub fn validate(&self, now: Instant, address: &SocketAddr, token: &[u8]) -> Result<()> {
+        if token.len() < TOKEN_TIME_LEN {
+            return Err(QuicError::TransportError(QUIC_ADDRESS_VALIDATION_FAILURE));
+        }
+
+        let issued = BigEndian::read_u64(&token[..TOKEN_TIME_LEN]);
+
+        hmac::verify(&self.key, &token_message(issued, address), &token[TOKEN_TIME_LEN..])
+            .map_err(|_| QuicError::TransportError(QUIC_ADDRESS_VALIDATION_FAILURE))?;
+
+        match self.millis_since_epoch(now).checked_sub(issued) {
+            Some(age) if Duration::from_millis(age) <= self.lifetime => Ok(()),
+            _ => Err(QuicError::TransportError(QUIC_ADDRESS_VALIDATION_FAILURE)),
+        }
+    }
+
+    fn sign(&self, issued: u64, address: &SocketAddr) -> hmac::Tag {
+        hmac::sign(&self.key, &token_message(issued, address))
+    }
+
+    fn millis_since_epoch(&self, now: Instant) -> u64 {
+        let elapsed = now.duration_since(self.epoch);
+
+        elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
+    }
+}
+
+fn token_message(issued: u64, address: &SocketAddr) -> Vec<u8> {
+    let mut message = Vec::with_capacity(TOKEN_TIME_LEN + 18);
+    message.write_u64::<BigEndian>(issued);
+
+    match *address {
+        SocketAddr::V4(ref address) => message.extend_from_slice(&address.ip().octets()),
+        SocketAddr::V6(ref address) => message.extend_from_slice(&address.ip().octets()),
+    }
+
+    message.write_u16::<BigEndian>(address.port());
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoints() -> (KeySchedule, KeySchedule) {
+        let client_secret = [1u8; SECRET_LEN];
+        let server_secret = [2u8; SECRET_LEN];
+
+        (KeySchedule::new(&client_secret, &server_secret).unwrap(),
+         KeySchedule::new(&server_secret, &client_secret).unwrap())
+    }
+
+    fn open(keys: &mut KeySchedule, now: Instant, key_phase: bool, packet_number: u64, header: &[u8],
+            sealed: &[u8]) -> Result<Vec<u8>> {
+        let mut payload = sealed.to_vec();
+        let len = keys.open_in_place(now, key_phase, packet_number, header, &mut payload)?;
+        payload.truncate(len);
+
+        Ok(payload)
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let (client, mut server) = endpoints();
+        let now = Instant::now();
+
+        let sealed = client.seal(7, b"header", b"payload").unwrap();
+        assert_eq!(sealed.len(), 7 + client.local_key().tag_len());
+
+        assert_eq!(open(&mut server, now, false, 7, b"header", &sealed).unwrap(), b"payload");
+        assert!(open(&mut server, now, false, 7, b"tampered", &sealed).is_err());
+    }
+
+    #[test]
+    fn peer_initiated_update() {
+        let (mut client, mut server) = endpoints();
+        let now = Instant::now();
+
+        assert!(client.initiate_update(now).unwrap());
+        assert!(client.key_phase());
+
+        // A second update has to wait for the peer to catch up.
+        assert!(!client.initiate_update(now).unwrap());
+
+        let sealed = client.seal(1, b"h", b"after update").unwrap();
+        assert_eq!(open(&mut server, now, true, 1, b"h", &sealed).unwrap(), b"after update");
+        assert!(server.key_phase());
+        assert_eq!(server.generation(), 1);
+
+        let reply = server.seal(1, b"h", b"reply").unwrap();
+        assert_eq!(open(&mut client, now, true, 1, b"h", &reply).unwrap(), b"reply");
+        assert!(client.initiate_update(now).unwrap());
+    }
+
+    #[test]
+    fn old_keys_retained_briefly() {
+        let (mut client, mut server) = endpoints();
+        let now = Instant::now();
+
+        let delayed = client.seal(1, b"h", b"delayed").unwrap();
+
+        client.initiate_update(now).unwrap();
+        let sealed = client.seal(2, b"h", b"new").unwrap();
+        open(&mut server, now, true, 2, b"h", &sealed).unwrap();
+
+        assert_eq!(open(&mut server, now, false, 1, b"h", &delayed).unwrap(), b"delayed");
+
+        let later = now + Duration::from_millis(OLD_KEY_RETENTION_MS);
+        assert!(open(&mut server, later, false, 1, b"h", &delayed).is_err());
+        assert_eq!(server.generation(), 1);
+    }
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // RFC 9001, appendix A.2: the client Initial packet.
+    #[test]
+    fn header_protection_aes_vector() {
+        let secret = from_hex("c00cf151ca5be075ed0ebfb5c80323c42d6b7db67881289af4008f1f6c357aea");
+        assert_eq!(hkdf_expand_label(&secret, b"quic hp", 16).unwrap(),
+                   from_hex("9f50449e04a0e810283a1e9933adedd2"));
+
+        let key = HeaderKey::from_secret(&aead::quic::AES_128, &secret).unwrap();
+        let sample = from_hex("d1b1c98dd7689fb8ec11d242b123dc9b");
+        assert_eq!(key.mask(&sample).unwrap().to_vec(), from_hex("437b9aec36"));
+
+        let header = from_hex("c300000001088394c8f03e5157080000449e00000002");
+        let mut packet = [&header[..], &sample[..]].concat();
+
+        key.protect(&mut packet, 18, 4, LONG_HEADER_BITS).unwrap();
+        assert_eq!(&packet[..header.len()], &from_hex("c000000001088394c8f03e5157080000449e7b9aec34")[..]);
+
+        let pn_len = key.unprotect(&mut packet, 18, LONG_HEADER_BITS, &|first| (first & 0x03) as usize + 1).unwrap();
+        assert_eq!(pn_len, 4);
+        assert_eq!(&packet[..header.len()], &header[..]);
+    }
+
+    // RFC 9001, appendix A.5: a short header protected with ChaCha20.
+    #[test]
+    fn header_protection_chacha20_vector() {
+        let secret = from_hex("9ac312a7f877468ebe69422748ad00a15443f18203a07d6060f688f30f21632b");
+        let key = HeaderKey::from_secret(&aead::quic::CHACHA20, &secret).unwrap();
+
+        let mut packet = from_hex("4200bff4655e5cd55c41f69080575d7999c25a5bfb");
+        assert_eq!(key.mask(&packet[5..21]).unwrap().to_vec(), from_hex("aefefe7d03"));
+
+        key.protect(&mut packet, 1, 3, SHORT_HEADER_BITS).unwrap();
+        assert_eq!(&packet[..4], &from_hex("4cfe4189")[..]);
+
+        key.unprotect(&mut packet, 1, SHORT_HEADER_BITS, &|first| (first & 0x03) as usize + 1).unwrap();
+        assert_eq!(&packet[..4], &from_hex("4200bff4")[..]);
+    }
+
+    #[test]
+    fn header_protection_needs_full_sample() {
+        let (client, _) = endpoints();
+        let mut packet = vec![0x43; 20];
+
+        assert!(client.local_header_key().protect(&mut packet, 1, 4, SHORT_HEADER_BITS).is_err());
+    }
+
+    const INITIAL_CONNECTION_ID: [u8; 8] = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+    // RFC 9001, appendix A.1.
+    #[test]
+    fn initial_secrets_vector() {
+        let (client, server) = initial_secrets(&INITIAL_SALT_V1, &INITIAL_CONNECTION_ID).unwrap();
+
+        assert_eq!(client, from_hex("c00cf151ca5be075ed0ebfb5c80323c42d6b7db67881289af4008f1f6c357aea"));
+        assert_eq!(hkdf_expand_label(&client, b"quic key", KEY_LEN).unwrap(), from_hex("1f369613dd76d5467730efcbe3b1a22d"));
+        assert_eq!(hkdf_expand_label(&client, b"quic iv", IV_LEN).unwrap(), from_hex("fa044b2f42a3fd3b46fb255c"));
+
+        assert_eq!(server, from_hex("3c199828fd139efd216c155ad844cc81fb82fa8d7446fa7d78be803acdda951b"));
+        assert_eq!(hkdf_expand_label(&server, b"quic key", KEY_LEN).unwrap(), from_hex("cf3a5331653c364c88f0f379b6067e37"));
+        assert_eq!(hkdf_expand_label(&server, b"quic iv", IV_LEN).unwrap(), from_hex("0ac1493ca1905853b0bba03e"));
+        assert_eq!(hkdf_expand_label(&server, b"quic hp", KEY_LEN).unwrap(), from_hex("c206b8d9b9f0f37644430b490eeaa314"));
+    }
+
+    // RFC 9001, appendix A.3: the server Initial packet, sealed and
+    // header-protected, then opened again by the client.
+    #[test]
+    fn initial_keys_server_packet_vector() {
+        let server = InitialKeys::new(Side::Server, &INITIAL_SALT_V1, &INITIAL_CONNECTION_ID).unwrap();
+        let client = InitialKeys::new(Side::Client, &INITIAL_SALT_V1, &INITIAL_CONNECTION_ID).unwrap();
+
+        let header = from_hex("c1000000010008f067a5502a4262b50040750001");
+        let payload = from_hex("02000000000600405a020000560303eefce7f7b37ba1d1632e96677825ddf73988cfc79825df566dc5430b9a045a1200130100002e00330024001d00209d3c940d89690b84d08a60993c144eca684d1081287c834d5311bcf32bb9da1a002b00020304");
+
+        let mut packet = [&header[..], &server.local.seal(1, &header, &payload).unwrap()[..]].concat();
+        server.local_header.protect(&mut packet, 18, 2, LONG_HEADER_BITS).unwrap();
+
+        assert_eq!(packet, from_hex("cf000000010008f067a5502a4262b5004075c0d95a482cd0991cd25b0aac406a5816b6394100f37a1c69797554780bb38cc5a99f5ede4cf73c3ec2493a1839b3dbcba3f6ea46c5b7684df3548e7ddeb9c3bf9c73cc3f3bded74b562bfb19fb84022f8ef4cdd93795d77d06edbb7aaf2f58891850abbdca3d20398c276456cbc42158407dd074ee"));
+
+        client.remote_header.unprotect(&mut packet, 18, LONG_HEADER_BITS, &|first| (first & 0x03) as usize + 1).unwrap();
+        assert_eq!(&packet[..header.len()], &header[..]);
+        let (header_bytes, payload_bytes) = packet.split_at_mut(header.len());
+        let len = client.remote.open_in_place(1, header_bytes, payload_bytes).unwrap();
+        assert_eq!(&payload_bytes[..len], &payload[..]);
+    }
+
+    #[test]
+    fn address_tokens_bind_address_and_time() {
+        let epoch = Instant::now();
+        let key = TokenKey::new(&[7; 32], epoch, Duration::from_secs(10));
+        let address = "192.0.2.1:4433".parse().unwrap();
+
+        let now = epoch + Duration::from_secs(5);
+        let token = key.issue(now, &address);
+
+        assert!(key.validate(now + Duration::from_secs(10), &address, &token).is_ok());
+
+        let rejects = |now: Instant, address: &SocketAddr, token: &[u8]| match key.validate(now, address, token) {
+            Err(QuicError::TransportError(error)) => error == QUIC_ADDRESS_VALIDATION_FAILURE,
+            _ => false,
+        };
+
+        assert!(rejects(now + Duration::from_secs(11), &address, &token));
+        assert!(rejects(now, &"192.0.2.1:4434".parse().unwrap(), &token));
+        assert!(rejects(now, &"[2001:db8::1]:4433".parse().unwrap(), &token));
+        assert!(rejects(now, &address, &token[..20]));
+
+        let mut tampered = token.to_vec();
+        tampered[7] ^= 1;
+        assert!(rejects(now, &address, &tampered));
+
+        let other = TokenKey::generate(epoch, Duration::from_secs(10)).unwrap();
+        assert!(other.validate(now, &address, &token).is_err());
+    }
+}
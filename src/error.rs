@@ -62,6 +62,87 @@ bitflags! {
         const QUIC_ADDRESS_VALIDATION_FAILURE            = 0x80000051,
         const QUIC_TOO_MANY_FRAME_GAPS                   = 0x8000005d,
         const QUIC_TOO_MANY_SESSIONS_ON_SERVER           = 0x80000060,
+
+        // RFC 9000 transport error codes, used by the v1 encoding.
+        const STREAM_LIMIT_ERROR                         = 0x04,
+        const FRAME_ENCODING_ERROR                       = 0x07,
+    }
+}
+
+/// Every defined error code. Codes are values, not combinations of bits,
+/// so one read off the wire has to match one of these exactly.
+const ERROR_CODES: [TransportErrorFlag; 57] = [
+    QUIC_INTERNAL_ERROR,
+    QUIC_STREAM_DATA_AFTER_TERMINATION,
+    QUIC_INVALID_PACKET_HEADER,
+    QUIC_INVALID_FRAME_DATA,
+    QUIC_MULTIPLE_TERMINATION_OFFSETS,
+    QUIC_STREAM_CANCELLED,
+    QUIC_CLOSED_CRITICAL_STREAM,
+    QUIC_MISSING_PAYLOAD,
+    QUIC_INVALID_STREAM_DATA,
+    QUIC_UNENCRYPTED_STREAM_DATA,
+    QUIC_MAYBE_CORRUPTED_MEMORY,
+    QUIC_INVALID_RST_STREAM_DATA,
+    QUIC_INVALID_CONNECTION_CLOSE_DATA,
+    QUIC_INVALID_GOAWAY_DATA,
+    QUIC_INVALID_WINDOW_UPDATE_DATA,
+    QUIC_INVALID_BLOCKED_DATA,
+    QUIC_INVALID_PATH_CLOSE_DATA,
+    QUIC_INVALID_ACK_DATA,
+    QUIC_INVALID_VERSION_NEGOTIATION_PACKET,
+    QUIC_INVALID_PUBLIC_RST_PACKET,
+    QUIC_DECRYPTION_FAILURE,
+    QUIC_ENCRYPTION_FAILURE,
+    QUIC_PACKET_TOO_LARGE,
+    QUIC_PEER_GOING_AWAY,
+    QUIC_INVALID_STREAM_ID,
+    QUIC_INVALID_PRIORITY,
+    QUIC_TOO_MANY_OPEN_STREAMS,
+    QUIC_TOO_MANY_AVAILABLE_STREAMS,
+    QUIC_PUBLIC_RESET,
+    QUIC_INVALID_VERSION,
+    QUIC_INVALID_HEADER_ID,
+    QUIC_INVALID_NEGOTIATED_VALUE,
+    QUIC_DECOMPRESSION_FAILURE,
+    QUIC_NETWORK_IDLE_TIMEOUT,
+    QUIC_HANDSHAKE_TIMEOUT,
+    QUIC_ERROR_MIGRATING_ADDRESS,
+    QUIC_ERROR_MIGRATING_PORT,
+    QUIC_EMPTY_STREAM_FRAME_NO_FIN,
+    QUIC_FLOW_CONTROL_RECEIVED_TOO_MUCH_DATA,
+    QUIC_FLOW_CONTROL_SENT_TOO_MUCH_DATA,
+    QUIC_FLOW_CONTROL_INVALID_WINDOW,
+    QUIC_CONNECTION_IP_POOLED,
+    QUIC_TOO_MANY_OUTSTANDING_SENT_PACKETS,
+    QUIC_TOO_MANY_OUTSTANDING_RECEIVED_PACKETS,
+    QUIC_CONNECTION_CANCELLED,
+    QUIC_BAD_PACKET_LOSS_RATE,
+    QUIC_PUBLIC_RESETS_POST_HANDSHAKE,
+    QUIC_TIMEOUTS_WITH_OPEN_STREAMS,
+    QUIC_TOO_MANY_RTOS,
+    QUIC_ENCRYPTION_LEVEL_INCORRECT,
+    QUIC_VERSION_NEGOTIATION_MISMATCH,
+    QUIC_IP_ADDRESS_CHANGED,
+    QUIC_ADDRESS_VALIDATION_FAILURE,
+    QUIC_TOO_MANY_FRAME_GAPS,
+    QUIC_TOO_MANY_SESSIONS_ON_SERVER,
+    STREAM_LIMIT_ERROR,
+    FRAME_ENCODING_ERROR,
+];
+
+impl TransportErrorFlag {
+    /// Maps an error code received on the wire back to its flag. Zero is a
+    /// clean close; anything else unrecognised is an internal error.
+    pub fn from_code(code: u32) -> TransportErrorFlag {
+        if code == 0 {
+            return TransportErrorFlag::empty();
+        }
+
+        ERROR_CODES.iter()
+            .cloned()
+            .find(|error| error.bits() == code)
+            .unwrap_or(QUIC_INTERNAL_ERROR)
     }
 }
 
@@ -91,7 +172,7 @@ impl Error for QuicError {
         }
     }
 
-    fn cause(&self) -> Option<&Error> {
+    fn cause(&self) -> Option<&dyn Error> {
         match *self {
             QuicError::Io(ref err) => Some(err),
             QuicError::FromUtf8Error(ref err) => Some(err),
@@ -130,4 +211,21 @@ impl fmt::Display for QuicError {
             QuicError::TransportError(ref err) => write!(f, "Transport Error: 0x{:X}", err.bits()),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_code_matches_exact_codes() {
+        assert_eq!(TransportErrorFlag::from_code(0x80000010), QUIC_PEER_GOING_AWAY);
+        assert_eq!(TransportErrorFlag::from_code(0x80000043), QUIC_HANDSHAKE_TIMEOUT);
+
+        // Both bits are defined codes on their own, but together they're
+        // not one.
+        assert_eq!(TransportErrorFlag::from_code(0x80000018 | 0x80000043), QUIC_INTERNAL_ERROR);
+        assert_eq!(TransportErrorFlag::from_code(0x1234), QUIC_INTERNAL_ERROR);
+        assert!(TransportErrorFlag::from_code(0).is_empty());
+    }
+}
@@ -1,10 +1,11 @@
+use std::cmp;
 use std::io::Cursor;
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 
 //mod error;
 use error::QuicError;
 use error::Result;
-use std::io::Read;
+use error::QUIC_INVALID_ACK_DATA;
 use util::OFSize;
 //use super::FrameType;
 //use frames::ACK;
@@ -16,9 +17,9 @@ const UFLOAT_16_MAX_EXPONENT: u16 = (1 << UFLOAT_16_EXPONENT_BITS) - 2; // 30
 const UFLOAT_16_MANTISSA_BITS: u16 = 16 - UFLOAT_16_EXPONENT_BITS; // 11
 const UFLOAT_16_MANTISSA_EFFECTIVE_BITS: u16 = UFLOAT_16_MANTISSA_BITS + 1; // 12
 const UFLOAT_16_MAX_VALUE: u64 =
-(((1u64 << UFLOAT_16_MANTISSA_EFFECTIVE_BITS) - 1) << UFLOAT_16_MAX_EXPONENT) as u64; // 0x3FFC0000000
+((1u64 << UFLOAT_16_MANTISSA_EFFECTIVE_BITS) - 1) << UFLOAT_16_MAX_EXPONENT; // 0x3FFC0000000
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct AckFrame {
     pub num_blocks: Option<u8>,
     pub num_ts: u8,
@@ -31,7 +32,7 @@ pub struct AckFrame {
     pub timestamps: Option<Vec<AckTimestamp>>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct AckBlock {
     pub gap: u8,
     pub block_len: u64,
@@ -60,7 +61,7 @@ impl AckBlock {
             OFSize::U8 => bytes.write_u8(self.block_len as u8),
             OFSize::U16 => bytes.write_u16::<BigEndian>(self.block_len as u16),
             OFSize::U32 => bytes.write_u32::<BigEndian>(self.block_len as u32),
-            OFSize::U48 => bytes.write_uint::<BigEndian>(self.block_len as u64, 6),
+            OFSize::U48 => bytes.write_uint::<BigEndian>(self.block_len, 6),
             _ => panic!("Higher than 48-bits not allowed in block length!")
         };
 
@@ -68,7 +69,7 @@ impl AckBlock {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct AckTimestampValue {
     pub microseconds: u64,
 }
@@ -102,8 +103,8 @@ impl AckTimestampValue {
 
         if value < (1 << UFLOAT_16_MANTISSA_EFFECTIVE_BITS) {
             value as u16
-        } else if value >= UFLOAT_16_MAX_VALUE as u64 {
-            u16::max_value() as u16
+        } else if value >= UFLOAT_16_MAX_VALUE {
+            u16::MAX
         } else {
             let mut offset = 16u16;
             let mut exponent = 0u16;
@@ -121,14 +122,14 @@ impl AckTimestampValue {
                 offset /= 2;
             }
 
-            (value as u16 + (exponent << UFLOAT_16_MANTISSA_BITS) as u16) as u16
+            value as u16 + (exponent << UFLOAT_16_MANTISSA_BITS)
         }
 
     }
 }
 
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct AckTimestamp {
     pub delta_la: u8,
     pub time_since_prev: AckTimestampValue
@@ -161,6 +162,10 @@ impl AckTimestamp {
 
 impl AckFrame {
     pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        AckFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize)> {
         let mut reader = Cursor::new(buf);
 
         let type_byte = reader.read_u8()?;
@@ -173,17 +178,15 @@ impl AckFrame {
         let la_len = match ll {
             0 => 1,
             1 => 2,
-            3 => 4,
-            4 => 6,
-            _ => return Err(QuicError::ParseError),
+            2 => 4,
+            _ => 6,
         } as usize;
 
         let ack_len = match mm {
             0 => 1,
             1 => 2,
-            3 => 4,
-            4 => 6,
-            _ => return Err(QuicError::ParseError),
+            2 => 4,
+            _ => 6,
         } as usize;
 
 //        let num_blocks;
@@ -207,18 +210,17 @@ impl AckFrame {
         if let Some(n_blocks) = num_blocks {
             let ack_block_section_len = (1 + ack_len) * (n_blocks as usize);
 
-            let mut reader_handle = reader.clone().take(ack_block_section_len as u64);
-
-            let mut ack_block_slice = Vec::new();
-            reader_handle.read_to_end(&mut ack_block_slice);
-
-            let new_pos = (reader.position() as u64) + (ack_block_section_len as u64);
+            let section_start = reader.position() as usize;
+            let section_end = section_start + ack_block_section_len;
 
-            reader.set_position(new_pos);
+            if section_end > buf.len() {
+                return Err(QuicError::TransportError(QUIC_INVALID_ACK_DATA));
+            }
 
+            reader.set_position(section_end as u64);
 
-            for block in ack_block_slice.chunks(1 + ack_len) {
-                let c_block = AckBlock::from_bytes(&block.to_vec(), ack_len)?;
+            for block in buf[section_start..section_end].chunks(1 + ack_len) {
+                let c_block = AckBlock::from_bytes(block, ack_len)?;
                 ack_blocks.push(c_block);
             }
         }
@@ -235,14 +237,19 @@ impl AckFrame {
             let first_ts_i = reader.read_u32::<BigEndian>()?;
             first_ts = Some(first_ts_i);
 
-            let ts_block_section_len = num_ts * 3;
+            let ts_block_section_len = (num_ts as usize) * 3;
+
+            let section_start = reader.position() as usize;
+            let section_end = section_start + ts_block_section_len;
 
-            let mut ts_block_slice = Vec::new();
-            let mut reader_handle = reader.clone().take(ts_block_section_len as u64);
-            reader_handle.read_to_end(&mut ts_block_slice);
+            if section_end > buf.len() {
+                return Err(QuicError::TransportError(QUIC_INVALID_ACK_DATA));
+            }
 
-            for block in ts_block_slice.chunks(3) {
-                let c_block = AckTimestamp::from_bytes(&block.to_vec())?;
+            reader.set_position(section_end as u64);
+
+            for block in buf[section_start..section_end].chunks(3) {
+                let c_block = AckTimestamp::from_bytes(block)?;
                 timestamps.push(c_block);
             }
         } else {
@@ -250,6 +257,10 @@ impl AckFrame {
             first_ts = None;
         }
 
+        if !ack_ranges_fit(largest_ack, first_ack_len, &ack_blocks) {
+            return Err(QuicError::TransportError(QUIC_INVALID_ACK_DATA));
+        }
+
         let ack_blocks_fin = match ack_blocks.len() {
             0 => None,
             _ => Some(ack_blocks),
@@ -260,7 +271,7 @@ impl AckFrame {
             _ => Some(timestamps),
         };
 
-        Ok(AckFrame {
+        Ok((AckFrame {
             num_blocks: num_blocks,
             num_ts: num_ts,
             largest_ack: largest_ack,
@@ -270,7 +281,7 @@ impl AckFrame {
             delta_la: delta_la,
             first_ts: first_ts,
             timestamps: ts_blocks_fin,
-        })
+        }, reader.position() as usize))
     }
 
     pub fn as_bytes(&self) -> Vec<u8> {
@@ -278,160 +289,205 @@ impl AckFrame {
 
         let mut type_byte = super::ACK.bits();
 
-        match self.num_blocks {
-            Some(_) => type_byte |= 0x10,
-            _ => type_byte |= 0x00
-        };
-
-        let largest_ack_size = optimal_field_size(self.largest_ack);
-
-        match largest_ack_size {
-            OFSize::U8 => type_byte |= 0x00,
-            OFSize::U16 => type_byte |= 0x04,
-            OFSize::U32 => type_byte |= 0x08,
-            OFSize::U48 => type_byte |= 0x0c,
-            _ => panic!("largest ack too large")
-        };
-
-        let max_block_len = match self.ack_blocks {
-            Some(ref ack_blocks) => ack_blocks.iter().max_by_key(|block| block.block_len),
-            None => None
-        };
+        if self.num_blocks.is_some() {
+            type_byte |= 0x10;
+        }
 
+        let (largest_ack_size, block_len_size) = self.field_sizes();
 
-        let block_len_size = match max_block_len {
-            None => None,
-            Some(max_len_block) => Some(optimal_field_size(max_len_block.block_len))
-        };
-
-        match block_len_size {
-            None => {},
-            Some(OFSize::U8) => type_byte |= 0x00,
-            Some(OFSize::U16) => type_byte |= 0x01,
-            Some(OFSize::U32) => type_byte |= 0x02,
-            Some(OFSize::U48) => type_byte |= 0x03,
-            _ => panic!("largest ack too large")
-        };
+        type_byte |= field_size_bits(largest_ack_size) << 2;
+        type_byte |= field_size_bits(block_len_size);
 
         bytes.write_u8(type_byte);
 
-        if let Some(ref ack_blocks) = self.ack_blocks {
-            bytes.write_u8(ack_blocks.len() as u8);
+        if self.num_blocks.is_some() {
+            let num_blocks = self.ack_blocks.as_ref().map_or(0, |blocks| blocks.len());
+            bytes.write_u8(num_blocks as u8);
         }
 
-        if let Some(ref ts_blocks) = self.timestamps {
-            let mut ts_len = ts_blocks.len();
-            if self.delta_la.is_some() {
-                ts_len += 0;
-            }
-
-            bytes.write_u8(ts_len as u8);
-        }
+        let num_ts = self.timestamps.as_ref().map_or(0, |timestamps| timestamps.len());
+        bytes.write_u8(num_ts as u8);
 
-        match largest_ack_size {
-            OFSize::U8 => {
-                bytes.write_u8(self.largest_ack as u8).unwrap()
-            },
-            OFSize::U16 => {
-                bytes.write_u16::<BigEndian>(self.largest_ack as u16).unwrap()
-            },
-            OFSize::U32 => {
-                bytes.write_u32::<BigEndian>(self.largest_ack as u32).unwrap()
-            },
-            OFSize::U48 => {
-                bytes.write_uint::<BigEndian>(self.largest_ack as u64, 6).unwrap()
-            },
-            _ => panic!("largest ack too large")
-        }
+        bytes.write_uint::<BigEndian>(self.largest_ack, field_size_len(largest_ack_size));
 
         bytes.write_u16::<BigEndian>(self.ack_delay);
 
+        bytes.write_uint::<BigEndian>(self.first_ack_len, field_size_len(block_len_size));
 
         if let Some(ref ack_blocks) = self.ack_blocks {
-            bytes.write_u16::<BigEndian>(ack_blocks.len() as u16);
-            let mut ack_block_bytes = Vec::new();
-            let ack_block_byte_vectors = ack_blocks.iter().map(|block|
-                block.as_bytes(block_len_size.unwrap())
-            );
-
-            for ack_block_byte_vector in ack_block_byte_vectors {
-                ack_block_bytes.extend(ack_block_byte_vector);
+            for block in ack_blocks {
+                bytes.extend(block.as_bytes(block_len_size));
             }
+        }
 
-            bytes.extend(ack_block_bytes);
+        if num_ts > 0 {
+            bytes.write_u8(self.delta_la.unwrap_or(0));
+            bytes.write_u32::<BigEndian>(self.first_ts.unwrap_or(0));
 
+            if let Some(ref timestamps) = self.timestamps {
+                for timestamp in timestamps {
+                    bytes.extend(timestamp.as_bytes());
+                }
+            }
         }
 
-        match self.delta_la {
-            None => {},
-            Some(la) => { let _ = bytes.write_u8(la); }
-        }
+        bytes
+    }
 
-        match self.first_ts {
-            None => {},
-            Some(ts) => { let _ = bytes.write_u32::<BigEndian>(ts); }
-        }
+    /// The acknowledgement delay decoded from its 16-bit float.
+    pub fn ack_delay_micros(&self) -> Result<u64> {
+        AckTimestampValue::from_u16(self.ack_delay).map(|value| value.microseconds)
+    }
 
+    /// The acknowledged packet numbers as inclusive (smallest, largest)
+    /// pairs, highest first. The first range runs back from the largest
+    /// acknowledged packet; each block then follows the previous one after
+    /// a gap.
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        let mut range_start = self.largest_ack.saturating_sub(self.first_ack_len);
+        let mut ranges = vec![(range_start, self.largest_ack)];
+
+        if let Some(ref blocks) = self.ack_blocks {
+            for block in blocks {
+                let range_end = match range_start.checked_sub(block.gap as u64 + 1) {
+                    Some(end) => end,
+                    None => break,
+                };
+
+                if block.block_len == 0 {
+                    range_start = range_end + 1;
+                    continue;
+                }
 
-        if let Some(ref ack_blocks) = self.timestamps {
-            let mut ack_block_bytes = Vec::new();
+                range_start = range_end.saturating_sub(block.block_len - 1);
+                ranges.push((range_start, range_end));
+            }
+        }
 
-            let ack_block_byte_vectors = ack_blocks.iter().map(|block|
-                block.as_bytes()
-            );
+        ranges
+    }
+
+    /// Builds the frame acknowledging `ranges`, given as inclusive
+    /// (smallest, largest) pairs, highest first and not touching. Gaps too
+    /// long for one block are bridged with empty blocks, and ranges past
+    /// what 255 blocks can describe are left out.
+    pub fn from_ranges(ranges: &[(u64, u64)], ack_delay: u16) -> AckFrame {
+        let (first_start, largest_ack) = ranges[0];
+        let mut ack_blocks = Vec::new();
+        let mut previous_start = first_start;
+
+        for &(start, end) in &ranges[1..] {
+            let mut gap = previous_start - end - 1;
+            let mut bridge = Vec::new();
+
+            while gap > u8::MAX as u64 {
+                bridge.push(AckBlock { gap: u8::MAX, block_len: 0 });
+                gap -= u8::MAX as u64;
+            }
 
-            for ack_block_byte_vector in ack_block_byte_vectors {
-                ack_block_bytes.extend(ack_block_byte_vector);
+            if ack_blocks.len() + bridge.len() >= u8::MAX as usize {
+                break;
             }
 
-            bytes.extend(ack_block_bytes)
+            ack_blocks.extend(bridge);
+            ack_blocks.push(AckBlock { gap: gap as u8, block_len: end - start + 1 });
+            previous_start = start;
         }
 
-        bytes
+        AckFrame {
+            num_blocks: if ack_blocks.is_empty() { None } else { Some(ack_blocks.len() as u8) },
+            num_ts: 0,
+            largest_ack: largest_ack,
+            ack_delay: ack_delay,
+            first_ack_len: largest_ack - first_start,
+            ack_blocks: if ack_blocks.is_empty() { None } else { Some(ack_blocks) },
+            delta_la: None,
+            first_ts: None,
+            timestamps: None,
+        }
     }
 
-    pub fn frame_len(buf: &[u8]) -> Result<usize> {
-        let mut reader = Cursor::new(buf);
+    /// Sizes of the largest acknowledged and block length fields.
+    fn field_sizes(&self) -> (OFSize, OFSize) {
+        // The first ACK block length shares its field size with the others.
+        let max_block_len = match self.ack_blocks {
+            Some(ref ack_blocks) => ack_blocks.iter().map(|block| block.block_len).max().unwrap_or(0),
+            None => 0,
+        };
 
-        let type_byte = reader.read_u8()?;
+        (optimal_field_size(self.largest_ack), optimal_field_size(cmp::max(self.first_ack_len, max_block_len)))
+    }
 
-        let mut len = 1 + 1 + 2;
+    /// Size of the frame on the wire.
+    pub fn frame_len(&self) -> usize {
+        let (largest_ack_size, block_len_size) = self.field_sizes();
 
-        let n = type_byte & 0x10 > 0;
-        let ll = (type_byte & 0x0c) >> 2;
-        let mm = type_byte & 0x03;
+        let num_blocks_len = if self.num_blocks.is_some() { 1 } else { 0 };
+        let num_blocks = self.ack_blocks.as_ref().map_or(0, |blocks| blocks.len());
+        let num_ts = self.timestamps.as_ref().map_or(0, |timestamps| timestamps.len());
+
+        // Type, block and timestamp counts, the fields, then the ACK delay.
+        let mut len = 1 + num_blocks_len + 1 + field_size_len(largest_ack_size) + 2 + field_size_len(block_len_size);
 
-        if n {
-            len += 1;
+        len += num_blocks * (1 + field_size_len(block_len_size));
+
+        if num_ts > 0 {
+            len += 1 + 4 + num_ts * 3;
         }
 
-        let la_len = match ll {
-            0 => 1,
-            1 => 2,
-            3 => 4,
-            4 => 6,
-            _ => return Err(QuicError::ParseError),
-        } as usize;
+        len
+    }
 
-        len += la_len;
+    /// Whether `packet_number` falls in one of the acknowledged ranges.
+    pub fn acknowledges(&self, packet_number: u64) -> bool {
+        self.ranges().iter().any(|&(start, end)| packet_number >= start && packet_number <= end)
+    }
+}
 
-        let ack_len = match mm {
-            0 => 1,
-            1 => 2,
-            3 => 4,
-            4 => 6,
-            _ => return Err(QuicError::ParseError),
-        } as usize;
+/// The LL and MM bits for a field size.
+fn field_size_bits(size: OFSize) -> u8 {
+    match size {
+        OFSize::U8 => 0,
+        OFSize::U16 => 1,
+        OFSize::U32 => 2,
+        _ => 3,
+    }
+}
+
+fn field_size_len(size: OFSize) -> usize {
+    match size {
+        OFSize::U8 => 1,
+        OFSize::U16 => 2,
+        OFSize::U32 => 4,
+        _ => 6,
+    }
+}
 
-        len += ack_len;
+/// Whether the acknowledged ranges all stay at or above packet zero.
+fn ack_ranges_fit(largest_ack: u64, first_ack_len: u64, blocks: &[AckBlock]) -> bool {
+    let mut range_start = match largest_ack.checked_sub(first_ack_len) {
+        Some(start) => start,
+        None => return false,
+    };
+
+    for block in blocks {
+        let range_end = match range_start.checked_sub(block.gap as u64 + 1) {
+            Some(end) => end,
+            None => return false,
+        };
 
-        if n {
-            let num_blocks = reader.read_u8()? as usize;
-            len += num_blocks * (1 + ack_len);
+        if block.block_len == 0 {
+            range_start = range_end + 1;
+            continue;
         }
 
-        Ok(len)
+        range_start = match range_end.checked_sub(block.block_len - 1) {
+            Some(start) => start,
+            None => return false,
+        };
     }
+
+    true
 }
 
 #[cfg(test)]
@@ -473,16 +529,16 @@ mod tests {
                 block_len: 14,
             },
             AckBlock {
-                gap: 87,
-                block_len: 645,
+                gap: 7,
+                block_len: 300,
             },
             AckBlock {
-                gap: 42,
-                block_len: 325,
+                gap: 2,
+                block_len: 25,
             },
             AckBlock {
-                gap: 236,
-                block_len: 734,
+                gap: 6,
+                block_len: 34,
             }
         ];
 
@@ -511,5 +567,81 @@ mod tests {
         let microseconds = AckTimestampValue::from_u16(time_since_prev).unwrap();
 
         assert_eq!(microseconds.microseconds, 4096);
+
+        let ack = AckFrame::from_ranges(&[(0, 0)], AckTimestampValue { microseconds: 25000 }.as_u16());
+        assert!(ack.ack_delay as u64 != 25000);
+        assert!(ack.ack_delay_micros().unwrap() <= 25000 && ack.ack_delay_micros().unwrap() > 24000);
+    }
+
+    #[test]
+    fn acknowledged_ranges() {
+        let ack_frame = AckFrame {
+            num_blocks: Some(1),
+            num_ts: 0,
+            largest_ack: 100,
+            ack_delay: 0,
+            first_ack_len: 4,
+            ack_blocks: Some(vec![AckBlock { gap: 5, block_len: 3 }]),
+            delta_la: None,
+            first_ts: None,
+            timestamps: None,
+        };
+
+        let acked: Vec<u64> = (80..110).filter(|&pn| ack_frame.acknowledges(pn)).collect();
+
+        assert_eq!(acked, vec![88, 89, 90, 96, 97, 98, 99, 100]);
+    }
+
+    #[test]
+    fn rejects_blocks_below_packet_zero() {
+        let ack_frame = AckFrame {
+            num_blocks: Some(1),
+            num_ts: 0,
+            largest_ack: 20,
+            ack_delay: 0,
+            first_ack_len: 4,
+            ack_blocks: Some(vec![AckBlock { gap: 10, block_len: 300 }]),
+            delta_la: None,
+            first_ts: None,
+            timestamps: None,
+        };
+
+        match AckFrame::from_bytes(&ack_frame.as_bytes()) {
+            Err(QuicError::TransportError(error)) => assert_eq!(error, QUIC_INVALID_ACK_DATA),
+            other => panic!("expected QUIC_INVALID_ACK_DATA, got {:?}", other),
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn reserializes_largest_48_bit_fields() {
+        // Found by fuzzing: six-byte fields used to be read as four bytes.
+        let mut buf = vec![0xaf, 0x00];
+        buf.extend_from_slice(&[0xff; 6]);
+        buf.extend_from_slice(&[0x00, 0x00]);
+        buf.extend_from_slice(&[0xff; 6]);
+
+        let (frame, len) = AckFrame::decode(&buf).unwrap();
+
+        assert_eq!(frame.as_bytes(), buf);
+        assert_eq!(AckFrame::decode(&frame.as_bytes()).unwrap(), (frame, len));
+    }
+
+    #[test]
+    fn from_ranges_round_trip() {
+        // Found by fuzzing: four-byte fields, which these ranges need, used
+        // to be rejected.
+        let ranges = vec![(70000, 70010), (50000, 69000), (1000, 1000), (0, 1)];
+        let frame = AckFrame::from_ranges(&ranges, 25);
+
+        let parsed_frame = AckFrame::from_bytes(&frame.as_bytes()).unwrap();
+
+        assert_eq!(parsed_frame, frame);
+        assert_eq!(parsed_frame.ranges(), ranges);
+        assert!(parsed_frame.acknowledges(62500));
+        assert!(!parsed_frame.acknowledges(2));
+        assert!(!parsed_frame.acknowledges(1001));
+
+        // 255 blocks can't bridge a gap this long.
+        assert_eq!(AckFrame::from_ranges(&[(100000, 100000), (0, 0)], 0).ranges(), vec![(100000, 100000)]);
+    }
+}
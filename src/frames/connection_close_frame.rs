@@ -1,9 +1,10 @@
 use std::io::Cursor;
-use std::io::Read;
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 use error::Result;
+use error::QuicError;
+use error::QUIC_INVALID_CONNECTION_CLOSE_DATA;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct ConnectionCloseFrame {
     pub error_code: u32,
     pub reason_length: u16,
@@ -37,6 +38,10 @@ impl ConnectionCloseFrame {
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<ConnectionCloseFrame> {
+        ConnectionCloseFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(ConnectionCloseFrame, usize)> {
         let mut reader = Cursor::new(buf);
 
         let _ = reader.read_u8()?;
@@ -45,34 +50,27 @@ impl ConnectionCloseFrame {
 
         let reason_length = reader.read_u16::<BigEndian>()?;
 
-        let reason_phrase = if reason_length > 0 {
-            let mut phrase_reader = reader.clone().take(reason_length as u64);
-            let mut phrase = Vec::new();
+        let reason_start = reader.position() as usize;
+        let reason_end = reason_start + reason_length as usize;
 
-            phrase_reader.read_to_end(&mut phrase);
+        if reason_end > buf.len() {
+            return Err(QuicError::TransportError(QUIC_INVALID_CONNECTION_CLOSE_DATA));
+        }
+
+        let reason_phrase = if reason_length > 0 {
+            let phrase = String::from_utf8(buf[reason_start..reason_end].to_vec())
+                .map_err(|_| QuicError::TransportError(QUIC_INVALID_CONNECTION_CLOSE_DATA))?;
 
-            Some(String::from_utf8(phrase)?)
+            Some(phrase)
         } else {
             None
         };
 
-        Ok(ConnectionCloseFrame {
+        Ok((ConnectionCloseFrame {
             error_code: error_code,
             reason_length: reason_length,
             reason_phrase: reason_phrase,
-        })
-    }
-
-    pub fn frame_len(buf: &[u8]) -> Result<usize> {
-        let mut reader = Cursor::new(buf);
-
-        let len = 1 + 4 + 2;
-
-        let _ = reader.read_u8()?;
-        let _ = reader.read_u32::<BigEndian>()?;
-        let reason_len = reader.read_u16::<BigEndian>()? as usize;
-
-        Ok(len + reason_len)
+        }, reason_end))
     }
 }
 
@@ -95,4 +93,21 @@ mod tests {
 
         assert_eq!(frame, parsed_frame);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rejects_reason_longer_than_frame() {
+        let frame = ConnectionCloseFrame {
+            error_code: 1,
+            reason_length: 5,
+            reason_phrase: Some("hello".to_string()),
+        };
+
+        let mut frame_bytes = frame.as_bytes();
+        frame_bytes.truncate(9);
+
+        match ConnectionCloseFrame::from_bytes(&frame_bytes) {
+            Err(QuicError::TransportError(error)) => assert_eq!(error, QUIC_INVALID_CONNECTION_CLOSE_DATA),
+            other => panic!("expected QUIC_INVALID_CONNECTION_CLOSE_DATA, got {:?}", other),
+        }
+    }
+}
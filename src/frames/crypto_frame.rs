@@ -0,0 +1,90 @@
+use std::io::Cursor;
+use bytes::Bytes;
+
+use error::QuicError;
+use error::Result;
+use util::{ReadVarint, WriteVarint, MAX_VARINT};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CryptoFrame {
+    pub offset: u64,
+    pub crypto_data: Bytes,
+}
+
+impl CryptoFrame {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.crypto_data.len() + 17);
+
+        bytes.write_varint(super::CRYPTO.bits());
+
+        bytes.write_varint(self.offset);
+
+        bytes.write_varint(self.crypto_data.len() as u64);
+
+        bytes.extend_from_slice(&self.crypto_data);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &Bytes) -> Result<CryptoFrame> {
+        CryptoFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    /// The crypto data is a slice of `buf`, not a copy.
+    pub fn decode(buf: &Bytes) -> Result<(CryptoFrame, usize)> {
+        let mut reader = Cursor::new(&buf[..]);
+
+        let _ = reader.read_varint()?;
+
+        let offset = reader.read_varint()?;
+
+        let length = reader.read_varint()?;
+
+        if offset + length > MAX_VARINT {
+            return Err(QuicError::ParseError);
+        }
+
+        let data_start = reader.position() as usize;
+
+        if length > (buf.len() - data_start) as u64 {
+            return Err(QuicError::ParseError);
+        }
+
+        let data_end = data_start + length as usize;
+
+        Ok((CryptoFrame {
+            offset: offset,
+            crypto_data: buf.slice(data_start, data_end),
+        }, data_end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let frame = CryptoFrame {
+            offset: 16384,
+            crypto_data: Bytes::from(&b"client hello"[..]),
+        };
+
+        let frame_bytes = Bytes::from(frame.as_bytes());
+        let parsed_frame = CryptoFrame::from_bytes(&frame_bytes).unwrap();
+
+        assert_eq!(frame, parsed_frame);
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let mut frame_bytes = CryptoFrame {
+            offset: 0,
+            crypto_data: Bytes::from(&b"client hello"[..]),
+        }.as_bytes();
+
+        frame_bytes.pop();
+
+        assert!(CryptoFrame::from_bytes(&Bytes::from(frame_bytes)).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use std::io::Cursor;
+
+use error::Result;
+use util::{ReadVarint, WriteVarint};
+use util::varint_len;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DataBlockedFrame {
+    pub maximum_data: u64,
+}
+
+impl DataBlockedFrame {
+    pub fn frame_len(&self) -> usize {
+        1 + varint_len(self.maximum_data)
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.frame_len());
+
+        bytes.write_varint(super::DATA_BLOCKED.bits());
+
+        bytes.write_varint(self.maximum_data);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<DataBlockedFrame> {
+        DataBlockedFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(DataBlockedFrame, usize)> {
+        let mut reader = Cursor::new(buf);
+
+        let _ = reader.read_varint()?;
+
+        let maximum_data = reader.read_varint()?;
+
+        Ok((DataBlockedFrame {
+            maximum_data: maximum_data,
+        }, reader.position() as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let frame = DataBlockedFrame {
+            maximum_data: 65536,
+        };
+
+        let frame_bytes = frame.as_bytes();
+        let parsed_frame = DataBlockedFrame::from_bytes(&frame_bytes).unwrap();
+
+        assert_eq!(frame, parsed_frame);
+    }
+}
@@ -2,7 +2,7 @@ use std::io::Cursor;
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 use error::Result;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct GoAwayFrame {
     pub largest_client_stream_id: u32,
     pub largest_server_stream_id: u32,
@@ -23,6 +23,10 @@ impl GoAwayFrame {
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<GoAwayFrame> {
+        GoAwayFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(GoAwayFrame, usize)> {
         let mut reader = Cursor::new(buf);
 
         let _ = reader.read_u8()?;
@@ -30,14 +34,10 @@ impl GoAwayFrame {
         let largest_client_stream_id = reader.read_u32::<BigEndian>()?;
         let largest_server_stream_id = reader.read_u32::<BigEndian>()?;
 
-        Ok(GoAwayFrame {
+        Ok((GoAwayFrame {
             largest_client_stream_id: largest_client_stream_id,
             largest_server_stream_id: largest_server_stream_id,
-        })
-    }
-
-    pub fn frame_len() -> Result<usize> {
-        Ok(9)
+        }, reader.position() as usize))
     }
 }
 
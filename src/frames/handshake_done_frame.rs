@@ -0,0 +1,45 @@
+use std::io::Cursor;
+
+use error::Result;
+use util::{ReadVarint, WriteVarint};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct HandshakeDoneFrame {}
+
+impl HandshakeDoneFrame {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1);
+
+        bytes.write_varint(super::HANDSHAKE_DONE.bits());
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<HandshakeDoneFrame> {
+        HandshakeDoneFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(HandshakeDoneFrame, usize)> {
+        let mut reader = Cursor::new(buf);
+
+        let _ = reader.read_varint()?;
+
+        Ok((HandshakeDoneFrame {}, reader.position() as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let frame = HandshakeDoneFrame {};
+
+        let frame_bytes = frame.as_bytes();
+        let parsed_frame = HandshakeDoneFrame::from_bytes(&frame_bytes).unwrap();
+
+        assert_eq!(frame_bytes, vec![0x1e]);
+        assert_eq!(frame, parsed_frame);
+    }
+}
@@ -2,7 +2,7 @@ use std::io::Cursor;
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 use error::Result;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct MaxDataFrame {
     pub max_data: u64,
 }
@@ -21,19 +21,19 @@ impl MaxDataFrame {
     }
     
     pub fn from_bytes(buf: &[u8]) -> Result<MaxDataFrame> {
+        MaxDataFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(MaxDataFrame, usize)> {
         let mut reader = Cursor::new(buf);
 
         let _ = reader.read_u8()?;
 
         let max_data = reader.read_u64::<BigEndian>()?;
 
-        Ok(MaxDataFrame {
+        Ok((MaxDataFrame {
             max_data: max_data,
-        })
-    }
-
-    pub fn frame_len() -> Result<usize> {
-        Ok(13)
+        }, reader.position() as usize))
     }
 }
 
@@ -24,6 +24,10 @@ impl MaxStreamDataFrame {
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<MaxStreamDataFrame> {
+        MaxStreamDataFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(MaxStreamDataFrame, usize)> {
         let mut reader = Cursor::new(buf);
 
         let _ = reader.read_u8()?;
@@ -32,14 +36,10 @@ impl MaxStreamDataFrame {
 
         let max_stream_data = reader.read_u64::<BigEndian>()?;
 
-        Ok(MaxStreamDataFrame {
+        Ok((MaxStreamDataFrame {
             stream_id: stream_id,
             max_stream_data: max_stream_data,
-        })
-    }
-
-    pub fn frame_len() -> Result<usize> {
-        Ok(13)
+        }, reader.position() as usize))
     }
 }
 
@@ -2,7 +2,7 @@ use std::io::Cursor;
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 use error::Result;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct MaxStreamIdFrame {
     pub max_stream_id: u32,
 }
@@ -21,19 +21,19 @@ impl MaxStreamIdFrame {
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<MaxStreamIdFrame> {
+        MaxStreamIdFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(MaxStreamIdFrame, usize)> {
         let mut reader = Cursor::new(buf);
 
         let _ = reader.read_u8()?;
 
         let maximum_data = reader.read_u32::<BigEndian>()?;
 
-        Ok(MaxStreamIdFrame {
+        Ok((MaxStreamIdFrame {
             max_stream_id: maximum_data,
-        })
-    }
-
-    pub fn frame_len() -> Result<usize> {
-        Ok(5)
+        }, reader.position() as usize))
     }
 }
 
@@ -0,0 +1,84 @@
+use std::io::Cursor;
+
+use error::QuicError;
+use error::Result;
+use util::{ReadVarint, WriteVarint};
+
+/// Stream counts can't exceed 2^60, since stream IDs have to fit in a
+/// varint.
+pub const MAX_STREAM_COUNT: u64 = 1 << 60;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MaxStreamsFrame {
+    pub bidirectional: bool,
+    pub maximum_streams: u64,
+}
+
+impl MaxStreamsFrame {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(9);
+
+        let frame_type = if self.bidirectional {
+            super::MAX_STREAMS_BIDI
+        } else {
+            super::MAX_STREAMS_UNI
+        };
+
+        bytes.write_varint(frame_type.bits());
+
+        bytes.write_varint(self.maximum_streams);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<MaxStreamsFrame> {
+        MaxStreamsFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(MaxStreamsFrame, usize)> {
+        let mut reader = Cursor::new(buf);
+
+        let frame_type = reader.read_varint()?;
+
+        let maximum_streams = reader.read_varint()?;
+
+        if maximum_streams > MAX_STREAM_COUNT {
+            return Err(QuicError::ParseError);
+        }
+
+        Ok((MaxStreamsFrame {
+            bidirectional: frame_type == super::MAX_STREAMS_BIDI.bits(),
+            maximum_streams: maximum_streams,
+        }, reader.position() as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        for &bidirectional in &[true, false] {
+            let frame = MaxStreamsFrame {
+                bidirectional: bidirectional,
+                maximum_streams: 1000,
+            };
+
+            let frame_bytes = frame.as_bytes();
+            let parsed_frame = MaxStreamsFrame::from_bytes(&frame_bytes).unwrap();
+
+            assert_eq!(frame, parsed_frame);
+        }
+    }
+
+    #[test]
+    fn rejects_too_many_streams() {
+        let frame_bytes = MaxStreamsFrame {
+            bidirectional: true,
+            maximum_streams: MAX_STREAM_COUNT + 1,
+        }.as_bytes();
+
+        assert!(MaxStreamsFrame::from_bytes(&frame_bytes).is_err());
+    }
+}
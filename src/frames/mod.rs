@@ -12,6 +12,23 @@ pub mod new_connection_id_frame;
 pub mod connection_close_frame;
 pub mod goaway_frame;
 pub mod reset_stream_frame;
+pub mod crypto_frame;
+pub mod new_token_frame;
+pub mod max_streams_frame;
+pub mod streams_blocked_frame;
+pub mod retire_connection_id_frame;
+pub mod path_challenge_frame;
+pub mod path_response_frame;
+pub mod handshake_done_frame;
+pub mod v1_ack_frame;
+pub mod v1_stream_frame;
+pub mod v1_reset_stream_frame;
+pub mod stop_sending_frame;
+pub mod v1_max_stream_data_frame;
+pub mod data_blocked_frame;
+pub mod stream_data_blocked_frame;
+pub mod v1_new_connection_id_frame;
+pub mod v1_connection_close_frame;
 
 use self::stream_frame::StreamFrame;
 use self::ack_frame::AckFrame;
@@ -27,12 +44,45 @@ use self::new_connection_id_frame::NewConnectionIdFrame;
 use self::connection_close_frame::ConnectionCloseFrame;
 use self::goaway_frame::GoAwayFrame;
 use self::reset_stream_frame::ResetStreamFrame;
+use self::crypto_frame::CryptoFrame;
+use self::new_token_frame::NewTokenFrame;
+use self::max_streams_frame::MaxStreamsFrame;
+use self::streams_blocked_frame::StreamsBlockedFrame;
+use self::retire_connection_id_frame::RetireConnectionIdFrame;
+use self::path_challenge_frame::PathChallengeFrame;
+use self::path_response_frame::PathResponseFrame;
+use self::handshake_done_frame::HandshakeDoneFrame;
+use self::v1_ack_frame::V1AckFrame;
+use self::v1_stream_frame::V1StreamFrame;
+use self::v1_reset_stream_frame::V1ResetStreamFrame;
+use self::stop_sending_frame::StopSendingFrame;
+use self::v1_max_stream_data_frame::V1MaxStreamDataFrame;
+use self::data_blocked_frame::DataBlockedFrame;
+use self::stream_data_blocked_frame::StreamDataBlockedFrame;
+use self::v1_new_connection_id_frame::V1NewConnectionIdFrame;
+use self::v1_connection_close_frame::V1ConnectionCloseFrame;
 
 use super::error::Result;
 use super::error::QuicError;
+use super::error::TransportErrorFlag;
+use super::error::QUIC_MISSING_PAYLOAD;
+use super::error::QUIC_INVALID_FRAME_DATA;
+use super::error::QUIC_INVALID_ACK_DATA;
+use super::error::QUIC_INVALID_STREAM_DATA;
+use super::error::QUIC_INVALID_RST_STREAM_DATA;
+use super::error::QUIC_INVALID_CONNECTION_CLOSE_DATA;
+use super::error::QUIC_INVALID_GOAWAY_DATA;
+use super::error::QUIC_INVALID_WINDOW_UPDATE_DATA;
+use super::error::QUIC_INVALID_BLOCKED_DATA;
+use super::error::FRAME_ENCODING_ERROR;
+
+use super::util::ReadVarint;
+use super::util::WriteVarint;
+use super::util::varint_len;
 
 use std::io::Cursor;
-use byteorder::{ReadBytesExt};
+
+use bytes::{Bytes, BytesMut};
 
 
 bitflags! {
@@ -54,12 +104,43 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// RFC 9000 frame types. These are varints on the wire and several
+    /// reuse draft-05 type bytes, so they can't share `FrameType`.
+    pub flags V1FrameType: u64 {
+        const V1_PADDING = 0x00,
+        const V1_PING = 0x01,
+        const V1_ACK = 0x02,
+        const ACK_ECN = 0x03,
+        const V1_RESET_STREAM = 0x04,
+        const STOP_SENDING = 0x05,
+        const CRYPTO = 0x06,
+        const NEW_TOKEN = 0x07,
+        const V1_STREAM = 0x08,
+        const V1_MAX_DATA = 0x10,
+        const V1_MAX_STREAM_DATA = 0x11,
+        const MAX_STREAMS_BIDI = 0x12,
+        const MAX_STREAMS_UNI = 0x13,
+        const DATA_BLOCKED = 0x14,
+        const STREAM_DATA_BLOCKED = 0x15,
+        const STREAMS_BLOCKED_BIDI = 0x16,
+        const STREAMS_BLOCKED_UNI = 0x17,
+        const V1_NEW_CONNECTION_ID = 0x18,
+        const RETIRE_CONNECTION_ID = 0x19,
+        const PATH_CHALLENGE = 0x1a,
+        const PATH_RESPONSE = 0x1b,
+        const V1_CONNECTION_CLOSE = 0x1c,
+        const APPLICATION_CLOSE = 0x1d,
+        const HANDSHAKE_DONE = 0x1e,
+    }
+}
+
 //pub trait QuicFrame {
 //    fn as_bytes(&self) -> Vec<u8>;
 //    fn from_bytes<T>(buf: &Vec<u8>) -> Result<T>;
 //}
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum QuicFrame {
     Stream(StreamFrame),
     Ack(AckFrame),
@@ -97,115 +178,385 @@ impl QuicFrame {
         }
     }
 
-    pub fn from_bytes(buf: &[u8]) -> Result<QuicFrame> {
+    /// Size of the frame on the wire, worked out without serializing it.
+    pub fn frame_len(&self) -> usize {
+        match *self {
+            QuicFrame::Stream(ref f) => f.frame_len(),
+            QuicFrame::Ack(ref f) => f.frame_len(),
+            QuicFrame::MaxData(_) => 1 + 8,
+            QuicFrame::MaxStreamData(_) => 1 + 4 + 8,
+            QuicFrame::MaxStreamId(_) => 1 + 4,
+            QuicFrame::Blocked(_) => 1,
+            QuicFrame::StreamBlocked(_) => 1 + 4,
+            QuicFrame::StreamIdNeeded(_) => 1,
+            QuicFrame::Padding(ref f) => f.length,
+            QuicFrame::Ping(_) => 1,
+            QuicFrame::NewConnectionId(_) => 1 + 2 + 8 + 4,
+            QuicFrame::ConnectionClose(ref f) => 1 + 4 + 2 + f.reason_phrase.as_ref().map_or(0, |phrase| phrase.len()),
+            QuicFrame::GoAway(_) => 1 + 4 + 4,
+            QuicFrame::ResetStream(_) => 1 + 4 + 4 + 8,
+        }
+    }
+
+    /// Serializes the frame onto the end of `buf`.
+    pub fn write_to(&self, buf: &mut BytesMut) {
+        match *self {
+            QuicFrame::Stream(ref f) => f.write_to(buf),
+            _ => buf.extend_from_slice(&self.as_bytes()),
+        }
+    }
+
+    pub fn from_bytes(buf: &Bytes) -> Result<QuicFrame> {
+        QuicFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    /// Parses the frame at the start of `buf`, returning it along with the
+    /// number of bytes it took up so the caller can move on to the next.
+    /// Malformed frames fail with the transport error the connection
+    /// should close with.
+    pub fn decode(buf: &Bytes) -> Result<(QuicFrame, usize)> {
         if buf.is_empty() {
-            return Err(QuicError::ParseError);
+            return Err(QuicError::TransportError(QUIC_MISSING_PAYLOAD));
         }
 
         let frame_type = buf[0];
 
-        if (frame_type & 0xf0) == ACK.bits() {
-            let frame = AckFrame::from_bytes(buf)?;
+        QuicFrame::decode_frame(buf, frame_type).map_err(|err| match err {
+            QuicError::TransportError(_) => err,
+            _ => QuicError::TransportError(invalid_data_error(frame_type)),
+        })
+    }
 
-            return Ok(QuicFrame::Ack(frame));
+    fn decode_frame(buf: &Bytes, frame_type: u8) -> Result<(QuicFrame, usize)> {
+        // ACK (101xxxxx) and STREAM (11xxxxxx) carry flags in the type byte.
+        if (frame_type & 0xe0) == ACK.bits() {
+            return AckFrame::decode(buf).map(|(f, len)| (QuicFrame::Ack(f), len));
         }
 
-        if (frame_type & 0xf0) == STREAM.bits() {
-            let frame = StreamFrame::from_bytes(buf)?;
+        if (frame_type & 0xc0) == STREAM.bits() {
+            return StreamFrame::decode(buf).map(|(f, len)| (QuicFrame::Stream(f), len));
+        }
 
-            return Ok(QuicFrame::Stream(frame));
+        match FrameType::from_bits(frame_type) {
+            Some(PADDING) => PaddingFrame::decode(buf).map(|(f, len)| (QuicFrame::Padding(f), len)),
+            Some(RST_STREAM) => ResetStreamFrame::decode(buf).map(|(f, len)| (QuicFrame::ResetStream(f), len)),
+            Some(CONNECTION_CLOSE) => ConnectionCloseFrame::decode(buf).map(|(f, len)| (QuicFrame::ConnectionClose(f), len)),
+            Some(GOAWAY) => GoAwayFrame::decode(buf).map(|(f, len)| (QuicFrame::GoAway(f), len)),
+            Some(MAX_DATA) => MaxDataFrame::decode(buf).map(|(f, len)| (QuicFrame::MaxData(f), len)),
+            Some(MAX_STREAM_DATA) => MaxStreamDataFrame::decode(buf).map(|(f, len)| (QuicFrame::MaxStreamData(f), len)),
+            Some(MAX_STREAM_ID) => MaxStreamIdFrame::decode(buf).map(|(f, len)| (QuicFrame::MaxStreamId(f), len)),
+            Some(PING) => PingFrame::decode(buf).map(|(f, len)| (QuicFrame::Ping(f), len)),
+            Some(BLOCKED) => BlockedFrame::decode(buf).map(|(f, len)| (QuicFrame::Blocked(f), len)),
+            Some(STREAM_BLOCKED) => StreamBlockedFrame::decode(buf).map(|(f, len)| (QuicFrame::StreamBlocked(f), len)),
+            Some(STREAM_ID_NEEDED) => StreamIdNeededFrame::decode(buf).map(|(f, len)| (QuicFrame::StreamIdNeeded(f), len)),
+            Some(NEW_CONNECTION_ID) => NewConnectionIdFrame::decode(buf).map(|(f, len)| (QuicFrame::NewConnectionId(f), len)),
+            _ => Err(QuicError::TransportError(QUIC_INVALID_FRAME_DATA)),
         }
+    }
+}
 
-        if frame_type == RST_STREAM.bits() {
-            let frame = ResetStreamFrame::from_bytes(buf)?;
+/// A frame in the RFC 9000 encoding. These are kept apart from `QuicFrame`,
+/// which holds only what draft-05 packets can carry. PADDING, PING and
+/// MAX_DATA look the same in both, so they share the draft-05 structs.
+#[derive(Debug, PartialEq, Clone)]
+pub enum V1Frame {
+    Padding(PaddingFrame),
+    Ping(PingFrame),
+    Ack(V1AckFrame),
+    ResetStream(V1ResetStreamFrame),
+    StopSending(StopSendingFrame),
+    Crypto(CryptoFrame),
+    NewToken(NewTokenFrame),
+    Stream(V1StreamFrame),
+    MaxData(MaxDataFrame),
+    MaxStreamData(V1MaxStreamDataFrame),
+    MaxStreams(MaxStreamsFrame),
+    DataBlocked(DataBlockedFrame),
+    StreamDataBlocked(StreamDataBlockedFrame),
+    StreamsBlocked(StreamsBlockedFrame),
+    NewConnectionId(V1NewConnectionIdFrame),
+    RetireConnectionId(RetireConnectionIdFrame),
+    PathChallenge(PathChallengeFrame),
+    PathResponse(PathResponseFrame),
+    ConnectionClose(V1ConnectionCloseFrame),
+    HandshakeDone(HandshakeDoneFrame),
+}
 
-            return Ok(QuicFrame::ResetStream(frame));
+impl V1Frame {
+    /// Size of the frame on the wire, worked out without serializing it.
+    pub fn frame_len(&self) -> usize {
+        match *self {
+            V1Frame::Padding(ref f) => f.length,
+            V1Frame::Ping(_) => varint_len(V1_PING.bits()),
+            V1Frame::Ack(ref f) => f.frame_len(),
+            V1Frame::ResetStream(ref f) => f.frame_len(),
+            V1Frame::StopSending(ref f) => f.frame_len(),
+            V1Frame::Crypto(ref f) =>
+                varint_len(CRYPTO.bits()) + varint_len(f.offset) + varint_len(f.crypto_data.len() as u64) + f.crypto_data.len(),
+            V1Frame::NewToken(ref f) => varint_len(NEW_TOKEN.bits()) + varint_len(f.token.len() as u64) + f.token.len(),
+            V1Frame::Stream(ref f) => f.frame_len(),
+            V1Frame::MaxData(ref f) => varint_len(V1_MAX_DATA.bits()) + varint_len(f.max_data),
+            V1Frame::MaxStreamData(ref f) => f.frame_len(),
+            V1Frame::MaxStreams(ref f) => varint_len(MAX_STREAMS_BIDI.bits()) + varint_len(f.maximum_streams),
+            V1Frame::DataBlocked(ref f) => f.frame_len(),
+            V1Frame::StreamDataBlocked(ref f) => f.frame_len(),
+            V1Frame::StreamsBlocked(ref f) => varint_len(STREAMS_BLOCKED_BIDI.bits()) + varint_len(f.stream_limit),
+            V1Frame::NewConnectionId(ref f) => f.frame_len(),
+            V1Frame::RetireConnectionId(ref f) => varint_len(RETIRE_CONNECTION_ID.bits()) + varint_len(f.sequence_number),
+            V1Frame::PathChallenge(ref f) => varint_len(PATH_CHALLENGE.bits()) + f.data.len(),
+            V1Frame::PathResponse(ref f) => varint_len(PATH_RESPONSE.bits()) + f.data.len(),
+            V1Frame::ConnectionClose(ref f) => f.frame_len(),
+            V1Frame::HandshakeDone(_) => varint_len(HANDSHAKE_DONE.bits()),
         }
+    }
 
-        if frame_type == CONNECTION_CLOSE.bits() {
-            let frame = ConnectionCloseFrame::from_bytes(buf)?;
-
-            return Ok(QuicFrame::ConnectionClose(frame));
+    /// Serializes the frame onto the end of `buf`. Fails for values too
+    /// large for a varint, leaving `buf` as it was.
+    pub fn write_to(&self, buf: &mut BytesMut) -> Result<()> {
+        match *self {
+            V1Frame::Padding(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::Ping(_) => buf.extend_from_slice(&[V1_PING.bits() as u8]),
+            V1Frame::Ack(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::ResetStream(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::StopSending(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::Crypto(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::NewToken(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::Stream(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::MaxData(ref f) => {
+                let mut bytes = Vec::with_capacity(9);
+
+                bytes.write_varint(V1_MAX_DATA.bits())?;
+                bytes.write_varint(f.max_data)?;
+
+                buf.extend_from_slice(&bytes);
+            },
+            V1Frame::MaxStreamData(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::MaxStreams(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::DataBlocked(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::StreamDataBlocked(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::StreamsBlocked(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::NewConnectionId(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::RetireConnectionId(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::PathChallenge(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::PathResponse(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::ConnectionClose(ref f) => buf.extend_from_slice(&f.as_bytes()),
+            V1Frame::HandshakeDone(ref f) => buf.extend_from_slice(&f.as_bytes()),
         }
 
-        if frame_type == GOAWAY.bits() {
-            let frame = GoAwayFrame::from_bytes(buf)?;
+        Ok(())
+    }
 
-            return Ok(QuicFrame::GoAway(frame));
+    /// Parses the frame at the start of `buf`, returning it along with the
+    /// number of bytes it took up. The type is a varint and the fields use
+    /// the v1 encodings; malformed frames fail with FRAME_ENCODING_ERROR.
+    pub fn decode(buf: &Bytes) -> Result<(V1Frame, usize)> {
+        if buf.is_empty() {
+            return Err(QuicError::TransportError(QUIC_MISSING_PAYLOAD));
         }
 
-        if frame_type == MAX_DATA.bits() {
-            let frame = MaxDataFrame::from_bytes(buf)?;
+        let frame_type = Cursor::new(&buf[..]).read_varint()?;
 
-            return Ok(QuicFrame::MaxData(frame));
-        }
+        V1Frame::decode_frame(buf, frame_type).map_err(|err| match err {
+            QuicError::TransportError(_) => err,
+            _ => QuicError::TransportError(FRAME_ENCODING_ERROR),
+        })
+    }
 
-        if frame_type == MAX_STREAM_DATA.bits() {
-            let frame = MaxStreamDataFrame::from_bytes(buf)?;
+    fn decode_frame(buf: &Bytes, frame_type: u64) -> Result<(V1Frame, usize)> {
+        // STREAM (0x08 to 0x0f) carries its flags in the low three bits.
+        if frame_type & !0x07 == V1_STREAM.bits() {
+            return V1StreamFrame::decode(buf).map(|(f, len)| (V1Frame::Stream(f), len));
+        }
 
-            return Ok(QuicFrame::MaxStreamData(frame));
+        match V1FrameType::from_bits(frame_type) {
+            Some(V1_PADDING) => PaddingFrame::decode(buf).map(|(f, len)| (V1Frame::Padding(f), len)),
+            Some(V1_PING) => {
+                let mut reader = Cursor::new(&buf[..]);
+                let _ = reader.read_varint()?;
+
+                Ok((V1Frame::Ping(PingFrame {}), reader.position() as usize))
+            },
+            Some(V1_ACK) | Some(ACK_ECN) => V1AckFrame::decode(buf).map(|(f, len)| (V1Frame::Ack(f), len)),
+            Some(V1_RESET_STREAM) => V1ResetStreamFrame::decode(buf).map(|(f, len)| (V1Frame::ResetStream(f), len)),
+            Some(STOP_SENDING) => StopSendingFrame::decode(buf).map(|(f, len)| (V1Frame::StopSending(f), len)),
+            Some(CRYPTO) => CryptoFrame::decode(buf).map(|(f, len)| (V1Frame::Crypto(f), len)),
+            Some(NEW_TOKEN) => NewTokenFrame::decode(buf).map(|(f, len)| (V1Frame::NewToken(f), len)),
+            Some(V1_MAX_DATA) => {
+                let mut reader = Cursor::new(&buf[..]);
+                let _ = reader.read_varint()?;
+                let max_data = reader.read_varint()?;
+
+                Ok((V1Frame::MaxData(MaxDataFrame { max_data: max_data }), reader.position() as usize))
+            },
+            Some(V1_MAX_STREAM_DATA) => V1MaxStreamDataFrame::decode(buf).map(|(f, len)| (V1Frame::MaxStreamData(f), len)),
+            Some(MAX_STREAMS_BIDI) | Some(MAX_STREAMS_UNI) => MaxStreamsFrame::decode(buf).map(|(f, len)| (V1Frame::MaxStreams(f), len)),
+            Some(DATA_BLOCKED) => DataBlockedFrame::decode(buf).map(|(f, len)| (V1Frame::DataBlocked(f), len)),
+            Some(STREAM_DATA_BLOCKED) => StreamDataBlockedFrame::decode(buf).map(|(f, len)| (V1Frame::StreamDataBlocked(f), len)),
+            Some(STREAMS_BLOCKED_BIDI) | Some(STREAMS_BLOCKED_UNI) => StreamsBlockedFrame::decode(buf).map(|(f, len)| (V1Frame::StreamsBlocked(f), len)),
+            Some(V1_NEW_CONNECTION_ID) => V1NewConnectionIdFrame::decode(buf).map(|(f, len)| (V1Frame::NewConnectionId(f), len)),
+            Some(RETIRE_CONNECTION_ID) => RetireConnectionIdFrame::decode(buf).map(|(f, len)| (V1Frame::RetireConnectionId(f), len)),
+            Some(PATH_CHALLENGE) => PathChallengeFrame::decode(buf).map(|(f, len)| (V1Frame::PathChallenge(f), len)),
+            Some(PATH_RESPONSE) => PathResponseFrame::decode(buf).map(|(f, len)| (V1Frame::PathResponse(f), len)),
+            Some(V1_CONNECTION_CLOSE) | Some(APPLICATION_CLOSE) => V1ConnectionCloseFrame::decode(buf).map(|(f, len)| (V1Frame::ConnectionClose(f), len)),
+            Some(HANDSHAKE_DONE) => HandshakeDoneFrame::decode(buf).map(|(f, len)| (V1Frame::HandshakeDone(f), len)),
+            _ => Err(QuicError::TransportError(FRAME_ENCODING_ERROR)),
         }
+    }
+}
 
-        if frame_type == MAX_STREAM_ID.bits() {
-            let frame = MaxStreamIdFrame::from_bytes(buf)?;
+/// The error for a frame of this type that is truncated or otherwise
+/// can't be read.
+fn invalid_data_error(frame_type: u8) -> TransportErrorFlag {
+    if (frame_type & 0xe0) == ACK.bits() {
+        return QUIC_INVALID_ACK_DATA;
+    }
 
-            return Ok(QuicFrame::MaxStreamId(frame));
-        }
+    if (frame_type & 0xc0) == STREAM.bits() {
+        return QUIC_INVALID_STREAM_DATA;
+    }
 
-        if frame_type == PING.bits()  {
-            let frame = PingFrame::from_bytes(buf)?;
+    match FrameType::from_bits(frame_type) {
+        Some(RST_STREAM) => QUIC_INVALID_RST_STREAM_DATA,
+        Some(CONNECTION_CLOSE) => QUIC_INVALID_CONNECTION_CLOSE_DATA,
+        Some(GOAWAY) => QUIC_INVALID_GOAWAY_DATA,
+        Some(MAX_DATA) | Some(MAX_STREAM_DATA) => QUIC_INVALID_WINDOW_UPDATE_DATA,
+        Some(BLOCKED) | Some(STREAM_BLOCKED) => QUIC_INVALID_BLOCKED_DATA,
+        _ => QUIC_INVALID_FRAME_DATA,
+    }
+}
 
-            return Ok(QuicFrame::Ping(frame));
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use version::QuicVersion;
+    use version::DRAFT_05;
+    use error::STREAM_LIMIT_ERROR;
 
-        if frame_type == BLOCKED.bits() {
-            let frame = BlockedFrame::from_bytes(buf)?;
+    fn decode_all(mut buf: Bytes) -> Vec<V1Frame> {
+        let mut parsed = Vec::new();
 
-            return Ok(QuicFrame::Blocked(frame));
+        while !buf.is_empty() {
+            let (frame, len) = V1Frame::decode(&buf).unwrap();
+            parsed.push(frame);
+            buf = buf.slice_from(len);
         }
 
-        if frame_type == STREAM_BLOCKED.bits() {
-            let frame = StreamBlockedFrame::from_bytes(buf)?;
+        parsed
+    }
 
-            return Ok(QuicFrame::StreamBlocked(frame));
+    #[test]
+    fn decode_v1_frames_in_sequence() {
+        let frames = vec![
+            V1Frame::Crypto(CryptoFrame { offset: 0, crypto_data: Bytes::from(&b"hello"[..]) }),
+            V1Frame::MaxStreams(MaxStreamsFrame { bidirectional: false, maximum_streams: 100 }),
+            V1Frame::HandshakeDone(HandshakeDoneFrame {}),
+            V1Frame::Padding(PaddingFrame { length: 3 }),
+        ];
+
+        let mut buf = BytesMut::new();
+        for frame in &frames {
+            frame.write_to(&mut buf).unwrap();
         }
 
-        if frame_type == STREAM_ID_NEEDED.bits() {
-            let frame = StreamIdNeededFrame::from_bytes(buf)?;
+        assert_eq!(decode_all(buf.freeze()), frames);
+    }
+
+    #[test]
+    fn decode_v1_reports_frame_errors() {
+        let truncated = Bytes::from(&[ACK_ECN.bits() as u8, 0x05][..]);
+        let unknown = Bytes::from(&[0x3f][..]);
+
+        let mut too_many_streams = StreamsBlockedFrame { bidirectional: true, stream_limit: 1 << 60 }.as_bytes();
+        too_many_streams[8] += 1;
+
+        match V1Frame::decode(&truncated) {
+            Err(QuicError::TransportError(code)) => assert_eq!(code, FRAME_ENCODING_ERROR),
+            other => panic!("unexpected result {:?}", other),
+        }
 
-            return Ok(QuicFrame::StreamIdNeeded(frame));
+        match V1Frame::decode(&unknown) {
+            Err(QuicError::TransportError(code)) => assert_eq!(code, FRAME_ENCODING_ERROR),
+            other => panic!("unexpected result {:?}", other),
         }
 
-        if frame_type == NEW_CONNECTION_ID.bits() {
-            let frame = NewConnectionIdFrame::from_bytes(buf)?;
+        match V1Frame::decode(&Bytes::from(too_many_streams)) {
+            Err(QuicError::TransportError(code)) => assert_eq!(code, STREAM_LIMIT_ERROR),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
 
-            return Ok(QuicFrame::NewConnectionId(frame));
+    #[test]
+    fn decode_v1_shared_frames() {
+        let frames = vec![
+            V1Frame::Ping(PingFrame {}),
+            V1Frame::Ack(V1AckFrame {
+                largest_acknowledged: 10,
+                ack_delay: 3,
+                first_ack_range: 2,
+                ack_ranges: vec![],
+                ecn_counts: None,
+            }),
+            V1Frame::Stream(V1StreamFrame {
+                stream_id: 4,
+                offset: 100,
+                length_present: true,
+                fin: true,
+                stream_data: Bytes::from(&b"hello"[..]),
+            }),
+            V1Frame::MaxData(MaxDataFrame { max_data: 1 << 20 }),
+            V1Frame::ConnectionClose(V1ConnectionCloseFrame {
+                error_code: FRAME_ENCODING_ERROR.bits() as u64,
+                frame_type: Some(V1_STREAM.bits()),
+                reason_phrase: "bad stream".to_string(),
+            }),
+        ];
+
+        let mut buf = BytesMut::new();
+        for frame in &frames {
+            let start = buf.len();
+            frame.write_to(&mut buf).unwrap();
+            assert_eq!(frame.frame_len(), buf.len() - start);
         }
 
-        Ok(QuicFrame::Padding(PaddingFrame{}))
+        assert_eq!(buf[0], V1_PING.bits() as u8);
+        assert_eq!(decode_all(buf.freeze()), frames);
     }
 
-    pub fn frame_length(buf: &[u8]) -> Result<usize> {
-        let mut reader = Cursor::new(buf);
+    #[test]
+    fn write_rejects_values_too_large_for_a_varint() {
+        let frame = V1Frame::MaxData(MaxDataFrame { max_data: u64::MAX });
+
+        let mut buf = BytesMut::new();
+        assert!(frame.write_to(&mut buf).is_err());
+        assert!(buf.is_empty());
+    }
 
-        let type_byte = reader.read_u8()?;
+    quickcheck! {
+        fn frames_round_trip(frame: QuicFrame) -> bool {
+            let bytes = Bytes::from(frame.as_bytes());
 
-        match FrameType::from_bits(type_byte) {
-            Some(PADDING) => Ok(PaddingFrame::frame_len()?),
-            Some(RST_STREAM) => Ok(ResetStreamFrame::frame_len()?),
-            Some(CONNECTION_CLOSE) => Ok(ConnectionCloseFrame::frame_len(buf)?),
-            Some(GOAWAY) => Ok(GoAwayFrame::frame_len()?),
-            Some(MAX_DATA) => Ok(MaxDataFrame::frame_len()?),
-            Some(MAX_STREAM_DATA) => Ok(MaxStreamDataFrame::frame_len()?),
-            Some(MAX_STREAM_ID) => Ok(MaxStreamIdFrame::frame_len()?),
-            Some(PING) => Ok(PingFrame::frame_len()?),
-            Some(BLOCKED) => Ok(BlockedFrame::frame_len()?),
-            Some(STREAM_BLOCKED) => Ok(StreamBlockedFrame::frame_len()?),
-            Some(STREAM_ID_NEEDED) => Ok(StreamIdNeededFrame::frame_len()?),
-            Some(NEW_CONNECTION_ID) => Ok(NewConnectionIdFrame::frame_len()?),
-            Some(ACK) => Ok(AckFrame::frame_len(buf)?),
-            Some(STREAM) => Ok(StreamFrame::frame_len(buf)?),
-            Some(_) | None => Err(QuicError::ParseError),
+            QuicFrame::decode(&bytes).ok() == Some((frame, bytes.len()))
+        }
+
+        fn v1_frames_round_trip(frame: V1Frame) -> bool {
+            let mut buf = BytesMut::new();
+
+            if frame.write_to(&mut buf).is_err() {
+                return true;
+            }
+
+            let len = buf.len();
+
+            len == frame.frame_len() && V1Frame::decode(&buf.freeze()).ok() == Some((frame, len))
+        }
+
+        fn draft_05_frames_round_trip(frame: QuicFrame) -> bool {
+            let mut buf = BytesMut::new();
+            DRAFT_05.write_frame(&frame, &mut buf);
+
+            let len = DRAFT_05.frame_len(&frame);
+
+            len == buf.len() && DRAFT_05.decode_frame(&buf.freeze()).ok() == Some((frame, len))
         }
     }
-}
\ No newline at end of file
+}
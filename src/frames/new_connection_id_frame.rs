@@ -2,7 +2,7 @@ use std::io::Cursor;
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 use error::Result;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct NewConnectionIdFrame {
     pub sequence: u16,
     pub connection_id: u64,
@@ -27,6 +27,10 @@ impl NewConnectionIdFrame {
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<NewConnectionIdFrame> {
+        NewConnectionIdFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(NewConnectionIdFrame, usize)> {
         let mut reader = Cursor::new(buf);
 
         let _ = reader.read_u8()?;
@@ -37,15 +41,11 @@ impl NewConnectionIdFrame {
 
         let packet_number_gap = reader.read_u32::<BigEndian>()?;
 
-        Ok(NewConnectionIdFrame {
+        Ok((NewConnectionIdFrame {
             sequence: sequence,
             connection_id: connection_id,
             packet_number_gap: packet_number_gap,
-        })
-    }
-
-    pub fn frame_len() -> Result<usize> {
-        Ok(11)
+        }, reader.position() as usize))
     }
 }
 
@@ -0,0 +1,74 @@
+use std::io::Cursor;
+use bytes::Bytes;
+
+use error::QuicError;
+use error::Result;
+use util::{ReadVarint, WriteVarint};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct NewTokenFrame {
+    pub token: Bytes,
+}
+
+impl NewTokenFrame {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.token.len() + 9);
+
+        bytes.write_varint(super::NEW_TOKEN.bits());
+
+        bytes.write_varint(self.token.len() as u64);
+
+        bytes.extend_from_slice(&self.token);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &Bytes) -> Result<NewTokenFrame> {
+        NewTokenFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &Bytes) -> Result<(NewTokenFrame, usize)> {
+        let mut reader = Cursor::new(&buf[..]);
+
+        let _ = reader.read_varint()?;
+
+        let length = reader.read_varint()?;
+
+        let token_start = reader.position() as usize;
+
+        // An empty token is a frame encoding error.
+        if length == 0 || length > (buf.len() - token_start) as u64 {
+            return Err(QuicError::ParseError);
+        }
+
+        let token_end = token_start + length as usize;
+
+        Ok((NewTokenFrame {
+            token: buf.slice(token_start, token_end),
+        }, token_end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let frame = NewTokenFrame {
+            token: Bytes::from(&[0x5a; 40][..]),
+        };
+
+        let frame_bytes = Bytes::from(frame.as_bytes());
+        let parsed_frame = NewTokenFrame::from_bytes(&frame_bytes).unwrap();
+
+        assert_eq!(frame, parsed_frame);
+    }
+
+    #[test]
+    fn rejects_empty_token() {
+        let frame_bytes = NewTokenFrame { token: Bytes::new() }.as_bytes();
+
+        assert!(NewTokenFrame::from_bytes(&Bytes::from(frame_bytes)).is_err());
+    }
+}
@@ -1,32 +1,30 @@
-use std::io::Cursor;
-use byteorder::{WriteBytesExt, ReadBytesExt};
 use error::Result;
+use error::QuicError;
 
+/// A run of PADDING octets. Each octet is a frame of its own on the wire,
+/// but consecutive ones are read and written together.
 #[derive(Debug, PartialEq, Copy, Clone)]
-pub struct PaddingFrame {}
+pub struct PaddingFrame {
+    pub length: usize,
+}
 
 impl PaddingFrame {
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(1);
-
-        let first_byte = super::PADDING.bits();
-
-        bytes.write_u8(first_byte);
-
-        bytes
+        vec![super::PADDING.bits(); self.length]
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<PaddingFrame> {
-        let mut reader = Cursor::new(buf);
-
-        let _ = reader.read_u8()?;
+        PaddingFrame::decode(buf).map(|(frame, _)| frame)
+    }
 
+    pub fn decode(buf: &[u8]) -> Result<(PaddingFrame, usize)> {
+        let length = buf.iter().take_while(|&&b| b == super::PADDING.bits()).count();
 
-        Ok(PaddingFrame {})
-    }
+        if length == 0 {
+            return Err(QuicError::ParseError);
+        }
 
-    pub fn frame_len() -> Result<usize> {
-        Ok(1)
+        Ok((PaddingFrame { length: length }, length))
     }
 }
 
@@ -36,11 +34,19 @@ mod tests {
 
     #[test]
     fn serialize() {
-        let frame = PaddingFrame { };
+        let frame = PaddingFrame { length: 1 };
 
         let frame_bytes = frame.as_bytes();
         let parsed_frame = PaddingFrame::from_bytes(&frame_bytes).unwrap();
 
         assert_eq!(frame, parsed_frame);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn consecutive_padding_is_one_run() {
+        let mut bytes = PaddingFrame { length: 300 }.as_bytes();
+        bytes.push(0x07);
+
+        assert_eq!(PaddingFrame::decode(&bytes).unwrap(), (PaddingFrame { length: 300 }, 300));
+    }
+}
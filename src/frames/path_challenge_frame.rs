@@ -0,0 +1,56 @@
+use std::io::{Cursor, Read};
+
+use error::Result;
+use util::{ReadVarint, WriteVarint};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PathChallengeFrame {
+    pub data: [u8; 8],
+}
+
+impl PathChallengeFrame {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(9);
+
+        bytes.write_varint(super::PATH_CHALLENGE.bits());
+
+        bytes.extend_from_slice(&self.data);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<PathChallengeFrame> {
+        PathChallengeFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(PathChallengeFrame, usize)> {
+        let mut reader = Cursor::new(buf);
+
+        let _ = reader.read_varint()?;
+
+        let mut data = [0; 8];
+        reader.read_exact(&mut data)?;
+
+        Ok((PathChallengeFrame {
+            data: data,
+        }, reader.position() as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let frame = PathChallengeFrame {
+            data: [1, 2, 3, 4, 5, 6, 7, 8],
+        };
+
+        let frame_bytes = frame.as_bytes();
+        let parsed_frame = PathChallengeFrame::from_bytes(&frame_bytes).unwrap();
+
+        assert_eq!(frame, parsed_frame);
+        assert!(PathChallengeFrame::from_bytes(&frame_bytes[..8]).is_err());
+    }
+}
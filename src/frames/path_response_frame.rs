@@ -0,0 +1,55 @@
+use std::io::{Cursor, Read};
+
+use error::Result;
+use util::{ReadVarint, WriteVarint};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PathResponseFrame {
+    pub data: [u8; 8],
+}
+
+impl PathResponseFrame {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(9);
+
+        bytes.write_varint(super::PATH_RESPONSE.bits());
+
+        bytes.extend_from_slice(&self.data);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<PathResponseFrame> {
+        PathResponseFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(PathResponseFrame, usize)> {
+        let mut reader = Cursor::new(buf);
+
+        let _ = reader.read_varint()?;
+
+        let mut data = [0; 8];
+        reader.read_exact(&mut data)?;
+
+        Ok((PathResponseFrame {
+            data: data,
+        }, reader.position() as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let frame = PathResponseFrame {
+            data: [8, 7, 6, 5, 4, 3, 2, 1],
+        };
+
+        let frame_bytes = frame.as_bytes();
+        let parsed_frame = PathResponseFrame::from_bytes(&frame_bytes).unwrap();
+
+        assert_eq!(frame, parsed_frame);
+    }
+}
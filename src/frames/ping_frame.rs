@@ -2,7 +2,7 @@ use std::io::Cursor;
 use byteorder::{WriteBytesExt, ReadBytesExt};
 use error::Result;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct PingFrame {}
 
 impl PingFrame {
@@ -17,16 +17,15 @@ impl PingFrame {
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<PingFrame> {
+        PingFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(PingFrame, usize)> {
         let mut reader = Cursor::new(buf);
 
         let _ = reader.read_u8()?;
 
-
-        Ok(PingFrame {})
-    }
-
-    pub fn frame_len() -> Result<usize> {
-        Ok(1)
+        Ok((PingFrame {}, reader.position() as usize))
     }
 }
 
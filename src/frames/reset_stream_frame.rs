@@ -2,7 +2,7 @@ use std::io::Cursor;
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 use error::Result;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct ResetStreamFrame {
     pub error_code: u32,
     pub stream_id: u32,
@@ -25,6 +25,10 @@ impl ResetStreamFrame {
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<ResetStreamFrame> {
+        ResetStreamFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(ResetStreamFrame, usize)> {
         let mut reader = Cursor::new(buf);
 
         let _ = reader.read_u8()?;
@@ -33,15 +37,11 @@ impl ResetStreamFrame {
         let stream_id = reader.read_u32::<BigEndian>()?;
         let final_offset = reader.read_u64::<BigEndian>()?;
 
-        Ok(ResetStreamFrame {
+        Ok((ResetStreamFrame {
             error_code: error_code,
             stream_id: stream_id,
             final_offset: final_offset,
-        })
-    }
-
-    pub fn frame_len() -> Result<usize> {
-        Ok(11)
+        }, reader.position() as usize))
     }
 }
 
@@ -0,0 +1,54 @@
+use std::io::Cursor;
+
+use error::Result;
+use util::{ReadVarint, WriteVarint};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RetireConnectionIdFrame {
+    pub sequence_number: u64,
+}
+
+impl RetireConnectionIdFrame {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(9);
+
+        bytes.write_varint(super::RETIRE_CONNECTION_ID.bits());
+
+        bytes.write_varint(self.sequence_number);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<RetireConnectionIdFrame> {
+        RetireConnectionIdFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(RetireConnectionIdFrame, usize)> {
+        let mut reader = Cursor::new(buf);
+
+        let _ = reader.read_varint()?;
+
+        let sequence_number = reader.read_varint()?;
+
+        Ok((RetireConnectionIdFrame {
+            sequence_number: sequence_number,
+        }, reader.position() as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let frame = RetireConnectionIdFrame {
+            sequence_number: 70000,
+        };
+
+        let frame_bytes = frame.as_bytes();
+        let parsed_frame = RetireConnectionIdFrame::from_bytes(&frame_bytes).unwrap();
+
+        assert_eq!(frame, parsed_frame);
+    }
+}
@@ -0,0 +1,66 @@
+use std::io::Cursor;
+
+use error::Result;
+use util::{ReadVarint, WriteVarint};
+use util::varint_len;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StopSendingFrame {
+    pub stream_id: u64,
+    pub application_error_code: u64,
+}
+
+impl StopSendingFrame {
+    pub fn frame_len(&self) -> usize {
+        1 + varint_len(self.stream_id) + varint_len(self.application_error_code)
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.frame_len());
+
+        bytes.write_varint(super::STOP_SENDING.bits());
+
+        bytes.write_varint(self.stream_id);
+
+        bytes.write_varint(self.application_error_code);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<StopSendingFrame> {
+        StopSendingFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(StopSendingFrame, usize)> {
+        let mut reader = Cursor::new(buf);
+
+        let _ = reader.read_varint()?;
+
+        let stream_id = reader.read_varint()?;
+
+        let application_error_code = reader.read_varint()?;
+
+        Ok((StopSendingFrame {
+            stream_id: stream_id,
+            application_error_code: application_error_code,
+        }, reader.position() as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let frame = StopSendingFrame {
+            stream_id: 8,
+            application_error_code: 0x10c,
+        };
+
+        let frame_bytes = frame.as_bytes();
+        let parsed_frame = StopSendingFrame::from_bytes(&frame_bytes).unwrap();
+
+        assert_eq!(frame, parsed_frame);
+    }
+}
@@ -2,7 +2,7 @@ use std::io::Cursor;
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 use error::Result;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct StreamBlockedFrame {
     pub stream_id: u32,
 }
@@ -21,19 +21,19 @@ impl StreamBlockedFrame {
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<StreamBlockedFrame> {
+        StreamBlockedFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(StreamBlockedFrame, usize)> {
         let mut reader = Cursor::new(buf);
 
         let _ = reader.read_u8()?;
 
         let maximum_data = reader.read_u32::<BigEndian>()?;
 
-        Ok(StreamBlockedFrame {
+        Ok((StreamBlockedFrame {
             stream_id: maximum_data,
-        })
-    }
-
-    pub fn frame_len() -> Result<usize> {
-        Ok(5)
+        }, reader.position() as usize))
     }
 }
 
@@ -0,0 +1,66 @@
+use std::io::Cursor;
+
+use error::Result;
+use util::{ReadVarint, WriteVarint};
+use util::varint_len;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StreamDataBlockedFrame {
+    pub stream_id: u64,
+    pub maximum_stream_data: u64,
+}
+
+impl StreamDataBlockedFrame {
+    pub fn frame_len(&self) -> usize {
+        1 + varint_len(self.stream_id) + varint_len(self.maximum_stream_data)
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.frame_len());
+
+        bytes.write_varint(super::STREAM_DATA_BLOCKED.bits());
+
+        bytes.write_varint(self.stream_id);
+
+        bytes.write_varint(self.maximum_stream_data);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<StreamDataBlockedFrame> {
+        StreamDataBlockedFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(StreamDataBlockedFrame, usize)> {
+        let mut reader = Cursor::new(buf);
+
+        let _ = reader.read_varint()?;
+
+        let stream_id = reader.read_varint()?;
+
+        let maximum_stream_data = reader.read_varint()?;
+
+        Ok((StreamDataBlockedFrame {
+            stream_id: stream_id,
+            maximum_stream_data: maximum_stream_data,
+        }, reader.position() as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let frame = StreamDataBlockedFrame {
+            stream_id: 6,
+            maximum_stream_data: 16384,
+        };
+
+        let frame_bytes = frame.as_bytes();
+        let parsed_frame = StreamDataBlockedFrame::from_bytes(&frame_bytes).unwrap();
+
+        assert_eq!(frame, parsed_frame);
+    }
+}
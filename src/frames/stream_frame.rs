@@ -1,10 +1,12 @@
 use std::io::Cursor;
-use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
+use byteorder::{ReadBytesExt, BigEndian};
+use bytes::{Bytes, BytesMut, BufMut};
 
 //mod error;
 use error::QuicError;
 use error::Result;
-use std::io::Read;
+use error::QUIC_INVALID_STREAM_DATA;
+use error::QUIC_EMPTY_STREAM_FRAME_NO_FIN;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct StreamFrame {
@@ -13,13 +15,18 @@ pub struct StreamFrame {
     pub data_length: Option<u16>,
     pub stream_id: u32,
     pub offset: u64,
-    pub stream_data: Vec<u8>,
+    pub stream_data: Bytes,
 }
 
 
 impl StreamFrame {
-    pub fn from_bytes(buf: &[u8]) -> Result<StreamFrame> {
-        let mut reader = Cursor::new(buf);
+    /// Parses a STREAM frame. The stream data is a slice of `buf`, not a copy.
+    pub fn from_bytes(buf: &Bytes) -> Result<StreamFrame> {
+        StreamFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &Bytes) -> Result<(StreamFrame, usize)> {
+        let mut reader = Cursor::new(&buf[..]);
         let first_octet = reader.read_u8()?;
 
         let fin = first_octet & 0x20 > 0;
@@ -28,7 +35,7 @@ impl StreamFrame {
         let oo = (first_octet >> 2) & 0x03;
         let ss = first_octet & 0x03;
 
-        #[allow(match_bool)]
+        #[allow(clippy::match_bool)]
         let data_length = match data_length_present {
             true => Some(reader.read_u16::<BigEndian>()?),
             false => None,
@@ -45,34 +52,46 @@ impl StreamFrame {
             _ => return Err(QuicError::ParseError)
         };
 
-        let mut stream_data = match data_length {
-            Some(length) => Vec::with_capacity(length as usize),
-            None => Vec::with_capacity(1252)
+        let data_start = reader.position() as usize;
+
+        let data_end = match data_length {
+            Some(length) => data_start + length as usize,
+            None => buf.len(),
         };
 
-        match data_length {
-            None => {
-                reader.read_to_end(&mut stream_data)?;
-            },
-            Some(length) => {
-                let mut reader_handle = reader.take(length as u64);
-                reader_handle.read_to_end(&mut stream_data)?;
-            }
+        if data_end > buf.len() {
+            return Err(QuicError::TransportError(QUIC_INVALID_STREAM_DATA));
+        }
+
+        if data_end == data_start && !fin {
+            return Err(QuicError::TransportError(QUIC_EMPTY_STREAM_FRAME_NO_FIN));
+        }
+
+        if offset.checked_add((data_end - data_start) as u64).is_none() {
+            return Err(QuicError::TransportError(QUIC_INVALID_STREAM_DATA));
         }
 
+        let stream_data = buf.slice(data_start, data_end);
 
-        Ok(StreamFrame {
+        Ok((StreamFrame {
             fin: fin,
             data_length_present: data_length_present,
             data_length: data_length,
             stream_id: stream_id,
             offset: offset,
             stream_data: stream_data,
-        })
+        }, data_end))
     }
 
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut byte_vector = Vec::new();
+        let mut buf = BytesMut::new();
+        self.write_to(&mut buf);
+
+        buf.to_vec()
+    }
+
+    pub fn write_to(&self, buf: &mut BytesMut) {
+        buf.reserve(self.frame_len());
 
         let mut type_byte = 0xc0;
 
@@ -80,86 +99,103 @@ impl StreamFrame {
             type_byte |= 0x20;
         }
 
-        // Signify that we are putting the data length in.
-        type_byte |= 0x10;
-
-        if self.offset != 0 {
-            if self.offset <= u16::max_value() as u64 {
-                type_byte |= 0x04;
-            } else if self.offset <= u32::max_value() as u64 {
-                type_byte |= 0x08;
-            } else {
-                type_byte |= 0x0c;
-            }
+        if self.data_length_present {
+            type_byte |= 0x10;
         }
 
+        type_byte |= match self.offset_len() {
+            0 => 0x00,
+            2 => 0x04,
+            4 => 0x08,
+            _ => 0x0c,
+        };
+
         // Skipping 3 byte stream_id's for now.
-        if self.stream_id <= (u8::max_value() as u32) {
-            byte_vector.write_u8(type_byte);
-            byte_vector.write_u16::<BigEndian>(self.data_length.unwrap());
-            byte_vector.write_u8(self.stream_id as u8);
-        } else if self.stream_id <= (u16::max_value() as u32) {
-            type_byte |= 0x01;
-            byte_vector.write_u8(type_byte);
-            byte_vector.write_u16::<BigEndian>(self.data_length.unwrap());
-            byte_vector.write_u16::<BigEndian>(self.stream_id as u16);
-        } else {
-            type_byte |= 0x03;
-            byte_vector.write_u8(type_byte);
-            byte_vector.write_u16::<BigEndian>(self.data_length.unwrap());
-            byte_vector.write_u32::<BigEndian>(self.stream_id as u32);
-        }
+        type_byte |= match self.stream_id_len() {
+            1 => 0x00,
+            2 => 0x01,
+            _ => 0x03,
+        };
 
+        buf.put_u8(type_byte);
 
-        if self.offset != 0 {
-            if self.offset <= u16::max_value() as u64 {
-                byte_vector.write_u16::<BigEndian>(self.offset as u16);
-            } else if self.offset <= u32::max_value() as u64 {
-                byte_vector.write_u32::<BigEndian>(self.offset as u32);
-            } else {
-                byte_vector.write_u64::<BigEndian>(self.offset);
-            }
+        if self.data_length_present {
+            buf.put_u16_be(self.stream_data.len() as u16);
         }
 
-        byte_vector.extend(&self.stream_data);
-
+        match self.stream_id_len() {
+            1 => buf.put_u8(self.stream_id as u8),
+            2 => buf.put_u16_be(self.stream_id as u16),
+            _ => buf.put_u32_be(self.stream_id),
+        }
 
+        match self.offset_len() {
+            0 => {},
+            2 => buf.put_u16_be(self.offset as u16),
+            4 => buf.put_u32_be(self.offset as u32),
+            _ => buf.put_u64_be(self.offset),
+        }
 
-        byte_vector
+        buf.extend_from_slice(&self.stream_data);
     }
 
-    pub fn frame_len(buf: &[u8]) -> Result<usize> {
-        let mut reader = Cursor::new(buf);
-
-        let type_byte = reader.read_u8()?;
+    /// Size of the frame on the wire, not counting the stream data.
+    pub fn header_len(&self) -> usize {
+        let data_length_len = if self.data_length_present { 2 } else { 0 };
 
-        let mut len: usize = 1;
+        1 + data_length_len + self.stream_id_len() + self.offset_len()
+    }
 
-        let data_length_present = type_byte & 0x10 > 0;
+    pub fn frame_len(&self) -> usize {
+        self.header_len() + self.stream_data.len()
+    }
 
-        let oo = (type_byte >> 2) & 0x03;
-        let ss = type_byte & 0x03;
+    /// Leaves the first `at` bytes of data in this frame and returns a frame
+    /// carrying the rest, which keeps the FIN bit.
+    pub fn split_off(&mut self, at: usize) -> StreamFrame {
+        let rest = self.stream_data.split_off(at);
+
+        let rest_frame = StreamFrame {
+            fin: self.fin,
+            data_length_present: self.data_length_present,
+            data_length: self.data_length.map(|_| rest.len() as u16),
+            stream_id: self.stream_id,
+            offset: self.offset + at as u64,
+            stream_data: rest,
+        };
 
-        len += (ss + 1) as usize;
+        self.fin = false;
+        self.data_length = self.data_length.map(|_| at as u16);
 
-        match oo {
-            0 => len += 0,
-            1 => len += 2,
-            2 => len += 4,
-            3 => len += 8,
-            _ => return Err(QuicError::ParseError)
-        };
+        rest_frame
+    }
 
-        if data_length_present {
-            let data_length = reader.read_u16::<BigEndian>()?;
-            len += 2;
+    /// Drops the data length field, which the last frame in a packet may do.
+    pub fn omit_data_length(&mut self) {
+        self.data_length_present = false;
+        self.data_length = None;
+    }
 
-            len += data_length as usize;
+    fn stream_id_len(&self) -> usize {
+        if self.stream_id <= (u8::MAX as u32) {
+            1
+        } else if self.stream_id <= (u16::MAX as u32) {
+            2
         } else {
-            len = buf.len();
+            4
         }
+    }
 
-        Ok(len)
+    fn offset_len(&self) -> usize {
+        if self.offset == 0 {
+            0
+        } else if self.offset <= u16::MAX as u64 {
+            2
+        } else if self.offset <= u32::MAX as u64 {
+            4
+        } else {
+            8
+        }
     }
 }
 
@@ -175,10 +211,10 @@ mod tests {
             data_length: Some(50),
             stream_id: 259,
             offset: 340,
-            stream_data: vec![10u8; 50],
+            stream_data: Bytes::from(vec![10u8; 50]),
         };
 
-        let bytes = frame.as_bytes();
+        let bytes = Bytes::from(frame.as_bytes());
         let parsed_frame = StreamFrame::from_bytes(&bytes).unwrap();
 
         assert_eq!(parsed_frame, frame);
@@ -192,12 +228,67 @@ mod tests {
             data_length: Some(1100),
             stream_id: 12590,
             offset: 780123,
-            stream_data: vec![10u8; 1100],
+            stream_data: Bytes::from(vec![10u8; 1100]),
         };
 
-        let bytes = frame.as_bytes();
+        let bytes = Bytes::from(frame.as_bytes());
         let parsed_frame = StreamFrame::from_bytes(&bytes).unwrap();
 
         assert_eq!(parsed_frame, frame);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parse_without_copying() {
+        let frame = StreamFrame {
+            fin: true,
+            data_length_present: true,
+            data_length: Some(100),
+            stream_id: 3,
+            offset: 0,
+            stream_data: Bytes::from(vec![7u8; 100]),
+        };
+
+        let mut buf = BytesMut::with_capacity(128);
+        frame.write_to(&mut buf);
+        let bytes = buf.freeze();
+
+        let parsed_frame = StreamFrame::from_bytes(&bytes).unwrap();
+
+        assert_eq!(parsed_frame, frame);
+        assert_eq!(parsed_frame.stream_data.as_ptr(), bytes[bytes.len() - 100..].as_ptr());
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let frame = StreamFrame {
+            fin: false,
+            data_length_present: true,
+            data_length: Some(10),
+            stream_id: 3,
+            offset: 0,
+            stream_data: Bytes::from(vec![1u8; 10]),
+        };
+
+        let mut bytes = frame.as_bytes();
+        bytes.truncate(8);
+
+        assert!(StreamFrame::from_bytes(&Bytes::from(bytes)).is_err());
+    }
+
+    #[test]
+    fn rejects_empty_frame_without_fin() {
+        let frame = StreamFrame {
+            fin: false,
+            data_length_present: true,
+            data_length: Some(0),
+            stream_id: 3,
+            offset: 0,
+            stream_data: Bytes::new(),
+        };
+
+        match StreamFrame::from_bytes(&Bytes::from(frame.as_bytes())) {
+            Err(QuicError::TransportError(error)) => assert_eq!(error, QUIC_EMPTY_STREAM_FRAME_NO_FIN),
+            other => panic!("expected QUIC_EMPTY_STREAM_FRAME_NO_FIN, got {:?}", other),
+        }
+    }
+}
@@ -3,7 +3,7 @@ use byteorder::{WriteBytesExt, ReadBytesExt};
 use error::Result;
 
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct StreamIdNeededFrame {}
 
 impl StreamIdNeededFrame {
@@ -18,16 +18,15 @@ impl StreamIdNeededFrame {
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<StreamIdNeededFrame> {
+        StreamIdNeededFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(StreamIdNeededFrame, usize)> {
         let mut reader = Cursor::new(buf);
 
         let _ = reader.read_u8()?;
 
-
-        Ok(StreamIdNeededFrame {})
-    }
-
-    pub fn frame_len() -> Result<usize> {
-        Ok(17)
+        Ok((StreamIdNeededFrame {}, reader.position() as usize))
     }
 }
 
@@ -0,0 +1,71 @@
+use std::io::Cursor;
+
+use error::QuicError;
+use error::Result;
+use error::STREAM_LIMIT_ERROR;
+use util::{ReadVarint, WriteVarint};
+
+use super::max_streams_frame::MAX_STREAM_COUNT;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StreamsBlockedFrame {
+    pub bidirectional: bool,
+    pub stream_limit: u64,
+}
+
+impl StreamsBlockedFrame {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(9);
+
+        let frame_type = if self.bidirectional {
+            super::STREAMS_BLOCKED_BIDI
+        } else {
+            super::STREAMS_BLOCKED_UNI
+        };
+
+        bytes.write_varint(frame_type.bits());
+
+        bytes.write_varint(self.stream_limit);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<StreamsBlockedFrame> {
+        StreamsBlockedFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(StreamsBlockedFrame, usize)> {
+        let mut reader = Cursor::new(buf);
+
+        let frame_type = reader.read_varint()?;
+
+        let stream_limit = reader.read_varint()?;
+
+        if stream_limit > MAX_STREAM_COUNT {
+            return Err(QuicError::TransportError(STREAM_LIMIT_ERROR));
+        }
+
+        Ok((StreamsBlockedFrame {
+            bidirectional: frame_type == super::STREAMS_BLOCKED_BIDI.bits(),
+            stream_limit: stream_limit,
+        }, reader.position() as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let frame = StreamsBlockedFrame {
+            bidirectional: false,
+            stream_limit: 3,
+        };
+
+        let frame_bytes = frame.as_bytes();
+        let parsed_frame = StreamsBlockedFrame::from_bytes(&frame_bytes).unwrap();
+
+        assert_eq!(frame, parsed_frame);
+    }
+}
@@ -0,0 +1,259 @@
+use std::io::Cursor;
+
+use error::QuicError;
+use error::Result;
+use error::FRAME_ENCODING_ERROR;
+use util::{ReadVarint, WriteVarint};
+use util::varint_len;
+
+/// The ack_delay_exponent of a peer that doesn't send one.
+pub const DEFAULT_ACK_DELAY_EXPONENT: u8 = 3;
+
+/// Exponents above this are invalid (RFC 9000, section 18.2).
+const MAX_ACK_DELAY_EXPONENT: u8 = 20;
+
+/// An ACK frame in the RFC 9000 encoding. Carrying ECN counts makes it an
+/// ACK_ECN frame.
+#[derive(Debug, PartialEq, Clone)]
+pub struct V1AckFrame {
+    pub largest_acknowledged: u64,
+    /// Encoded as microseconds scaled down by the peer's ack_delay_exponent.
+    pub ack_delay: u64,
+    pub first_ack_range: u64,
+    pub ack_ranges: Vec<AckRange>,
+    pub ecn_counts: Option<EcnCounts>,
+}
+
+/// A range of acknowledged packets below the previous one. `gap` is the
+/// number of unacknowledged packets between them, less one, and `length`
+/// the number of packets in this range, less one.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct AckRange {
+    pub gap: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct EcnCounts {
+    pub ect0_count: u64,
+    pub ect1_count: u64,
+    pub ecn_ce_count: u64,
+}
+
+impl V1AckFrame {
+    fn frame_type(&self) -> u64 {
+        match self.ecn_counts {
+            Some(_) => super::ACK_ECN.bits(),
+            None => super::V1_ACK.bits(),
+        }
+    }
+
+    pub fn frame_len(&self) -> usize {
+        let ranges: usize = self.ack_ranges.iter()
+            .map(|range| varint_len(range.gap) + varint_len(range.length))
+            .sum();
+
+        let ecn_counts = match self.ecn_counts {
+            Some(ref counts) => varint_len(counts.ect0_count) + varint_len(counts.ect1_count) + varint_len(counts.ecn_ce_count),
+            None => 0,
+        };
+
+        varint_len(self.frame_type()) + varint_len(self.largest_acknowledged) + varint_len(self.ack_delay)
+            + varint_len(self.ack_ranges.len() as u64) + varint_len(self.first_ack_range) + ranges + ecn_counts
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.write_varint(self.frame_type());
+
+        bytes.write_varint(self.largest_acknowledged);
+
+        bytes.write_varint(self.ack_delay);
+
+        bytes.write_varint(self.ack_ranges.len() as u64);
+
+        bytes.write_varint(self.first_ack_range);
+
+        for range in &self.ack_ranges {
+            bytes.write_varint(range.gap);
+            bytes.write_varint(range.length);
+        }
+
+        if let Some(ref counts) = self.ecn_counts {
+            bytes.write_varint(counts.ect0_count);
+
+            bytes.write_varint(counts.ect1_count);
+
+            bytes.write_varint(counts.ecn_ce_count);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<V1AckFrame> {
+        V1AckFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(V1AckFrame, usize)> {
+        let mut reader = Cursor::new(buf);
+
+        let frame_type = reader.read_varint()?;
+
+        let largest_acknowledged = reader.read_varint()?;
+
+        let ack_delay = reader.read_varint()?;
+
+        let range_count = reader.read_varint()?;
+
+        let first_ack_range = reader.read_varint()?;
+
+        // Each range takes at least two bytes, which bounds the count by
+        // what's left of the buffer before anything is allocated.
+        if range_count > (buf.len() as u64 - reader.position()) / 2 {
+            return Err(QuicError::TransportError(FRAME_ENCODING_ERROR));
+        }
+
+        let mut ack_ranges = Vec::with_capacity(range_count as usize);
+
+        for _ in 0..range_count {
+            let gap = reader.read_varint()?;
+            let length = reader.read_varint()?;
+
+            ack_ranges.push(AckRange {
+                gap: gap,
+                length: length,
+            });
+        }
+
+        let ecn_counts = if frame_type == super::ACK_ECN.bits() {
+            Some(EcnCounts {
+                ect0_count: reader.read_varint()?,
+                ect1_count: reader.read_varint()?,
+                ecn_ce_count: reader.read_varint()?,
+            })
+        } else {
+            None
+        };
+
+        let frame = V1AckFrame {
+            largest_acknowledged: largest_acknowledged,
+            ack_delay: ack_delay,
+            first_ack_range: first_ack_range,
+            ack_ranges: ack_ranges,
+            ecn_counts: ecn_counts,
+        };
+
+        if frame.smallest_acknowledged().is_none() {
+            return Err(QuicError::TransportError(FRAME_ENCODING_ERROR));
+        }
+
+        Ok((frame, reader.position() as usize))
+    }
+
+    /// The acknowledgement delay in microseconds, given the exponent the
+    /// sender scaled it down by.
+    pub fn ack_delay_micros(&self, ack_delay_exponent: u8) -> u64 {
+        let scale = 1u64 << ::std::cmp::min(ack_delay_exponent, MAX_ACK_DELAY_EXPONENT);
+
+        self.ack_delay.saturating_mul(scale)
+    }
+
+    /// The acknowledged packet numbers as inclusive (smallest, largest)
+    /// pairs, highest first. `None` if a range would go below packet 0.
+    pub fn ranges(&self) -> Option<Vec<(u64, u64)>> {
+        let mut largest = self.largest_acknowledged;
+        let mut smallest = largest.checked_sub(self.first_ack_range)?;
+        let mut ranges = vec![(smallest, largest)];
+
+        for range in &self.ack_ranges {
+            largest = smallest.checked_sub(range.gap)?.checked_sub(2)?;
+            smallest = largest.checked_sub(range.length)?;
+            ranges.push((smallest, largest));
+        }
+
+        Some(ranges)
+    }
+
+    fn smallest_acknowledged(&self) -> Option<u64> {
+        self.ranges().and_then(|ranges| ranges.last().map(|&(smallest, _)| smallest))
+    }
+
+    pub fn acknowledges(&self, packet_number: u64) -> bool {
+        match self.ranges() {
+            Some(ranges) => ranges.iter().any(|&(smallest, largest)| {
+                packet_number >= smallest && packet_number <= largest
+            }),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> V1AckFrame {
+        V1AckFrame {
+            largest_acknowledged: 100,
+            ack_delay: 25,
+            first_ack_range: 4,
+            ack_ranges: vec![
+                AckRange { gap: 3, length: 10 },
+                AckRange { gap: 0, length: 0 },
+            ],
+            ecn_counts: Some(EcnCounts {
+                ect0_count: 80,
+                ect1_count: 0,
+                ecn_ce_count: 2,
+            }),
+        }
+    }
+
+    #[test]
+    fn serialize() {
+        let mut frame = frame();
+
+        let frame_bytes = frame.as_bytes();
+        assert_eq!(frame_bytes[0] as u64, super::super::ACK_ECN.bits());
+        assert_eq!(V1AckFrame::from_bytes(&frame_bytes).unwrap(), frame);
+
+        frame.ecn_counts = None;
+
+        let frame_bytes = frame.as_bytes();
+        assert_eq!(frame_bytes[0] as u64, super::super::V1_ACK.bits());
+        assert_eq!(V1AckFrame::from_bytes(&frame_bytes).unwrap(), frame);
+    }
+
+    #[test]
+    fn acknowledged_ranges() {
+        let frame = frame();
+
+        assert_eq!(frame.ranges(), Some(vec![(96, 100), (81, 91), (79, 79)]));
+        assert!(frame.acknowledges(96));
+        assert!(!frame.acknowledges(92));
+        assert!(frame.acknowledges(81));
+        assert!(!frame.acknowledges(80));
+        assert!(frame.acknowledges(79));
+    }
+
+    #[test]
+    fn ack_delay_is_scaled_by_the_exponent() {
+        let frame = frame();
+
+        assert_eq!(frame.ack_delay_micros(DEFAULT_ACK_DELAY_EXPONENT), 200);
+        assert_eq!(frame.ack_delay_micros(0), 25);
+        assert_eq!(V1AckFrame { ack_delay: u64::MAX, ..frame }.ack_delay_micros(1), u64::MAX);
+    }
+
+    #[test]
+    fn rejects_ranges_below_packet_zero() {
+        let mut frame = frame();
+        frame.ack_ranges.push(AckRange { gap: 100, length: 0 });
+
+        match V1AckFrame::from_bytes(&frame.as_bytes()) {
+            Err(QuicError::TransportError(code)) => assert_eq!(code, FRAME_ENCODING_ERROR),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::io::Cursor;
+use bytes::Bytes;
+
+use error::QuicError;
+use error::Result;
+use util::{ReadVarint, WriteVarint};
+use util::varint_len;
+
+/// A CONNECTION_CLOSE frame in the RFC 9000 encoding. Transport closes
+/// name the frame type that caused them; application closes (type 0x1d)
+/// leave it out.
+#[derive(Debug, PartialEq, Clone)]
+pub struct V1ConnectionCloseFrame {
+    pub error_code: u64,
+    pub frame_type: Option<u64>,
+    pub reason_phrase: String,
+}
+
+impl V1ConnectionCloseFrame {
+    fn type_byte(&self) -> u64 {
+        match self.frame_type {
+            Some(_) => super::V1_CONNECTION_CLOSE.bits(),
+            None => super::APPLICATION_CLOSE.bits(),
+        }
+    }
+
+    pub fn frame_len(&self) -> usize {
+        let reason_len = self.reason_phrase.len();
+
+        1 + varint_len(self.error_code) + self.frame_type.map_or(0, varint_len) + varint_len(reason_len as u64) + reason_len
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.frame_len());
+
+        bytes.write_varint(self.type_byte());
+
+        bytes.write_varint(self.error_code);
+
+        if let Some(frame_type) = self.frame_type {
+            bytes.write_varint(frame_type);
+        }
+
+        bytes.write_varint(self.reason_phrase.len() as u64);
+
+        bytes.extend_from_slice(self.reason_phrase.as_bytes());
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &Bytes) -> Result<V1ConnectionCloseFrame> {
+        V1ConnectionCloseFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &Bytes) -> Result<(V1ConnectionCloseFrame, usize)> {
+        let mut reader = Cursor::new(&buf[..]);
+
+        let type_byte = reader.read_varint()?;
+
+        let error_code = reader.read_varint()?;
+
+        let frame_type = if type_byte == super::V1_CONNECTION_CLOSE.bits() {
+            Some(reader.read_varint()?)
+        } else {
+            None
+        };
+
+        let reason_len = reader.read_varint()?;
+        let reason_start = reader.position() as usize;
+
+        if reason_len > (buf.len() - reason_start) as u64 {
+            return Err(QuicError::ParseError);
+        }
+
+        let reason_end = reason_start + reason_len as usize;
+
+        // The reason is only there for people to read, so a peer sending
+        // bad UTF-8 in it isn't worth closing over.
+        let reason_phrase = String::from_utf8_lossy(&buf[reason_start..reason_end]).into_owned();
+
+        Ok((V1ConnectionCloseFrame {
+            error_code: error_code,
+            frame_type: frame_type,
+            reason_phrase: reason_phrase,
+        }, reason_end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        for &frame_type in &[Some(0x08), None] {
+            let frame = V1ConnectionCloseFrame {
+                error_code: 0x07,
+                frame_type: frame_type,
+                reason_phrase: "bad frame".to_string(),
+            };
+
+            let frame_bytes = Bytes::from(frame.as_bytes());
+            assert_eq!(frame_bytes.len(), frame.frame_len());
+
+            let parsed_frame = V1ConnectionCloseFrame::from_bytes(&frame_bytes).unwrap();
+
+            assert_eq!(frame, parsed_frame);
+            assert!(V1ConnectionCloseFrame::from_bytes(&frame_bytes.slice_to(frame_bytes.len() - 1)).is_err());
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::io::Cursor;
+
+use error::Result;
+use util::{ReadVarint, WriteVarint};
+use util::varint_len;
+
+/// A MAX_STREAM_DATA frame in the RFC 9000 encoding.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct V1MaxStreamDataFrame {
+    pub stream_id: u64,
+    pub maximum_stream_data: u64,
+}
+
+impl V1MaxStreamDataFrame {
+    pub fn frame_len(&self) -> usize {
+        1 + varint_len(self.stream_id) + varint_len(self.maximum_stream_data)
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.frame_len());
+
+        bytes.write_varint(super::V1_MAX_STREAM_DATA.bits());
+
+        bytes.write_varint(self.stream_id);
+
+        bytes.write_varint(self.maximum_stream_data);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<V1MaxStreamDataFrame> {
+        V1MaxStreamDataFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(V1MaxStreamDataFrame, usize)> {
+        let mut reader = Cursor::new(buf);
+
+        let _ = reader.read_varint()?;
+
+        let stream_id = reader.read_varint()?;
+
+        let maximum_stream_data = reader.read_varint()?;
+
+        Ok((V1MaxStreamDataFrame {
+            stream_id: stream_id,
+            maximum_stream_data: maximum_stream_data,
+        }, reader.position() as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let frame = V1MaxStreamDataFrame {
+            stream_id: 2,
+            maximum_stream_data: 1 << 20,
+        };
+
+        let frame_bytes = frame.as_bytes();
+        let parsed_frame = V1MaxStreamDataFrame::from_bytes(&frame_bytes).unwrap();
+
+        assert_eq!(frame, parsed_frame);
+    }
+}
@@ -0,0 +1,111 @@
+use std::io::{Cursor, Read};
+use byteorder::ReadBytesExt;
+use bytes::Bytes;
+
+use error::QuicError;
+use error::Result;
+use util::{ReadVarint, WriteVarint};
+use util::varint_len;
+
+/// A NEW_CONNECTION_ID frame in the RFC 9000 encoding, where connection
+/// IDs are 1 to 20 bytes long.
+#[derive(Debug, PartialEq, Clone)]
+pub struct V1NewConnectionIdFrame {
+    pub sequence_number: u64,
+    pub retire_prior_to: u64,
+    pub connection_id: Bytes,
+    pub stateless_reset_token: [u8; 16],
+}
+
+impl V1NewConnectionIdFrame {
+    pub fn frame_len(&self) -> usize {
+        1 + varint_len(self.sequence_number) + varint_len(self.retire_prior_to) + 1 + self.connection_id.len() + 16
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.frame_len());
+
+        bytes.write_varint(super::V1_NEW_CONNECTION_ID.bits());
+
+        bytes.write_varint(self.sequence_number);
+
+        bytes.write_varint(self.retire_prior_to);
+
+        bytes.push(self.connection_id.len() as u8);
+
+        bytes.extend_from_slice(&self.connection_id);
+
+        bytes.extend_from_slice(&self.stateless_reset_token);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &Bytes) -> Result<V1NewConnectionIdFrame> {
+        V1NewConnectionIdFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &Bytes) -> Result<(V1NewConnectionIdFrame, usize)> {
+        let mut reader = Cursor::new(&buf[..]);
+
+        let _ = reader.read_varint()?;
+
+        let sequence_number = reader.read_varint()?;
+
+        let retire_prior_to = reader.read_varint()?;
+
+        if retire_prior_to > sequence_number {
+            return Err(QuicError::ParseError);
+        }
+
+        let id_len = reader.read_u8()? as usize;
+        let id_start = reader.position() as usize;
+
+        if !(1..=20).contains(&id_len) || id_len > buf.len() - id_start {
+            return Err(QuicError::ParseError);
+        }
+
+        reader.set_position((id_start + id_len) as u64);
+
+        let mut stateless_reset_token = [0; 16];
+        reader.read_exact(&mut stateless_reset_token)?;
+
+        Ok((V1NewConnectionIdFrame {
+            sequence_number: sequence_number,
+            retire_prior_to: retire_prior_to,
+            connection_id: buf.slice(id_start, id_start + id_len),
+            stateless_reset_token: stateless_reset_token,
+        }, reader.position() as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let frame = V1NewConnectionIdFrame {
+            sequence_number: 3,
+            retire_prior_to: 1,
+            connection_id: Bytes::from(&[0xab; 8][..]),
+            stateless_reset_token: [7; 16],
+        };
+
+        let frame_bytes = Bytes::from(frame.as_bytes());
+        assert_eq!(frame_bytes.len(), frame.frame_len());
+
+        let parsed_frame = V1NewConnectionIdFrame::from_bytes(&frame_bytes).unwrap();
+
+        assert_eq!(frame, parsed_frame);
+    }
+
+    #[test]
+    fn rejects_bad_connection_id_lengths() {
+        for &id_len in &[0, 21] {
+            let mut frame_bytes = vec![super::super::V1_NEW_CONNECTION_ID.bits() as u8, 1, 0, id_len];
+            frame_bytes.extend_from_slice(&[0; 40]);
+
+            assert!(V1NewConnectionIdFrame::from_bytes(&Bytes::from(frame_bytes)).is_err());
+        }
+    }
+}
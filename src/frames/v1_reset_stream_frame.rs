@@ -0,0 +1,74 @@
+use std::io::Cursor;
+
+use error::Result;
+use util::{ReadVarint, WriteVarint};
+use util::varint_len;
+
+/// A RESET_STREAM frame in the RFC 9000 encoding.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct V1ResetStreamFrame {
+    pub stream_id: u64,
+    pub application_error_code: u64,
+    pub final_size: u64,
+}
+
+impl V1ResetStreamFrame {
+    pub fn frame_len(&self) -> usize {
+        1 + varint_len(self.stream_id) + varint_len(self.application_error_code) + varint_len(self.final_size)
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.frame_len());
+
+        bytes.write_varint(super::V1_RESET_STREAM.bits());
+
+        bytes.write_varint(self.stream_id);
+
+        bytes.write_varint(self.application_error_code);
+
+        bytes.write_varint(self.final_size);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<V1ResetStreamFrame> {
+        V1ResetStreamFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(V1ResetStreamFrame, usize)> {
+        let mut reader = Cursor::new(buf);
+
+        let _ = reader.read_varint()?;
+
+        let stream_id = reader.read_varint()?;
+
+        let application_error_code = reader.read_varint()?;
+
+        let final_size = reader.read_varint()?;
+
+        Ok((V1ResetStreamFrame {
+            stream_id: stream_id,
+            application_error_code: application_error_code,
+            final_size: final_size,
+        }, reader.position() as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let frame = V1ResetStreamFrame {
+            stream_id: 4,
+            application_error_code: 0x101,
+            final_size: 70000,
+        };
+
+        let frame_bytes = frame.as_bytes();
+        let parsed_frame = V1ResetStreamFrame::from_bytes(&frame_bytes).unwrap();
+
+        assert_eq!(frame, parsed_frame);
+    }
+}
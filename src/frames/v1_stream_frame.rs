@@ -0,0 +1,158 @@
+use std::io::Cursor;
+use bytes::Bytes;
+
+use error::QuicError;
+use error::Result;
+use util::{ReadVarint, WriteVarint, MAX_VARINT};
+use util::varint_len;
+
+const OFF_BIT: u64 = 0x04;
+const LEN_BIT: u64 = 0x02;
+const FIN_BIT: u64 = 0x01;
+
+/// A STREAM frame in the RFC 9000 encoding. The offset is only written
+/// when it isn't zero; without a length the data runs to the end of the
+/// packet.
+#[derive(Debug, PartialEq, Clone)]
+pub struct V1StreamFrame {
+    pub stream_id: u64,
+    pub offset: u64,
+    pub length_present: bool,
+    pub fin: bool,
+    pub stream_data: Bytes,
+}
+
+impl V1StreamFrame {
+    fn frame_type(&self) -> u64 {
+        let mut frame_type = super::V1_STREAM.bits();
+
+        if self.offset != 0 {
+            frame_type |= OFF_BIT;
+        }
+
+        if self.length_present {
+            frame_type |= LEN_BIT;
+        }
+
+        if self.fin {
+            frame_type |= FIN_BIT;
+        }
+
+        frame_type
+    }
+
+    pub fn frame_len(&self) -> usize {
+        let offset_len = if self.offset != 0 { varint_len(self.offset) } else { 0 };
+        let length_len = if self.length_present { varint_len(self.stream_data.len() as u64) } else { 0 };
+
+        1 + varint_len(self.stream_id) + offset_len + length_len + self.stream_data.len()
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.frame_len());
+
+        bytes.write_varint(self.frame_type());
+
+        bytes.write_varint(self.stream_id);
+
+        if self.offset != 0 {
+            bytes.write_varint(self.offset);
+        }
+
+        if self.length_present {
+            bytes.write_varint(self.stream_data.len() as u64);
+        }
+
+        bytes.extend_from_slice(&self.stream_data);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &Bytes) -> Result<V1StreamFrame> {
+        V1StreamFrame::decode(buf).map(|(frame, _)| frame)
+    }
+
+    /// The stream data is a slice of `buf`, not a copy.
+    pub fn decode(buf: &Bytes) -> Result<(V1StreamFrame, usize)> {
+        let mut reader = Cursor::new(&buf[..]);
+
+        let frame_type = reader.read_varint()?;
+
+        let stream_id = reader.read_varint()?;
+
+        let offset = if frame_type & OFF_BIT != 0 {
+            reader.read_varint()?
+        } else {
+            0
+        };
+
+        let data_start = reader.position() as usize;
+
+        let (data_start, data_end) = if frame_type & LEN_BIT != 0 {
+            let length = reader.read_varint()?;
+            let data_start = reader.position() as usize;
+
+            if length > (buf.len() - data_start) as u64 {
+                return Err(QuicError::ParseError);
+            }
+
+            (data_start, data_start + length as usize)
+        } else {
+            (data_start, buf.len())
+        };
+
+        // The end of the data has to stay within what a varint can hold.
+        if offset + (data_end - data_start) as u64 > MAX_VARINT {
+            return Err(QuicError::ParseError);
+        }
+
+        Ok((V1StreamFrame {
+            stream_id: stream_id,
+            offset: offset,
+            length_present: frame_type & LEN_BIT != 0,
+            fin: frame_type & FIN_BIT != 0,
+            stream_data: buf.slice(data_start, data_end),
+        }, data_end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        for &(offset, length_present, fin) in &[(0, false, false), (70000, true, true), (1, true, false)] {
+            let frame = V1StreamFrame {
+                stream_id: 4,
+                offset: offset,
+                length_present: length_present,
+                fin: fin,
+                stream_data: Bytes::from(&b"hello"[..]),
+            };
+
+            let frame_bytes = Bytes::from(frame.as_bytes());
+            assert_eq!(frame_bytes.len(), frame.frame_len());
+
+            let (parsed_frame, len) = V1StreamFrame::decode(&frame_bytes).unwrap();
+
+            assert_eq!(parsed_frame, frame);
+            assert_eq!(len, frame_bytes.len());
+        }
+    }
+
+    #[test]
+    fn rejects_data_past_the_end() {
+        let mut frame_bytes = V1StreamFrame {
+            stream_id: 4,
+            offset: 0,
+            length_present: true,
+            fin: false,
+            stream_data: Bytes::from(&b"hello"[..]),
+        }.as_bytes();
+
+        frame_bytes.pop();
+
+        assert!(V1StreamFrame::from_bytes(&Bytes::from(frame_bytes)).is_err());
+    }
+}
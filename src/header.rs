@@ -1,26 +1,33 @@
 
 use std::io::Cursor;
 
-use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use byteorder::{ReadBytesExt, BigEndian};
+use bytes::{Bytes, BytesMut, BufMut};
 
 use error::Result;
 use error::QuicError;
 
+use util::{ReadVarint, WriteVarint};
+use util::varint_len;
+
 use packet::ShortPacketType;
 use packet::PacketType;
+use packet::CLIENT_CLEARTEXT;
+use packet::RETRY;
+use packet::VERSION_NEGOTIATION;
 
 use packet::ONE_BYTE;
 use packet::TWO_BYTES;
 use packet::FOUR_BYTES;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum PacketNumber {
     OneByte(u8),
     TwoBytes(u16),
     FourBytes(u32),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct ShortHeader {
     pub key_phase_bit: bool,
     pub conn_id_bit: bool,
@@ -29,15 +36,19 @@ pub struct ShortHeader {
     pub packet_type: ShortPacketType
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct LongHeader {
     pub packet_type: PacketType,
     pub connection_id: u64,
     pub packet_number: u32,
     pub version: u32,
+    /// Address validation token. Only Client Initial and Retry packets
+    /// carry one, and only in versions that have Retry; it is empty for
+    /// the rest.
+    pub token: Bytes,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum QuicHeader {
     Short(ShortHeader),
     Long(LongHeader),
@@ -45,8 +56,16 @@ pub enum QuicHeader {
 
 impl ShortHeader {
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(13);
-        let mut first_octet = 0 as u8;
+        let mut buf = BytesMut::with_capacity(13);
+        self.write_to(&mut buf);
+
+        buf.to_vec()
+    }
+
+    pub fn write_to(&self, bytes: &mut BytesMut) {
+        bytes.reserve(13);
+
+        let mut first_octet = 0u8;
 
         if self.conn_id_bit {
             first_octet |= 0x40;
@@ -58,20 +77,24 @@ impl ShortHeader {
 
         first_octet |= self.packet_type.bits() & 0x1f;
 
-        bytes.write_u8(first_octet);
+        bytes.put_u8(first_octet);
 
         if self.conn_id_bit {
-            bytes.write_u64::<BigEndian>(self.connection_id.expect("Packet ID not present but conn_id_bit set") as u64);
+            bytes.put_u64_be(self.connection_id.expect("Packet ID not present but conn_id_bit set"));
         }
 
 
         match self.packet_type {
-            ONE_BYTE => bytes.write_u8(self.packet_number as u8),
-            TWO_BYTES => bytes.write_u16::<BigEndian>(self.packet_number as u16),
-            FOUR_BYTES | _ => bytes.write_u32::<BigEndian>(self.packet_number as u32),
+            ONE_BYTE => bytes.put_u8(self.packet_number as u8),
+            TWO_BYTES => bytes.put_u16_be(self.packet_number as u16),
+            _ => bytes.put_u32_be(self.packet_number as u32),
         };
+    }
 
-        bytes
+    pub fn header_len(&self) -> usize {
+        let conn_id_len = if self.conn_id_bit { 8 } else { 0 };
+
+        1 + conn_id_len + self.packet_type.packet_number_len()
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<ShortHeader> {
@@ -116,18 +139,71 @@ impl ShortHeader {
 
 impl LongHeader {
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(17);
+        let mut buf = BytesMut::with_capacity(self.header_len());
+        self.write_to(&mut buf);
+
+        buf.to_vec()
+    }
+
+    pub fn header_len(&self) -> usize {
+        17
+    }
+
+    /// Everything but Version Negotiation and Retry is sealed, the
+    /// cleartext types with the Initial keys.
+    pub fn is_protected(&self) -> bool {
+        self.packet_type != VERSION_NEGOTIATION && self.packet_type != RETRY
+    }
+
+    /// Whether the packet type has a token field, where the version has
+    /// tokens at all.
+    pub fn carries_token(&self) -> bool {
+        self.packet_type == CLIENT_CLEARTEXT || self.packet_type == RETRY
+    }
+
+    /// Size of the length-prefixed token field.
+    pub fn token_len(&self) -> usize {
+        varint_len(self.token.len() as u64) + self.token.len()
+    }
+
+    pub fn write_to(&self, bytes: &mut BytesMut) {
+        bytes.reserve(self.header_len());
+
         let first_octet = 0x80 | self.packet_type.bits();
 
-        bytes.write_u8(first_octet);
+        bytes.put_u8(first_octet);
+
+        bytes.put_u64_be(self.connection_id);
+
+        bytes.put_u32_be(self.packet_number);
+
+        bytes.put_u32_be(self.version);
+    }
+
+    /// Appends the length-prefixed token, which follows the header in
+    /// versions that have one.
+    pub fn write_token(&self, bytes: &mut BytesMut) {
+        let mut token_len = Vec::with_capacity(8);
+        token_len.write_varint(self.token.len() as u64);
 
-        bytes.write_u64::<BigEndian>(self.connection_id);
+        bytes.extend_from_slice(&token_len);
+        bytes.extend_from_slice(&self.token);
+    }
 
-        bytes.write_u32::<BigEndian>(self.packet_number as u32);
+    /// Reads the length-prefixed token from the start of `buf`.
+    pub fn read_token(&mut self, buf: &[u8]) -> Result<()> {
+        let mut reader = Cursor::new(buf);
 
-        bytes.write_u32::<BigEndian>(self.version as u32);
+        let token_len = reader.read_varint()?;
+        let token_start = reader.position() as usize;
 
-        bytes
+        if token_len > (buf.len() - token_start) as u64 {
+            return Err(QuicError::ParseError);
+        }
+
+        self.token = Bytes::from(&buf[token_start..token_start + token_len as usize]);
+
+        Ok(())
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<LongHeader> {
@@ -155,10 +231,27 @@ impl LongHeader {
             connection_id: connection_id,
             packet_number: packet_number,
             version: version,
+            token: Bytes::new(),
         })
     }
 }
 
+impl QuicHeader {
+    pub fn header_len(&self) -> usize {
+        match *self {
+            QuicHeader::Short(ref header) => header.header_len(),
+            QuicHeader::Long(ref header) => header.header_len(),
+        }
+    }
+
+    pub fn packet_number(&self) -> u64 {
+        match *self {
+            QuicHeader::Short(ref header) => header.packet_number,
+            QuicHeader::Long(ref header) => header.packet_number as u64,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -171,6 +264,7 @@ mod tests {
             connection_id: 2522352u64,
             packet_number: 25u32,
             version: 0x1,
+            token: Bytes::new(),
         };
 
         let long_header_bytes = long_header.as_bytes();
@@ -194,4 +288,18 @@ mod tests {
 
         assert_eq!(header, header_parsed);
     }
+
+    quickcheck! {
+        fn long_headers_round_trip(header: LongHeader) -> bool {
+            let bytes = header.as_bytes();
+
+            bytes.len() == header.header_len() && LongHeader::from_bytes(&bytes).ok() == Some(header)
+        }
+
+        fn short_headers_round_trip(header: ShortHeader) -> bool {
+            let bytes = header.as_bytes();
+
+            bytes.len() == header.header_len() && ShortHeader::from_bytes(&bytes).ok() == Some(header)
+        }
+    }
 }
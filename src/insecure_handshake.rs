@@ -0,0 +1,305 @@
+//! A stand-in for the TLS handshake, which this crate does not have yet.
+//! NOT SECURE: it is only built with the `insecure-handshake` feature.
+//!
+//! The client sends a `Hello` on stream 0 in its Client Initial: a random
+//! value and its transport parameters, in the clear. The server answers on
+//! stream 0 with the same random and its own parameters. Both ends derive
+//! the 1-RTT keys from the random the same way Initial keys come from the
+//! connection ID, so anyone who sees the first packet can read the
+//! connection. It lets the endpoints run against each other, nothing more.
+
+use std::fs::OpenOptions;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::{Async, Sink, Stream};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use error::Result;
+use error::QuicError;
+use error::QUIC_HANDSHAKE_TIMEOUT;
+use error::QUIC_INTERNAL_ERROR;
+use error::QUIC_INVALID_VERSION;
+
+use capture::KeyLog;
+
+use client::QuicClient;
+
+use connection::ConnectionState;
+use connection::QuicConnection;
+use connection::Side;
+
+use crypto::initial_secrets;
+use crypto::KeySchedule;
+
+use transport_parameters::TransportParameters;
+
+use version;
+use version::QuicVersion;
+
+pub const RANDOM_LEN: usize = 32;
+
+/// Key log labels for the secrets. They are not TLS secrets, so they don't
+/// borrow the TLS labels that would have analyzers take them for some.
+pub const CLIENT_SECRET_LABEL: &str = "INSECURE_HANDSHAKE_CLIENT_SECRET";
+pub const SERVER_SECRET_LABEL: &str = "INSECURE_HANDSHAKE_SERVER_SECRET";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Hello {
+    pub random: [u8; RANDOM_LEN],
+    pub transport_parameters: TransportParameters,
+}
+
+impl Hello {
+    /// A client's hello, with a fresh random.
+    pub fn new(transport_parameters: TransportParameters) -> Result<Hello> {
+        let mut random = [0; RANDOM_LEN];
+        SystemRandom::new().fill(&mut random).map_err(|_| QuicError::TransportError(QUIC_INTERNAL_ERROR))?;
+
+        Ok(Hello {
+            random: random,
+            transport_parameters: transport_parameters,
+        })
+    }
+
+    /// The server's answer to `self`.
+    pub fn reply(&self, transport_parameters: TransportParameters) -> Hello {
+        Hello {
+            random: self.random,
+            transport_parameters: transport_parameters,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.random.to_vec();
+        bytes.extend(self.transport_parameters.as_bytes());
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Hello> {
+        let mut reader = Cursor::new(buf);
+
+        let mut random = [0; RANDOM_LEN];
+        reader.read_exact(&mut random)?;
+
+        Ok(Hello {
+            random: random,
+            transport_parameters: TransportParameters::from_bytes(&buf[RANDOM_LEN..])?,
+        })
+    }
+}
+
+/// Reads the peer's hello off stream 0, or returns None if it hasn't
+/// arrived yet. The hello always fits in the first packet, so it arrives
+/// in one piece.
+pub fn read_hello(connection: &mut QuicConnection) -> Result<Option<Hello>> {
+    let stream = match connection.stream(0) {
+        Some(stream) => stream,
+        None => return Ok(None),
+    };
+
+    match stream.poll()? {
+        Async::Ready(Some(bytes)) => Hello::from_bytes(&bytes).map(Some),
+        Async::Ready(None) | Async::NotReady => Ok(None),
+    }
+}
+
+/// Client side: sets up the 1-RTT keys for the connection's version and
+/// writes the hello to stream 0, for the connection to send in a padded
+/// Client Initial. The keys come first so the server's answer can be read.
+pub fn send_hello(connection: &mut QuicConnection, hello: &Hello) -> Result<()> {
+    set_1rtt_keys(connection, Side::Client, &hello.random)?;
+
+    if let Some(stream) = connection.stream(0) {
+        stream.start_send(Bytes::from(hello.as_bytes()))?;
+    }
+
+    Ok(())
+}
+
+/// Client side: completes the handshake once the server's answer to
+/// `hello` is in. Returns whether it has.
+pub fn finish(connection: &mut QuicConnection, hello: &Hello, now: Instant) -> Result<bool> {
+    let reply = match read_hello(connection)? {
+        Some(reply) => reply,
+        None => return Ok(false),
+    };
+
+    if reply.random != hello.random {
+        return Err(QuicError::ParseError);
+    }
+
+    connection.on_handshake_complete(now, reply.transport_parameters, false);
+
+    Ok(true)
+}
+
+/// Sets up the 1-RTT keys for whichever version the connection has
+/// settled on. They come from the version's salt, so a client redoes this
+/// after Version Negotiation.
+pub fn set_1rtt_keys(connection: &mut QuicConnection, side: Side, random: &[u8]) -> Result<()> {
+    let version = version::find(connection.version())
+        .ok_or(QuicError::TransportError(QUIC_INVALID_VERSION))?;
+    let (local_secret, remote_secret) = secrets(side, version, random)?;
+
+    connection.set_1rtt_keys(KeySchedule::new(&local_secret, &remote_secret)?);
+
+    Ok(())
+}
+
+/// Server side: once the client's hello is in, sets up the 1-RTT keys,
+/// completes the handshake and answers with the connection's own transport
+/// parameters. Returns the client's hello, or None if there is nothing to
+/// answer yet.
+pub fn answer(connection: &mut QuicConnection, now: Instant) -> Result<Option<Hello>> {
+    if connection.state != ConnectionState::Handshaking {
+        return Ok(None);
+    }
+
+    let hello = match read_hello(connection)? {
+        Some(hello) => hello,
+        None => return Ok(None),
+    };
+
+    set_1rtt_keys(connection, Side::Server, &hello.random)?;
+    connection.on_handshake_complete(now, hello.transport_parameters.clone(), false);
+
+    let reply = hello.reply(connection.config.transport_parameters.clone());
+
+    if let Some(stream) = connection.stream(0) {
+        stream.start_send(Bytes::from(reply.as_bytes()))?;
+    }
+
+    Ok(Some(hello))
+}
+
+/// The 1-RTT secrets as (local, remote), the way round `KeySchedule::new`
+/// takes them.
+pub fn secrets(side: Side, version: &dyn QuicVersion, random: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let (client_secret, server_secret) = initial_secrets(version.initial_salt(), random)?;
+
+    Ok(match side {
+        Side::Client => (client_secret, server_secret),
+        Side::Server => (server_secret, client_secret),
+    })
+}
+
+/// Logs the connection's secrets under the labels above.
+pub fn log_secrets<W: Write>(key_log: &mut KeyLog<W>, connection: &QuicConnection, random: &[u8]) -> Result<()> {
+    let version = version::find(connection.version())
+        .ok_or(QuicError::TransportError(QUIC_INVALID_VERSION))?;
+    let (client_secret, server_secret) = secrets(Side::Client, version, random)?;
+
+    key_log.log(CLIENT_SECRET_LABEL, random, &client_secret)?;
+    key_log.log(SERVER_SECRET_LABEL, random, &server_secret)?;
+
+    Ok(())
+}
+
+impl QuicClient {
+    /// Runs the handshake until the server answers or `timeout` passes.
+    /// The connection sends the Client Initial again as its probe timer
+    /// fires, and straight away after Version Negotiation or Retry.
+    pub fn connect(&mut self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let hello = Hello::new(self.config.connection.transport_parameters.clone())?;
+
+        send_hello(&mut self.connection, &hello)?;
+        self.flush()?;
+
+        loop {
+            if self.connection.is_closed() {
+                return Err(QuicError::TransportError(self.connection.close_reason.unwrap_or(QUIC_HANDSHAKE_TIMEOUT)));
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                return Err(QuicError::TransportError(QUIC_HANDSHAKE_TIMEOUT));
+            }
+
+            let version = self.connection.version();
+
+            self.poll(deadline - now)?;
+
+            if self.connection.version() != version {
+                set_1rtt_keys(&mut self.connection, Side::Client, &hello.random)?;
+            }
+
+            if finish(&mut self.connection, &hello, Instant::now())? {
+                break;
+            }
+        }
+
+        if let Some(ref path) = self.config.key_log {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            log_secrets(&mut KeyLog::new(file), &self.connection, &hello.random)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Async, Sink, Stream};
+    use simulator::{LinkConfig, Simulator};
+    use version::DRAFT_05;
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = Hello::new(TransportParameters::default()).unwrap();
+        let reply = hello.reply(TransportParameters { idle_timeout: 5, ..TransportParameters::default() });
+
+        assert_eq!(Hello::from_bytes(&hello.as_bytes()).unwrap(), hello);
+        assert_eq!(Hello::from_bytes(&reply.as_bytes()).unwrap().random, hello.random);
+        assert!(Hello::from_bytes(&hello.as_bytes()[..RANDOM_LEN]).is_err());
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_keys() {
+        let random = [7; RANDOM_LEN];
+        let (client_local, client_remote) = secrets(Side::Client, &DRAFT_05, &random).unwrap();
+        let (server_local, server_remote) = secrets(Side::Server, &DRAFT_05, &random).unwrap();
+
+        assert_eq!(client_local, server_remote);
+        assert_eq!(client_remote, server_local);
+        assert!(client_local != client_remote);
+    }
+
+    #[test]
+    fn connects_and_echoes() {
+        let mut sim = Simulator::connected(LinkConfig::default(), 1);
+
+        assert!(sim.client.stats().handshake_duration.is_some());
+
+        let id = sim.client.open_stream().unwrap();
+        sim.client.stream(id).unwrap().start_send(Bytes::from(&b"ping"[..])).unwrap();
+
+        let mut echoed = Vec::new();
+
+        let done = sim.run_while_not(Duration::from_secs(5), |sim| {
+            while let Some(id) = sim.server.readable_stream() {
+                let stream = sim.server.stream(id).unwrap();
+
+                while let Ok(Async::Ready(Some(data))) = stream.poll() {
+                    stream.start_send(data).unwrap();
+                }
+            }
+
+            while let Ok(Async::Ready(Some(data))) = sim.client.stream(id).unwrap().poll() {
+                echoed.extend_from_slice(&data);
+            }
+
+            echoed.len() >= 4
+        });
+
+        assert!(done);
+        assert_eq!(echoed, b"ping");
+    }
+}
@@ -1,5 +1,9 @@
 #![allow(dead_code)]
 #![allow(unused_must_use)]
+// bitflags 0.8 expands to `try!`.
+#![allow(deprecated)]
+// Struct literals spell out `field: field`.
+#![allow(clippy::redundant_field_names)]
 
 extern crate core;
 extern crate byteorder;
@@ -12,18 +16,44 @@ extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_service;
 extern crate bytes;
+extern crate ring;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_json;
+#[cfg(test)]
+#[macro_use]
+extern crate quickcheck;
 
 
 // Private modules
-mod frames;
-mod header;
 mod util;
 
 // Public modules
 pub mod error;
+pub mod frames;
+pub mod header;
 pub mod packet;
 pub mod client;
 pub mod stream;
+pub mod connection;
+pub mod transport_parameters;
+pub mod session;
+pub mod crypto;
+#[cfg(any(test, feature = "insecure-handshake"))]
+pub mod insecure_handshake;
+pub mod capture;
+pub mod mtu;
+pub mod recovery;
+pub mod qlog;
+pub mod server;
+pub mod version;
+
+#[cfg(test)]
+mod simulator;
+
+#[cfg(test)]
+mod arbitrary;
 
 #[cfg(test)]
 mod tests {
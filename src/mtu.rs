@@ -0,0 +1,297 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use packet::MAX_PACKET_SIZE;
+
+/// Probes of one size lost in a row before that size is given up on.
+const MAX_PROBES: u32 = 3;
+
+/// How long to wait for a probe to be acknowledged, in milliseconds.
+const PROBE_TIMEOUT_MS: u64 = 1000;
+
+/// How long after a search finishes before probing for more, in seconds.
+const RAISE_TIMEOUT_SECS: u64 = 600;
+
+/// The search stops once the candidate sizes are this close together.
+const SEARCH_GRANULARITY: usize = 32;
+
+/// Packets above `MAX_PACKET_SIZE` lost with none acknowledged in between
+/// before the path is taken to have stopped carrying them.
+const BLACK_HOLE_THRESHOLD: u32 = 3;
+
+#[derive(Debug)]
+struct Probe {
+    packet_number: u64,
+    size: usize,
+    deadline: Instant,
+}
+
+/// Packetization layer path MTU discovery (RFC 8899) for one network path.
+/// Starts from `MAX_PACKET_SIZE`, which every path must carry, and probes
+/// with padded packets up to the local limit: the limit itself first, then
+/// binary search below the smallest size that went missing.
+#[derive(Debug)]
+pub struct PathMtu {
+    max_packet_size: usize,
+    local_limit: usize,
+    /// Smallest size known not to get through, or one past the local limit.
+    search_limit: usize,
+    in_flight: Option<Probe>,
+    lost_probes: u32,
+    next_search: Option<Instant>,
+    /// The oldest packet above `MAX_PACKET_SIZE` still waiting for an
+    /// acknowledgement that the path carries packets that big.
+    unconfirmed: Option<u64>,
+    lost_packets: u32,
+}
+
+impl PathMtu {
+    /// `local_limit` is the largest packet the local interface can send.
+    /// Limits at or below `MAX_PACKET_SIZE` disable probing.
+    pub fn new(local_limit: usize) -> PathMtu {
+        PathMtu {
+            max_packet_size: MAX_PACKET_SIZE,
+            local_limit: local_limit,
+            search_limit: local_limit + 1,
+            in_flight: None,
+            lost_probes: 0,
+            next_search: None,
+            unconfirmed: None,
+            lost_packets: 0,
+        }
+    }
+
+    /// Largest packet confirmed to get through on this path.
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    fn enabled(&self) -> bool {
+        self.local_limit > MAX_PACKET_SIZE
+    }
+
+    fn probe_size(&self) -> Option<usize> {
+        if self.search_limit <= self.max_packet_size + SEARCH_GRANULARITY {
+            None
+        } else if self.search_limit > self.local_limit {
+            Some(self.local_limit)
+        } else {
+            Some((self.max_packet_size + self.search_limit) / 2)
+        }
+    }
+
+    /// Size of the probe to send now, if one is due.
+    pub fn poll_probe(&mut self, now: Instant) -> Option<usize> {
+        if !self.enabled() || self.in_flight.is_some() {
+            return None;
+        }
+
+        match self.next_search {
+            Some(time) if now >= time => {
+                self.next_search = None;
+                self.search_limit = self.local_limit + 1;
+            },
+            Some(_) => return None,
+            None => {},
+        }
+
+        let size = self.probe_size();
+
+        if size.is_none() {
+            self.next_search = Some(now + Duration::from_secs(RAISE_TIMEOUT_SECS));
+        }
+
+        size
+    }
+
+    pub fn on_probe_sent(&mut self, now: Instant, packet_number: u64, size: usize) {
+        self.in_flight = Some(Probe {
+            packet_number: packet_number,
+            size: size,
+            deadline: now + Duration::from_millis(PROBE_TIMEOUT_MS),
+        });
+    }
+
+    pub fn probe_packet_number(&self) -> Option<u64> {
+        self.in_flight.as_ref().map(|probe| probe.packet_number)
+    }
+
+    /// The probe in flight got through, so its size becomes the new maximum.
+    pub fn on_probe_acked(&mut self) {
+        if let Some(probe) = self.in_flight.take() {
+            self.max_packet_size = cmp::max(self.max_packet_size, probe.size);
+            self.lost_probes = 0;
+        }
+    }
+
+    /// Called for every packet sent other than probes.
+    pub fn on_packet_sent(&mut self, packet_number: u64, size: usize) {
+        if size > MAX_PACKET_SIZE && self.unconfirmed.is_none() {
+            self.unconfirmed = Some(packet_number);
+        }
+    }
+
+    pub fn unconfirmed_packet_number(&self) -> Option<u64> {
+        self.unconfirmed
+    }
+
+    /// A packet above the base size got through, so losses so far were
+    /// not the path dropping big packets.
+    pub fn on_packet_acked(&mut self) {
+        self.unconfirmed = None;
+        self.lost_packets = 0;
+    }
+
+    /// Black hole detection: if `BLACK_HOLE_THRESHOLD` packets above the
+    /// base size are lost before one is acknowledged, the path may have
+    /// changed under us. We drop back to `MAX_PACKET_SIZE` and search again
+    /// below the size that stopped getting through.
+    pub fn on_packet_lost(&mut self, packet_number: u64, size: usize) {
+        if size <= MAX_PACKET_SIZE || size > self.max_packet_size {
+            return;
+        }
+
+        if self.unconfirmed == Some(packet_number) {
+            self.unconfirmed = None;
+        }
+
+        self.lost_packets += 1;
+
+        if self.lost_packets < BLACK_HOLE_THRESHOLD {
+            return;
+        }
+
+        debug!(max_packet_size = self.max_packet_size; "path stopped carrying full-size packets");
+
+        self.search_limit = self.max_packet_size;
+        self.max_packet_size = MAX_PACKET_SIZE;
+        self.in_flight = None;
+        self.lost_probes = 0;
+        self.next_search = None;
+        self.unconfirmed = None;
+        self.lost_packets = 0;
+    }
+
+    pub fn next_timeout(&self) -> Option<Instant> {
+        match self.in_flight {
+            Some(ref probe) => Some(probe.deadline),
+            None => self.next_search,
+        }
+    }
+
+    /// An unacknowledged probe counts as lost once its deadline passes. The
+    /// same size is tried again until it has been lost `MAX_PROBES` times,
+    /// and after that the search continues below it.
+    pub fn on_timeout(&mut self, now: Instant) {
+        let lost = match self.in_flight {
+            Some(ref probe) => now >= probe.deadline,
+            None => false,
+        };
+
+        if !lost {
+            return;
+        }
+
+        let probe = self.in_flight.take().unwrap();
+        self.lost_probes += 1;
+
+        if self.lost_probes >= MAX_PROBES {
+            self.search_limit = probe.size;
+            self.lost_probes = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends probes against a path carrying packets up to `path_mtu` bytes
+    /// until the search settles.
+    fn search(path_mtu: usize, local_limit: usize) -> PathMtu {
+        let mut mtu = PathMtu::new(local_limit);
+        let mut now = Instant::now();
+        let mut packet_number = 0;
+
+        while let Some(size) = mtu.poll_probe(now) {
+            mtu.on_probe_sent(now, packet_number, size);
+            packet_number += 1;
+
+            if size <= path_mtu {
+                mtu.on_probe_acked();
+            } else {
+                now = mtu.next_timeout().unwrap();
+                mtu.on_timeout(now);
+            }
+        }
+
+        mtu
+    }
+
+    #[test]
+    fn disabled_at_minimum_size() {
+        let mut mtu = PathMtu::new(MAX_PACKET_SIZE);
+
+        assert_eq!(mtu.poll_probe(Instant::now()), None);
+        assert_eq!(mtu.next_timeout(), None);
+    }
+
+    #[test]
+    fn confirms_local_limit_first() {
+        let mtu = search(9000, 8952);
+
+        assert_eq!(mtu.max_packet_size(), 8952);
+    }
+
+    #[test]
+    fn falls_back_below_lost_probes() {
+        let mtu = search(1500, 8952);
+
+        assert!(mtu.max_packet_size() <= 1500);
+        assert!(mtu.max_packet_size() > 1500 - SEARCH_GRANULARITY);
+    }
+
+    #[test]
+    fn retries_lost_probe_before_giving_up() {
+        let mut mtu = PathMtu::new(8952);
+        let now = Instant::now();
+
+        for packet_number in 0..MAX_PROBES as u64 {
+            assert_eq!(mtu.poll_probe(now), Some(8952));
+            mtu.on_probe_sent(now, packet_number, 8952);
+            mtu.on_timeout(now + Duration::from_millis(PROBE_TIMEOUT_MS));
+        }
+
+        assert_eq!(mtu.poll_probe(now), Some((MAX_PACKET_SIZE + 8952) / 2));
+        assert_eq!(mtu.max_packet_size(), MAX_PACKET_SIZE);
+    }
+
+    #[test]
+    fn falls_back_when_full_size_packets_vanish() {
+        let mut mtu = search(9000, 8952);
+        let now = Instant::now();
+
+        // One acknowledgement in between starts the count over.
+        for packet_number in 100..103 {
+            mtu.on_packet_sent(packet_number, 8952);
+        }
+
+        mtu.on_packet_lost(100, 8952);
+        mtu.on_packet_lost(101, 8952);
+        assert_eq!(mtu.unconfirmed_packet_number(), None);
+        mtu.on_packet_sent(103, 8952);
+        assert_eq!(mtu.unconfirmed_packet_number(), Some(103));
+        mtu.on_packet_acked();
+        mtu.on_packet_lost(102, 8952);
+        assert_eq!(mtu.max_packet_size(), 8952);
+
+        // Losses of small packets say nothing about the path MTU.
+        mtu.on_packet_lost(104, MAX_PACKET_SIZE);
+        mtu.on_packet_lost(105, 8952);
+        mtu.on_packet_lost(106, 8952);
+        assert_eq!(mtu.max_packet_size(), MAX_PACKET_SIZE);
+
+        // The search starts over below the size that stopped getting through.
+        assert_eq!(mtu.poll_probe(now), Some((MAX_PACKET_SIZE + 8952) / 2));
+    }
+}
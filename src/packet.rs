@@ -2,19 +2,43 @@
 
 use error::QuicError;
 use error::Result;
+use error::QUIC_MISSING_PAYLOAD;
 
 
+use std::collections::VecDeque;
 use std::io::Cursor;
-use std::io::Read;
 
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
+use bytes::{Bytes, BytesMut};
 
 
+use crypto::PacketKey;
+
 use header::QuicHeader;
-use header::ShortHeader;
-use header::LongHeader;
+
+use version::QuicVersion;
+use version::DRAFT_05;
 
 use frames::QuicFrame;
+use frames::stream_frame::StreamFrame;
+use frames::padding_frame::PaddingFrame;
+
+/// The draft version this implementation speaks.
+pub const QUIC_VERSION: u32 = 0xff000005;
+
+/// Draft-05 with the token field of later drafts in Client Initial and
+/// Retry headers, so that a server can validate addresses. The number is
+/// from the reserved 0x?a?a?a?a pattern, which is never assigned to a real
+/// version, so peers that don't know it answer with Version Negotiation.
+/// Both ends have to opt in.
+pub const QUIC_VERSION_RETRY: u32 = 0x5a0a0a0a;
+
+/// Largest packet we send until path MTU discovery finds that more fits,
+/// sized for the IPv6 minimum MTU along with the IP and UDP headers.
+pub const MAX_PACKET_SIZE: usize = 1232;
+
+/// Largest payload a UDP datagram can carry.
+const MAX_UDP_PAYLOAD: usize = 65527;
 
 bitflags! {
     pub flags ShortPacketType: u8 {
@@ -24,6 +48,16 @@ bitflags! {
     }
 }
 
+impl ShortPacketType {
+    pub fn packet_number_len(&self) -> usize {
+        match *self {
+            ONE_BYTE => 1,
+            TWO_BYTES => 2,
+            _ => 4,
+        }
+    }
+}
+
 bitflags! {
     pub flags PacketType: u8 {
         const VERSION_NEGOTIATION = 0x01,
@@ -34,15 +68,16 @@ bitflags! {
         const RTT1_ENCRYPTED_PHASE0 = 0x06,
         const RTT1_ENCRYPTED_PHASE1 = 0x07,
         const PUBLIC_RESET = 0x08,
+        const RETRY = 0x09,
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct PublicResetPayload {
 
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct VersionNegotiationPayload {
     pub versions: Vec<u32>,
 }
@@ -51,7 +86,7 @@ impl VersionNegotiationPayload {
     pub fn from_bytes(buf: &[u8]) -> Result<VersionNegotiationPayload> {
         let mut reader = Cursor::new(buf);
 
-        let mut versions = Vec::with_capacity((buf.len() / 4) as usize);
+        let mut versions = Vec::with_capacity(buf.len() / 4);
 
         while let Ok(version) = reader.read_u32::<BigEndian>() {
             versions.push(version);
@@ -63,15 +98,17 @@ impl VersionNegotiationPayload {
     }
 
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(self.versions.len());
+        let mut bytes = Vec::with_capacity(4 * self.versions.len());
 
-        self.versions.iter().map(|&version| bytes.write_u32::<BigEndian>(version));
+        for &version in &self.versions {
+            bytes.write_u32::<BigEndian>(version);
+        }
 
         bytes
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum QuicPayload {
     Frames(Vec<QuicFrame>),
     PublicReset(PublicResetPayload),
@@ -80,133 +117,421 @@ pub enum QuicPayload {
 
 impl QuicPayload {
     pub fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        self.write_to(&mut buf);
+
+        buf.to_vec()
+    }
+
+    pub fn write_to(&self, buf: &mut BytesMut) {
+        self.encode(&DRAFT_05, buf)
+    }
+
+    pub fn encode(&self, version: &dyn QuicVersion, buf: &mut BytesMut) {
         match *self {
             QuicPayload::Frames(ref frames) => {
-                frames.iter().map(|frame| {
-                    frame.as_bytes()
-                })
-                    .collect::<Vec<_>>()
-                    .concat()
+                for frame in frames {
+                    version.write_frame(frame, buf);
+                }
             },
-            QuicPayload::PublicReset(_) => vec![],
-            QuicPayload::VersionNegotiation(ref version_payload) => version_payload.as_bytes(),
+            QuicPayload::PublicReset(_) => {},
+            QuicPayload::VersionNegotiation(ref version_payload) => buf.extend_from_slice(&version_payload.as_bytes()),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct QuicPacket {
     pub header: QuicHeader,
     pub payload: QuicPayload,
 }
 
 impl QuicPacket {
-    pub fn from_bytes(buf: &[u8]) -> Result<QuicPacket> {
-        let mut reader = Cursor::new(buf);
-        let first_byte = reader.read_uint::<BigEndian>(1)? as u8;
+    /// Parses a draft-05 datagram. Frame payloads such as STREAM data are
+    /// slices of `buf` rather than copies.
+    pub fn from_bytes(buf: &Bytes) -> Result<QuicPacket> {
+        QuicPacket::decode(&DRAFT_05, buf)
+    }
 
-        if first_byte & 0x80 != 0 { // Long Header
-            let mut header_bytes = [0u8; 17];
+    /// Like `from_bytes`, but reads protected payloads as cleartext frames.
+    /// Only for tests and unprotected captures.
+    pub fn from_bytes_unprotected(buf: &Bytes) -> Result<QuicPacket> {
+        QuicPacket::decode_unprotected(&DRAFT_05, buf)
+    }
 
-            reader.read_exact(&mut header_bytes);
+    /// Parses a datagram in the given version's wire format. Short-header,
+    /// 0-RTT and cleartext payloads are protected, so they are left for the
+    /// caller to decrypt.
+    pub fn decode(version: &dyn QuicVersion, buf: &Bytes) -> Result<QuicPacket> {
+        let header = version.parse_header(buf)?;
+        let payload_bytes = buf.slice_from(version.header_len(&header));
+
+        let header = match header {
+            QuicHeader::Long(header) => header,
+            header => {
+                // TODO: Decrypt frames and return the payloads.
+                return Ok(QuicPacket {
+                    header: header,
+                    payload: QuicPayload::Frames(vec![]),
+                });
+            },
+        };
 
-            let header = LongHeader::from_bytes(&header_bytes)?;
+        let payload = match header.packet_type {
+            // Despite the names, protected with the Initial keys.
+            CLIENT_CLEARTEXT | NON_FINAL_CLEARTEXT | FINAL_SERVER_CLEAR_TEXT => QuicPayload::Frames(vec![]),
+            RTT0_ENCRYPTED => QuicPayload::Frames(vec![]),
+            VERSION_NEGOTIATION =>
+                QuicPayload::VersionNegotiation(VersionNegotiationPayload::from_bytes(&payload_bytes)?),
+            // Everything a Retry has to say is in its header.
+            RETRY => QuicPayload::Frames(vec![]),
+            _ => return Err(QuicError::ParseError),
+        };
 
-            let mut payload_bytes = Vec::new();
-            let _ = reader.read_to_end(&mut payload_bytes);
+        Ok(QuicPacket {
+            header: QuicHeader::Long(header),
+            payload: payload
+        })
+    }
 
+    pub fn decode_unprotected(version: &dyn QuicVersion, buf: &Bytes) -> Result<QuicPacket> {
+        let header = version.parse_header(buf)?;
 
-            let payload = match PacketType::from_bits(first_byte) {
-                Some(CLIENT_CLEARTEXT) | Some(NON_FINAL_CLEARTEXT) | Some(FINAL_SERVER_CLEAR_TEXT) =>
-                    QuicPayload::Frames(QuicPacket::parse_decrypted_payload(payload_bytes.as_slice())?),
-                Some(VERSION_NEGOTIATION) =>
-                    QuicPayload::VersionNegotiation(VersionNegotiationPayload::from_bytes(&payload_bytes)?),
-                Some(_) | None => return Err(QuicError::ParseError),
-            };
+        match header {
+            QuicHeader::Long(ref header) if !header.is_protected() => return QuicPacket::decode(version, buf),
+            _ => {},
+        }
 
-            Ok(QuicPacket {
-                header: QuicHeader::Long(header),
-                payload: payload
-            })
-        } else { // ShortHeader
-            let packet_type = match ShortPacketType::from_bits(first_byte & 0x1f) {
-                Some(pt) => pt,
-                None => return Err(QuicError::ParseError)
-            };
+        let frames = QuicPacket::parse_payload(version, &buf.slice_from(version.header_len(&header)))?;
 
-            let mut header_len = 1;
+        Ok(QuicPacket {
+            header: header,
+            payload: QuicPayload::Frames(frames),
+        })
+    }
 
-            let conn_id_bit = first_byte & 0x40 > 0;
+    pub fn as_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = BytesMut::new();
+        self.write_to(&mut buf)?;
 
-            if conn_id_bit {
-                header_len += 8;
-            }
+        Ok(buf.to_vec())
+    }
+
+    /// Serializes the packet onto the end of `buf`. On error `buf` is left
+    /// as it was.
+    pub fn write_to(&self, buf: &mut BytesMut) -> Result<()> {
+        self.encode(&DRAFT_05, buf)
+    }
 
-            match packet_type {
-                ONE_BYTE => header_len += 1,
-                TWO_BYTES => header_len += 2,
-                FOUR_BYTES => header_len += 4,
-                _ => return Err(QuicError::ParseError)
-            };
+    pub fn encode(&self, version: &dyn QuicVersion, buf: &mut BytesMut) -> Result<()> {
+        let start = buf.len();
 
-            let mut header_bytes = vec![0u8; header_len];
+        version.write_header(&self.header, buf);
+        self.payload.encode(version, buf);
 
-            reader.read_exact(&mut header_bytes);
+        if buf.len() - start > MAX_UDP_PAYLOAD {
+            buf.truncate(start);
+            return Err(QuicError::PacketTooLarge);
+        }
 
-            let header = ShortHeader::from_bytes(header_bytes.as_slice())?;
+        Ok(())
+    }
 
-            // TODO: Decrypt frames and return the payloads.
-            Ok(QuicPacket {
-                header: QuicHeader::Short(header),
-                payload: QuicPayload::Frames(vec![])
-            })
+    /// Serializes a long-header packet onto the end of `buf` with its
+    /// payload sealed by `key`. On error `buf` is left as it was.
+    pub fn seal(&self, version: &dyn QuicVersion, key: &PacketKey, buf: &mut BytesMut) -> Result<()> {
+        let start = buf.len();
+        version.write_header(&self.header, buf);
+
+        let mut payload = buf.split_off(buf.len());
+
+        self.payload.encode(version, &mut payload);
+
+        if let Err(err) = key.seal_in_place(self.header.packet_number(), &buf[start..], &mut payload) {
+            buf.truncate(start);
+            return Err(err);
         }
+
+        buf.unsplit(payload);
+
+        Ok(())
     }
 
-    pub fn as_bytes(&self) -> Result<Vec<u8>> {
-        let header_bytes = match self.header {
-            QuicHeader::Short(ref header) => header.as_bytes(),
-            QuicHeader::Long(ref header) => header.as_bytes(),
-        };
+    /// Decrypts a long-header packet sealed with `key` in place. Frames
+    /// slice the datagram.
+    pub fn open(version: &dyn QuicVersion, key: &PacketKey, mut buf: BytesMut) -> Result<QuicPacket> {
+        let header = version.parse_header(&buf)?;
 
-        let payload_bytes = self.payload.as_bytes();
+        if let QuicHeader::Short(_) = header {
+            return Err(QuicError::ParseError);
+        }
 
-        let packet_bytes = [header_bytes, payload_bytes].concat();
+        let header_len = version.header_len(&header);
 
-        if packet_bytes.len() > 1232 {
-            return Err(QuicError::PacketTooLarge);
+        if buf.len() < header_len {
+            return Err(QuicError::ParseError);
         }
 
-        Ok(packet_bytes)
+        let len = {
+            let (header_bytes, payload) = buf.split_at_mut(header_len);
+            key.open_in_place(header.packet_number(), header_bytes, payload)?
+        };
+
+        let payload = buf.freeze().slice(header_len, header_len + len);
+
+        Ok(QuicPacket {
+            header: header,
+            payload: QuicPayload::Frames(QuicPacket::parse_payload(version, &payload)?),
+        })
     }
 
-    pub fn parse_decrypted_payload(buf: &[u8]) -> Result<Vec<QuicFrame>> {
+    pub fn parse_decrypted_payload(buf: &Bytes) -> Result<Vec<QuicFrame>> {
+        QuicPacket::parse_payload(&DRAFT_05, buf)
+    }
+
+    pub fn parse_payload(version: &dyn QuicVersion, buf: &Bytes) -> Result<Vec<QuicFrame>> {
+        if buf.is_empty() {
+            return Err(QuicError::TransportError(QUIC_MISSING_PAYLOAD));
+        }
+
         let mut frames: Vec<QuicFrame> = Vec::new();
 
         let mut position = 0;
-        loop {
-            let frame_len = QuicFrame::frame_length(&buf[position..])?;
-            let frame_end = position + frame_len;
-
-            let frame = QuicFrame::from_bytes(&buf[position..frame_end])?;
+        while position < buf.len() {
+            let (frame, frame_len) = version.decode_frame(&buf.slice_from(position))?;
 
             position += frame_len;
 
             frames.push(frame);
+        }
+
+        Ok(frames)
+    }
+}
+
+/// Assembles a packet from queued frames, keeping track of how much room
+/// is left once the header and the AEAD tag are accounted for.
+#[derive(Debug)]
+pub struct PacketBuilder {
+    version: &'static dyn QuicVersion,
+    header: QuicHeader,
+    frames: Vec<QuicFrame>,
+    max_packet_size: usize,
+    remaining: usize,
+    full: bool,
+}
+
+impl PacketBuilder {
+    /// `tag_len` is how much sealing the payload will add to it, or zero
+    /// if the packet goes out unprotected.
+    pub fn new(version: &'static dyn QuicVersion, header: QuicHeader, max_packet_size: usize, tag_len: usize) -> PacketBuilder {
+        let overhead = version.header_len(&header) + tag_len;
+
+        PacketBuilder {
+            version: version,
+            header: header,
+            frames: Vec::new(),
+            max_packet_size: max_packet_size,
+            remaining: max_packet_size.saturating_sub(overhead),
+            full: false,
+        }
+    }
+
+    /// Payload bytes that can still be added.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Size of the packet so far, header and tag included. A STREAM frame
+    /// that ends the packet is counted without its data length, as
+    /// `finish` will write it.
+    pub fn len(&self) -> usize {
+        let omitted = match self.frames.last() {
+            Some(QuicFrame::Stream(frame)) if frame.data_length_present => {
+                let mut last = frame.clone();
+                last.omit_data_length();
+                self.stream_frame_len(frame) - self.stream_frame_len(&last)
+            },
+            _ => 0,
+        };
+
+        self.max_packet_size - self.remaining - omitted
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Adds a frame, handing it back if there is no room for it. STREAM
+    /// frames are split to fill whatever space is left.
+    pub fn push(&mut self, frame: QuicFrame) -> Option<QuicFrame> {
+        let frame = match frame {
+            QuicFrame::Stream(stream_frame) => return self.push_stream(stream_frame).map(QuicFrame::Stream),
+            frame => frame,
+        };
+
+        let frame_len = self.version.frame_len(&frame);
+
+        if self.full || frame_len > self.remaining {
+            return Some(frame);
+        }
+
+        self.remaining -= frame_len;
+        self.frames.push(frame);
+
+        None
+    }
+
+    /// Adds as much of `frame` as fits and returns the rest, if any.
+    pub fn push_stream(&mut self, mut frame: StreamFrame) -> Option<StreamFrame> {
+        if self.full {
+            return Some(frame);
+        }
+
+        frame.data_length_present = true;
 
-            if position == buf.len() {
-                break;
+        let frame_len = self.stream_frame_len(&frame);
+
+        if frame_len <= self.remaining {
+            // Anything that fits in a packet is short enough for the
+            // 16-bit length field.
+            frame.data_length = Some(frame.stream_data.len() as u16);
+            self.remaining -= frame_len;
+            self.frames.push(QuicFrame::Stream(frame));
+
+            return None;
+        }
+
+        // Anything we split off ends the packet, so it can run to the end
+        // without a data length.
+        let mut header_only = StreamFrame {
+            stream_data: Bytes::new(),
+            ..frame.clone()
+        };
+        header_only.omit_data_length();
+
+        let available = self.remaining.saturating_sub(self.stream_frame_len(&header_only));
+
+        if available == 0 {
+            return Some(frame);
+        }
+
+        let rest = if frame.stream_data.len() > available {
+            Some(frame.split_off(available))
+        } else {
+            None
+        };
+
+        frame.omit_data_length();
+
+        self.remaining = 0;
+        self.full = true;
+        self.frames.push(QuicFrame::Stream(frame));
+
+        rest
+    }
+
+    fn stream_frame_len(&self, frame: &StreamFrame) -> usize {
+        self.version.frame_len(&QuicFrame::Stream(frame.clone()))
+    }
+
+    /// Fills the rest of the packet with PADDING.
+    pub fn pad(&mut self) {
+        if self.remaining > 0 {
+            self.frames.push(QuicFrame::Padding(PaddingFrame { length: self.remaining }));
+        }
+
+        self.remaining = 0;
+        self.full = true;
+    }
+
+    /// Packs as many of `frames` as fit: ACKs first, then other control
+    /// frames, then STREAM data. Whatever doesn't fit stays queued in its
+    /// original order.
+    pub fn fill(&mut self, frames: &mut VecDeque<QuicFrame>) {
+        for priority in 0..3 {
+            let mut unsent = VecDeque::with_capacity(frames.len());
+
+            while let Some(frame) = frames.pop_front() {
+                if frame_priority(&frame) != priority {
+                    unsent.push_back(frame);
+                    continue;
+                }
+
+                if let Some(frame) = self.push(frame) {
+                    unsent.push_back(frame);
+                }
             }
+
+            *frames = unsent;
         }
+    }
 
-        Ok(frames)
+    /// Returns the packet, or `None` if no frames were added.
+    pub fn finish(mut self) -> Option<QuicPacket> {
+        if self.frames.is_empty() {
+            return None;
+        }
+
+        if let Some(&mut QuicFrame::Stream(ref mut frame)) = self.frames.last_mut() {
+            frame.omit_data_length();
+        }
+
+        Some(QuicPacket {
+            header: self.header,
+            payload: QuicPayload::Frames(self.frames),
+        })
+    }
+}
+
+fn frame_priority(frame: &QuicFrame) -> u8 {
+    match *frame {
+        QuicFrame::Ack(_) => 0,
+        QuicFrame::Stream(_) => 2,
+        _ => 1,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use header::ShortHeader;
+    use header::LongHeader;
     use frames;
+    use frames::stream_frame::StreamFrame;
+    use error::TransportErrorFlag;
+    use error::QUIC_INVALID_FRAME_DATA;
+    use error::QUIC_INVALID_GOAWAY_DATA;
+
+    #[test]
+    fn serialize_0rtt_packet() {
+        let packet = QuicPacket {
+            header: QuicHeader::Long(LongHeader {
+                packet_type: RTT0_ENCRYPTED,
+                connection_id: 9001,
+                packet_number: 12,
+                version: QUIC_VERSION,
+                token: Bytes::new(),
+            }),
+            payload: QuicPayload::Frames(vec![
+                QuicFrame::Stream(StreamFrame {
+                    fin: true,
+                    data_length_present: true,
+                    data_length: Some(5),
+                    stream_id: 1,
+                    offset: 0,
+                    stream_data: Bytes::from(vec![1, 2, 3, 4, 5]),
+                }),
+            ]),
+        };
+
+        let mut buf = BytesMut::new();
+        packet.write_to(&mut buf).unwrap();
+        let buf = buf.freeze();
+
+        assert_eq!(QuicPacket::from_bytes_unprotected(&buf).unwrap(), packet);
+        assert_eq!(QuicPacket::from_bytes(&buf).unwrap().payload, QuicPayload::Frames(vec![]));
+    }
 
     #[test]
     fn parse_decrypted_payload_1() {
@@ -218,20 +543,20 @@ mod tests {
                 reason_phrase: Some(reason.to_string()),
             }),
             QuicFrame::MaxStreamData(frames::max_stream_data_frame::MaxStreamDataFrame {
-                stream_id: 20099 as u32,
-                max_stream_data: 290 as u64,
+                stream_id: 20099,
+                max_stream_data: 290,
             }),
             QuicFrame::MaxStreamData(frames::max_stream_data_frame::MaxStreamDataFrame {
-                stream_id: 20099 as u32,
-                max_stream_data: 290 as u64,
+                stream_id: 20099,
+                max_stream_data: 290,
             }),
             QuicFrame::MaxStreamData(frames::max_stream_data_frame::MaxStreamDataFrame {
-                stream_id: 20099 as u32,
-                max_stream_data: 290 as u64,
+                stream_id: 20099,
+                max_stream_data: 290,
             }),
             QuicFrame::MaxStreamData(frames::max_stream_data_frame::MaxStreamDataFrame {
-                stream_id: 20099 as u32,
-                max_stream_data: 290 as u64,
+                stream_id: 20099,
+                max_stream_data: 290,
             }),
         ];
         
@@ -242,9 +567,178 @@ mod tests {
             bytes.extend(frame_bytes);
         }
 
-        let parsed_frames = QuicPacket::parse_decrypted_payload(&bytes).unwrap();
+        let parsed_frames = QuicPacket::parse_decrypted_payload(&Bytes::from(bytes)).unwrap();
 
         assert_eq!(&frames, &parsed_frames);
     }
-}
 
+    #[test]
+    fn parse_flagged_ack_and_stream_frames() {
+        let bytes = vec![
+            0xa0, 0x00, 0x05, 0x00, 0x0a, 0x03, // ACK, 1-byte largest ack and block length
+            0x07, // PING
+            0xe0, 0x03, 0x01, 0x02, 0x03, // STREAM with FIN, no data length, stream 3
+        ];
+
+        let frames = QuicPacket::parse_decrypted_payload(&Bytes::from(bytes)).unwrap();
+
+        assert_eq!(frames.len(), 3);
+
+        match frames[0] {
+            QuicFrame::Ack(ref ack) => {
+                assert_eq!(ack.largest_ack, 5);
+                assert_eq!(ack.ack_delay, 10);
+                assert_eq!(ack.first_ack_len, 3);
+            },
+            ref other => panic!("expected ACK, got {:?}", other),
+        }
+
+        assert_eq!(frames[1], QuicFrame::Ping(frames::ping_frame::PingFrame {}));
+
+        match frames[2] {
+            QuicFrame::Stream(ref stream) => {
+                assert!(stream.fin);
+                assert_eq!(stream.stream_id, 3);
+                assert_eq!(&stream.stream_data[..], &[1, 2, 3]);
+            },
+            ref other => panic!("expected STREAM, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_rejects_truncated_frame() {
+        let mut bytes = QuicFrame::GoAway(frames::goaway_frame::GoAwayFrame {
+            largest_client_stream_id: 7,
+            largest_server_stream_id: 8,
+        }).as_bytes();
+        bytes.truncate(6);
+
+        assert!(QuicPacket::parse_decrypted_payload(&Bytes::from(bytes)).is_err());
+    }
+
+    fn short_header() -> QuicHeader {
+        QuicHeader::Short(ShortHeader {
+            key_phase_bit: false,
+            conn_id_bit: true,
+            connection_id: Some(1),
+            packet_number: 1,
+            packet_type: FOUR_BYTES,
+        })
+    }
+
+    fn stream_frame(stream_id: u32, len: usize) -> QuicFrame {
+        QuicFrame::Stream(StreamFrame {
+            fin: true,
+            data_length_present: true,
+            data_length: Some(len as u16),
+            stream_id: stream_id,
+            offset: 0,
+            stream_data: Bytes::from(vec![9u8; len]),
+        })
+    }
+
+    #[test]
+    fn builder_fills_packet_in_priority_order() {
+        let ack = QuicFrame::Ack(frames::ack_frame::AckFrame {
+            num_blocks: None,
+            num_ts: 0,
+            largest_ack: 5,
+            ack_delay: 0,
+            first_ack_len: 0,
+            ack_blocks: None,
+            delta_la: None,
+            first_ts: None,
+            timestamps: None,
+        });
+
+        let mut queue = VecDeque::new();
+        queue.push_back(stream_frame(3, 2000));
+        queue.push_back(QuicFrame::Ping(frames::ping_frame::PingFrame {}));
+        queue.push_back(ack);
+
+        let mut builder = PacketBuilder::new(&DRAFT_05, short_header(), MAX_PACKET_SIZE, 16);
+        builder.fill(&mut queue);
+        assert_eq!(builder.remaining(), 0);
+
+        let packet = builder.finish().unwrap();
+
+        let mut buf = BytesMut::new();
+        packet.write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), MAX_PACKET_SIZE - 16);
+
+        let sent = match packet.payload {
+            QuicPayload::Frames(ref frames) => frames,
+            _ => panic!("expected frames"),
+        };
+
+        match (&sent[0], &sent[1], &sent[2]) {
+            (QuicFrame::Ack(_), QuicFrame::Ping(_), QuicFrame::Stream(stream)) => {
+                assert!(!stream.fin);
+                assert!(!stream.data_length_present);
+
+                match queue.pop_front() {
+                    Some(QuicFrame::Stream(ref rest)) => {
+                        assert!(rest.fin);
+                        assert_eq!(rest.offset, stream.stream_data.len() as u64);
+                        assert_eq!(stream.stream_data.len() + rest.stream_data.len(), 2000);
+                    },
+                    other => panic!("expected the rest of the stream data, got {:?}", other),
+                }
+            },
+            _ => panic!("unexpected frame order: {:?}", sent),
+        }
+
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn builder_omits_length_of_last_stream_frame() {
+        let mut queue = VecDeque::new();
+        queue.push_back(stream_frame(1, 10));
+        queue.push_back(stream_frame(3, 10));
+
+        let mut builder = PacketBuilder::new(&DRAFT_05, short_header(), MAX_PACKET_SIZE, 0);
+        builder.fill(&mut queue);
+        let len = builder.len();
+        let packet = builder.finish().unwrap();
+
+        let mut buf = BytesMut::new();
+        packet.write_to(&mut buf).unwrap();
+
+        // Header, then 2 + 2 + 10 bytes with a length and 2 + 10 without.
+        assert_eq!(buf.len(), 13 + 14 + 12);
+        assert_eq!(len, buf.len());
+
+        let parsed = QuicPacket::from_bytes_unprotected(&buf.freeze()).unwrap();
+        assert_eq!(parsed.payload, packet.payload);
+    }
+
+    fn transport_error(result: Result<Vec<QuicFrame>>) -> TransportErrorFlag {
+        match result {
+            Err(QuicError::TransportError(error)) => error,
+            other => panic!("expected a transport error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_errors_name_the_frame() {
+        // Unknown frame type.
+        assert_eq!(transport_error(QuicPacket::parse_decrypted_payload(&Bytes::from(vec![0x0f]))),
+                   QUIC_INVALID_FRAME_DATA);
+
+        // GOAWAY cut short.
+        assert_eq!(transport_error(QuicPacket::parse_decrypted_payload(&Bytes::from(vec![0x03, 0, 0]))),
+                   QUIC_INVALID_GOAWAY_DATA);
+
+        assert_eq!(transport_error(QuicPacket::parse_decrypted_payload(&Bytes::new())),
+                   QUIC_MISSING_PAYLOAD);
+    }
+
+    quickcheck! {
+        fn packets_round_trip(packet: QuicPacket) -> bool {
+            let bytes = Bytes::from(packet.as_bytes().unwrap());
+
+            QuicPacket::from_bytes_unprotected(&bytes).ok() == Some(packet)
+        }
+    }
+}
@@ -0,0 +1,441 @@
+//! qlog event traces (draft-ietf-quic-qlog-main-schema-03, JSON-SEQ
+//! serialization), readable by qvis and the other standard visualizers.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use connection::Side;
+
+use frames::QuicFrame;
+
+use header::QuicHeader;
+
+use packet::QuicPacket;
+use packet::QuicPayload;
+use packet::PacketType;
+use packet::{VERSION_NEGOTIATION, CLIENT_CLEARTEXT, NON_FINAL_CLEARTEXT, FINAL_SERVER_CLEAR_TEXT};
+use packet::{RTT0_ENCRYPTED, RTT1_ENCRYPTED_PHASE0, RTT1_ENCRYPTED_PHASE1, PUBLIC_RESET, RETRY};
+
+use recovery::PacketNumberSpace;
+use recovery::Recovery;
+use recovery::SentPacket;
+
+use stream::QuicStream;
+use stream::StreamState;
+
+/// Where a connection's qlog records go. The first record is the trace
+/// header; every one after it is an event.
+pub trait QlogSink: fmt::Debug + Send {
+    fn write_record(&mut self, record: &Value) -> io::Result<()>;
+}
+
+/// Writes a connection's trace to a file of its own, named after the
+/// connection ID and the side the trace was taken from.
+#[derive(Debug)]
+pub struct FileSink {
+    writer: BufWriter<File>,
+}
+
+impl FileSink {
+    pub fn create(dir: &Path, connection_id: u64, side: Side) -> io::Result<FileSink> {
+        let name = format!("{:016x}-{}.sqlog", connection_id, side_name(side));
+
+        Ok(FileSink {
+            writer: BufWriter::new(File::create(dir.join(name))?),
+        })
+    }
+}
+
+impl QlogSink for FileSink {
+    fn write_record(&mut self, record: &Value) -> io::Result<()> {
+        // JSON-SEQ: each record starts with an ASCII record separator.
+        self.writer.write_all(b"\x1e")?;
+        ::serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.write_all(b"\n")
+    }
+}
+
+/// Turns what a connection does into qlog events.
+#[derive(Debug)]
+pub struct Qlog {
+    sink: Box<dyn QlogSink>,
+    start: Instant,
+    metrics: Option<Value>,
+    stream_states: HashMap<u32, StreamState>,
+}
+
+impl Qlog {
+    /// Writes the trace header. Event times are relative to `start`.
+    pub fn new(mut sink: Box<dyn QlogSink>, side: Side, connection_id: u64, start: Instant) -> Qlog {
+        let reference_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+
+        let _ = sink.write_record(&json!({
+            "qlog_version": "0.3",
+            "qlog_format": "JSON-SEQ",
+            "trace": {
+                "vantage_point": { "type": side_name(side) },
+                "common_fields": {
+                    "ODCID": format!("{:016x}", connection_id),
+                    "time_format": "relative",
+                    "reference_time": millis(reference_time),
+                },
+            },
+        }));
+
+        Qlog {
+            sink: sink,
+            start: start,
+            metrics: None,
+            stream_states: HashMap::new(),
+        }
+    }
+
+    fn event(&mut self, now: Instant, name: &str, data: Value) {
+        let time = if now > self.start { now - self.start } else { Duration::from_secs(0) };
+
+        // Tracing is for debugging; a sink that fails must not take the
+        // connection down with it.
+        let _ = self.sink.write_record(&json!({
+            "time": millis(time),
+            "name": name,
+            "data": data,
+        }));
+    }
+
+    pub fn packet_sent(&mut self, now: Instant, packet: &QuicPacket, length: usize) {
+        let data = packet_json(packet, Some(length));
+        self.event(now, "transport:packet_sent", data);
+    }
+
+    pub fn packet_received(&mut self, now: Instant, packet: &QuicPacket) {
+        let data = packet_json(packet, None);
+        self.event(now, "transport:packet_received", data);
+    }
+
+    pub fn packet_lost(&mut self, now: Instant, space: PacketNumberSpace, packet: &SentPacket) {
+        self.event(now, "recovery:packet_lost", json!({
+            "header": { "packet_type": space_packet_type(space), "packet_number": packet.packet_number },
+            "frames": packet.frames.iter().map(frame_json).collect::<Vec<_>>(),
+        }));
+    }
+
+    /// Only logs the metrics that changed since the last call.
+    pub fn metrics_updated(&mut self, now: Instant, recovery: &Recovery) {
+        let rtt = recovery.rtt();
+        let metrics = json!({
+            "min_rtt": millis(rtt.min()),
+            "smoothed_rtt": millis(rtt.smoothed()),
+            "latest_rtt": millis(rtt.latest()),
+            "rtt_variance": millis(rtt.variance()),
+            "congestion_window": recovery.congestion_window(),
+            "bytes_in_flight": recovery.bytes_in_flight(),
+        });
+
+        let mut changed = metrics.as_object().cloned().unwrap_or_default();
+
+        if let Some(Value::Object(ref previous)) = self.metrics {
+            changed.retain(|name, value| previous.get(name) != Some(value));
+        }
+
+        if changed.is_empty() {
+            return;
+        }
+
+        self.metrics = Some(metrics);
+        self.event(now, "recovery:metrics_updated", Value::Object(changed));
+    }
+
+    /// Logs every stream that appeared or changed state since the last call.
+    pub fn streams_updated(&mut self, now: Instant, streams: &HashMap<u32, QuicStream>) {
+        let mut ids: Vec<u32> = streams.keys().cloned().collect();
+        ids.sort();
+
+        for id in ids {
+            let new = streams[&id].state;
+            let old = self.stream_states.insert(id, new);
+
+            if old == Some(new) {
+                continue;
+            }
+
+            let mut data = json!({ "stream_id": id, "new": stream_state_name(new) });
+
+            if let Some(old) = old {
+                data["old"] = json!(stream_state_name(old));
+            }
+
+            self.event(now, "transport:stream_state_updated", data);
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+fn side_name(side: Side) -> &'static str {
+    match side {
+        Side::Client => "client",
+        Side::Server => "server",
+    }
+}
+
+fn stream_state_name(state: StreamState) -> &'static str {
+    match state {
+        StreamState::Idle => "idle",
+        StreamState::Open => "open",
+        StreamState::HalfClosedLocal => "half_closed_local",
+        StreamState::HalfClosedRemote => "half_closed_remote",
+        StreamState::Closed => "closed",
+    }
+}
+
+/// Lost packets are only known by their space; 0-RTT ones are logged as
+/// 1-RTT, which shares their space.
+fn space_packet_type(space: PacketNumberSpace) -> &'static str {
+    match space {
+        PacketNumberSpace::Initial => "initial",
+        PacketNumberSpace::Handshake => "handshake",
+        PacketNumberSpace::Application => "1RTT",
+    }
+}
+
+fn packet_type_name(packet_type: PacketType) -> &'static str {
+    match packet_type {
+        VERSION_NEGOTIATION => "version_negotiation",
+        CLIENT_CLEARTEXT => "initial",
+        NON_FINAL_CLEARTEXT | FINAL_SERVER_CLEAR_TEXT => "handshake",
+        RTT0_ENCRYPTED => "0RTT",
+        RTT1_ENCRYPTED_PHASE0 | RTT1_ENCRYPTED_PHASE1 => "1RTT",
+        PUBLIC_RESET => "stateless_reset",
+        RETRY => "retry",
+        _ => "unknown",
+    }
+}
+
+pub fn header_json(header: &QuicHeader) -> Value {
+    match *header {
+        QuicHeader::Long(ref header) => {
+            let mut json = json!({
+                "packet_type": packet_type_name(header.packet_type),
+                "packet_number": header.packet_number,
+                "version": format!("{:08x}", header.version),
+                "dcid": format!("{:016x}", header.connection_id),
+            });
+
+            if !header.token.is_empty() {
+                json["token"] = json!({ "length": header.token.len() });
+            }
+
+            json
+        },
+        QuicHeader::Short(ref header) => {
+            let mut json = json!({
+                "packet_type": "1RTT",
+                "packet_number": header.packet_number,
+                "key_phase": header.key_phase_bit as u8,
+            });
+
+            if let Some(connection_id) = header.connection_id {
+                json["dcid"] = json!(format!("{:016x}", connection_id));
+            }
+
+            json
+        },
+    }
+}
+
+fn packet_json(packet: &QuicPacket, length: Option<usize>) -> Value {
+    let mut json = json!({ "header": header_json(&packet.header) });
+
+    match packet.payload {
+        QuicPayload::Frames(ref frames) => {
+            json["frames"] = Value::Array(frames.iter().map(frame_json).collect());
+        },
+        QuicPayload::VersionNegotiation(ref payload) => {
+            json["supported_versions"] = Value::Array(payload.versions.iter()
+                .map(|version| json!(format!("{:08x}", version)))
+                .collect());
+        },
+        QuicPayload::PublicReset(_) => {},
+    }
+
+    if let Some(length) = length {
+        json["raw"] = json!({ "length": length });
+    }
+
+    json
+}
+
+/// A summary of the frame: its type and fields, but not the data it
+/// carries.
+pub fn frame_json(frame: &QuicFrame) -> Value {
+    match *frame {
+        QuicFrame::Stream(ref f) => json!({
+            "frame_type": "stream",
+            "stream_id": f.stream_id,
+            "offset": f.offset,
+            "length": f.stream_data.len(),
+            "fin": f.fin,
+        }),
+        QuicFrame::Ack(ref f) => json!({
+            "frame_type": "ack",
+            "ack_delay": millis(Duration::from_micros(f.ack_delay_micros().unwrap_or(0))),
+            "acked_ranges": f.ranges().iter().rev().map(|&(smallest, largest)| json!([smallest, largest])).collect::<Vec<_>>(),
+        }),
+        QuicFrame::MaxData(ref f) => json!({ "frame_type": "max_data", "maximum": f.max_data }),
+        QuicFrame::MaxStreamData(ref f) => json!({
+            "frame_type": "max_stream_data",
+            "stream_id": f.stream_id,
+            "maximum": f.max_stream_data,
+        }),
+        QuicFrame::MaxStreamId(ref f) => json!({ "frame_type": "max_stream_id", "maximum": f.max_stream_id }),
+        QuicFrame::Blocked(_) => json!({ "frame_type": "data_blocked" }),
+        QuicFrame::StreamBlocked(ref f) => json!({ "frame_type": "stream_data_blocked", "stream_id": f.stream_id }),
+        QuicFrame::StreamIdNeeded(_) => json!({ "frame_type": "stream_id_needed" }),
+        QuicFrame::Padding(ref f) => json!({ "frame_type": "padding", "length": f.length }),
+        QuicFrame::Ping(_) => json!({ "frame_type": "ping" }),
+        QuicFrame::NewConnectionId(ref f) => json!({
+            "frame_type": "new_connection_id",
+            "sequence_number": f.sequence,
+            "connection_id": format!("{:016x}", f.connection_id),
+        }),
+        QuicFrame::ConnectionClose(ref f) => json!({
+            "frame_type": "connection_close",
+            "error_space": "transport",
+            "error_code": f.error_code,
+            "reason": f.reason_phrase.clone().unwrap_or_default(),
+        }),
+        QuicFrame::GoAway(ref f) => json!({
+            "frame_type": "goaway",
+            "largest_client_stream_id": f.largest_client_stream_id,
+            "largest_server_stream_id": f.largest_server_stream_id,
+        }),
+        QuicFrame::ResetStream(ref f) => json!({
+            "frame_type": "reset_stream",
+            "stream_id": f.stream_id,
+            "error_code": f.error_code,
+            "final_size": f.final_offset,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use bytes::Bytes;
+    use frames::ack_frame::AckFrame;
+    use frames::stream_frame::StreamFrame;
+    use header::ShortHeader;
+    use packet::FOUR_BYTES;
+
+    #[derive(Debug, Clone, Default)]
+    struct MemorySink(Arc<Mutex<Vec<Value>>>);
+
+    impl QlogSink for MemorySink {
+        fn write_record(&mut self, record: &Value) -> io::Result<()> {
+            self.0.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn header_then_events() {
+        let sink = MemorySink::default();
+        let now = Instant::now();
+        let mut qlog = Qlog::new(Box::new(sink.clone()), Side::Client, 0xabc, now);
+
+        let packet = QuicPacket {
+            header: QuicHeader::Short(ShortHeader {
+                key_phase_bit: false,
+                conn_id_bit: true,
+                connection_id: Some(0xabc),
+                packet_number: 7,
+                packet_type: FOUR_BYTES,
+            }),
+            payload: QuicPayload::Frames(vec![
+                QuicFrame::Ack(AckFrame::from_ranges(&[(5, 6), (0, 3)], 1500)),
+                QuicFrame::Stream(StreamFrame {
+                    fin: true,
+                    data_length_present: true,
+                    data_length: Some(3),
+                    stream_id: 1,
+                    offset: 10,
+                    stream_data: Bytes::from(&b"abc"[..]),
+                }),
+            ]),
+        };
+
+        qlog.packet_sent(now + Duration::from_millis(5), &packet, 40);
+
+        let records = sink.0.lock().unwrap();
+        assert_eq!(records[0]["trace"]["vantage_point"]["type"], "client");
+        assert_eq!(records[0]["trace"]["common_fields"]["ODCID"], "0000000000000abc");
+
+        let event = &records[1];
+        assert_eq!(event["name"], "transport:packet_sent");
+        assert_eq!(event["time"], 5.0);
+        assert_eq!(event["data"]["header"]["packet_number"], 7);
+        assert_eq!(event["data"]["raw"]["length"], 40);
+        assert_eq!(event["data"]["frames"][0]["acked_ranges"], json!([[0, 3], [5, 6]]));
+        assert_eq!(event["data"]["frames"][0]["ack_delay"], 1.5);
+        assert_eq!(event["data"]["frames"][1], json!({
+            "frame_type": "stream", "stream_id": 1, "offset": 10, "length": 3, "fin": true,
+        }));
+    }
+
+    #[test]
+    fn metrics_only_log_changes() {
+        let sink = MemorySink::default();
+        let now = Instant::now();
+        let mut qlog = Qlog::new(Box::new(sink.clone()), Side::Server, 1, now);
+        let mut recovery = Recovery::new();
+
+        recovery.on_packet_sent(PacketNumberSpace::Application, SentPacket {
+            packet_number: 0,
+            time_sent: now,
+            size: 1200,
+            ack_eliciting: true,
+            frames: Vec::new(),
+        });
+
+        qlog.metrics_updated(now, &recovery);
+        qlog.metrics_updated(now, &recovery);
+        recovery.discard(PacketNumberSpace::Application);
+        qlog.metrics_updated(now, &recovery);
+
+        let records = sink.0.lock().unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(records[1]["data"].get("smoothed_rtt").is_some());
+        assert_eq!(records[1]["data"]["congestion_window"], recovery.congestion_window());
+        assert_eq!(records[2]["data"], json!({ "bytes_in_flight": 0 }));
+    }
+
+    #[test]
+    fn stream_state_changes() {
+        let sink = MemorySink::default();
+        let now = Instant::now();
+        let mut qlog = Qlog::new(Box::new(sink.clone()), Side::Server, 1, now);
+
+        let mut streams = HashMap::new();
+        streams.insert(1, QuicStream::new(1, 1000).unwrap());
+        qlog.streams_updated(now, &streams);
+        qlog.streams_updated(now, &streams);
+
+        streams.get_mut(&1).unwrap().state = StreamState::Closed;
+        qlog.streams_updated(now, &streams);
+
+        let records = sink.0.lock().unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1]["data"], json!({ "stream_id": 1, "new": "idle" }));
+        assert_eq!(records[2]["data"], json!({ "stream_id": 1, "old": "idle", "new": "closed" }));
+    }
+}
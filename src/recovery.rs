@@ -0,0 +1,780 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+use error::Result;
+use error::QuicError;
+use error::QUIC_INVALID_ACK_DATA;
+
+use frames::QuicFrame;
+use frames::ack_frame::AckFrame;
+use frames::ack_frame::AckTimestampValue;
+
+use header::QuicHeader;
+
+use packet::CLIENT_CLEARTEXT;
+use packet::NON_FINAL_CLEARTEXT;
+use packet::FINAL_SERVER_CLEAR_TEXT;
+
+/// Packets this far below the largest acknowledged are declared lost.
+const PACKET_THRESHOLD: u64 = 3;
+
+/// Lower bound on timer and RTT precision, in milliseconds.
+const GRANULARITY_MS: u64 = 1;
+
+/// RTT assumed before the first sample, in milliseconds.
+const INITIAL_RTT_MS: u64 = 333;
+
+/// How long we may hold back an acknowledgement, in milliseconds.
+const MAX_ACK_DELAY_MS: u64 = 25;
+
+/// How many received ranges are kept for ACK frames. Older ones are
+/// forgotten; the peer will have seen them acknowledged by then.
+const MAX_ACK_RANGES: usize = 32;
+
+/// The datagram size the congestion window is counted in.
+const MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// Congestion window at the start of a connection, and the least it is
+/// ever cut to, in datagrams.
+const INITIAL_WINDOW_PACKETS: usize = 10;
+const MINIMUM_WINDOW_PACKETS: usize = 2;
+
+/// Packet numbers start again from zero in each space, and packets in one
+/// are only ever acknowledged from the same space.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PacketNumberSpace {
+    Initial,
+    Handshake,
+    Application,
+}
+
+impl PacketNumberSpace {
+    /// The cleartext packets of both sides play the part of Initial ones,
+    /// so each side acknowledges the other's in kind. Draft-05 has nothing
+    /// that maps onto the Handshake space. Everything else carries
+    /// application data.
+    pub fn from_header(header: &QuicHeader) -> PacketNumberSpace {
+        match *header {
+            QuicHeader::Long(ref header) => match header.packet_type {
+                CLIENT_CLEARTEXT | NON_FINAL_CLEARTEXT | FINAL_SERVER_CLEAR_TEXT => PacketNumberSpace::Initial,
+                _ => PacketNumberSpace::Application,
+            },
+            QuicHeader::Short(_) => PacketNumberSpace::Application,
+        }
+    }
+
+    fn index(&self) -> usize {
+        match *self {
+            PacketNumberSpace::Initial => 0,
+            PacketNumberSpace::Handshake => 1,
+            PacketNumberSpace::Application => 2,
+        }
+    }
+}
+
+const SPACES: [PacketNumberSpace; 3] = [
+    PacketNumberSpace::Initial,
+    PacketNumberSpace::Handshake,
+    PacketNumberSpace::Application,
+];
+
+/// Whether a packet carrying `frame` has to be acknowledged.
+pub fn is_ack_eliciting(frame: &QuicFrame) -> bool {
+    !matches!(*frame, QuicFrame::Ack(_) | QuicFrame::Padding(_) | QuicFrame::ConnectionClose(_))
+}
+
+#[derive(Debug, Clone)]
+pub struct SentPacket {
+    pub packet_number: u64,
+    pub time_sent: Instant,
+    pub size: usize,
+    /// Whether the peer has to acknowledge it. Only these count towards
+    /// the probe timeout.
+    pub ack_eliciting: bool,
+    /// Frames to send again if the packet is lost.
+    pub frames: Vec<QuicFrame>,
+}
+
+/// Round-trip time estimate (RFC 9002, section 5).
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+    latest: Duration,
+    smoothed: Option<Duration>,
+    variance: Duration,
+    min: Duration,
+}
+
+impl RttEstimator {
+    fn new() -> RttEstimator {
+        let initial = Duration::from_millis(INITIAL_RTT_MS);
+
+        RttEstimator {
+            latest: initial,
+            smoothed: None,
+            variance: initial / 2,
+            min: initial,
+        }
+    }
+
+    pub fn latest(&self) -> Duration {
+        self.latest
+    }
+
+    pub fn smoothed(&self) -> Duration {
+        self.smoothed.unwrap_or(Duration::from_millis(INITIAL_RTT_MS))
+    }
+
+    pub fn variance(&self) -> Duration {
+        self.variance
+    }
+
+    pub fn min(&self) -> Duration {
+        self.min
+    }
+
+    /// Takes a new sample. The peer's reported `ack_delay` is only
+    /// subtracted where that doesn't take us below the minimum RTT.
+    fn update(&mut self, latest: Duration, ack_delay: Duration) {
+        self.latest = latest;
+
+        let smoothed = match self.smoothed {
+            Some(smoothed) => smoothed,
+            None => {
+                self.smoothed = Some(latest);
+                self.variance = latest / 2;
+                self.min = latest;
+                return;
+            },
+        };
+
+        self.min = cmp::min(self.min, latest);
+
+        let adjusted = if latest >= self.min + ack_delay {
+            latest - ack_delay
+        } else {
+            latest
+        };
+
+        let deviation = smoothed.abs_diff(adjusted);
+
+        self.variance = (self.variance * 3 + deviation) / 4;
+        self.smoothed = Some((smoothed * 7 + adjusted) / 8);
+    }
+
+    /// How long to wait before declaring a packet sent before a later,
+    /// acknowledged one lost.
+    fn loss_delay(&self) -> Duration {
+        let rtt = cmp::max(self.latest, self.smoothed());
+
+        cmp::max(rtt * 9 / 8, Duration::from_millis(GRANULARITY_MS))
+    }
+
+    fn probe_timeout(&self) -> Duration {
+        self.smoothed() + cmp::max(self.variance * 4, Duration::from_millis(GRANULARITY_MS))
+    }
+}
+
+/// Sending and receiving state for one packet number space.
+#[derive(Debug)]
+struct PacketSpace {
+    next_packet_number: u64,
+    sent: BTreeMap<u64, SentPacket>,
+    largest_acked: Option<u64>,
+    loss_time: Option<Instant>,
+    last_ack_eliciting_sent: Option<Instant>,
+    /// Received packet numbers as inclusive (smallest, largest) ranges,
+    /// highest first.
+    received: Vec<(u64, u64)>,
+    largest_received_time: Option<Instant>,
+    ack_pending: bool,
+}
+
+impl PacketSpace {
+    fn new() -> PacketSpace {
+        PacketSpace {
+            next_packet_number: 0,
+            sent: BTreeMap::new(),
+            largest_acked: None,
+            loss_time: None,
+            last_ack_eliciting_sent: None,
+            received: Vec::new(),
+            largest_received_time: None,
+            ack_pending: false,
+        }
+    }
+
+    fn ack_eliciting_in_flight(&self) -> bool {
+        self.sent.values().any(|packet| packet.ack_eliciting)
+    }
+
+    fn on_packet_received(&mut self, now: Instant, packet_number: u64) {
+        let largest = match self.received.first() {
+            Some(&(_, largest)) => packet_number > largest,
+            None => true,
+        };
+
+        if largest {
+            self.largest_received_time = Some(now);
+        }
+
+        let position = self.received.iter().position(|&(_, largest)| largest < packet_number);
+        let index = position.unwrap_or(self.received.len());
+
+        if index > 0 && self.received[index - 1].0 <= packet_number {
+            return;
+        }
+
+        self.received.insert(index, (packet_number, packet_number));
+
+        // Merge with the neighbours on either side where they now touch.
+        if index + 1 < self.received.len() && self.received[index + 1].1 + 1 == packet_number {
+            self.received[index].0 = self.received[index + 1].0;
+            self.received.remove(index + 1);
+        }
+
+        if index > 0 && self.received[index - 1].0 == packet_number + 1 {
+            self.received[index - 1].0 = self.received[index].0;
+            self.received.remove(index);
+        }
+
+        self.received.truncate(MAX_ACK_RANGES);
+    }
+
+    fn detect_lost_packets(&mut self, now: Instant, rtt: &RttEstimator) -> Vec<SentPacket> {
+        self.loss_time = None;
+
+        let largest_acked = match self.largest_acked {
+            Some(largest) => largest,
+            None => return Vec::new(),
+        };
+
+        let loss_delay = rtt.loss_delay();
+        let mut lost = Vec::new();
+
+        for (&packet_number, packet) in self.sent.range(..largest_acked) {
+            let lost_by_time = packet.time_sent + loss_delay <= now;
+            let lost_by_count = largest_acked >= packet_number + PACKET_THRESHOLD;
+
+            if lost_by_time || lost_by_count {
+                lost.push(packet_number);
+            } else {
+                let loss_time = packet.time_sent + loss_delay;
+                self.loss_time = Some(self.loss_time.map_or(loss_time, |time| cmp::min(time, loss_time)));
+            }
+        }
+
+        lost.iter().filter_map(|packet_number| self.sent.remove(packet_number)).collect()
+    }
+}
+
+/// What an expired recovery timer asks of the connection.
+#[derive(Debug)]
+pub enum Expiry {
+    /// These packets are now considered lost; their frames should be sent
+    /// again.
+    Lost(PacketNumberSpace, Vec<SentPacket>),
+    /// Nothing has been acknowledged for a while. An ack-eliciting packet
+    /// should be sent in this space to find out what happened.
+    Probe(PacketNumberSpace),
+}
+
+/// Decides how many bytes may be in flight. `Recovery` tells it about
+/// every acknowledged packet and every batch of lost ones.
+pub trait CongestionController: Debug + Send {
+    /// Bytes that may be in flight at once.
+    fn window(&self) -> usize;
+
+    fn on_packet_acked(&mut self, packet: &SentPacket);
+
+    /// Called with every batch of packets declared lost, including empty
+    /// ones.
+    fn on_packets_lost(&mut self, now: Instant, lost: &[SentPacket]);
+}
+
+/// NewReno (RFC 9002, section 7).
+#[derive(Debug)]
+pub struct NewReno {
+    window: usize,
+    /// None until the first loss: slow start lasts until then.
+    ssthresh: Option<usize>,
+    /// Losses of packets sent before this are part of the same congestion
+    /// event and don't shrink the window again.
+    recovery_start: Option<Instant>,
+}
+
+impl Default for NewReno {
+    fn default() -> NewReno {
+        NewReno::new()
+    }
+}
+
+impl NewReno {
+    pub fn new() -> NewReno {
+        NewReno {
+            window: INITIAL_WINDOW_PACKETS * MAX_DATAGRAM_SIZE,
+            ssthresh: None,
+            recovery_start: None,
+        }
+    }
+
+    fn in_recovery(&self, time_sent: Instant) -> bool {
+        match self.recovery_start {
+            Some(start) => time_sent <= start,
+            None => false,
+        }
+    }
+}
+
+impl CongestionController for NewReno {
+    fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Grows the window by the packet's size in slow start, and by about a
+    /// datagram per window's worth acknowledged after that.
+    fn on_packet_acked(&mut self, packet: &SentPacket) {
+        if !packet.ack_eliciting || self.in_recovery(packet.time_sent) {
+            return;
+        }
+
+        match self.ssthresh {
+            Some(ssthresh) if self.window >= ssthresh => {
+                self.window += MAX_DATAGRAM_SIZE * packet.size / self.window;
+            },
+            _ => self.window += packet.size,
+        }
+    }
+
+    /// Halves the window, once for all the packets lost from one window.
+    fn on_packets_lost(&mut self, now: Instant, lost: &[SentPacket]) {
+        let sent = lost.iter()
+            .filter(|packet| packet.ack_eliciting)
+            .map(|packet| packet.time_sent)
+            .max();
+
+        match sent {
+            Some(sent) if !self.in_recovery(sent) => {},
+            _ => return,
+        }
+
+        let window = cmp::max(self.window / 2, MINIMUM_WINDOW_PACKETS * MAX_DATAGRAM_SIZE);
+
+        self.recovery_start = Some(now);
+        self.ssthresh = Some(window);
+        self.window = window;
+    }
+}
+
+/// No congestion control at all: sending is limited only by flow control.
+/// For measuring a link, not for shared networks.
+#[derive(Debug, Default)]
+pub struct Unlimited;
+
+impl CongestionController for Unlimited {
+    fn window(&self) -> usize {
+        usize::MAX
+    }
+
+    fn on_packet_acked(&mut self, _packet: &SentPacket) {}
+
+    fn on_packets_lost(&mut self, _now: Instant, _lost: &[SentPacket]) {}
+}
+
+/// Acknowledgement tracking and loss recovery (RFC 9002) across the three
+/// packet number spaces. Congestion control is left to a
+/// `CongestionController`, NewReno unless told otherwise.
+#[derive(Debug)]
+pub struct Recovery {
+    spaces: [PacketSpace; 3],
+    rtt: RttEstimator,
+    pto_count: u32,
+    congestion: Box<dyn CongestionController>,
+}
+
+impl Default for Recovery {
+    fn default() -> Recovery {
+        Recovery::new()
+    }
+}
+
+impl Recovery {
+    pub fn new() -> Recovery {
+        Recovery::with_congestion_controller(Box::new(NewReno::new()))
+    }
+
+    pub fn with_congestion_controller(congestion: Box<dyn CongestionController>) -> Recovery {
+        Recovery {
+            spaces: [PacketSpace::new(), PacketSpace::new(), PacketSpace::new()],
+            rtt: RttEstimator::new(),
+            pto_count: 0,
+            congestion: congestion,
+        }
+    }
+
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// Bytes that may be in flight at once.
+    pub fn congestion_window(&self) -> usize {
+        self.congestion.window()
+    }
+
+    /// Whether the congestion window leaves room for another packet. The
+    /// packet may take bytes in flight past the window, but only by itself.
+    pub fn can_send(&self) -> bool {
+        self.bytes_in_flight() < self.congestion.window()
+    }
+
+    /// The probe timeout before any backoff, allowing for the peer's
+    /// acknowledgement delay.
+    pub fn probe_timeout(&self) -> Duration {
+        self.rtt.probe_timeout() + Duration::from_millis(MAX_ACK_DELAY_MS)
+    }
+
+    /// Bytes in ack-eliciting packets that have been neither acknowledged
+    /// nor declared lost.
+    pub fn bytes_in_flight(&self) -> usize {
+        self.spaces.iter()
+            .flat_map(|space| space.sent.values())
+            .filter(|packet| packet.ack_eliciting)
+            .map(|packet| packet.size)
+            .sum()
+    }
+
+    fn space(&self, space: PacketNumberSpace) -> &PacketSpace {
+        &self.spaces[space.index()]
+    }
+
+    fn space_mut(&mut self, space: PacketNumberSpace) -> &mut PacketSpace {
+        &mut self.spaces[space.index()]
+    }
+
+    pub fn next_packet_number(&self, space: PacketNumberSpace) -> u64 {
+        self.space(space).next_packet_number
+    }
+
+    /// Records a packet sent with the space's next packet number.
+    pub fn on_packet_sent(&mut self, space: PacketNumberSpace, packet: SentPacket) {
+        let space = self.space_mut(space);
+
+        debug_assert_eq!(packet.packet_number, space.next_packet_number);
+        space.next_packet_number = packet.packet_number + 1;
+
+        if packet.ack_eliciting {
+            space.last_ack_eliciting_sent = Some(packet.time_sent);
+        }
+
+        space.sent.insert(packet.packet_number, packet);
+    }
+
+    pub fn on_packet_received(&mut self, space: PacketNumberSpace, now: Instant, packet_number: u64, ack_eliciting: bool) {
+        let space = self.space_mut(space);
+
+        space.on_packet_received(now, packet_number);
+
+        if ack_eliciting {
+            space.ack_pending = true;
+        }
+    }
+
+    /// An ACK frame for the space, if an ack-eliciting packet has arrived
+    /// since the last one.
+    pub fn poll_ack(&mut self, space: PacketNumberSpace, now: Instant) -> Option<AckFrame> {
+        let space = self.space_mut(space);
+
+        if !space.ack_pending {
+            return None;
+        }
+
+        space.ack_pending = false;
+
+        let delay = space.largest_received_time.map_or(Duration::from_secs(0), |time| now - time);
+        let microseconds = delay.as_secs() * 1_000_000 + delay.subsec_nanos() as u64 / 1000;
+        let ack_delay = AckTimestampValue { microseconds: microseconds }.as_u16();
+
+        Some(AckFrame::from_ranges(&space.received, ack_delay))
+    }
+
+    /// Processes an ACK from the peer and returns the packets it shows to
+    /// be lost. Acknowledging a packet we never sent is an error.
+    pub fn on_ack_received(&mut self, space: PacketNumberSpace, now: Instant, frame: &AckFrame) -> Result<Vec<SentPacket>> {
+        let ranges = frame.ranges();
+        let ack_delay = Duration::from_micros(AckTimestampValue::from_u16(frame.ack_delay)?.microseconds);
+
+        let ack_delay = match space {
+            PacketNumberSpace::Application => cmp::min(ack_delay, Duration::from_millis(MAX_ACK_DELAY_MS)),
+            _ => Duration::from_secs(0),
+        };
+
+        let (rtt_sample, newly_acked) = {
+            let packet_space = self.space_mut(space);
+
+            if frame.largest_ack >= packet_space.next_packet_number {
+                return Err(QuicError::TransportError(QUIC_INVALID_ACK_DATA));
+            }
+
+            packet_space.largest_acked = Some(packet_space.largest_acked.map_or(frame.largest_ack, |largest| cmp::max(largest, frame.largest_ack)));
+
+            let mut newly_acked = Vec::new();
+
+            for &(start, end) in &ranges {
+                let acked: Vec<u64> = packet_space.sent.range(start..end + 1).map(|(&packet_number, _)| packet_number).collect();
+
+                for packet_number in acked {
+                    if let Some(packet) = packet_space.sent.remove(&packet_number) {
+                        newly_acked.push(packet);
+                    }
+                }
+            }
+
+            // Only a newly acknowledged largest packet gives an RTT sample,
+            // and only if it was one the peer had to acknowledge promptly.
+            let largest = newly_acked.iter().find(|packet| packet.packet_number == frame.largest_ack);
+
+            let rtt_sample = match largest {
+                Some(packet) if newly_acked.iter().any(|packet| packet.ack_eliciting) => Some(now - packet.time_sent),
+                _ => None,
+            };
+
+            (rtt_sample, newly_acked)
+        };
+
+        if let Some(latest) = rtt_sample {
+            self.rtt.update(latest, ack_delay);
+            self.pto_count = 0;
+        }
+
+        for packet in &newly_acked {
+            self.congestion.on_packet_acked(packet);
+        }
+
+        let rtt = self.rtt.clone();
+        let lost = self.space_mut(space).detect_lost_packets(now, &rtt);
+        self.congestion.on_packets_lost(now, &lost);
+
+        Ok(lost)
+    }
+
+    /// Forgets everything sent in a space, for when its keys are thrown
+    /// away or its packets have to be sent again regardless.
+    pub fn discard(&mut self, space: PacketNumberSpace) -> Vec<SentPacket> {
+        let space = self.space_mut(space);
+
+        space.loss_time = None;
+        space.last_ack_eliciting_sent = None;
+
+        ::std::mem::take(&mut space.sent).into_values().collect()
+    }
+
+    /// Frames of the ack-eliciting packets still in flight in a space,
+    /// oldest first. A probe sends these again rather than only a PING.
+    pub fn unacked_frames(&self, space: PacketNumberSpace) -> Vec<QuicFrame> {
+        self.space(space).sent.values()
+            .filter(|packet| packet.ack_eliciting)
+            .flat_map(|packet| packet.frames.iter().cloned())
+            .collect()
+    }
+
+    fn earliest_loss_time(&self) -> Option<(Instant, PacketNumberSpace)> {
+        SPACES.iter()
+            .filter_map(|&space| self.space(space).loss_time.map(|time| (time, space)))
+            .min_by_key(|&(time, _)| time)
+    }
+
+    fn probe_deadline(&self) -> Option<(Instant, PacketNumberSpace)> {
+        let backoff = 1 << cmp::min(self.pto_count, 16);
+
+        SPACES.iter()
+            .filter(|&&space| self.space(space).ack_eliciting_in_flight())
+            .filter_map(|&space| {
+                let mut timeout = self.rtt.probe_timeout();
+
+                if space == PacketNumberSpace::Application {
+                    timeout += Duration::from_millis(MAX_ACK_DELAY_MS);
+                }
+
+                self.space(space).last_ack_eliciting_sent.map(|time| (time + timeout * backoff, space))
+            })
+            .min_by_key(|&(time, _)| time)
+    }
+
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.earliest_loss_time()
+            .or_else(|| self.probe_deadline())
+            .map(|(time, _)| time)
+    }
+
+    pub fn on_timeout(&mut self, now: Instant) -> Option<Expiry> {
+        if let Some((time, space)) = self.earliest_loss_time() {
+            if now < time {
+                return None;
+            }
+
+            let rtt = self.rtt.clone();
+            let lost = self.space_mut(space).detect_lost_packets(now, &rtt);
+            self.congestion.on_packets_lost(now, &lost);
+
+            return Some(Expiry::Lost(space, lost));
+        }
+
+        match self.probe_deadline() {
+            Some((time, space)) if now >= time => {
+                self.pto_count += 1;
+                Some(Expiry::Probe(space))
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frames::ping_frame::PingFrame;
+
+    fn send(recovery: &mut Recovery, space: PacketNumberSpace, now: Instant) -> u64 {
+        let packet_number = recovery.next_packet_number(space);
+
+        recovery.on_packet_sent(space, SentPacket {
+            packet_number: packet_number,
+            time_sent: now,
+            size: 100,
+            ack_eliciting: true,
+            frames: vec![QuicFrame::Ping(PingFrame {})],
+        });
+
+        packet_number
+    }
+
+    fn lost_packet_numbers(lost: &[SentPacket]) -> Vec<u64> {
+        lost.iter().map(|packet| packet.packet_number).collect()
+    }
+
+    #[test]
+    fn received_packets_become_ack_ranges() {
+        let mut recovery = Recovery::new();
+        let now = Instant::now();
+
+        for &packet_number in &[0, 1, 2, 5, 4, 9, 3, 7] {
+            recovery.on_packet_received(PacketNumberSpace::Application, now, packet_number, true);
+        }
+
+        let frame = recovery.poll_ack(PacketNumberSpace::Application, now).unwrap();
+        assert_eq!(frame.ranges(), vec![(9, 9), (7, 7), (0, 5)]);
+        assert!(recovery.poll_ack(PacketNumberSpace::Application, now).is_none());
+
+        // Packets that only carry ACKs don't need acknowledging themselves.
+        recovery.on_packet_received(PacketNumberSpace::Application, now, 10, false);
+        assert!(recovery.poll_ack(PacketNumberSpace::Application, now).is_none());
+    }
+
+    #[test]
+    fn spaces_are_independent() {
+        let mut recovery = Recovery::new();
+        let now = Instant::now();
+
+        send(&mut recovery, PacketNumberSpace::Initial, now);
+        send(&mut recovery, PacketNumberSpace::Initial, now);
+
+        assert_eq!(recovery.next_packet_number(PacketNumberSpace::Handshake), 0);
+        assert_eq!(send(&mut recovery, PacketNumberSpace::Application, now), 0);
+
+        // An ACK in one space says nothing about packets in another.
+        let ack = AckFrame::from_ranges(&[(0, 1)], 0);
+        assert!(recovery.on_ack_received(PacketNumberSpace::Handshake, now, &ack).is_err());
+        assert!(recovery.on_ack_received(PacketNumberSpace::Initial, now, &ack).unwrap().is_empty());
+        assert!(recovery.space(PacketNumberSpace::Application).ack_eliciting_in_flight());
+    }
+
+    #[test]
+    fn packet_threshold_loss() {
+        let mut recovery = Recovery::new();
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            send(&mut recovery, PacketNumberSpace::Application, now);
+        }
+
+        let later = now + Duration::from_millis(50);
+        let lost = recovery.on_ack_received(PacketNumberSpace::Application, later, &AckFrame::from_ranges(&[(4, 4)], 0)).unwrap();
+
+        assert_eq!(lost_packet_numbers(&lost), vec![0, 1]);
+        assert_eq!(recovery.rtt().smoothed(), Duration::from_millis(50));
+
+        // Packets 2 and 3 are lost once a quarter RTT more has gone by.
+        let deadline = recovery.next_timeout().unwrap();
+        assert_eq!(deadline, now + Duration::from_millis(50) * 9 / 8);
+
+        match recovery.on_timeout(deadline) {
+            Some(Expiry::Lost(PacketNumberSpace::Application, lost)) => assert_eq!(lost_packet_numbers(&lost), vec![2, 3]),
+            other => panic!("unexpected expiry {:?}", other),
+        }
+    }
+
+    #[test]
+    fn loss_halves_the_congestion_window_once() {
+        let mut recovery = Recovery::new();
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            send(&mut recovery, PacketNumberSpace::Application, now);
+        }
+
+        let later = now + Duration::from_millis(50);
+        recovery.on_ack_received(PacketNumberSpace::Application, later, &AckFrame::from_ranges(&[(4, 4)], 0)).unwrap();
+
+        assert_eq!(recovery.congestion_window(), (INITIAL_WINDOW_PACKETS * MAX_DATAGRAM_SIZE + 100) / 2);
+
+        // Packets 2 and 3 went out before the loss was seen, so losing them
+        // too is the same congestion event.
+        let deadline = recovery.next_timeout().unwrap();
+        recovery.on_timeout(deadline);
+
+        let window = recovery.congestion_window();
+        assert_eq!(window, (INITIAL_WINDOW_PACKETS * MAX_DATAGRAM_SIZE + 100) / 2);
+
+        send(&mut recovery, PacketNumberSpace::Application, deadline);
+        recovery.on_ack_received(PacketNumberSpace::Application, deadline + Duration::from_millis(50), &AckFrame::from_ranges(&[(5, 5)], 0)).unwrap();
+
+        assert_eq!(recovery.congestion_window(), window + MAX_DATAGRAM_SIZE * 100 / window);
+    }
+
+    #[test]
+    fn unlimited_ignores_loss() {
+        let mut recovery = Recovery::with_congestion_controller(Box::new(Unlimited));
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            send(&mut recovery, PacketNumberSpace::Application, now);
+        }
+
+        recovery.on_ack_received(PacketNumberSpace::Application, now + Duration::from_millis(50), &AckFrame::from_ranges(&[(4, 4)], 0)).unwrap();
+
+        assert_eq!(recovery.congestion_window(), usize::MAX);
+        assert!(recovery.can_send());
+    }
+
+    #[test]
+    fn probe_timeout_backs_off() {
+        let mut recovery = Recovery::new();
+        let now = Instant::now();
+
+        send(&mut recovery, PacketNumberSpace::Initial, now);
+
+        let first = recovery.next_timeout().unwrap();
+        // The smoothed RTT plus four times the initial variance of half of it.
+        assert_eq!(first, now + Duration::from_millis(INITIAL_RTT_MS) * 3);
+
+        assert!(recovery.on_timeout(first - Duration::from_millis(1)).is_none());
+
+        match recovery.on_timeout(first) {
+            Some(Expiry::Probe(PacketNumberSpace::Initial)) => {},
+            other => panic!("unexpected expiry {:?}", other),
+        }
+
+        assert_eq!(recovery.next_timeout().unwrap() - now, (first - now) * 2);
+    }
+}
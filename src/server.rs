@@ -0,0 +1,527 @@
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use byteorder::{ByteOrder, BigEndian};
+use bytes::{Bytes, BytesMut};
+
+use error::Result;
+
+use connection::ConnectionConfig;
+use connection::QuicConnection;
+use connection::Side;
+
+use crypto::TokenKey;
+
+use header::QuicHeader;
+use header::LongHeader;
+
+use packet::QuicPacket;
+use packet::QuicPayload;
+use packet::CLIENT_CLEARTEXT;
+use packet::RETRY;
+
+use version;
+use version::QuicVersion;
+use version::DRAFT_05;
+
+/// Until a client's address is validated, the server sends it at most this
+/// many times what it has received from it.
+pub const AMPLIFICATION_FACTOR: usize = 3;
+
+/// Client Initials in smaller datagrams are dropped before anything is
+/// sent back or set up for them.
+pub const MIN_INITIAL_SIZE: usize = 1200;
+
+/// Retry and Version Negotiation packets waiting to go out. Any more are
+/// dropped; the clients will try again.
+const MAX_STATELESS_REPLIES: usize = 64;
+
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    /// Used for every connection the server accepts.
+    pub connection: ConnectionConfig,
+    /// With a key set, a Client Initial without a token is answered with a
+    /// Retry instead of a connection, and the client has to prove it can
+    /// receive at its address by sending the token back. Only versions
+    /// with Retry are validated this way.
+    pub token_key: Option<Arc<TokenKey>>,
+}
+
+#[derive(Debug)]
+struct Peer {
+    address: SocketAddr,
+    connection: QuicConnection,
+    validated: bool,
+    bytes_received: usize,
+    bytes_sent: usize,
+}
+
+impl Peer {
+    /// How large a datagram the peer has earned us the right to send it.
+    fn send_limit(&mut self) -> usize {
+        // Completing the handshake shows the peer saw what we sent it.
+        if self.connection.is_open() {
+            self.validated = true;
+        }
+
+        if self.validated {
+            self.connection.max_packet_size()
+        } else {
+            (AMPLIFICATION_FACTOR * self.bytes_received).saturating_sub(self.bytes_sent)
+        }
+    }
+}
+
+/// Server endpoint. Like `QuicConnection` it performs no I/O: the owner
+/// hands it every datagram arriving on the socket along with where it came
+/// from, and sends what `poll_datagram` returns.
+#[derive(Debug)]
+pub struct QuicServer {
+    pub config: ServerConfig,
+    connections: BTreeMap<u64, Peer>,
+    incoming: VecDeque<u64>,
+    /// Packets answered without setting up a connection, with the version
+    /// to write them in.
+    stateless: VecDeque<(SocketAddr, &'static dyn QuicVersion, QuicPacket)>,
+    /// Where `poll_datagram` starts looking, so that every connection gets
+    /// its turn.
+    next_peer: u64,
+}
+
+impl QuicServer {
+    /// Connections are accepted in any of the supported versions, whatever
+    /// `config.connection.version` says.
+    pub fn new(config: ServerConfig) -> Result<QuicServer> {
+        Ok(QuicServer {
+            config: config,
+            connections: BTreeMap::new(),
+            incoming: VecDeque::new(),
+            stateless: VecDeque::new(),
+            next_peer: 0,
+        })
+    }
+
+    /// Routes a datagram to its connection, or starts a new one for a
+    /// Client Initial. A Client Initial in a version we don't speak is
+    /// answered with Version Negotiation, and one carrying a token that
+    /// doesn't check out is dropped with `QUIC_ADDRESS_VALIDATION_FAILURE`.
+    /// Client Initials under `MIN_INITIAL_SIZE` and datagrams for no known
+    /// connection are dropped.
+    pub fn on_datagram(&mut self, now: Instant, remote: SocketAddr, buf: BytesMut) -> Result<()> {
+        let connection_id = match connection_id(&buf) {
+            Some(connection_id) => connection_id,
+            None => return Ok(()),
+        };
+
+        if let Some(peer) = self.connections.get_mut(&connection_id) {
+            peer.bytes_received += buf.len();
+
+            return peer.connection.on_datagram(now, buf);
+        }
+
+        // Anything that could start a connection has to fill a datagram, so
+        // that what we send back is no more than three times what we got.
+        if buf[0] & 0x80 == 0 || buf.len() < MIN_INITIAL_SIZE {
+            return Ok(());
+        }
+
+        let version = match version::detect(&buf) {
+            Some(version) => version,
+            None => {
+                // Every version shares the draft-05 long header layout up to
+                // the version field.
+                if let QuicHeader::Long(header) = DRAFT_05.parse_header(&buf)? {
+                    self.reply(remote, &DRAFT_05, version::negotiation_packet(&header));
+                }
+
+                return Ok(());
+            },
+        };
+
+        let header = match version.parse_header(&buf)? {
+            QuicHeader::Long(header) => header,
+            QuicHeader::Short(_) => return Ok(()),
+        };
+
+        if header.packet_type != CLIENT_CLEARTEXT {
+            return Ok(());
+        }
+
+        let validated = match self.config.token_key {
+            Some(ref key) if version.has_retry() && header.token.is_empty() => {
+                let retry = retry_packet(&header, key.issue(now, &remote));
+                self.reply(remote, version, retry);
+                return Ok(());
+            },
+            Some(ref key) if version.has_retry() => {
+                key.validate(now, &remote, &header.token)?;
+                true
+            },
+            _ => false,
+        };
+
+        let config = ConnectionConfig {
+            version: version.number(),
+            ..self.config.connection.clone()
+        };
+
+        let bytes_received = buf.len();
+        let mut connection = QuicConnection::new(Side::Server, connection_id, config, now)?;
+        connection.on_datagram(now, buf)?;
+
+        self.connections.insert(connection_id, Peer {
+            address: remote,
+            connection: connection,
+            validated: validated,
+            bytes_received: bytes_received,
+            bytes_sent: 0,
+        });
+        self.incoming.push_back(connection_id);
+
+        Ok(())
+    }
+
+    fn reply(&mut self, remote: SocketAddr, version: &'static dyn QuicVersion, packet: QuicPacket) {
+        if self.stateless.len() < MAX_STATELESS_REPLIES {
+            self.stateless.push_back((remote, version, packet));
+        }
+    }
+
+    /// Returns the ID of the next connection started by a client, if any.
+    pub fn accept(&mut self) -> Option<u64> {
+        self.incoming.pop_front()
+    }
+
+    pub fn connection(&mut self, connection_id: u64) -> Option<&mut QuicConnection> {
+        self.connections.get_mut(&connection_id).map(|peer| &mut peer.connection)
+    }
+
+    /// Writes the next datagram to send onto the end of `buf` and returns
+    /// where it should go, or None if there is nothing to send. Connections
+    /// take turns, one datagram each.
+    pub fn poll_datagram(&mut self, now: Instant, buf: &mut BytesMut) -> Result<Option<SocketAddr>> {
+        if let Some((address, version, packet)) = self.stateless.pop_front() {
+            packet.encode(version, buf)?;
+            return Ok(Some(address));
+        }
+
+        let ids: Vec<u64> = self.connections.range(self.next_peer..)
+            .chain(self.connections.range(..self.next_peer))
+            .map(|(&connection_id, _)| connection_id)
+            .collect();
+
+        for connection_id in ids {
+            let peer = match self.connections.get_mut(&connection_id) {
+                Some(peer) => peer,
+                None => continue,
+            };
+
+            let limit = peer.send_limit();
+
+            if limit == 0 {
+                continue;
+            }
+
+            let start = buf.len();
+
+            if peer.connection.poll_datagram_within(now, buf, limit)? {
+                peer.bytes_sent += buf.len() - start;
+                self.next_peer = connection_id.wrapping_add(1);
+                return Ok(Some(peer.address));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.connections.values()
+            .filter_map(|peer| peer.connection.next_timeout())
+            .min()
+    }
+
+    /// Fires expired connection timers and forgets connections that have
+    /// closed. Returns the closed connections, so their IDs and stats are
+    /// still there for whoever is reporting on them.
+    pub fn on_timeout(&mut self, now: Instant) -> Vec<QuicConnection> {
+        for peer in self.connections.values_mut() {
+            match peer.connection.next_timeout() {
+                Some(deadline) if deadline <= now => peer.connection.on_timeout(now),
+                _ => {},
+            }
+        }
+
+        let closed: Vec<u64> = self.connections.iter()
+            .filter(|&(_, peer)| peer.connection.is_closed())
+            .map(|(&connection_id, _)| connection_id)
+            .collect();
+
+        closed.into_iter()
+            .filter_map(|connection_id| self.connections.remove(&connection_id))
+            .map(|peer| peer.connection)
+            .collect()
+    }
+}
+
+/// Both header forms put the connection ID right after the first byte,
+/// where header protection leaves it readable.
+fn connection_id(buf: &[u8]) -> Option<u64> {
+    if buf.len() < 9 || buf[0] & 0xc0 == 0 {
+        return None;
+    }
+
+    Some(BigEndian::read_u64(&buf[1..9]))
+}
+
+fn retry_packet(initial: &LongHeader, token: Bytes) -> QuicPacket {
+    QuicPacket {
+        header: QuicHeader::Long(LongHeader {
+            packet_type: RETRY,
+            connection_id: initial.connection_id,
+            packet_number: initial.packet_number,
+            version: initial.version,
+            token: token,
+        }),
+        payload: QuicPayload::Frames(vec![]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use futures::Sink;
+    use crypto::InitialKeys;
+    use error::QuicError;
+    use error::QUIC_ADDRESS_VALIDATION_FAILURE;
+    use frames::QuicFrame;
+    use frames::padding_frame::PaddingFrame;
+    use version::DRAFT_05_RETRY;
+
+    fn client_address() -> SocketAddr {
+        "192.0.2.1:4433".parse().unwrap()
+    }
+
+    fn client_initial(version: &'static dyn QuicVersion, connection_id: u64, token: Bytes, padding: usize) -> BytesMut {
+        let packet = QuicPacket {
+            header: QuicHeader::Long(LongHeader {
+                packet_type: CLIENT_CLEARTEXT,
+                connection_id: connection_id,
+                packet_number: 1,
+                version: version.number(),
+                token: token,
+            }),
+            payload: QuicPayload::Frames(vec![QuicFrame::Padding(PaddingFrame { length: padding })]),
+        };
+
+        let keys = InitialKeys::from_connection_id(Side::Client, version.initial_salt(), connection_id).unwrap();
+
+        let mut buf = BytesMut::new();
+        packet.seal(version, &keys.local, &mut buf).unwrap();
+
+        buf
+    }
+
+    fn retrying_server(now: Instant) -> QuicServer {
+        QuicServer::new(ServerConfig {
+            token_key: Some(Arc::new(TokenKey::new(&[9; 32], now, Duration::from_secs(10)))),
+            ..ServerConfig::default()
+        }).unwrap()
+    }
+
+    #[test]
+    fn accepts_client_initial() {
+        let now = Instant::now();
+        let mut server = QuicServer::new(ServerConfig::default()).unwrap();
+
+        server.on_datagram(now, client_address(), client_initial(&DRAFT_05, 42, Bytes::new(), 1200)).unwrap();
+
+        assert_eq!(server.accept(), Some(42));
+        assert!(server.connection(42).is_some());
+        assert_eq!(server.accept(), None);
+    }
+
+    #[test]
+    fn returns_connections_closed_by_timeout() {
+        let now = Instant::now();
+        let mut server = QuicServer::new(ServerConfig::default()).unwrap();
+
+        server.on_datagram(now, client_address(), client_initial(&DRAFT_05, 42, Bytes::new(), 1200)).unwrap();
+        assert!(server.on_timeout(now).is_empty());
+
+        let idle = server.connection(42).unwrap().idle_timeout();
+        let closed = server.on_timeout(now + idle);
+
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].connection_id, 42);
+        assert!(server.connection(42).is_none());
+    }
+
+    #[test]
+    fn drops_small_client_initial() {
+        let now = Instant::now();
+        let mut server = retrying_server(now);
+
+        server.on_datagram(now, client_address(), client_initial(&DRAFT_05_RETRY, 42, Bytes::new(), 1000)).unwrap();
+
+        let mut buf = BytesMut::new();
+        assert_eq!(server.poll_datagram(now, &mut buf).unwrap(), None);
+        assert_eq!(server.accept(), None);
+    }
+
+    #[test]
+    fn retry_then_accept_with_token() {
+        let now = Instant::now();
+        let mut server = retrying_server(now);
+
+        server.on_datagram(now, client_address(), client_initial(&DRAFT_05_RETRY, 42, Bytes::new(), 1200)).unwrap();
+        assert_eq!(server.accept(), None);
+
+        let mut buf = BytesMut::new();
+        assert_eq!(server.poll_datagram(now, &mut buf).unwrap(), Some(client_address()));
+
+        let retry = QuicPacket::decode(&DRAFT_05_RETRY, &buf.freeze()).unwrap();
+        let token = match retry.header {
+            QuicHeader::Long(ref header) if header.packet_type == RETRY => header.token.clone(),
+            ref header => panic!("expected a Retry, got {:?}", header),
+        };
+
+        server.on_datagram(now, client_address(), client_initial(&DRAFT_05_RETRY, 42, token, 1200)).unwrap();
+        assert_eq!(server.accept(), Some(42));
+    }
+
+    #[test]
+    fn draft_05_is_not_retried() {
+        let now = Instant::now();
+        let mut server = retrying_server(now);
+
+        server.on_datagram(now, client_address(), client_initial(&DRAFT_05, 42, Bytes::new(), 1200)).unwrap();
+        assert_eq!(server.accept(), Some(42));
+    }
+
+    #[test]
+    fn rejects_token_from_other_address() {
+        let now = Instant::now();
+        let mut server = retrying_server(now);
+
+        let token = server.config.token_key.as_ref().unwrap().issue(now, &"192.0.2.2:4433".parse().unwrap());
+
+        match server.on_datagram(now, client_address(), client_initial(&DRAFT_05_RETRY, 42, token, 1200)) {
+            Err(QuicError::TransportError(error)) => assert_eq!(error, QUIC_ADDRESS_VALIDATION_FAILURE),
+            other => panic!("expected an address validation failure, got {:?}", other),
+        }
+
+        assert_eq!(server.accept(), None);
+        assert!(server.connection(42).is_none());
+    }
+
+    #[test]
+    fn caps_stateless_replies() {
+        let now = Instant::now();
+        let mut server = retrying_server(now);
+
+        for connection_id in 0..MAX_STATELESS_REPLIES as u64 + 10 {
+            let initial = client_initial(&DRAFT_05_RETRY, connection_id, Bytes::new(), 1200);
+            server.on_datagram(now, client_address(), initial).unwrap();
+        }
+
+        let mut buf = BytesMut::new();
+        let mut replies = 0;
+
+        while server.poll_datagram(now, &mut buf).unwrap().is_some() {
+            replies += 1;
+        }
+
+        assert_eq!(replies, MAX_STATELESS_REPLIES);
+    }
+
+    #[test]
+    fn negotiates_unknown_version() {
+        let now = Instant::now();
+        let mut server = QuicServer::new(ServerConfig::default()).unwrap();
+
+        let header = LongHeader {
+            packet_type: CLIENT_CLEARTEXT,
+            connection_id: 42,
+            packet_number: 1,
+            version: 0x1a2a3a4a,
+            token: Bytes::new(),
+        };
+
+        let mut datagram = BytesMut::from(header.as_bytes());
+        datagram.extend_from_slice(&[0; MIN_INITIAL_SIZE]);
+        server.on_datagram(now, client_address(), datagram).unwrap();
+        assert_eq!(server.accept(), None);
+
+        let mut buf = BytesMut::new();
+        assert_eq!(server.poll_datagram(now, &mut buf).unwrap(), Some(client_address()));
+
+        match QuicPacket::decode(&DRAFT_05, &buf.freeze()).unwrap().payload {
+            QuicPayload::VersionNegotiation(ref payload) => {
+                assert_eq!(payload.versions, vec![DRAFT_05.number(), DRAFT_05_RETRY.number()]);
+            },
+            ref payload => panic!("expected Version Negotiation, got {:?}", payload),
+        }
+    }
+
+    /// Queues more handshake data on connection 42 than one datagram takes.
+    fn queue_handshake_data(server: &mut QuicServer, connection_id: u64) {
+        let stream = server.connection(connection_id).unwrap().stream(0).unwrap();
+        stream.start_send(Bytes::from(vec![0; 10000])).unwrap();
+    }
+
+    #[test]
+    fn limits_sending_to_unvalidated_address() {
+        let now = Instant::now();
+        let mut server = QuicServer::new(ServerConfig::default()).unwrap();
+
+        let initial = client_initial(&DRAFT_05, 42, Bytes::new(), 1300);
+        let credit = AMPLIFICATION_FACTOR * initial.len();
+
+        server.on_datagram(now, client_address(), initial).unwrap();
+        queue_handshake_data(&mut server, 42);
+
+        let mut sent = Vec::new();
+        let mut buf = BytesMut::new();
+
+        while let Some(address) = server.poll_datagram(now, &mut buf).unwrap() {
+            assert_eq!(address, client_address());
+            sent.push(buf.len());
+            buf.clear();
+        }
+
+        // The last datagram uses up what is left rather than waiting for a
+        // full packet's worth.
+        assert_eq!(sent.iter().sum::<usize>(), credit);
+        assert!(*sent.last().unwrap() < server.connection(42).unwrap().max_packet_size());
+
+        // Each datagram from the client earns some more.
+        server.on_datagram(now, client_address(), client_initial(&DRAFT_05, 42, Bytes::new(), 1300)).unwrap();
+        assert_eq!(server.poll_datagram(now, &mut buf).unwrap(), Some(client_address()));
+    }
+
+    #[test]
+    fn connections_take_turns() {
+        let now = Instant::now();
+        let mut server = QuicServer::new(ServerConfig::default()).unwrap();
+        let other_address: SocketAddr = "192.0.2.2:4433".parse().unwrap();
+
+        server.on_datagram(now, client_address(), client_initial(&DRAFT_05, 42, Bytes::new(), 1200)).unwrap();
+        server.on_datagram(now, other_address, client_initial(&DRAFT_05, 43, Bytes::new(), 1200)).unwrap();
+        queue_handshake_data(&mut server, 42);
+        queue_handshake_data(&mut server, 43);
+
+        let mut buf = BytesMut::new();
+        let mut addresses = Vec::new();
+
+        for _ in 0..4 {
+            addresses.push(server.poll_datagram(now, &mut buf).unwrap().unwrap());
+            buf.clear();
+        }
+
+        assert_eq!(addresses, vec![client_address(), other_address, client_address(), other_address]);
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::Debug;
+
+use transport_parameters::TransportParameters;
+
+/// Everything a client keeps from an earlier connection in order to resume
+/// it and send 0-RTT data on the next one.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SessionTicket {
+    pub ticket: Vec<u8>,
+    /// The server's transport parameters from the connection that issued
+    /// the ticket. Early data must respect these limits.
+    pub transport_parameters: TransportParameters,
+}
+
+/// Client-side store of resumption tickets, keyed by server name.
+#[derive(Debug, Default)]
+pub struct SessionCache {
+    tickets: HashMap<String, SessionTicket>,
+}
+
+impl SessionCache {
+    pub fn new() -> SessionCache {
+        SessionCache::default()
+    }
+
+    pub fn insert(&mut self, server_name: &str, ticket: SessionTicket) {
+        self.tickets.insert(server_name.to_string(), ticket);
+    }
+
+    /// Tickets are single-use, so this removes the ticket from the cache.
+    pub fn take(&mut self, server_name: &str) -> Option<SessionTicket> {
+        self.tickets.remove(server_name)
+    }
+}
+
+/// Server-side policy deciding whether early data presented under a ticket
+/// may be accepted. 0-RTT data can be replayed by an attacker, so
+/// implementations should refuse tickets they have already seen.
+pub trait AntiReplay: Debug + Send {
+    fn accept_early_data(&mut self, ticket: &[u8]) -> bool;
+}
+
+/// Accepts early data at most once per ticket.
+#[derive(Debug, Default)]
+pub struct SingleUseTickets {
+    seen: HashSet<Vec<u8>>,
+}
+
+impl AntiReplay for SingleUseTickets {
+    fn accept_early_data(&mut self, ticket: &[u8]) -> bool {
+        self.seen.insert(ticket.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_tickets_are_single_use() {
+        let ticket = SessionTicket {
+            ticket: vec![1, 2, 3],
+            transport_parameters: TransportParameters::default(),
+        };
+
+        let mut cache = SessionCache::new();
+        cache.insert("example.com", ticket.clone());
+
+        assert_eq!(cache.take("example.com"), Some(ticket));
+        assert_eq!(cache.take("example.com"), None);
+    }
+
+    #[test]
+    fn single_use_tickets_reject_replay() {
+        let mut anti_replay = SingleUseTickets::default();
+
+        assert!(anti_replay.accept_early_data(&[4, 5, 6]));
+        assert!(!anti_replay.accept_early_data(&[4, 5, 6]));
+        assert!(anti_replay.accept_early_data(&[7]));
+    }
+}
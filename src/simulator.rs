@@ -0,0 +1,478 @@
+//! Deterministic network simulation for tests.
+
+use std::cmp;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use futures::{Async, Sink, Stream};
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use error::Result;
+
+use connection::ConnectionConfig;
+use connection::QuicConnection;
+use connection::Side;
+
+use insecure_handshake;
+use insecure_handshake::Hello;
+use insecure_handshake::RANDOM_LEN;
+
+/// How a simulated link treats the datagrams sent over it. Loss,
+/// duplication and reordering are probabilities between 0 and 1.
+#[derive(Debug, Clone)]
+pub struct LinkConfig {
+    /// One-way delay every datagram sees.
+    pub delay: Duration,
+    /// Up to this much extra delay, picked at random per datagram.
+    pub jitter: Duration,
+    pub loss: f64,
+    pub duplication: f64,
+    /// Chance that a datagram is held back by another `delay`, so that
+    /// datagrams sent after it overtake it.
+    pub reordering: f64,
+    /// Bytes per second the link can carry. Datagrams queue behind each
+    /// other when it is full.
+    pub bandwidth: Option<u64>,
+}
+
+impl Default for LinkConfig {
+    fn default() -> LinkConfig {
+        LinkConfig {
+            delay: Duration::from_millis(10),
+            jitter: Duration::from_millis(0),
+            loss: 0.0,
+            duplication: 0.0,
+            reordering: 0.0,
+            bandwidth: None,
+        }
+    }
+}
+
+/// What happened to the datagrams sent over a link.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkStats {
+    pub sent: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub delivered: u64,
+    pub bytes_delivered: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct InFlight {
+    arrival: Instant,
+    sequence: u64,
+    datagram: Bytes,
+}
+
+// Reversed so that the heap pops the earliest arrival first, and datagrams
+// arriving together come out in the order they were sent.
+impl Ord for InFlight {
+    fn cmp(&self, other: &InFlight) -> cmp::Ordering {
+        (other.arrival, other.sequence).cmp(&(self.arrival, self.sequence))
+    }
+}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &InFlight) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// One direction of a simulated network path.
+#[derive(Debug)]
+pub struct Link {
+    pub config: LinkConfig,
+    pub stats: LinkStats,
+    rng: XorShiftRng,
+    in_flight: BinaryHeap<InFlight>,
+    next_sequence: u64,
+    busy_until: Option<Instant>,
+}
+
+impl Link {
+    pub fn new(config: LinkConfig, seed: u64) -> Link {
+        // XorShift needs a seed that isn't all zeroes.
+        let rng = XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, 0x9e37_79b9, 0x7f4a_7c15]);
+
+        Link {
+            config: config,
+            stats: LinkStats::default(),
+            rng: rng,
+            in_flight: BinaryHeap::new(),
+            next_sequence: 0,
+            busy_until: None,
+        }
+    }
+
+    pub fn send(&mut self, now: Instant, datagram: Bytes) {
+        self.stats.sent += 1;
+
+        if self.chance(self.config.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+
+        let departure = match self.config.bandwidth {
+            Some(bandwidth) => {
+                let start = cmp::max(now, self.busy_until.unwrap_or(now));
+                let departure = start + Duration::from_nanos(datagram.len() as u64 * 1_000_000_000 / bandwidth);
+                self.busy_until = Some(departure);
+
+                departure
+            },
+            None => now,
+        };
+
+        if self.chance(self.config.duplication) {
+            self.stats.duplicated += 1;
+            self.schedule(departure, datagram.clone());
+        }
+
+        self.schedule(departure, datagram);
+    }
+
+    fn schedule(&mut self, departure: Instant, datagram: Bytes) {
+        let mut arrival = departure + self.config.delay + self.jitter();
+
+        if self.chance(self.config.reordering) {
+            self.stats.reordered += 1;
+            arrival += self.config.delay;
+        }
+
+        self.in_flight.push(InFlight {
+            arrival: arrival,
+            sequence: self.next_sequence,
+            datagram: datagram,
+        });
+        self.next_sequence += 1;
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen::<f64>() < probability
+    }
+
+    fn jitter(&mut self) -> Duration {
+        let jitter = self.config.jitter;
+        let max_nanos = jitter.as_secs() * 1_000_000_000 + jitter.subsec_nanos() as u64;
+
+        if max_nanos == 0 {
+            return Duration::from_nanos(0);
+        }
+
+        Duration::from_nanos(self.rng.gen_range(0, max_nanos + 1))
+    }
+
+    pub fn next_arrival(&self) -> Option<Instant> {
+        self.in_flight.peek().map(|in_flight| in_flight.arrival)
+    }
+
+    /// Takes the next datagram that has arrived by `now`.
+    pub fn poll(&mut self, now: Instant) -> Option<Bytes> {
+        match self.next_arrival() {
+            Some(arrival) if arrival <= now => {},
+            _ => return None,
+        }
+
+        let in_flight = self.in_flight.pop()?;
+
+        self.stats.delivered += 1;
+        self.stats.bytes_delivered += in_flight.datagram.len() as u64;
+
+        Some(in_flight.datagram)
+    }
+}
+
+/// Connects a client and a server connection over a pair of simulated
+/// links. Time only moves when the simulator moves it, and every random
+/// choice comes from the seed, so a run can be repeated exactly.
+#[derive(Debug)]
+pub struct Simulator {
+    pub client: QuicConnection,
+    pub server: QuicConnection,
+    pub to_server: Link,
+    pub to_client: Link,
+    now: Instant,
+}
+
+impl Simulator {
+    /// Both directions use `link`. The clock starts at `start`, which
+    /// should be the time the connections were created with.
+    pub fn new(client: QuicConnection, server: QuicConnection, link: LinkConfig, seed: u64, start: Instant) -> Simulator {
+        Simulator {
+            client: client,
+            server: server,
+            to_server: Link::new(link.clone(), seed),
+            to_client: Link::new(link, seed.wrapping_add(1)),
+            now: start,
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Runs the connections until `deadline`, moving the clock from one
+    /// event to the next.
+    pub fn run_until(&mut self, deadline: Instant) {
+        loop {
+            self.transmit();
+
+            match self.next_event() {
+                Some(time) if time <= deadline => {
+                    self.now = cmp::max(self.now, time);
+                    self.process();
+                },
+                _ => break,
+            }
+        }
+
+        self.now = cmp::max(self.now, deadline);
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        let deadline = self.now + duration;
+        self.run_until(deadline);
+    }
+
+    /// Runs until `done` returns true, or gives up once `limit` has passed.
+    /// Returns whether `done` was satisfied.
+    pub fn run_while_not<F>(&mut self, limit: Duration, mut done: F) -> bool
+        where F: FnMut(&mut Simulator) -> bool
+    {
+        let deadline = self.now + limit;
+
+        loop {
+            if done(self) {
+                return true;
+            }
+
+            self.transmit();
+
+            match self.next_event() {
+                Some(time) if time <= deadline => {
+                    self.now = cmp::max(self.now, time);
+                    self.process();
+                },
+                _ => {
+                    self.now = cmp::max(self.now, deadline);
+                    return done(self);
+                },
+            }
+        }
+    }
+
+    fn transmit(&mut self) {
+        let now = self.now;
+
+        transmit(&mut self.client, &mut self.to_server, now);
+        transmit(&mut self.server, &mut self.to_client, now);
+    }
+
+    fn next_event(&self) -> Option<Instant> {
+        [
+            self.to_server.next_arrival(),
+            self.to_client.next_arrival(),
+            self.client.next_timeout(),
+            self.server.next_timeout(),
+        ].iter().filter_map(|&time| time).min()
+    }
+
+    fn process(&mut self) {
+        let now = self.now;
+
+        // Errors close the connection, which is what a test wants to see;
+        // there is nothing more to do with them here.
+        while let Some(datagram) = self.to_server.poll(now) {
+            let _ = self.server.on_datagram(now, BytesMut::from(datagram));
+        }
+
+        while let Some(datagram) = self.to_client.poll(now) {
+            let _ = self.client.on_datagram(now, BytesMut::from(datagram));
+        }
+
+        for connection in &mut [&mut self.client, &mut self.server] {
+            match connection.next_timeout() {
+                Some(deadline) if deadline <= now => connection.on_timeout(now),
+                _ => {},
+            }
+        }
+    }
+
+    /// A client and server with default configurations, connected over
+    /// `link` by the real handshake.
+    pub fn connected(link: LinkConfig, seed: u64) -> Simulator {
+        let now = Instant::now();
+
+        let client = QuicConnection::new(Side::Client, 1, ConnectionConfig::default(), now).unwrap();
+        let server = QuicConnection::new(Side::Server, 1, ConnectionConfig::default(), now).unwrap();
+
+        let mut sim = Simulator::new(client, server, link, seed, now);
+        assert!(sim.handshake([seed as u8; RANDOM_LEN], Duration::from_secs(10)));
+
+        sim
+    }
+
+    /// Sends `data` on a new client stream and runs until the server has
+    /// read all of it. Returns whether it arrived intact.
+    pub fn transfer(&mut self, data: &[u8]) -> bool {
+        let id = self.client.open_stream().unwrap();
+
+        for chunk in data.chunks(1000) {
+            self.client.stream(id).unwrap().start_send(Bytes::from(chunk)).unwrap();
+        }
+
+        let mut received = Vec::new();
+
+        self.run_while_not(Duration::from_secs(20), |sim| {
+            if let Some(stream) = sim.server.stream(id) {
+                while let Ok(Async::Ready(Some(bytes))) = stream.poll() {
+                    received.extend_from_slice(&bytes);
+                }
+            }
+
+            received.len() >= data.len()
+        }) && received == data
+    }
+
+    /// Runs the stand-in handshake from `insecure_handshake` over the links. The
+    /// client's random is given rather than drawn so that runs repeat.
+    /// Returns whether both ends got through it within `limit`.
+    pub fn handshake(&mut self, random: [u8; RANDOM_LEN], limit: Duration) -> bool {
+        let hello = Hello {
+            random: random,
+            transport_parameters: self.client.config.transport_parameters.clone(),
+        };
+
+        if insecure_handshake::send_hello(&mut self.client, &hello).is_err() {
+            return false;
+        }
+
+        let mut failed = false;
+
+        let done = self.run_while_not(limit, |sim| {
+            sim.answer_hello(&hello).unwrap_or_else(|_| {
+                failed = true;
+                true
+            })
+        });
+
+        done && !failed
+    }
+
+    /// Moves the handshake along on both ends. True once both are open.
+    fn answer_hello(&mut self, hello: &Hello) -> Result<bool> {
+        let now = self.now;
+
+        insecure_handshake::answer(&mut self.server, now)?;
+        insecure_handshake::finish(&mut self.client, hello, now)?;
+
+        Ok(self.client.is_open() && self.server.is_open())
+    }
+}
+
+fn transmit(connection: &mut QuicConnection, link: &mut Link, now: Instant) {
+    loop {
+        let mut buf = BytesMut::new();
+
+        match connection.poll_datagram(now, &mut buf) {
+            Ok(true) => link.send(now, buf.freeze()),
+            _ => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn clean_link_delivers_after_one_way_delay() {
+        let mut sim = Simulator::connected(LinkConfig::default(), 1);
+        let start = sim.now();
+
+        assert!(sim.transfer(&data(3000)));
+        assert_eq!(sim.now() - start, Duration::from_millis(10));
+        assert_eq!(sim.to_server.stats.lost, 0);
+    }
+
+    #[test]
+    fn handshake_survives_a_lost_client_initial() {
+        let now = Instant::now();
+
+        let client = QuicConnection::new(Side::Client, 1, ConnectionConfig::default(), now).unwrap();
+        let server = QuicConnection::new(Side::Server, 1, ConnectionConfig::default(), now).unwrap();
+
+        let mut sim = Simulator::new(client, server, LinkConfig { loss: 1.0, ..LinkConfig::default() }, 1, now);
+        sim.to_client.config.loss = 0.0;
+
+        assert!(!sim.handshake([1; RANDOM_LEN], Duration::from_millis(500)));
+
+        sim.to_server.config.loss = 0.0;
+
+        let hello = Hello { random: [1; RANDOM_LEN], transport_parameters: sim.client.config.transport_parameters.clone() };
+        assert!(sim.run_while_not(Duration::from_secs(10), |sim| sim.answer_hello(&hello).unwrap()));
+        assert!(sim.to_server.stats.lost > 0);
+    }
+
+    #[test]
+    fn recovers_from_an_unreliable_link() {
+        let link = LinkConfig {
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(15),
+            loss: 0.1,
+            duplication: 0.05,
+            reordering: 0.1,
+            ..LinkConfig::default()
+        };
+        let mut sim = Simulator::connected(link, 7);
+
+        assert!(sim.transfer(&data(50000)));
+        assert!(sim.to_server.stats.lost > 0);
+        assert!(sim.to_server.stats.reordered > 0);
+    }
+
+    #[test]
+    fn bandwidth_paces_delivery() {
+        let link = LinkConfig {
+            bandwidth: Some(100000),
+            ..LinkConfig::default()
+        };
+        let mut sim = Simulator::connected(link, 1);
+        let start = sim.now();
+
+        assert!(sim.transfer(&data(50000)));
+
+        // 50kB at 100kB/s takes half a second, plus headers.
+        let elapsed = sim.now() - start;
+        assert!(elapsed > Duration::from_millis(500) && elapsed < Duration::from_millis(600), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let link = LinkConfig {
+            jitter: Duration::from_millis(30),
+            loss: 0.2,
+            duplication: 0.1,
+            reordering: 0.2,
+            ..LinkConfig::default()
+        };
+
+        let run = |seed| {
+            let mut sim = Simulator::connected(link.clone(), seed);
+            let start = sim.now();
+            sim.transfer(&data(20000));
+
+            (sim.now() - start, sim.to_server.stats.clone(), sim.to_client.stats.clone(), sim.client.rtt().smoothed())
+        };
+
+        assert_eq!(run(3), run(3));
+        assert!(run(3) != run(4));
+    }
+}
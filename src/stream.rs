@@ -1,8 +1,15 @@
 //use std::rc::Rc;
 //use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+
+use bytes::{Bytes, BytesMut};
 
 use error::Result;
 use error::QuicError;
+use error::TransportErrorFlag;
+use error::QUIC_MULTIPLE_TERMINATION_OFFSETS;
+use error::QUIC_STREAM_DATA_AFTER_TERMINATION;
 use frames::stream_frame::StreamFrame;
 use futures::Poll;
 use futures::Async;
@@ -12,7 +19,10 @@ use futures::Stream;
 use futures::Sink;
 use futures::StartSend;
 
-#[derive(Debug, PartialEq)]
+/// Most data one STREAM frame can carry, since its length field is 16 bits.
+const MAX_FRAME_DATA: usize = 0xffff;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum StreamState {
     Idle,
     Open,
@@ -24,13 +34,18 @@ pub enum StreamState {
 #[derive(Debug, PartialEq)]
 pub struct QuicStream {
     pub id: u32,
+    /// The connection the stream belongs to, for logging.
+    pub conn_id: u64,
     pub state: StreamState,
     pub max_data: u64,
     pub offset: u64,
     pub send_offset: u64,
     pub frame_queue: Vec<StreamFrame>,
     pub next_offset: u64,
-    prepared_stream: Vec<u8>,
+    pub error: Option<TransportErrorFlag>,
+    /// Where the peer's data ends, once its FIN has arrived.
+    fin_offset: Option<u64>,
+    prepared_stream: VecDeque<Bytes>,
     frames_to_send: Vec<StreamFrame>
 }
 
@@ -38,87 +53,194 @@ impl QuicStream {
     pub fn new(id: u32, max_data: u64) -> Result<QuicStream> {
         Ok(QuicStream {
             id: id,
+            conn_id: 0,
             state: StreamState::Idle,
             max_data: max_data,
             offset: 0,
             frame_queue: Vec::with_capacity(128),
             next_offset: 0,
-            prepared_stream: Vec::with_capacity(1024),
+            error: None,
+            fin_offset: None,
+            prepared_stream: VecDeque::with_capacity(16),
             send_offset: 0,
             frames_to_send: Vec::with_capacity(1024),
         })
     }
 
-    pub fn on_receive_frame(&mut self, frame: &StreamFrame) -> Option<Vec<u8>> {
-        self.frame_queue.push(frame.clone());
+    /// Fails the stream because its connection closed. Data already
+    /// delivered can still be read; after that, reads return the error.
+    pub fn on_connection_error(&mut self, error: TransportErrorFlag) {
+        if self.state == StreamState::Closed {
+            return;
+        }
+
+        debug!(conn_id = self.conn_id, stream_id = self.id, error:? = error; "stream closed by connection error");
+
+        self.state = StreamState::Closed;
+        self.error = Some(error);
+        self.frames_to_send.clear();
+    }
+
+    /// Takes the next frame written to the stream that is waiting to go out.
+    pub fn poll_send_frame(&mut self) -> Option<StreamFrame> {
+        if self.frames_to_send.is_empty() {
+            None
+        } else {
+            Some(self.frames_to_send.remove(0))
+        }
+    }
+
+    /// Queues a received frame and returns whatever data became readable as
+    /// a result. The stream's data is shared with the frames, not copied,
+    /// unless several frames become readable at once. Data past the FIN is
+    /// QUIC_STREAM_DATA_AFTER_TERMINATION, and a FIN that moves the end of
+    /// the stream is QUIC_MULTIPLE_TERMINATION_OFFSETS.
+    pub fn on_receive_frame(&mut self, frame: &StreamFrame) -> Result<Option<Bytes>> {
+        trace!(conn_id = self.conn_id, stream_id = self.id, offset = frame.offset, len = frame.stream_data.len(), fin = frame.fin; "stream frame received");
+
+        let end = frame.offset + frame.stream_data.len() as u64;
+
+        let error = match self.fin_offset {
+            Some(fin_offset) if frame.fin && end != fin_offset => Some(QUIC_MULTIPLE_TERMINATION_OFFSETS),
+            Some(fin_offset) if end > fin_offset => Some(QUIC_STREAM_DATA_AFTER_TERMINATION),
+            None if frame.fin && end < self.received_offset() => Some(QUIC_MULTIPLE_TERMINATION_OFFSETS),
+            _ => None,
+        };
+
+        if let Some(error) = error {
+            debug!(conn_id = self.conn_id, stream_id = self.id, end = end, fin_offset:? = self.fin_offset, error:? = error; "stream data past its end");
+            return Err(QuicError::TransportError(error));
+        }
+
+        if frame.fin {
+            self.fin_offset = Some(end);
+        }
+
+        if !frame.stream_data.is_empty() {
+            self.frame_queue.push(frame.clone());
+        }
+
         self.frame_queue.sort_by_key(|f| f.offset);
         self.frame_queue.dedup_by_key(|f| f.offset);
 
         let mut next_offset = self.next_offset;
 
-        let mut bytes: Vec<u8> = Vec::with_capacity(256);
+        let mut chunks: Vec<Bytes> = Vec::new();
 
         for f in &self.frame_queue {
-            if f.offset == next_offset {
-                bytes.extend(&f.stream_data);
+            if f.offset == next_offset && !f.stream_data.is_empty() {
+                chunks.push(f.stream_data.clone());
 
                 next_offset += f.stream_data.len() as u64;
             }
         }
 
-        if !bytes.is_empty() {
+        if !chunks.is_empty() {
             self.frame_queue = self.frame_queue.iter()
                 .filter(|f| f.offset >= next_offset)
-                .map(|f| f.clone())
+                .cloned()
                 .collect();
 
-            // Set stream state to half-closed (remote) if
-            // we receive a packet with the fin flag.
-            if frame.fin {
-                self.state = StreamState::HalfClosedRemote;
-            }
-
             self.next_offset = next_offset;
-            self.prepared_stream.extend(bytes.clone());
-            Some(bytes)
+            self.prepared_stream.extend(chunks.iter().cloned());
+            self.check_fin();
+
+            if chunks.len() == 1 {
+                Ok(chunks.pop())
+            } else {
+                let mut bytes = BytesMut::with_capacity(chunks.iter().map(|c| c.len()).sum());
+
+                for chunk in &chunks {
+                    bytes.extend_from_slice(chunk);
+                }
+
+                Ok(Some(bytes.freeze()))
+            }
         } else {
-            None
+            self.check_fin();
+            Ok(None)
+        }
+    }
+
+    /// The end of the furthest data received so far.
+    fn received_offset(&self) -> u64 {
+        self.frame_queue.iter()
+            .map(|f| f.offset + f.stream_data.len() as u64)
+            .fold(self.next_offset, |a, b| a.max(b))
+    }
+
+    /// Whether all of the peer's data has arrived, up to its FIN.
+    pub fn is_finished(&self) -> bool {
+        self.fin_offset == Some(self.next_offset)
+    }
+
+    /// Half-closes the stream once everything up to the peer's FIN has
+    /// arrived, whatever order it came in.
+    fn check_fin(&mut self) {
+        if !self.is_finished() {
+            return;
+        }
+
+        let state = match self.state {
+            StreamState::Idle | StreamState::Open => StreamState::HalfClosedRemote,
+            StreamState::HalfClosedLocal => StreamState::Closed,
+            state => state,
+        };
+
+        if state != self.state {
+            debug!(conn_id = self.conn_id, stream_id = self.id, from:? = self.state, to:? = state; "stream finished by peer");
+            self.state = state;
         }
     }
 }
 
 impl Stream for QuicStream {
-    type Item = Vec<u8>;
+    type Item = Bytes;
     type Error = QuicError;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        if self.prepared_stream.is_empty() {
-            Ok(Async::NotReady)
-        } else {
-            let returned_bytes = self.prepared_stream.clone();
-            self.prepared_stream.clear();
-
-            Ok(Async::Ready(Some(returned_bytes)))
+        match self.prepared_stream.pop_front() {
+            Some(bytes) => Ok(Async::Ready(Some(bytes))),
+            None => match self.error {
+                Some(error) => Err(QuicError::TransportError(error)),
+                None if self.is_finished() => Ok(Async::Ready(None)),
+                None => Ok(Async::NotReady),
+            },
         }
     }
 }
 
 impl Sink for QuicStream {
-    type SinkItem = Vec<u8>;
+    type SinkItem = Bytes;
     type SinkError = QuicError;
 
+    /// Queues `item` to be sent, in as many frames as its length needs.
+    /// Fails once the stream is finished on our side.
     fn start_send(&mut self,
-                  item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        let frame = StreamFrame {
-            fin: false,
-            data_length_present: true,
-            data_length: Some(item.len() as u16),
-            stream_id: self.id,
-            offset: self.send_offset,
-            stream_data: item.clone(),
-        };
+                  mut item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if let Some(error) = self.error {
+            return Err(QuicError::TransportError(error));
+        }
+
+        if self.state == StreamState::HalfClosedLocal || self.state == StreamState::Closed {
+            return Err(QuicError::TransportError(QUIC_STREAM_DATA_AFTER_TERMINATION));
+        }
 
-        self.frames_to_send.push(frame);
+        while !item.is_empty() {
+            let data = item.split_to(cmp::min(item.len(), MAX_FRAME_DATA));
+
+            let frame = StreamFrame {
+                fin: false,
+                data_length_present: true,
+                data_length: Some(data.len() as u16),
+                stream_id: self.id,
+                offset: self.send_offset,
+                stream_data: data,
+            };
+
+            self.send_offset += frame.stream_data.len() as u64;
+            self.frames_to_send.push(frame);
+        }
 
         Ok(AsyncSink::Ready)
     }
@@ -126,6 +248,33 @@ impl Sink for QuicStream {
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
         Ok(Async::Ready(()))
     }
+
+    /// Sends a FIN after whatever has been written so far.
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        if let Some(error) = self.error {
+            return Err(QuicError::TransportError(error));
+        }
+
+        let state = match self.state {
+            StreamState::Idle | StreamState::Open => StreamState::HalfClosedLocal,
+            StreamState::HalfClosedRemote => StreamState::Closed,
+            StreamState::HalfClosedLocal | StreamState::Closed => return Ok(Async::Ready(())),
+        };
+
+        self.frames_to_send.push(StreamFrame {
+            fin: true,
+            data_length_present: true,
+            data_length: Some(0),
+            stream_id: self.id,
+            offset: self.send_offset,
+            stream_data: Bytes::new(),
+        });
+
+        debug!(conn_id = self.conn_id, stream_id = self.id, from:? = self.state, to:? = state; "stream finished locally");
+        self.state = state;
+
+        Ok(Async::Ready(()))
+    }
 }
 
 #[cfg(test)]
@@ -140,7 +289,7 @@ mod tests {
             data_length: Some(15),
             stream_id: 1,
             offset: 0,
-            stream_data: vec![1u8; 15],
+            stream_data: Bytes::from(vec![1u8; 15]),
         };
 
         let frame_2 = StreamFrame {
@@ -149,7 +298,7 @@ mod tests {
             data_length: Some(25),
             stream_id: 1,
             offset: 15,
-            stream_data: vec![2u8; 25],
+            stream_data: Bytes::from(vec![2u8; 25]),
         };
 
         let frame_3 = StreamFrame {
@@ -158,7 +307,7 @@ mod tests {
             data_length: Some(45),
             stream_id: 1,
             offset: 40,
-            stream_data: vec![3u8; 45],
+            stream_data: Bytes::from(vec![3u8; 45]),
         };
 
         let frame_4 = StreamFrame {
@@ -167,7 +316,7 @@ mod tests {
             data_length: Some(20),
             stream_id: 1,
             offset: 85,
-            stream_data: vec![4u8; 20],
+            stream_data: Bytes::from(vec![4u8; 20]),
         };
 
         let frame_5 = StreamFrame {
@@ -176,7 +325,7 @@ mod tests {
             data_length: Some(10),
             stream_id: 1,
             offset: 105,
-            stream_data: vec![5u8; 10],
+            stream_data: Bytes::from(vec![5u8; 10]),
         };
 
         let frame_6 = StreamFrame {
@@ -185,7 +334,7 @@ mod tests {
             data_length: Some(15),
             stream_id: 1,
             offset: 115,
-            stream_data: vec![6u8; 15],
+            stream_data: Bytes::from(vec![6u8; 15]),
         };
 
         let frame_7 = StreamFrame {
@@ -194,7 +343,7 @@ mod tests {
             data_length: Some(12),
             stream_id: 1,
             offset: 130,
-            stream_data: vec![7u8; 12],
+            stream_data: Bytes::from(vec![7u8; 12]),
         };
 
         let mut stream = QuicStream::new(1, 250000).unwrap();
@@ -204,35 +353,123 @@ mod tests {
 //            Ok(())
 //        });
 
-        let r_1 = stream.on_receive_frame(&frame_1);
+        let r_1 = stream.on_receive_frame(&frame_1).unwrap();
 
         assert_eq!(frame_1.stream_data, r_1.unwrap());
 
-        let r_3 = stream.on_receive_frame(&frame_3);
+        let r_3 = stream.on_receive_frame(&frame_3).unwrap();
 
         assert_eq!(r_3, None);
 
-        let r_2 = stream.on_receive_frame(&frame_2);
+        let r_2 = stream.on_receive_frame(&frame_2).unwrap();
 
         assert_eq!(r_2.unwrap(), [frame_2.stream_data.clone(), frame_3.stream_data.clone()].concat());
 
-        let r_4 = stream.on_receive_frame(&frame_4);
+        let r_4 = stream.on_receive_frame(&frame_4).unwrap();
 
         assert_eq!(r_4.unwrap(), frame_4.stream_data);
 
-        let r_5 = stream.on_receive_frame(&frame_5);
+        let r_5 = stream.on_receive_frame(&frame_5).unwrap();
 
         assert_eq!(r_5.unwrap(), frame_5.stream_data);
 
-        let r_7 = stream.on_receive_frame(&frame_7);
+        let r_7 = stream.on_receive_frame(&frame_7).unwrap();
 
         assert_eq!(r_7, None);
 
-        let r_6 = stream.on_receive_frame(&frame_6);
+        let r_6 = stream.on_receive_frame(&frame_6).unwrap();
 
         assert_eq!(r_6.unwrap(), [frame_6.stream_data.clone(), frame_7.stream_data.clone()].concat());
+    }
+
+    #[test]
+    fn fin_ends_the_stream() {
+        let data = |offset: u64, bytes: &'static [u8], fin: bool| StreamFrame {
+            fin: fin,
+            data_length_present: true,
+            data_length: Some(bytes.len() as u16),
+            stream_id: 1,
+            offset: offset,
+            stream_data: Bytes::from(bytes),
+        };
+
+        let mut stream = QuicStream::new(1, 1000).unwrap();
+
+        // An empty FIN after a gap only takes effect once the gap fills.
+        stream.on_receive_frame(&data(3, b"", true)).unwrap();
+        assert_eq!(stream.state, StreamState::Idle);
+        assert_eq!(stream.poll().unwrap(), Async::NotReady);
+
+        stream.on_receive_frame(&data(0, b"abc", false)).unwrap();
+        assert_eq!(stream.state, StreamState::HalfClosedRemote);
+        assert_eq!(stream.poll().unwrap(), Async::Ready(Some(Bytes::from(&b"abc"[..]))));
+        assert_eq!(stream.poll().unwrap(), Async::Ready(None));
 
-        println!("stream = {:?}", stream);
+        stream.start_send(Bytes::from(&b"xy"[..])).unwrap();
+        stream.close().unwrap();
+        assert_eq!(stream.state, StreamState::Closed);
 
+        stream.poll_send_frame().unwrap();
+        let fin = stream.poll_send_frame().unwrap();
+        assert!(fin.fin && fin.stream_data.is_empty());
+        assert_eq!(fin.offset, 2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn data_cannot_go_past_the_fin() {
+        let data = |offset: u64, len: usize, fin: bool| StreamFrame {
+            fin: fin,
+            data_length_present: true,
+            data_length: Some(len as u16),
+            stream_id: 1,
+            offset: offset,
+            stream_data: Bytes::from(vec![0; len]),
+        };
+
+        let error = |result: Result<Option<Bytes>>| match result {
+            Err(QuicError::TransportError(code)) => Some(code),
+            _ => None,
+        };
+
+        let mut stream = QuicStream::new(1, 1000).unwrap();
+        stream.on_receive_frame(&data(10, 5, false)).unwrap();
+
+        // A FIN short of data already received.
+        assert_eq!(error(stream.on_receive_frame(&data(0, 5, true))), Some(QUIC_MULTIPLE_TERMINATION_OFFSETS));
+
+        stream.on_receive_frame(&data(0, 5, false)).unwrap();
+        stream.on_receive_frame(&data(5, 15, true)).unwrap();
+
+        assert_eq!(error(stream.on_receive_frame(&data(15, 10, false))), Some(QUIC_STREAM_DATA_AFTER_TERMINATION));
+        assert_eq!(error(stream.on_receive_frame(&data(15, 3, true))), Some(QUIC_MULTIPLE_TERMINATION_OFFSETS));
+
+        // Repeats of what the peer already sent are fine.
+        stream.on_receive_frame(&data(5, 15, true)).unwrap();
+        stream.on_receive_frame(&data(0, 5, false)).unwrap();
+    }
+
+    #[test]
+    fn large_writes_are_split_into_frames() {
+        let mut stream = QuicStream::new(1, 1000).unwrap();
+        stream.start_send(Bytes::from(vec![1; MAX_FRAME_DATA + 10])).unwrap();
+
+        let first = stream.poll_send_frame().unwrap();
+        let second = stream.poll_send_frame().unwrap();
+
+        assert_eq!(first.data_length, Some(MAX_FRAME_DATA as u16));
+        assert_eq!(second.data_length, Some(10));
+        assert_eq!(second.offset, MAX_FRAME_DATA as u64);
+        assert!(stream.poll_send_frame().is_none());
+    }
+
+    #[test]
+    fn no_writes_after_close() {
+        let mut stream = QuicStream::new(1, 1000).unwrap();
+        stream.close().unwrap();
+
+        match stream.start_send(Bytes::from(&b"late"[..])) {
+            Err(QuicError::TransportError(code)) => assert_eq!(code, QUIC_STREAM_DATA_AFTER_TERMINATION),
+            result => panic!("expected an error, got {:?}", result),
+        }
+    }
+}
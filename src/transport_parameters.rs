@@ -0,0 +1,142 @@
+use std::io::Cursor;
+use std::io::Read;
+
+use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
+
+use error::Result;
+use error::QuicError;
+
+pub const INITIAL_MAX_STREAM_DATA: u16 = 0x0000;
+pub const INITIAL_MAX_DATA: u16 = 0x0001;
+pub const INITIAL_MAX_STREAM_ID: u16 = 0x0002;
+pub const IDLE_TIMEOUT: u16 = 0x0003;
+
+/// Upper bound on the idle timeout either endpoint may advertise, in seconds.
+pub const MAX_IDLE_TIMEOUT: u16 = 600;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TransportParameters {
+    pub initial_max_stream_data: u32,
+    pub initial_max_data: u32,
+    pub initial_max_stream_id: u32,
+    /// Idle timeout in seconds.
+    pub idle_timeout: u16,
+}
+
+impl Default for TransportParameters {
+    fn default() -> TransportParameters {
+        TransportParameters {
+            initial_max_stream_data: 65536,
+            initial_max_data: 1024,
+            initial_max_stream_id: 100,
+            idle_timeout: 30,
+        }
+    }
+}
+
+impl TransportParameters {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut params = Vec::with_capacity(26);
+
+        write_parameter(&mut params, INITIAL_MAX_STREAM_DATA, &u32_bytes(self.initial_max_stream_data));
+        write_parameter(&mut params, INITIAL_MAX_DATA, &u32_bytes(self.initial_max_data));
+        write_parameter(&mut params, INITIAL_MAX_STREAM_ID, &u32_bytes(self.initial_max_stream_id));
+
+        let mut idle_timeout = Vec::with_capacity(2);
+        idle_timeout.write_u16::<BigEndian>(self.idle_timeout);
+        write_parameter(&mut params, IDLE_TIMEOUT, &idle_timeout);
+
+        let mut bytes = Vec::with_capacity(2 + params.len());
+        bytes.write_u16::<BigEndian>(params.len() as u16);
+        bytes.extend(params);
+
+        bytes
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<TransportParameters> {
+        let mut reader = Cursor::new(buf);
+
+        let params_len = reader.read_u16::<BigEndian>()? as u64;
+
+        if params_len > (buf.len() as u64) - 2 {
+            return Err(QuicError::ParseError);
+        }
+
+        let mut params = TransportParameters::default();
+        let mut seen_idle_timeout = false;
+
+        while reader.position() < 2 + params_len {
+            let id = reader.read_u16::<BigEndian>()?;
+            let len = reader.read_u16::<BigEndian>()?;
+
+            let mut value = Vec::with_capacity(len as usize);
+            reader.by_ref().take(len as u64).read_to_end(&mut value)?;
+
+            if value.len() != len as usize {
+                return Err(QuicError::ParseError);
+            }
+
+            let mut value_reader = Cursor::new(&value);
+
+            match id {
+                INITIAL_MAX_STREAM_DATA => params.initial_max_stream_data = value_reader.read_u32::<BigEndian>()?,
+                INITIAL_MAX_DATA => params.initial_max_data = value_reader.read_u32::<BigEndian>()?,
+                INITIAL_MAX_STREAM_ID => params.initial_max_stream_id = value_reader.read_u32::<BigEndian>()?,
+                IDLE_TIMEOUT => {
+                    params.idle_timeout = value_reader.read_u16::<BigEndian>()?;
+                    seen_idle_timeout = true;
+                },
+                // Unknown parameters are ignored.
+                _ => {},
+            }
+        }
+
+        if !seen_idle_timeout || params.idle_timeout > MAX_IDLE_TIMEOUT {
+            return Err(QuicError::ParseError);
+        }
+
+        Ok(params)
+    }
+}
+
+fn u32_bytes(value: u32) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4);
+    bytes.write_u32::<BigEndian>(value);
+    bytes
+}
+
+fn write_parameter(bytes: &mut Vec<u8>, id: u16, value: &[u8]) {
+    bytes.write_u16::<BigEndian>(id);
+    bytes.write_u16::<BigEndian>(value.len() as u16);
+    bytes.extend(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let params = TransportParameters {
+            initial_max_stream_data: 2352,
+            initial_max_data: 91,
+            initial_max_stream_id: 12,
+            idle_timeout: 45,
+        };
+
+        let params_bytes = params.as_bytes();
+        let parsed_params = TransportParameters::from_bytes(&params_bytes).unwrap();
+
+        assert_eq!(params, parsed_params);
+    }
+
+    #[test]
+    fn rejects_excessive_idle_timeout() {
+        let params = TransportParameters {
+            idle_timeout: MAX_IDLE_TIMEOUT + 1,
+            ..TransportParameters::default()
+        };
+
+        assert!(TransportParameters::from_bytes(&params.as_bytes()).is_err());
+    }
+}
@@ -1,5 +1,8 @@
+use std::io;
 
-#[derive(Debug, Copy, Clone)]
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum OFSize {
     U8,
     U16,
@@ -9,15 +12,114 @@ pub enum OFSize {
 }
 
 pub fn optimal_field_size(num: u64) -> OFSize {
-    if num < u8::max_value() as u64 {
+    if num <= u8::MAX as u64 {
         OFSize::U8
-    } else if num < u16::max_value() as u64 {
+    } else if num <= u16::MAX as u64 {
         OFSize::U16
-    } else if num < u32::max_value() as u64 {
+    } else if num <= u32::MAX as u64 {
         OFSize::U32
-    }  else if num < 2u64.pow(48) - 1 {
+    }  else if num < 1 << 48 {
         OFSize::U48
     } else {
         OFSize::U64
     }
-}
\ No newline at end of file
+}
+
+/// Largest value a variable-length integer can hold (2^62 - 1).
+pub const MAX_VARINT: u64 = (1 << 62) - 1;
+
+/// Number of bytes `num` takes up as a variable-length integer.
+pub fn varint_len(num: u64) -> usize {
+    if num < 1 << 6 {
+        1
+    } else if num < 1 << 14 {
+        2
+    } else if num < 1 << 30 {
+        4
+    } else {
+        8
+    }
+}
+
+/// Reads RFC 9000 variable-length integers: the top two bits of the first
+/// byte give the length (1, 2, 4 or 8 bytes) and the rest is the value.
+pub trait ReadVarint: io::Read {
+    fn read_varint(&mut self) -> io::Result<u64> {
+        let first = self.read_u8()?;
+        let len = 1 << (first >> 6);
+
+        let mut num = (first & 0x3f) as u64;
+
+        for _ in 1..len {
+            num = (num << 8) | self.read_u8()? as u64;
+        }
+
+        Ok(num)
+    }
+}
+
+impl<R: io::Read + ?Sized> ReadVarint for R {}
+
+pub trait WriteVarint: io::Write {
+    /// Writes `num` in the shortest encoding. Values above `MAX_VARINT`
+    /// fail with `InvalidInput`.
+    fn write_varint(&mut self, num: u64) -> io::Result<()> {
+        match varint_len(num) {
+            1 => self.write_u8(num as u8),
+            2 => self.write_u16::<BigEndian>(0x4000 | num as u16),
+            4 => self.write_u32::<BigEndian>(0x8000_0000 | num as u32),
+            _ if num <= MAX_VARINT => self.write_u64::<BigEndian>(0xc000_0000_0000_0000 | num),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "varint out of range")),
+        }
+    }
+}
+
+impl<W: io::Write + ?Sized> WriteVarint for W {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn field_size_includes_largest_value() {
+        assert_eq!(optimal_field_size(0xff), OFSize::U8);
+        assert_eq!(optimal_field_size(0x100), OFSize::U16);
+        assert_eq!(optimal_field_size(0xffff_ffff), OFSize::U32);
+        assert_eq!(optimal_field_size(0xffff_ffff_ffff), OFSize::U48);
+        assert_eq!(optimal_field_size(0x1_0000_0000_0000), OFSize::U64);
+    }
+
+    #[test]
+    fn varint_examples() {
+        // The sample encodings from RFC 9000, appendix A.1.
+        let samples: Vec<(u64, Vec<u8>)> = vec![
+            (151288809941952652, vec![0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c]),
+            (494878333, vec![0x9d, 0x7f, 0x3e, 0x7d]),
+            (15293, vec![0x7b, 0xbd]),
+            (37, vec![0x25]),
+        ];
+
+        for (num, encoded) in samples {
+            let mut bytes = Vec::new();
+            bytes.write_varint(num).unwrap();
+
+            assert_eq!(bytes, encoded);
+            assert_eq!(varint_len(num), encoded.len());
+            assert_eq!(Cursor::new(&encoded).read_varint().unwrap(), num);
+        }
+
+        // Non-minimal encodings are still accepted.
+        assert_eq!(Cursor::new(&[0x40, 0x25]).read_varint().unwrap(), 37);
+    }
+
+    #[test]
+    fn varint_limits() {
+        let mut bytes = Vec::new();
+
+        assert!(bytes.write_varint(MAX_VARINT).is_ok());
+        assert!(bytes.write_varint(MAX_VARINT + 1).is_err());
+        assert_eq!(Cursor::new(&bytes).read_varint().unwrap(), MAX_VARINT);
+        assert!(Cursor::new(&[0x9d, 0x7f]).read_varint().is_err());
+    }
+}
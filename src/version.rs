@@ -0,0 +1,370 @@
+use std::fmt::Debug;
+
+use byteorder::{ByteOrder, BigEndian};
+use bytes::{Bytes, BytesMut};
+
+use error::Result;
+
+use crypto::INITIAL_SALT_V1;
+use crypto::LONG_HEADER_BITS;
+use crypto::SHORT_HEADER_BITS;
+
+use frames::QuicFrame;
+
+use header::QuicHeader;
+use header::LongHeader;
+use header::ShortHeader;
+
+use packet::QuicPacket;
+use packet::ShortPacketType;
+use packet::QuicPayload;
+use packet::VersionNegotiationPayload;
+use packet::VERSION_NEGOTIATION;
+use packet::QUIC_VERSION;
+use packet::QUIC_VERSION_RETRY;
+
+/// One QUIC wire format. Everything that changes between drafts, such as
+/// the header layout, integer encodings and the set of frames, goes
+/// through this so that new versions can sit alongside the old ones.
+pub trait QuicVersion: Debug + Send + Sync {
+    /// The version number carried in long headers.
+    fn number(&self) -> u32;
+
+    /// Whether `buf` starts with a long header naming this version.
+    fn matches(&self, buf: &[u8]) -> bool;
+
+    fn parse_header(&self, buf: &[u8]) -> Result<QuicHeader>;
+
+    fn write_header(&self, header: &QuicHeader, buf: &mut BytesMut);
+
+    /// Parses the frame at the start of `buf` and returns how many bytes
+    /// it took up.
+    fn decode_frame(&self, buf: &Bytes) -> Result<(QuicFrame, usize)>;
+
+    fn write_frame(&self, frame: &QuicFrame, buf: &mut BytesMut);
+
+    /// Salt for deriving the Initial secrets.
+    fn initial_salt(&self) -> &'static [u8];
+
+    /// First-byte bits hidden by header protection.
+    fn protected_bits(&self, first_byte: u8) -> u8 {
+        if first_byte & 0x80 != 0 {
+            LONG_HEADER_BITS
+        } else {
+            SHORT_HEADER_BITS
+        }
+    }
+
+    /// Where the packet number starts in a short header.
+    fn short_packet_number_offset(&self, first_byte: u8) -> usize;
+
+    /// Length of a short header's packet number, from its unprotected first
+    /// byte.
+    fn packet_number_len(&self, first_byte: u8) -> usize;
+
+    /// Size of the header on the wire. Worked out rather than serialized,
+    /// since packets are sized with it as they are built.
+    fn header_len(&self, header: &QuicHeader) -> usize;
+
+    fn frame_len(&self, frame: &QuicFrame) -> usize;
+
+    /// Whether Client Initial and Retry headers carry a token, so that a
+    /// server can answer with Retry.
+    fn has_retry(&self) -> bool {
+        false
+    }
+}
+
+/// draft-ietf-quic-transport-05: 64-bit connection IDs, the version after
+/// the packet number in long headers, and fixed-width frame fields.
+#[derive(Debug)]
+pub struct Draft05;
+
+pub static DRAFT_05: Draft05 = Draft05;
+
+impl QuicVersion for Draft05 {
+    fn number(&self) -> u32 {
+        QUIC_VERSION
+    }
+
+    fn matches(&self, buf: &[u8]) -> bool {
+        buf.len() >= 17 && buf[0] & 0x80 != 0 && BigEndian::read_u32(&buf[13..17]) == QUIC_VERSION
+    }
+
+    fn parse_header(&self, buf: &[u8]) -> Result<QuicHeader> {
+        if !buf.is_empty() && buf[0] & 0x80 != 0 {
+            Ok(QuicHeader::Long(LongHeader::from_bytes(buf)?))
+        } else {
+            Ok(QuicHeader::Short(ShortHeader::from_bytes(buf)?))
+        }
+    }
+
+    fn write_header(&self, header: &QuicHeader, buf: &mut BytesMut) {
+        match *header {
+            QuicHeader::Short(ref header) => header.write_to(buf),
+            QuicHeader::Long(ref header) => header.write_to(buf),
+        }
+    }
+
+    fn decode_frame(&self, buf: &Bytes) -> Result<(QuicFrame, usize)> {
+        QuicFrame::decode(buf)
+    }
+
+    fn write_frame(&self, frame: &QuicFrame, buf: &mut BytesMut) {
+        frame.write_to(buf)
+    }
+
+    fn header_len(&self, header: &QuicHeader) -> usize {
+        header.header_len()
+    }
+
+    fn frame_len(&self, frame: &QuicFrame) -> usize {
+        frame.frame_len()
+    }
+
+    /// Draft-05 has no Initial packet protection of its own, so it borrows
+    /// the version 1 salt.
+    fn initial_salt(&self) -> &'static [u8] {
+        &INITIAL_SALT_V1
+    }
+
+    /// The key phase bit sits outside the low five bits here, so it gets
+    /// covered as well.
+    fn protected_bits(&self, first_byte: u8) -> u8 {
+        if first_byte & 0x80 != 0 {
+            LONG_HEADER_BITS
+        } else {
+            0x20 | SHORT_HEADER_BITS
+        }
+    }
+
+    fn short_packet_number_offset(&self, first_byte: u8) -> usize {
+        if first_byte & 0x40 != 0 {
+            9
+        } else {
+            1
+        }
+    }
+
+    fn packet_number_len(&self, first_byte: u8) -> usize {
+        ShortPacketType::from_bits(first_byte & 0x1f).map_or(4, |packet_type| packet_type.packet_number_len())
+    }
+}
+
+/// Draft-05 with a token after the version in Client Initial and Retry
+/// headers. Everything else is as in `Draft05`.
+#[derive(Debug)]
+pub struct Draft05Retry;
+
+pub static DRAFT_05_RETRY: Draft05Retry = Draft05Retry;
+
+impl QuicVersion for Draft05Retry {
+    fn number(&self) -> u32 {
+        QUIC_VERSION_RETRY
+    }
+
+    fn matches(&self, buf: &[u8]) -> bool {
+        buf.len() >= 17 && buf[0] & 0x80 != 0 && BigEndian::read_u32(&buf[13..17]) == QUIC_VERSION_RETRY
+    }
+
+    fn parse_header(&self, buf: &[u8]) -> Result<QuicHeader> {
+        let mut header = match DRAFT_05.parse_header(buf)? {
+            QuicHeader::Long(header) => header,
+            header => return Ok(header),
+        };
+
+        if header.carries_token() {
+            header.read_token(&buf[header.header_len()..])?;
+        }
+
+        Ok(QuicHeader::Long(header))
+    }
+
+    fn write_header(&self, header: &QuicHeader, buf: &mut BytesMut) {
+        DRAFT_05.write_header(header, buf);
+
+        if let QuicHeader::Long(ref header) = *header {
+            if header.carries_token() {
+                header.write_token(buf);
+            }
+        }
+    }
+
+    fn decode_frame(&self, buf: &Bytes) -> Result<(QuicFrame, usize)> {
+        DRAFT_05.decode_frame(buf)
+    }
+
+    fn write_frame(&self, frame: &QuicFrame, buf: &mut BytesMut) {
+        DRAFT_05.write_frame(frame, buf)
+    }
+
+    fn header_len(&self, header: &QuicHeader) -> usize {
+        match *header {
+            QuicHeader::Long(ref header) if header.carries_token() => header.header_len() + header.token_len(),
+            ref header => DRAFT_05.header_len(header),
+        }
+    }
+
+    fn frame_len(&self, frame: &QuicFrame) -> usize {
+        DRAFT_05.frame_len(frame)
+    }
+
+    fn initial_salt(&self) -> &'static [u8] {
+        DRAFT_05.initial_salt()
+    }
+
+    fn protected_bits(&self, first_byte: u8) -> u8 {
+        DRAFT_05.protected_bits(first_byte)
+    }
+
+    fn short_packet_number_offset(&self, first_byte: u8) -> usize {
+        DRAFT_05.short_packet_number_offset(first_byte)
+    }
+
+    fn packet_number_len(&self, first_byte: u8) -> usize {
+        DRAFT_05.packet_number_len(first_byte)
+    }
+
+    fn has_retry(&self) -> bool {
+        true
+    }
+}
+
+/// The versions we speak, most preferred first.
+pub static SUPPORTED_VERSIONS: &[&dyn QuicVersion] = &[&DRAFT_05, &DRAFT_05_RETRY];
+
+pub fn find(number: u32) -> Option<&'static dyn QuicVersion> {
+    SUPPORTED_VERSIONS.iter().cloned().find(|version| version.number() == number)
+}
+
+/// Works out which of our versions a long-header packet uses.
+pub fn detect(buf: &[u8]) -> Option<&'static dyn QuicVersion> {
+    SUPPORTED_VERSIONS.iter().cloned().find(|version| version.matches(buf))
+}
+
+/// Picks our most preferred version out of those the peer offered.
+pub fn negotiate(offered: &[u32]) -> Option<&'static dyn QuicVersion> {
+    SUPPORTED_VERSIONS.iter().cloned().find(|version| offered.contains(&version.number()))
+}
+
+/// The Version Negotiation packet a server sends in answer to a long
+/// header whose version it doesn't speak.
+pub fn negotiation_packet(header: &LongHeader) -> QuicPacket {
+    QuicPacket {
+        header: QuicHeader::Long(LongHeader {
+            packet_type: VERSION_NEGOTIATION,
+            connection_id: header.connection_id,
+            packet_number: header.packet_number,
+            version: header.version,
+            token: Bytes::new(),
+        }),
+        payload: QuicPayload::VersionNegotiation(VersionNegotiationPayload {
+            versions: SUPPORTED_VERSIONS.iter().map(|version| version.number()).collect(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packet::CLIENT_CLEARTEXT;
+    use packet::RETRY;
+    use frames::ack_frame::AckBlock;
+    use frames::ack_frame::AckFrame;
+
+    fn long_header(version: u32) -> LongHeader {
+        LongHeader {
+            packet_type: CLIENT_CLEARTEXT,
+            connection_id: 7,
+            packet_number: 1,
+            version: version,
+            token: Bytes::new(),
+        }
+    }
+
+    #[test]
+    fn detects_draft_05() {
+        let bytes = long_header(QUIC_VERSION).as_bytes();
+
+        assert_eq!(detect(&bytes).map(|version| version.number()), Some(QUIC_VERSION));
+        assert!(detect(&long_header(0x1a2a3a4a).as_bytes()).is_none());
+    }
+
+    #[test]
+    fn negotiation_round_trip() {
+        let packet = negotiation_packet(&long_header(0x1a2a3a4a));
+
+        let mut buf = BytesMut::new();
+        packet.encode(&DRAFT_05, &mut buf).unwrap();
+        let parsed = QuicPacket::decode(&DRAFT_05, &buf.freeze()).unwrap();
+
+        let offered = match parsed.payload {
+            QuicPayload::VersionNegotiation(ref payload) => payload.versions.clone(),
+            _ => panic!("expected a version negotiation payload"),
+        };
+
+        assert_eq!(negotiate(&offered).map(|version| version.number()), Some(QUIC_VERSION));
+        assert!(negotiate(&[0x1a2a3a4a]).is_none());
+    }
+
+    #[test]
+    fn draft_05_lengths_match_encoding() {
+        let header = QuicHeader::Long(long_header(QUIC_VERSION));
+        let frame = QuicFrame::Ack(AckFrame {
+            num_blocks: Some(1),
+            num_ts: 0,
+            largest_ack: 300,
+            ack_delay: 0,
+            first_ack_len: 2,
+            ack_blocks: Some(vec![AckBlock { gap: 1, block_len: 4 }]),
+            delta_la: None,
+            first_ts: None,
+            timestamps: None,
+        });
+
+        let mut buf = BytesMut::new();
+        DRAFT_05.write_header(&header, &mut buf);
+        assert_eq!(DRAFT_05.header_len(&header), buf.len());
+
+        let mut buf = BytesMut::new();
+        DRAFT_05.write_frame(&frame, &mut buf);
+        assert_eq!(DRAFT_05.frame_len(&frame), buf.len());
+    }
+
+    #[test]
+    fn only_draft_05_retry_carries_tokens() {
+        for &packet_type in &[CLIENT_CLEARTEXT, RETRY] {
+            let header = QuicHeader::Long(LongHeader {
+                packet_type: packet_type,
+                token: Bytes::from(&[0xab; 70][..]),
+                ..long_header(QUIC_VERSION_RETRY)
+            });
+
+            let mut buf = BytesMut::new();
+            DRAFT_05_RETRY.write_header(&header, &mut buf);
+            assert_eq!(buf.len(), DRAFT_05_RETRY.header_len(&header));
+            assert_eq!(buf.len(), 17 + 2 + 70);
+
+            assert_eq!(DRAFT_05_RETRY.parse_header(&buf).unwrap(), header);
+            assert!(DRAFT_05_RETRY.parse_header(&buf[..buf.len() - 1]).is_err());
+
+            // Draft-05 itself has no token field, so leaves it out.
+            let mut buf = BytesMut::new();
+            DRAFT_05.write_header(&header, &mut buf);
+            assert_eq!(buf.len(), 17);
+            assert_eq!(DRAFT_05.header_len(&header), 17);
+        }
+    }
+
+    quickcheck! {
+        fn draft_05_retry_headers_round_trip(header: LongHeader, token: Vec<u8>) -> bool {
+            let token = if header.carries_token() { Bytes::from(token) } else { Bytes::new() };
+            let header = QuicHeader::Long(LongHeader { token: token, ..header });
+
+            let mut buf = BytesMut::new();
+            DRAFT_05_RETRY.write_header(&header, &mut buf);
+
+            buf.len() == DRAFT_05_RETRY.header_len(&header) && DRAFT_05_RETRY.parse_header(&buf).ok() == Some(header)
+        }
+    }
+}